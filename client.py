@@ -8,7 +8,7 @@ HOST = "127.0.0.1"  # The server's hostname or IP address
 PORT = 6480  # The port used by the server
 
 HEADER_BYTE = 0x0F
-TYPE_REGISTER = 0x01
+TYPE_CONNECT = 0x01
 TYPE_PUBLISH = 0x02
 TYPE_SUBSCRIBE = 0x03
 TYPE_UNSUBSCRIBE = 0x04
@@ -23,28 +23,34 @@ SUPPORTED_PUBSUB_VERSIONS = [PUBSUB_VERSION]
 class Header:
     HEADER_BYTE = 0x0F
     PADDING = 0x00
-    REGISTER = 0x01
+    CONNECT = 0x01
     PUBLISH = 0x02
     SUBSCRIBE = 0x03
     UNSUBSCRIBE = 0x04
     QUERY = 0x05
-    REGISTERACK = 0x0A
+    ADMIN = 0x06
+    CONNECTACK = 0x0A
     PUBLISHACK = 0x0B
     SUBSCRIBEACK = 0x0C
     UNSUBSCRIBEACK = 0x0D
     QUERYRESP = 0x0E
+    ERROR = 0x0F
+    ADMINRESP = 0x10
 
     type_dict = {
-        REGISTER: "REGISTER",
+        CONNECT: "CONNECT",
         PUBLISH: "PUBLISH",
         SUBSCRIBE: "SUBSCRIBE",
         UNSUBSCRIBE: "UNSUBSCRIBE",
         QUERY: "QUERY",
-        REGISTERACK: "REGISTERACK",
+        ADMIN: "ADMIN",
+        CONNECTACK: "CONNECTACK",
         PUBLISHACK: "PUBLISHACK",
         SUBSCRIBEACK: "SUBSCRIBEACK",
         UNSUBSCRIBEACK: "UNSUBSCRIBEACK",
         QUERYRESP: "QUERYRESP",
+        ERROR: "ERROR",
+        ADMINRESP: "ADMINRESP",
     }
 
     def __init__(
@@ -132,22 +138,28 @@ class pubsub_client:
         self.recv_thread = threading.Thread(target=self.recv, args=(self.sock,))
         self.should_stop = False
 
+    def recv_exact(self, s: socket.socket, length: int) -> bytes:
+        buf = bytes()
+        while len(buf) < length:
+            chunk = s.recv(length - len(buf))
+            if not chunk:
+                raise Exception("connection closed")
+            buf += chunk
+        return buf
+
     def recv_pkt(self, buf: bytes, s: socket.socket) -> Pkt:
-        # print(buf)
-        topic = ""
-        msg: bytes = bytes()
+        # every packet, the acks included, is followed by exactly
+        # its topic and its message, nothing else.
         header = Header(bytes=buf)
-        # print(header)
-        topic = s.recv(header.topic_length)
-        if header.pkt_type == TYPE_PUBLISH or header.pkt_type == TYPE_QUERYRESP:
-            msg = s.recv(header.length())
+        topic = self.recv_exact(s, header.topic_length)
+        msg = self.recv_exact(s, header.length())
         pkt = Pkt(pkt_type=header.pkt_type, topic=bytes(topic), message=msg)
         return pkt
 
     def recv(self, s: socket.socket, callback: Callable[[str, bytes], None]) -> None:
         try:
             while True:
-                x = s.recv(8)
+                x = self.recv_exact(s, 8)
                 if x:
                     pkt = self.recv_pkt(x, s)
                     callback(pkt.topic.decode("utf-8"), pkt.message)
@@ -213,10 +225,9 @@ class pubsub_client:
             message=message,
         ).bytes()
         self.sock.send(pkt)
-        response = self.sock.recv(8)
-        if response:
-            pkt = self.recv_pkt(response, self.sock)
-            print(f"topic: {pkt.topic}")
+        response = self.recv_exact(self.sock, 8)
+        pkt = self.recv_pkt(response, self.sock)
+        print(f"{Header.type_dict[pkt.header.pkt_type]} topic: {pkt.topic}")
 
     def subscribe(self, topic: str) -> None:
         def recv_callback(topic, msg):
@@ -5,6 +5,8 @@ async fn main() -> Result<(), anyhow::Error> {
         port: 6480,
         cert: None,
         cert_password: None,
+        client_cert: None,
+        client_key: None,
     };
     // initialize the client.
     let mut client =
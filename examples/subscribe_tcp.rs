@@ -1,4 +1,4 @@
-use tokio::select;
+use simple_pub_sub::message::Msg;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -7,6 +7,8 @@ async fn main() -> Result<(), anyhow::Error> {
         port: 6480,
         cert: None,
         cert_password: None,
+        client_cert: None,
+        client_key: None,
     };
 
     // initialize the client.
@@ -14,21 +16,11 @@ async fn main() -> Result<(), anyhow::Error> {
         simple_pub_sub::client::Client::new(simple_pub_sub::client::PubSubClient::Tcp(client_type));
 
     client.connect().await?;
-    // subscribe to the given topic.
-    client.subscribe("abc".to_string()).await?;
+    // handle the messages of the given topic.
+    client.on("abc".to_string(), |msg: Msg| {
+        println!("{}:{:?}", msg.topic, msg.message);
+    });
 
-    loop {
-        select! {
-            msg = client.read_message()=>{
-                match  msg{
-                    Ok(msg)=>{
-                        println!("{}:{:?}", msg.topic, msg.message);
-                    }
-                    Err(e)=>{
-                        println!("Error: {:?}", e);
-                    }
-                }
-            }
-        }
-    }
+    // subscribe and dispatch the messages until the connection is lost.
+    client.run().await
 }
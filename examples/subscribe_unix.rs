@@ -1,4 +1,4 @@
-use tokio::select;
+use simple_pub_sub::message::Msg;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -12,21 +12,11 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // connect the client.
     client.connect().await?;
-    // subscribe to the given topic.
-    client.subscribe("abc".to_string()).await?;
+    // handle the messages of the given topic.
+    client.on("abc".to_string(), |msg: Msg| {
+        println!("{}:{:?}", msg.topic, msg.message);
+    });
 
-    loop {
-        select! {
-            msg = client.read_message()=>{
-                match  msg{
-                    Ok(msg)=>{
-                        println!("{}:{:?}", msg.topic, msg.message);
-                    }
-                    Err(e)=>{
-                        println!("Error: {:?}", e);
-                    }
-                }
-            }
-        }
-    }
+    // subscribe and dispatch the messages until the connection is lost.
+    client.run().await
 }
@@ -93,10 +93,12 @@ impl Header {
     ///```
     pub fn response_header(&self) -> Result<Header> {
         let resp_type: PktType = match self.pkt_type {
+            PktType::CONNECT => PktType::CONNECTACK,
             PktType::SUBSCRIBE => PktType::SUBSCRIBEACK,
             PktType::PUBLISH => PktType::PUBLISHACK,
             PktType::UNSUBSCRIBE => PktType::UNSUBSCRIBEACK,
             PktType::QUERY => PktType::QUERYRESP,
+            PktType::ADMIN => PktType::ADMINRESP,
             _ => {
                 return Err(anyhow!(HeaderError::InvalidResponseType));
             }
@@ -162,13 +164,19 @@ impl TryFrom<&[u8]> for Header {
         }
 
         let pkt_type: PktType = match bytes[PACKET_BYTE] {
+            CONNECT => PktType::CONNECT,
             PUBLISH => PktType::PUBLISH,
             SUBSCRIBE => PktType::SUBSCRIBE,
             UNSUBSCRIBE => PktType::UNSUBSCRIBE,
             QUERY => PktType::QUERY,
+            ADMIN => PktType::ADMIN,
+            CONNECTACK => PktType::CONNECTACK,
             PUBLISHACK => PktType::PUBLISHACK,
             SUBSCRIBEACK => PktType::SUBSCRIBEACK,
+            UNSUBSCRIBEACK => PktType::UNSUBSCRIBEACK,
             QUERYRESP => PktType::QUERYRESP,
+            ERROR => PktType::ERROR,
+            ADMINRESP => PktType::ADMINRESP,
             _ => {
                 bail!(HeaderError::InvalidPacketType);
             }
@@ -177,7 +185,11 @@ impl TryFrom<&[u8]> for Header {
         if bytes[TOPIC_LENGTH_BYTE] == 0 {
             // topic would be absent for the response header
             match pkt_type {
-                PktType::PUBLISH | PktType::SUBSCRIBE | PktType::UNSUBSCRIBE | PktType::QUERY => {
+                PktType::PUBLISH
+                | PktType::SUBSCRIBE
+                | PktType::UNSUBSCRIBE
+                | PktType::QUERY
+                | PktType::ADMIN => {
                     bail!(HeaderError::InvalidTopicLength);
                 }
                 _ => {}
@@ -188,16 +200,22 @@ impl TryFrom<&[u8]> for Header {
         let message_length =
             ((bytes[MESSAGE_LENGTH_BYTE_0] as u16) << 8) | bytes[MESSAGE_LENGTH_BYTE_1] as u16;
 
-        // message length can't be 0 for the publish
-        // or the query packet
+        // message length can't be 0 for the publish,
+        // the query, the admin or the connect packet
         if message_length == 0 {
             match pkt_type {
+                PktType::CONNECT => {
+                    bail!(HeaderError::InvalidMessageLength(0));
+                }
                 PktType::PUBLISH => {
                     bail!(HeaderError::InvalidMessageLength(0));
                 }
                 PktType::QUERY => {
                     bail!(HeaderError::InvalidMessageLength(0));
                 }
+                PktType::ADMIN => {
+                    bail!(HeaderError::InvalidMessageLength(0));
+                }
                 _ => {}
             };
         }
@@ -15,6 +15,8 @@ pub mod constants {
     /// the header length
     pub const HEADER_LEN: usize = 8;
 
+    /// Packet Type Connect
+    pub const CONNECT: u8 = 0x01;
     /// Packet Type Publish
     pub const PUBLISH: u8 = 0x02;
     /// Packet Type Subscribe
@@ -23,6 +25,10 @@ pub mod constants {
     pub const UNSUBSCRIBE: u8 = 0x04;
     /// Packet Type Query
     pub const QUERY: u8 = 0x05;
+    /// Packet Type Admin
+    pub const ADMIN: u8 = 0x06;
+    /// Packet Type Connect Acknowledgement
+    pub const CONNECTACK: u8 = 0x0A;
     /// Packet Type Publish Acknowledgement
     pub const PUBLISHACK: u8 = 0x0B;
     /// Packet Type Subscribe Acknowledgement
@@ -31,6 +37,10 @@ pub mod constants {
     pub const UNSUBSCRIBEACK: u8 = 0x0D;
     /// Packet Type Query Response
     pub const QUERYRESP: u8 = 0x0E;
+    /// Packet Type Error
+    pub const ERROR: u8 = 0x0F;
+    /// Packet Type Admin Response
+    pub const ADMINRESP: u8 = 0x10;
 }
 
 #[cfg(test)]
@@ -71,6 +81,21 @@ mod tests {
         .is_err());
     }
 
+    #[test]
+    fn connect_header_parse_fail() {
+        // The connect packet must carry the credentials
+        // Header { header: 15, version: [0, 1], pkt_type: CONNECT, topic_length: 0, message_length: 0, padding: 0 }
+        assert!(Header::try_from(vec![
+            15, // `HEADER_BYTE`
+            0, 1, // `VERSION_BYTE_0`, `VERSION_BYTE_1`
+            1, // `PktType`
+            0, // `TOPIC_LENGTH_BYTE`
+            0, 0, // `MESSAGE_LENGTH_BYTE_0`, `MESSAGE_LENGTH_BYTE_1`
+            0, // `PADDING_BYTE`
+        ])
+        .is_err());
+    }
+
     #[test]
     fn message_parse_pass() {
         use crate::message::Msg;
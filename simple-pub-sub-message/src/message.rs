@@ -99,7 +99,8 @@ impl Msg {
     }
 }
 
-/// returns a response `Msg`.
+/// returns the bytes of the response packet for the `Msg`,
+/// the response is a complete packet with the topic in it.
 /// ```
 /// use simple_pub_sub_message::message::Msg;
 /// use simple_pub_sub_message::PktType;
@@ -108,9 +109,7 @@ impl Msg {
 /// let response_msg = get_msg_response(msg);
 /// ```
 pub fn get_msg_response(msg: Msg) -> Result<Vec<u8>> {
-    let mut resp: Vec<u8> = msg.response_msg(msg.message.clone())?.bytes();
-    resp.extend(msg.topic.bytes());
-    Ok(resp)
+    Ok(msg.response_msg(msg.message.clone())?.bytes())
 }
 
 impl PartialEq for Msg {
@@ -5,6 +5,8 @@ use std::fmt::Display;
 #[repr(u8)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum PktType {
+    /// connect, carries the credentials of the client
+    CONNECT = CONNECT,
     /// publish
     PUBLISH = PUBLISH,
     /// subscribe
@@ -13,6 +15,10 @@ pub enum PktType {
     UNSUBSCRIBE = UNSUBSCRIBE,
     /// query the topics
     QUERY = QUERY,
+    /// admin request, the topic is the operation
+    ADMIN = ADMIN,
+    /// acknowledgement to connect
+    CONNECTACK = CONNECTACK,
     /// acknowledgement to publish
     PUBLISHACK = PUBLISHACK,
     /// acknowledgement to subscribe
@@ -21,6 +27,10 @@ pub enum PktType {
     UNSUBSCRIBEACK = UNSUBSCRIBEACK,
     /// response to the query packet
     QUERYRESP = QUERYRESP,
+    /// error, the message contains the reason
+    ERROR = ERROR,
+    /// response to the admin packet
+    ADMINRESP = ADMINRESP,
 }
 
 impl PktType {
@@ -33,14 +43,19 @@ impl PktType {
     /// ```
     pub fn byte(&self) -> u8 {
         match self {
+            PktType::CONNECT => CONNECT,
             PktType::PUBLISH => PUBLISH,
             PktType::SUBSCRIBE => SUBSCRIBE,
             PktType::UNSUBSCRIBE => UNSUBSCRIBE,
             PktType::QUERY => QUERY,
+            PktType::ADMIN => ADMIN,
+            PktType::CONNECTACK => CONNECTACK,
             PktType::PUBLISHACK => PUBLISHACK,
             PktType::SUBSCRIBEACK => SUBSCRIBEACK,
             PktType::UNSUBSCRIBEACK => UNSUBSCRIBEACK,
             PktType::QUERYRESP => QUERYRESP,
+            PktType::ERROR => ERROR,
+            PktType::ADMINRESP => ADMINRESP,
         }
     }
 }
@@ -48,14 +63,19 @@ impl PktType {
 impl Display for PktType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let pkt = match self {
+            PktType::CONNECT => "CONNECT".to_string(),
             PktType::PUBLISH => "PUBLISH".to_string(),
             PktType::SUBSCRIBE => "SUBSCRIBE".to_string(),
             PktType::UNSUBSCRIBE => "UNSUBSCRIBE".to_string(),
             PktType::QUERY => "QUERY".to_string(),
+            PktType::ADMIN => "ADMIN".to_string(),
+            PktType::CONNECTACK => "CONNECT_ACK".to_string(),
             PktType::PUBLISHACK => "PUBLISH_ACK".to_string(),
             PktType::SUBSCRIBEACK => "SUBSCRIBE_ACK".to_string(),
             PktType::UNSUBSCRIBEACK => "UNSUBSCRIBE_ACK".to_string(),
             PktType::QUERYRESP => "QUERY_RESP".to_string(),
+            PktType::ERROR => "ERROR".to_string(),
+            PktType::ADMINRESP => "ADMIN_RESP".to_string(),
         };
         write!(f, "{}", pkt)
     }
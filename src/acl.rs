@@ -0,0 +1,368 @@
+//! Per-topic access control for the broker.
+//!
+//! The acl file contains one rule per line:
+//! ```text
+//! # <allow|deny> <identity|*> <actions|all> <topic pattern>
+//! allow billing-svc publish billing/#
+//! deny  *           publish billing/#
+//! allow *           subscribe,query #
+//! allow ops         admin #
+//! allow edge-broker bridge #
+//! ```
+//! The rules are checked in order and the first matching rule wins,
+//! the request is denied when no rule matches.
+//! `all` matches every action except `admin` and `bridge`, the admin operations
+//! must be allowed explicitly, the topic is the name of the operation.
+//! The `bridge` action on `#` lets an authenticated client connect as a bridge.
+//! Topic patterns are `/` separated, `+` matches a single level
+//! and `#` matches all the remaining levels.
+//! A subscription to a pattern is allowed only when the allowing rule covers
+//! every topic of the pattern and no earlier deny rule shares a topic with it.
+use crate::PktType;
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::fmt::Display;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// action performed by the client on a topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Publish,
+    Subscribe,
+    Unsubscribe,
+    Query,
+    /// admin operation, the topic is the operation name
+    Admin,
+    /// connects as a bridge, checked on `#`
+    Bridge,
+}
+
+impl TryFrom<&PktType> for Action {
+    type Error = anyhow::Error;
+
+    fn try_from(pkt_type: &PktType) -> Result<Action> {
+        match pkt_type {
+            PktType::PUBLISH => Ok(Action::Publish),
+            PktType::SUBSCRIBE => Ok(Action::Subscribe),
+            PktType::UNSUBSCRIBE => Ok(Action::Unsubscribe),
+            PktType::QUERY => Ok(Action::Query),
+            PktType::ADMIN => Ok(Action::Admin),
+            _ => bail!("No acl action for the packet type: {}", pkt_type),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Action> {
+        match s {
+            "publish" => Ok(Action::Publish),
+            "subscribe" => Ok(Action::Subscribe),
+            "unsubscribe" => Ok(Action::Unsubscribe),
+            "query" => Ok(Action::Query),
+            "admin" => Ok(Action::Admin),
+            "bridge" => Ok(Action::Bridge),
+            _ => bail!("Invalid acl action: {}", s),
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self {
+            Action::Publish => "publish",
+            Action::Subscribe => "subscribe",
+            Action::Unsubscribe => "unsubscribe",
+            Action::Query => "query",
+            Action::Admin => "admin",
+            Action::Bridge => "bridge",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+/// single allow/deny rule.
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    /// identity of the client, `None` matches any client.
+    identity: Option<String>,
+    /// actions, empty matches all the actions except admin and bridge.
+    actions: Vec<Action>,
+    pattern: String,
+}
+
+impl Rule {
+    fn matches(&self, identity: Option<&str>, action: Action, topic: &str) -> bool {
+        if let Some(rule_identity) = &self.identity {
+            if Some(rule_identity.as_str()) != identity {
+                return false;
+            }
+        }
+        let action_matches = if self.actions.is_empty() {
+            !matches!(action, Action::Admin | Action::Bridge)
+        } else {
+            self.actions.contains(&action)
+        };
+        // a subscription pattern is allowed only by a rule covering all its topics,
+        // and denied by any rule sharing a topic with it.
+        let topic_matched = if self.allow {
+            topic_matches(&self.pattern, topic)
+        } else {
+            patterns_overlap(&self.pattern, topic)
+        };
+        action_matches && topic_matched
+    }
+}
+
+/// Access control list, loaded from the acl file.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// loads the acl from the given file.
+    pub fn load(path: &str) -> Result<Acl> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Error while reading the acl file: {path}"))?;
+        content
+            .parse()
+            .with_context(|| format!("Error while parsing the acl file: {path}"))
+    }
+
+    /// returns true if the client with the given identity can perform the action on the topic.
+    /// ```
+    /// use simple_pub_sub::acl::{Acl, Action};
+    /// let acl: Acl = "allow billing-svc publish billing/#\n\
+    ///                 deny * publish billing/#\n\
+    ///                 allow * all #"
+    ///     .parse()
+    ///     .unwrap();
+    /// assert!(acl.is_allowed(Some("billing-svc"), Action::Publish, "billing/invoices"));
+    /// assert!(!acl.is_allowed(Some("alice"), Action::Publish, "billing/invoices"));
+    /// assert!(acl.is_allowed(None, Action::Subscribe, "billing/invoices"));
+    /// // `all` does not include the admin operations.
+    /// assert!(!acl.is_allowed(Some("billing-svc"), Action::Admin, "kick"));
+    ///
+    /// // the patterns including a denied topic are denied.
+    /// let acl: Acl = "deny * subscribe billing/#\n\
+    ///                 allow * subscribe #"
+    ///     .parse()
+    ///     .unwrap();
+    /// assert!(!acl.is_allowed(None, Action::Subscribe, "#"));
+    /// assert!(!acl.is_allowed(None, Action::Subscribe, "+/invoices"));
+    /// assert!(acl.is_allowed(None, Action::Subscribe, "sensors/+"));
+    /// ```
+    pub fn is_allowed(&self, identity: Option<&str>, action: Action, topic: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(identity, action, topic))
+            .map(|rule| rule.allow)
+            .unwrap_or(false)
+    }
+}
+
+impl FromStr for Acl {
+    type Err = anyhow::Error;
+
+    fn from_str(content: &str) -> Result<Acl> {
+        let mut rules = vec![];
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [policy, identity, actions, pattern] = fields[..] else {
+                bail!("Invalid rule at line {}: {}", n + 1, line);
+            };
+            let allow = match policy {
+                "allow" => true,
+                "deny" => false,
+                _ => bail!("Invalid policy at line {}: {}", n + 1, policy),
+            };
+            let identity = match identity {
+                "*" => None,
+                identity => Some(identity.to_string()),
+            };
+            let actions = match actions {
+                "all" => vec![],
+                actions => actions
+                    .split(',')
+                    .map(Action::from_str)
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!("Invalid actions at line {}", n + 1))?,
+            };
+            rules.push(Rule {
+                allow,
+                identity,
+                actions,
+                pattern: pattern.to_string(),
+            });
+        }
+        Ok(Acl { rules })
+    }
+}
+
+/// returns true if the topic matches the pattern.
+/// ```
+/// use simple_pub_sub::acl::topic_matches;
+/// assert!(topic_matches("billing/#", "billing/invoices/2024"));
+/// assert!(topic_matches("billing/#", "billing"));
+/// assert!(topic_matches("sensors/+/temp", "sensors/kitchen/temp"));
+/// assert!(!topic_matches("sensors/+/temp", "sensors/kitchen/humidity"));
+/// assert!(topic_matches("abc", "abc"));
+/// // the subscription patterns are only covered by the wider patterns.
+/// assert!(topic_matches("sensors/#", "sensors/+/temp"));
+/// assert!(!topic_matches("sensors/+", "sensors/#"));
+/// ```
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in pattern.split('/') {
+        match level {
+            "#" => return true,
+            // `+` does not cover the `#` of a subscription pattern.
+            "+" => {
+                if matches!(topic_levels.next(), None | Some("#")) {
+                    return false;
+                }
+            }
+            level => {
+                if topic_levels.next() != Some(level) {
+                    return false;
+                }
+            }
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// returns true if a topic matches both patterns.
+/// ```
+/// use simple_pub_sub::acl::patterns_overlap;
+/// assert!(patterns_overlap("billing/#", "#"));
+/// assert!(patterns_overlap("billing/#", "+/invoices"));
+/// assert!(patterns_overlap("sensors/+/temp", "sensors/kitchen/+"));
+/// assert!(!patterns_overlap("billing/#", "sensors/+"));
+/// assert!(!patterns_overlap("sensors/+", "sensors/+/temp"));
+/// ```
+pub fn patterns_overlap(a: &str, b: &str) -> bool {
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some("#"), _) | (_, Some("#")) | (None, None) => return true,
+            (None, _) | (_, None) => return false,
+            (Some("+"), _) | (_, Some("+")) => {}
+            (Some(a_level), Some(b_level)) => {
+                if a_level != b_level {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// returns true if the topic is a subscription pattern with `+` or `#`.
+/// ```
+/// use simple_pub_sub::acl::is_pattern;
+/// assert!(is_pattern("sensors/+/temp"));
+/// assert!(!is_pattern("sensors/kitchen/temp"));
+/// ```
+pub fn is_pattern(topic: &str) -> bool {
+    topic.split('/').any(|level| level == "+" || level == "#")
+}
+
+/// returns true if the topic or pattern fits in a packet and the wildcards are
+/// valid, `+` and `#` must fill a whole level and `#` must be the last one.
+/// ```
+/// use simple_pub_sub::acl::is_valid_pattern;
+/// assert!(is_valid_pattern("sensors/+/temp"));
+/// assert!(is_valid_pattern("sensors/#"));
+/// assert!(!is_valid_pattern("sensors/#/temp"));
+/// assert!(!is_valid_pattern("sensors/kitchen+"));
+/// ```
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    if pattern.is_empty() || pattern.len() > usize::from(u8::MAX) {
+        return false;
+    }
+    let levels = pattern.split('/').collect::<Vec<_>>();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains(['+', '#']),
+    })
+}
+
+/// returns true if the topic is valid for the request, the subscription patterns
+/// must fit in a packet with `#` as the last level and the published topics can
+/// not have wildcards. As in MQTT `+` and `#` are wildcards only when they fill a
+/// whole level, the levels like `c++` or `chat#1` are plain names.
+/// ```
+/// use simple_pub_sub::acl::is_valid_request_topic;
+/// use simple_pub_sub::PktType;
+/// assert!(is_valid_request_topic(&PktType::SUBSCRIBE, "sensors/+/temp"));
+/// assert!(!is_valid_request_topic(&PktType::PUBLISH, "sensors/+/temp"));
+/// assert!(!is_valid_request_topic(&PktType::SUBSCRIBE, "sensors/#/temp"));
+/// assert!(is_valid_request_topic(&PktType::PUBLISH, "chat#1/c++"));
+/// ```
+pub fn is_valid_request_topic(pkt_type: &PktType, topic: &str) -> bool {
+    let valid = || {
+        let levels = topic.split('/').collect::<Vec<_>>();
+        !topic.is_empty()
+            && topic.len() <= usize::from(u8::MAX)
+            && !levels[..levels.len() - 1].contains(&"#")
+    };
+    match pkt_type {
+        PktType::PUBLISH => valid() && !is_pattern(topic),
+        PktType::SUBSCRIBE | PktType::UNSUBSCRIBE => valid(),
+        _ => true,
+    }
+}
+
+/// Audit log for the denied requests, one json object per line.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<std::fs::File>,
+}
+
+impl AuditLog {
+    /// opens the audit log in the append mode.
+    pub fn open(path: &str) -> Result<AuditLog> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Error while opening the audit log: {path}"))?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// records the denied request.
+    pub fn denied(&self, client_id: &str, identity: Option<&str>, action: Action, topic: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let entry = json!({
+            "timestamp": timestamp,
+            "client_id": client_id,
+            "identity": identity,
+            "action": action.to_string(),
+            "topic": topic,
+            "result": "denied",
+        });
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(e) = writeln!(file, "{}", entry) {
+                warn!("Error while writing the audit log: {:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Admin operations of the broker.
+//!
+//! The requests are sent as `ADMIN` packets, the topic of the packet is
+//! the operation and the message is the json encoded [`AdminRequest`].
+//! The broker replies with an `ADMIN_RESP` packet carrying the json result,
+//! or an `ERROR` packet.
+//!
+//! The operations are authorized by the acl with the `admin` action on the
+//! operation name, e.g. `allow ops admin #`. Without an acl no client can
+//! use them, the tokens never can.
+use serde::{Deserialize, Serialize};
+
+/// admin request to the broker.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// lists the connected clients.
+    Clients,
+    /// disconnects the client.
+    Kick { client_id: String },
+    /// removes the subscription of the client to the topic.
+    Unsubscribe { client_id: String, topic: String },
+    /// removes all the subscriptions to the topic.
+    DropTopic { topic: String },
+}
+
+impl AdminRequest {
+    /// name of the operation, used as the topic of the `ADMIN` packet.
+    /// ```
+    /// use simple_pub_sub::admin::AdminRequest;
+    /// let request = AdminRequest::Kick { client_id: "abc".to_string() };
+    /// assert_eq!(request.operation(), "kick");
+    /// assert_eq!(
+    ///     serde_json::to_string(&request).unwrap(),
+    ///     r#"{"op":"kick","client_id":"abc"}"#
+    /// );
+    /// ```
+    pub fn operation(&self) -> &'static str {
+        match self {
+            AdminRequest::Clients => "clients",
+            AdminRequest::Kick { .. } => "kick",
+            AdminRequest::Unsubscribe { .. } => "unsubscribe",
+            AdminRequest::DropTopic { .. } => "drop_topic",
+        }
+    }
+}
+
+/// connected client, as listed by [`AdminRequest::Clients`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: String,
+    /// peer address of the connection
+    pub address: String,
+    /// authenticated identity of the client
+    pub identity: Option<String>,
+    /// connect time, seconds since the unix epoch
+    pub connected_at: u64,
+    /// subscribed topics
+    pub subscriptions: Vec<String>,
+    /// bytes of the messages held by the broker for the client
+    #[serde(default)]
+    pub memory: usize,
+}
@@ -0,0 +1,196 @@
+//! Password authentication for the broker.
+//!
+//! The credentials file contains one `username:hash` entry per line,
+//! the hashes are salted argon2 PHC strings.
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// payload of the `CONNECT` packet, serialized as json.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectRequest {
+    /// username for the password authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// password for the password authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// signed bearer token for the token authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// the client is a bridge to another broker
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bridge: bool,
+    /// stable id of the client session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    /// the broker keeps the subscriptions of the session and queues its messages
+    /// while the client is disconnected, a stored session is discarded when not set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub persistent: bool,
+}
+
+/// payload of the `CONNECTACK` packet when a session id or the bridge flag is sent,
+/// serialized as json.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectAck {
+    /// the persistent session was resumed with its subscriptions
+    #[serde(default)]
+    pub session_present: bool,
+    /// the client was accepted as a bridge
+    #[serde(default)]
+    pub bridge: bool,
+}
+
+/// Credentials used by the client to authenticate with the server.
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    /// username and password, verified against the credentials file of the server
+    Password { username: String, password: String },
+    /// signed token, verified with the token key of the server
+    Token(String),
+}
+
+impl ConnectRequest {
+    /// returns true if the request carries a password or a token.
+    pub(crate) fn has_credentials(&self) -> bool {
+        self.username.is_some() || self.password.is_some() || self.token.is_some()
+    }
+}
+
+impl From<&Credentials> for ConnectRequest {
+    fn from(credentials: &Credentials) -> Self {
+        match credentials {
+            Credentials::Password { username, password } => ConnectRequest {
+                username: Some(username.clone()),
+                password: Some(password.clone()),
+                ..Default::default()
+            },
+            Credentials::Token(token) => ConnectRequest {
+                token: Some(token.clone()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The credentials file with the salted password hashes.
+/// the file is read again for every verification,
+/// so the users added with `simple-pub-sub passwd` are picked up without a restart.
+#[derive(Debug, Clone)]
+pub struct CredentialsFile {
+    path: String,
+}
+
+impl CredentialsFile {
+    /// opens the credentials file at the given path, the file must exist and be valid.
+    pub fn open(path: &str) -> Result<CredentialsFile> {
+        read_credentials(path)?;
+        Ok(CredentialsFile {
+            path: path.to_string(),
+        })
+    }
+
+    /// verifies the password for the given user.
+    pub async fn verify(&self, username: &str, password: &str) -> Result<bool> {
+        let path = self.path.clone();
+        let username = username.to_string();
+        let password = password.to_string();
+        // hashing is expensive, keep it off the async workers.
+        tokio::task::spawn_blocking(move || {
+            let credentials = read_credentials(&path)?;
+            let Some(hash) = credentials.get(&username) else {
+                return Ok(false);
+            };
+            let hash = PasswordHash::new(hash).map_err(|e| anyhow!("Invalid hash: {e}"))?;
+            Ok(Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok())
+        })
+        .await?
+    }
+}
+
+/// returns the salted argon2 hash for the given password.
+/// ```
+/// let hash = simple_pub_sub::auth::hash_password("password").unwrap();
+/// assert!(hash.starts_with("$argon2"));
+/// ```
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Error while hashing the password: {e}"))?
+        .to_string())
+}
+
+/// adds the user to the credentials file, replaces the password if the user exists.
+/// the file is created if it does not exist.
+pub fn add_user(path: &str, username: &str, password: &str) -> Result<()> {
+    if username.is_empty() || username.contains([':', '\n']) {
+        bail!("Invalid username: {username:?}");
+    }
+    let mut credentials = if std::path::Path::new(path).exists() {
+        read_credentials(path)?
+    } else {
+        BTreeMap::new()
+    };
+    credentials.insert(username.to_string(), hash_password(password)?);
+    write_credentials(path, &credentials)
+}
+
+/// removes the user from the credentials file, returns `false` if the user was not present.
+pub fn remove_user(path: &str, username: &str) -> Result<bool> {
+    let mut credentials = read_credentials(path)?;
+    let removed = credentials.remove(username).is_some();
+    write_credentials(path, &credentials)?;
+    Ok(removed)
+}
+
+/// returns the users in the credentials file.
+pub fn list_users(path: &str) -> Result<Vec<String>> {
+    Ok(read_credentials(path)?.into_keys().collect())
+}
+
+/// reads the `username:hash` entries from the credentials file.
+fn read_credentials(path: &str) -> Result<BTreeMap<String, String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Error while reading the credentials file: {path}"))?;
+    let mut credentials = BTreeMap::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((username, hash)) = line.split_once(':') else {
+            bail!(
+                "Invalid entry in the credentials file {path} at line {}",
+                n + 1
+            );
+        };
+        credentials.insert(username.to_string(), hash.to_string());
+    }
+    Ok(credentials)
+}
+
+/// writes the entries to the credentials file, readable only by the owner.
+fn write_credentials(path: &str, credentials: &BTreeMap<String, String>) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Error while writing the credentials file: {path}"))?;
+    for (username, hash) in credentials {
+        writeln!(file, "{username}:{hash}")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Load generator to measure the throughput and the latency of a broker.
+//!
+//! The publishers send the messages round robin on the benchmark topics, the
+//! subscribers are spread over the topics. Every payload starts with the time it
+//! was published at, relative to the start of the benchmark, so the subscribers
+//! running in the same process measure the end-to-end latency.
+use crate::auth::Credentials;
+use crate::client::{Client, PubSubClient};
+use crate::PktType;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout_at, Instant};
+use tracing::{debug, info};
+
+/// bytes of the timestamp at the start of the payloads.
+const TIMESTAMP_SIZE: usize = 8;
+/// time the subscribers wait for the messages still in flight after the publishers stop.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// pause of a publisher ahead of its rate.
+const RATE_TICK: Duration = Duration::from_millis(1);
+
+/// Benchmark of a broker.
+/// ```
+/// use simple_pub_sub::bench::Bench;
+/// use simple_pub_sub::client::{PubSubClient, PubSubTcpClient};
+/// async fn bench() {
+///   let bench = Bench {
+///     client_type: PubSubClient::Tcp(PubSubTcpClient {
+///       server: "localhost".to_string(),
+///       port: 6480,
+///       cert: None,
+///       cert_password: None,
+///       client_cert: None,
+///       client_key: None,
+///     }),
+///     credentials: None,
+///     publishers: 4,
+///     subscribers: 4,
+///     topics: 1,
+///     message_size: 256,
+///     rate: None,
+///     duration: std::time::Duration::from_secs(10),
+///   };
+///   let report = bench.run().await.unwrap();
+///   println!("{report}");
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Bench {
+    pub client_type: PubSubClient,
+    pub credentials: Option<Credentials>,
+    /// number of the publishing clients
+    pub publishers: usize,
+    /// number of the subscribing clients
+    pub subscribers: usize,
+    /// number of the topics the messages are published on
+    pub topics: usize,
+    /// size of the payloads in bytes, at least the size of the timestamp
+    pub message_size: usize,
+    /// messages per second for all the publishers, as fast as possible when not set
+    pub rate: Option<u64>,
+    /// time the messages are published for
+    pub duration: Duration,
+}
+
+/// end-to-end latency percentiles in microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Latency {
+    pub p50: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+/// result of the benchmark.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BenchReport {
+    /// messages published
+    pub published: u64,
+    /// messages received by all the subscribers
+    pub received: u64,
+    /// time the messages were published for, in seconds
+    pub duration: f64,
+    /// messages received per second
+    pub msgs_per_sec: f64,
+    /// payload megabytes received per second
+    pub mb_per_sec: f64,
+    pub latency: Latency,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "published:  {} messages", self.published)?;
+        writeln!(f, "received:   {} messages", self.received)?;
+        writeln!(f, "duration:   {:.2} s", self.duration)?;
+        writeln!(
+            f,
+            "throughput: {:.0} msgs/s, {:.2} MB/s",
+            self.msgs_per_sec, self.mb_per_sec
+        )?;
+        write!(
+            f,
+            "latency:    p50 {} us, p99 {} us, p999 {} us, max {} us",
+            self.latency.p50, self.latency.p99, self.latency.p999, self.latency.max
+        )
+    }
+}
+
+impl Bench {
+    /// runs the benchmark, the subscribers are connected before the publishers start.
+    pub async fn run(&self) -> Result<BenchReport> {
+        let topics = (0..self.topics.max(1))
+            .map(|i| format!("bench/{i}"))
+            .collect::<Vec<_>>();
+        let message_size = self.message_size.max(TIMESTAMP_SIZE);
+
+        let mut subscribers = Vec::with_capacity(self.subscribers);
+        for i in 0..self.subscribers {
+            let mut client = self.connect().await?;
+            client.subscribe(topics[i % topics.len()].clone()).await?;
+            // the subscription is active once acknowledged.
+            let ack = client.read_message().await?;
+            if ack.header.pkt_type != PktType::SUBSCRIBEACK {
+                bail!(
+                    "Unexpected response to the subscribe: {:?}",
+                    ack.header.pkt_type
+                );
+            }
+            subscribers.push(client);
+        }
+        let mut publishers = Vec::with_capacity(self.publishers);
+        for _ in 0..self.publishers {
+            publishers.push(self.connect().await?);
+        }
+        info!(
+            "Benchmark with {} publishers, {} subscribers on {} topics for {:?}",
+            self.publishers,
+            self.subscribers,
+            topics.len(),
+            self.duration
+        );
+
+        let start = Instant::now();
+        let deadline = start + self.duration;
+        let mut receiving = JoinSet::new();
+        for client in subscribers {
+            receiving.spawn(receive(client, start, deadline + DRAIN_TIMEOUT));
+        }
+        let rate = self
+            .rate
+            .map(|rate| rate as f64 / self.publishers.max(1) as f64);
+        let mut publishing = JoinSet::new();
+        for (i, client) in publishers.into_iter().enumerate() {
+            publishing.spawn(publish(
+                client,
+                topics.clone(),
+                i,
+                message_size,
+                rate,
+                start,
+                deadline,
+            ));
+        }
+
+        let mut published = 0;
+        while let Some(sent) = publishing.join_next().await {
+            published += sent??;
+        }
+        let duration = start.elapsed().min(self.duration).as_secs_f64();
+        let per_sec = |count: u64| {
+            if duration > 0.0 {
+                count as f64 / duration
+            } else {
+                0.0
+            }
+        };
+        let mut latencies = vec![];
+        while let Some(received) = receiving.join_next().await {
+            latencies.extend(received??);
+        }
+        latencies.sort_unstable();
+
+        let received = latencies.len() as u64;
+        let bytes = received * message_size as u64;
+        Ok(BenchReport {
+            published,
+            received,
+            duration,
+            msgs_per_sec: per_sec(received),
+            mb_per_sec: per_sec(bytes) / 1_000_000.0,
+            latency: Latency {
+                p50: percentile(&latencies, 0.5),
+                p99: percentile(&latencies, 0.99),
+                p999: percentile(&latencies, 0.999),
+                max: latencies.last().copied().unwrap_or_default(),
+            },
+        })
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let mut client = Client::new(self.client_type.clone());
+        if let Some(credentials) = &self.credentials {
+            client.credentials(credentials.clone());
+        }
+        client.connect().await?;
+        Ok(client)
+    }
+}
+
+/// publishes until the deadline, returns the number of the published messages.
+async fn publish(
+    mut client: Client,
+    topics: Vec<String>,
+    offset: usize,
+    message_size: usize,
+    rate: Option<f64>,
+    start: Instant,
+    deadline: Instant,
+) -> Result<u64> {
+    let mut sent = 0;
+    let mut payload = vec![0; message_size];
+    while Instant::now() < deadline {
+        if let Some(rate) = rate {
+            // ahead of the rate, waits for the next message to be due.
+            if sent as f64 >= start.elapsed().as_secs_f64() * rate {
+                sleep(RATE_TICK).await;
+                continue;
+            }
+        }
+        let elapsed = start.elapsed().as_nanos() as u64;
+        payload[..TIMESTAMP_SIZE].copy_from_slice(&elapsed.to_be_bytes());
+        let topic = topics[(offset + sent as usize) % topics.len()].clone();
+        client.publish(topic, payload.clone()).await?;
+        sent += 1;
+    }
+    debug!("Publisher {} sent {} messages", offset, sent);
+    Ok(sent)
+}
+
+/// receives until the deadline, returns the latencies of the messages in microseconds.
+async fn receive(mut client: Client, start: Instant, deadline: Instant) -> Result<Vec<u64>> {
+    let mut latencies = vec![];
+    while let Ok(msg) = timeout_at(deadline, client.read_message()).await {
+        let msg = msg?;
+        if msg.header.pkt_type != PktType::PUBLISH || msg.message.len() < TIMESTAMP_SIZE {
+            continue;
+        }
+        let mut timestamp = [0; TIMESTAMP_SIZE];
+        timestamp.copy_from_slice(&msg.message[..TIMESTAMP_SIZE]);
+        let published = Duration::from_nanos(u64::from_be_bytes(timestamp));
+        latencies.push(start.elapsed().saturating_sub(published).as_micros() as u64);
+    }
+    Ok(latencies)
+}
+
+/// nearest-rank percentile of the sorted values.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -0,0 +1,184 @@
+//! Broker to broker bridge.
+//!
+//! The bridge connects as a client to the local and the remote broker and
+//! forwards the messages on the configured topic patterns in one or both
+//! directions, the connections are re-established with a backoff when they fail.
+//!
+//! The bridge connections are marked in the `CONNECT` packet, the brokers do
+//! not send the messages published by a bridge to the other bridges. The mark
+//! is only honored for an authenticated client allowed the `bridge` acl action,
+//! the `CONNECTACK` reports whether it was. So a message crosses at most one
+//! bridge and the loops are not possible, the brokers that must share the topics
+//! need a bridge between each other.
+//! The incoming topics are only forwarded when both the brokers accepted the
+//! bridge, otherwise the messages would be sent back and forth forever. The
+//! outgoing topics are forwarded in any case.
+//! The messages published while a connection is down are not forwarded.
+use crate::auth::Credentials;
+use crate::client::{Client, PubSubClient};
+use crate::error::PubSubError;
+use crate::PktType;
+use anyhow::Result;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// first delay before reconnecting, doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// direction of the bridged topics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// from the local to the remote broker
+    Out,
+    /// from the remote to the local broker
+    In,
+    /// both the ways
+    Both,
+}
+
+/// topic pattern forwarded by the bridge, `+` and `#` are supported.
+#[derive(Debug, Clone)]
+pub struct BridgeTopic {
+    pub pattern: String,
+    pub direction: Direction,
+}
+
+/// broker connected by the bridge.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub client_type: PubSubClient,
+    pub credentials: Option<Credentials>,
+}
+
+impl Endpoint {
+    /// connects to the broker as a bridge.
+    async fn connect(&self) -> Result<Client> {
+        let mut client = Client::new(self.client_type.clone());
+        if let Some(credentials) = &self.credentials {
+            client.credentials(credentials.clone());
+        }
+        client.bridge();
+        client.connect().await?;
+        Ok(client)
+    }
+}
+
+/// Bridge between two brokers.
+/// ```
+/// use simple_pub_sub::bridge::{Bridge, BridgeTopic, Direction, Endpoint};
+/// use simple_pub_sub::client::{PubSubClient, PubSubTcpClient};
+/// async fn bridge() {
+///   let endpoint = |server: &str| Endpoint {
+///     client_type: PubSubClient::Tcp(PubSubTcpClient {
+///       server: server.to_string(),
+///       port: 6480,
+///       cert: None,
+///       cert_password: None,
+///       client_cert: None,
+///       client_key: None,
+///     }),
+///     credentials: None,
+///   };
+///   let bridge = Bridge {
+///     local: endpoint("localhost"),
+///     remote: endpoint("site-b.example.com"),
+///     topics: vec![BridgeTopic {
+///       pattern: "sensors/#".to_string(),
+///       direction: Direction::Out,
+///     }],
+///     max_backoff: std::time::Duration::from_secs(30),
+///   };
+///   bridge.run().await;
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Bridge {
+    pub local: Endpoint,
+    pub remote: Endpoint,
+    pub topics: Vec<BridgeTopic>,
+    /// maximum delay between the reconnect attempts
+    pub max_backoff: Duration,
+}
+
+impl Bridge {
+    /// runs the bridge, returns only when no topics are configured.
+    pub async fn run(&self) {
+        let patterns = |directions: [Direction; 2]| {
+            self.topics
+                .iter()
+                .filter(|topic| directions.contains(&topic.direction))
+                .map(|topic| topic.pattern.clone())
+                .collect::<Vec<_>>()
+        };
+        let outgoing = patterns([Direction::Out, Direction::Both]);
+        let incoming = patterns([Direction::In, Direction::Both]);
+        tokio::join!(
+            self.forward("out", &self.local, &self.remote, outgoing, false),
+            self.forward("in", &self.remote, &self.local, incoming, true),
+        );
+    }
+
+    /// forwards the messages on the patterns from the source to the destination,
+    /// reconnects when any of the connections fails. Stops when `granted` is set
+    /// and any of the brokers did not accept the connections as a bridge.
+    async fn forward(
+        &self,
+        name: &str,
+        source: &Endpoint,
+        dest: &Endpoint,
+        patterns: Vec<String>,
+        granted: bool,
+    ) {
+        if patterns.is_empty() {
+            return;
+        }
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let result: Result<()> = async {
+                let mut source = source.connect().await?;
+                let mut dest = dest.connect().await?;
+                if granted && !(source.bridge_granted() && dest.bridge_granted()) {
+                    anyhow::bail!(PubSubError::NotAuthorized("bridge".to_string()));
+                }
+                for pattern in &patterns {
+                    source.subscribe(pattern.clone()).await?;
+                }
+                info!("Bridge {} connected, forwarding: {:?}", name, patterns);
+                backoff = INITIAL_BACKOFF;
+                loop {
+                    let msg = source.read_message().await?;
+                    if msg.header.pkt_type != PktType::PUBLISH {
+                        continue;
+                    }
+                    let topic = msg.topic.clone();
+                    if let Err(e) = dest.publish(msg.topic, msg.message).await {
+                        // the message is dropped when it is rejected by the destination.
+                        match e.downcast_ref::<PubSubError>() {
+                            Some(PubSubError::ServerError(reason)) => warn!(
+                                "Bridge {} could not forward the message on {}: {}",
+                                name, topic, reason
+                            ),
+                            _ => return Err(e),
+                        }
+                    }
+                }
+            }
+            .await;
+            if let Err(e) = result {
+                if let Some(PubSubError::NotAuthorized(_)) = e.downcast_ref::<PubSubError>() {
+                    error!(
+                        "Bridge {} refused, the brokers must accept the bridge to forward: {:?}",
+                        name, patterns
+                    );
+                    return;
+                }
+                warn!(
+                    "Bridge {} disconnected: {}, retrying in {:?}",
+                    name, e, backoff
+                );
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 /// client mode
 #[derive(clap::ValueEnum, Clone)]
@@ -18,31 +18,244 @@ pub enum LogLevel {
     Debug,
 }
 
+/// format of the log lines
+#[derive(clap::ValueEnum, Clone)]
+pub enum LogFormat {
+    /// human readable text
+    Text,
+    /// one JSON object per line, with the fields of the spans
+    Json,
+}
+
+/// how often the log file is rotated
+#[derive(clap::ValueEnum, Clone)]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// what happens to the publishes over the rate limit
+#[derive(clap::ValueEnum, Clone)]
+pub enum RateLimitMode {
+    /// delay the reads from the client
+    Throttle,
+    /// drop the message and reply with an error
+    Reject,
+}
+
+/// options for the tcp and websocket server/client
+#[derive(Args)]
+pub struct TcpArgs {
+    /// host
+    pub host: String,
+    /// port
+    pub port: u16,
+
+    /// tls certificate, PKCS#12 (.pfx) or PEM when used with `--key`
+    #[clap(short, long)]
+    pub cert: Option<String>,
+
+    /// private key (.pem) for the tls certificate (server)
+    #[clap(short, long)]
+    pub key: Option<String>,
+
+    /// tls certificate password
+    #[clap(short = 'p', long)]
+    pub cert_password: Option<String>,
+
+    /// CA certificate to verify the client certificates (server), enables mutual tls
+    #[clap(long)]
+    pub client_ca: Option<String>,
+
+    /// client certificate for mutual tls (client)
+    #[clap(long)]
+    pub client_cert: Option<String>,
+
+    /// private key for the client certificate (client)
+    #[clap(long)]
+    pub client_key: Option<String>,
+}
+
 /// the server type subcommand
 #[derive(Subcommand)]
 pub enum ServerType {
     /// tcp server
-    Tcp {
-        /// host
-        host: String,
-        /// port
-        port: u16,
-
-        /// tls certificate
-        #[clap(short, long)]
-        cert: Option<String>,
-
-        /// tls certificate password
-        #[clap(short = 'p', long)]
-        cert_password: Option<String>,
-    },
+    Tcp(TcpArgs),
+    /// websocket server, uses tls (wss) when the certificate is set
+    #[clap(name = "websocket")]
+    WebSocket(TcpArgs),
     /// unix server
     Unix {
         /// path
         path: String,
+
+        /// permissions of the socket file in octal, e.g. 660 (server)
+        #[clap(long, value_parser = parse_mode)]
+        mode: Option<u32>,
+
+        /// owner of the socket file, user name or uid (server)
+        #[clap(long)]
+        owner: Option<String>,
+
+        /// group of the socket file, group name or gid (server)
+        #[clap(long)]
+        group: Option<String>,
     },
 }
 
+/// parses the octal file mode.
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("invalid octal mode {mode}: {e}"))
+}
+
+/// options for the broker, common for all the server types
+#[derive(Args)]
+pub struct BrokerArgs {
+    /// credentials file, the clients must authenticate when set
+    #[clap(long, global = true)]
+    pub credentials: Option<String>,
+
+    /// acl file with the allow/deny rules for the topics
+    #[clap(long, global = true)]
+    pub acl: Option<String>,
+
+    /// file to record the requests denied by the acl
+    #[clap(long, global = true)]
+    pub audit_log: Option<String>,
+
+    /// file with the HMAC secret to verify the HS256 tokens
+    #[clap(long, global = true)]
+    pub token_secret: Option<String>,
+
+    /// Ed25519 public key (.pem) to verify the EdDSA tokens
+    #[clap(long, global = true)]
+    pub token_public_key: Option<String>,
+
+    /// rate limit file with the per client and per topic limits
+    #[clap(long, global = true)]
+    pub rate_limits: Option<String>,
+
+    /// what happens to the publishes over the rate limit
+    #[clap(long, global = true, value_enum, default_value = "throttle")]
+    pub rate_limit_action: RateLimitMode,
+
+    /// address (host:port) for the http gateway
+    #[clap(long, global = true)]
+    pub http: Option<String>,
+
+    /// address (host:port) for the prometheus metrics endpoint
+    #[clap(long, global = true)]
+    pub metrics: Option<String>,
+
+    /// topic pattern counted separately in the metrics, can be repeated
+    #[clap(long = "metrics-topic", global = true)]
+    pub metrics_topics: Vec<String>,
+
+    /// address (host:port) for the MQTT 3.1.1 listener
+    #[clap(long, global = true)]
+    pub mqtt: Option<String>,
+
+    /// address (host:port) for the Redis pub/sub listener
+    #[clap(long, global = true)]
+    pub redis: Option<String>,
+
+    /// address (host:port) for the NATS listener
+    #[clap(long, global = true)]
+    pub nats: Option<String>,
+
+    /// seconds a disconnected persistent session is kept [default: 3600]
+    #[clap(long, global = true)]
+    pub session_expiry: Option<u64>,
+
+    /// messages queued for a disconnected persistent session [default: 1024]
+    #[clap(long, global = true, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub session_queue: Option<usize>,
+
+    /// bytes of messages held by the broker, the publishes over it are rejected
+    #[clap(long, global = true)]
+    pub memory_limit: Option<usize>,
+
+    /// bytes of messages held for a single client
+    #[clap(long, global = true)]
+    pub client_memory_limit: Option<usize>,
+}
+
+/// credentials of the client
+#[derive(Args)]
+pub struct AuthArgs {
+    /// username to authenticate with
+    #[clap(short, long, global = true)]
+    pub username: Option<String>,
+
+    /// password to authenticate with
+    #[clap(long, global = true)]
+    pub password: Option<String>,
+
+    /// signed token to authenticate with
+    #[clap(long, global = true, conflicts_with = "username")]
+    pub token: Option<String>,
+}
+
+/// admin operations
+#[derive(Subcommand)]
+pub enum AdminAction {
+    /// list the connected clients
+    Clients {
+        /// server type, tcp or unix
+        #[clap(subcommand)]
+        server_type: ServerType,
+    },
+    /// disconnect a client
+    Kick {
+        /// id of the client
+        client_id: String,
+        /// server type, tcp or unix
+        #[clap(subcommand)]
+        server_type: ServerType,
+    },
+    /// remove the subscription of a client to a topic
+    Unsubscribe {
+        /// id of the client
+        client_id: String,
+        /// topic
+        topic: String,
+        /// server type, tcp or unix
+        #[clap(subcommand)]
+        server_type: ServerType,
+    },
+    /// remove all the subscriptions to a topic
+    DropTopic {
+        /// topic
+        topic: String,
+        /// server type, tcp or unix
+        #[clap(subcommand)]
+        server_type: ServerType,
+    },
+}
+
+/// manage the users in the credentials file
+#[derive(Subcommand)]
+pub enum PasswdAction {
+    /// add a user or replace the password of an existing user
+    Add {
+        /// username
+        username: String,
+        /// password, read from the stdin when not given
+        #[clap(short, long)]
+        password: Option<String>,
+    },
+    /// remove a user
+    Remove {
+        /// username
+        username: String,
+    },
+    /// list the users
+    List,
+}
+
 /// the main command
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -54,6 +267,22 @@ pub struct Cli {
     #[clap(long, global = true)]
     pub log_level: Option<LogLevel>,
 
+    /// log format
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// log file, the logs are written to the stderr when not set
+    #[clap(long, global = true)]
+    pub log_file: Option<String>,
+
+    /// how often the log file is rotated, the date is appended to the file name
+    #[clap(long, global = true, value_enum, default_value = "daily")]
+    pub log_rotation: LogRotation,
+
+    /// number of the rotated log files to keep, all of them are kept when not set
+    #[clap(long, global = true)]
+    pub log_max_files: Option<usize>,
+
     /// queue size
     #[clap(short = 'C', long, global = true)]
     pub capacity: Option<usize>,
@@ -67,6 +296,9 @@ pub enum Commands {
         /// server type, tcp or unix
         #[clap(subcommand)]
         server_type: ServerType,
+
+        #[clap(flatten)]
+        broker: Box<BrokerArgs>,
     },
     /// Client
     Client {
@@ -79,6 +311,155 @@ pub enum Commands {
         topic: String,
         /// message to be published
         message: Option<String>,
+
+        /// id of a persistent session, the broker keeps the subscriptions
+        /// and queues the messages while the client is disconnected
+        #[clap(long, global = true)]
+        session: Option<String>,
+
+        #[clap(flatten)]
+        auth: AuthArgs,
+    },
+    /// measure the throughput and the latency of a broker
+    Bench {
+        /// server type, tcp or unix
+        #[clap(subcommand)]
+        server_type: ServerType,
+
+        /// number of the publishing clients
+        #[clap(long, default_value_t = 1, global = true)]
+        publishers: usize,
+
+        /// number of the subscribing clients
+        #[clap(long, default_value_t = 1, global = true)]
+        subscribers: usize,
+
+        /// number of the topics the messages are published on
+        #[clap(long, default_value_t = 1, global = true)]
+        topics: usize,
+
+        /// size of the payloads in bytes
+        #[clap(long, default_value_t = 128, global = true)]
+        message_size: usize,
+
+        /// messages per second for all the publishers, as fast as possible when not set
+        #[clap(long, global = true)]
+        rate: Option<u64>,
+
+        /// time the messages are published for in seconds
+        #[clap(long, default_value_t = 10, global = true)]
+        duration: u64,
+
+        /// print the report as JSON
+        #[clap(long, global = true)]
+        json: bool,
+
+        #[clap(flatten)]
+        auth: AuthArgs,
+    },
+    /// admin operations on the connected clients and the topics
+    Admin {
+        #[clap(subcommand)]
+        action: AdminAction,
+
+        #[clap(flatten)]
+        auth: AuthArgs,
+    },
+    /// forward topics between two brokers
+    Bridge {
+        /// local broker: tcp://host:port, ws://host:port or unix:///path
+        #[clap(long)]
+        local: String,
+
+        /// remote broker: tcp://host:port, ws://host:port or unix:///path
+        #[clap(long)]
+        remote: String,
+
+        /// topic pattern forwarded from the local to the remote broker, can be repeated
+        #[clap(long = "out")]
+        outgoing: Vec<String>,
+
+        /// topic pattern forwarded from the remote to the local broker, can be repeated
+        #[clap(long = "in")]
+        incoming: Vec<String>,
+
+        /// topic pattern forwarded in both the directions, can be repeated
+        #[clap(long)]
+        both: Vec<String>,
+
+        /// CA certificate of the local broker, connects with tls when set
+        #[clap(long)]
+        local_cert: Option<String>,
+
+        /// username for the local broker
+        #[clap(long)]
+        local_username: Option<String>,
+
+        /// password for the local broker
+        #[clap(long)]
+        local_password: Option<String>,
+
+        /// signed token for the local broker
+        #[clap(long, conflicts_with = "local_username")]
+        local_token: Option<String>,
+
+        /// CA certificate of the remote broker, connects with tls when set
+        #[clap(long)]
+        remote_cert: Option<String>,
+
+        /// username for the remote broker
+        #[clap(long)]
+        remote_username: Option<String>,
+
+        /// password for the remote broker
+        #[clap(long)]
+        remote_password: Option<String>,
+
+        /// signed token for the remote broker
+        #[clap(long, conflicts_with = "remote_username")]
+        remote_token: Option<String>,
+
+        /// maximum delay between the reconnect attempts in seconds
+        #[clap(long, default_value_t = 30)]
+        max_backoff: u64,
+    },
+    /// manage the credentials file
+    Passwd {
+        #[clap(subcommand)]
+        action: PasswdAction,
+
+        /// credentials file
+        #[clap(short, long, default_value = "simple-pub-sub.passwd", global = true)]
+        file: String,
+    },
+    /// mint a signed token for the token authentication
+    Token {
+        /// subject (identity) of the token
+        subject: String,
+
+        /// topic pattern the token can publish to, can be repeated
+        #[clap(long)]
+        publish: Vec<String>,
+
+        /// topic pattern the token can subscribe to, can be repeated
+        #[clap(long)]
+        subscribe: Vec<String>,
+
+        /// validity of the token in seconds
+        #[clap(long, default_value_t = 3600)]
+        expires_in: u64,
+
+        /// file with the HMAC secret, signs a HS256 token
+        #[clap(
+            long,
+            required_unless_present = "private_key",
+            conflicts_with = "private_key"
+        )]
+        secret: Option<String>,
+
+        /// Ed25519 private key (.pem), signs an EdDSA token
+        #[clap(long)]
+        private_key: Option<String>,
     },
     /// bash completions
     /// supported shells: [bash, zsh, fish, Elvish, Powershell]
@@ -0,0 +1,245 @@
+//! Handler based subscriber API of the client.
+//!
+//! The handlers are registered with [`Client::on`] for a topic pattern, [`Client::run`]
+//! subscribes to the patterns and calls every handler matching the topic of an
+//! incoming message, at most [`Client::concurrency`] at once.
+use super::{is_connection_error, Client};
+use crate::acl;
+use crate::message::Msg;
+use crate::PktType;
+use anyhow::Result;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{error, warn};
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type BoxHandler = Arc<dyn Fn(Msg) -> BoxFuture + Send + Sync>;
+type ErrorHandler = Arc<dyn Fn(HandlerError) + Send + Sync>;
+
+/// Marker of the handlers returning the result directly.
+pub struct SyncHandler;
+
+/// Marker of the handlers returning a future.
+pub struct AsyncHandler;
+
+/// Closure handling the messages of a topic pattern, see [`Client::on`].
+///
+/// Implemented for the sync closures `Fn(Msg) -> R` and for the async ones
+/// `Fn(Msg) -> impl Future<Output = R>`, where `R` is `()` or a `Result<(), E>`.
+pub trait Handler<M>: Send + Sync + 'static {
+    fn into_handler(self) -> BoxHandler;
+}
+
+impl<F, R> Handler<(SyncHandler, R)> for F
+where
+    F: Fn(Msg) -> R + Send + Sync + 'static,
+    R: HandlerResult,
+{
+    fn into_handler(self) -> BoxHandler {
+        let handler = Arc::new(self);
+        Arc::new(move |msg| {
+            let handler = handler.clone();
+            Box::pin(async move { handler(msg).into_result() })
+        })
+    }
+}
+
+impl<F, Fut> Handler<(AsyncHandler, Fut)> for F
+where
+    F: Fn(Msg) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: HandlerResult,
+{
+    fn into_handler(self) -> BoxHandler {
+        Arc::new(move |msg| {
+            let future = self(msg);
+            Box::pin(async move { future.await.into_result() })
+        })
+    }
+}
+
+/// Value returned by a handler.
+pub trait HandlerResult {
+    fn into_result(self) -> Result<()>;
+}
+
+impl HandlerResult for () {
+    fn into_result(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: Into<anyhow::Error>> HandlerResult for Result<(), E> {
+    fn into_result(self) -> Result<()> {
+        self.map_err(Into::into)
+    }
+}
+
+/// Error returned by a handler, or its panic.
+#[derive(Debug)]
+pub struct HandlerError {
+    /// pattern of the handler
+    pub pattern: String,
+    /// topic of the message
+    pub topic: String,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Handler of {} failed on {}: {}",
+            self.pattern, self.topic, self.error
+        )
+    }
+}
+
+/// Registered handlers of a client.
+pub(crate) struct Handlers {
+    routes: Vec<(String, BoxHandler)>,
+    on_error: Option<ErrorHandler>,
+    concurrency: usize,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        Handlers {
+            routes: vec![],
+            on_error: None,
+            concurrency: 1,
+        }
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handlers")
+            .field(
+                "patterns",
+                &self.routes.iter().map(|(p, _)| p).collect::<Vec<_>>(),
+            )
+            .field("concurrency", &self.concurrency)
+            .finish()
+    }
+}
+
+impl Client {
+    /// Registers the handler of the messages published to the topics matching
+    /// the pattern, called by [`Client::run`].
+    /// The handler is a sync or an async closure, returning nothing or a result,
+    /// the errors are reported to [`Client::on_error`].
+    /// ```
+    /// use simple_pub_sub::client::{self, PubSubClient, Client};
+    /// use simple_pub_sub::message::Msg;
+    /// async fn run(){
+    ///   let client_type = simple_pub_sub::client::PubSubTcpClient {
+    ///          server: "localhost".to_string(),
+    ///          port: 6480,
+    ///          cert: None,
+    ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
+    ///   };
+    ///   let mut client = Client::new(PubSubClient::Tcp(client_type));
+    ///   client.on("logs/#".to_string(), |msg: Msg| client::on_message(msg.topic, msg.message));
+    ///   client.on("orders/+".to_string(), |msg: Msg| async move {
+    ///       let order: serde_json::Value = serde_json::from_slice(&msg.message)?;
+    ///       println!("{order}");
+    ///       Ok::<_, anyhow::Error>(())
+    ///   });
+    ///   client.connect().await.unwrap();
+    ///   client.run().await.unwrap();
+    /// }
+    /// ```
+    pub fn on<M>(&mut self, pattern: String, handler: impl Handler<M>) {
+        self.handlers.routes.push((pattern, handler.into_handler()));
+    }
+
+    /// sets the callback of the handler errors, they are logged otherwise.
+    pub fn on_error(&mut self, on_error: impl Fn(HandlerError) + Send + Sync + 'static) {
+        self.handlers.on_error = Some(Arc::new(on_error));
+    }
+
+    /// sets the number of handlers running at once, 1 by default so the
+    /// messages are handled in order.
+    pub fn concurrency(&mut self, limit: usize) {
+        self.handlers.concurrency = limit.max(1);
+    }
+
+    /// Subscribes to the patterns of the handlers and dispatches the incoming
+    /// messages to them, connects first if the client is not connected.
+    /// Runs until the connection is lost and cannot be recovered, then waits for
+    /// the running handlers and returns the error.
+    pub async fn run(&mut self) -> Result<()> {
+        if self.stream.is_none() {
+            self.connect().await?;
+        }
+        let patterns: Vec<String> = self
+            .handlers
+            .routes
+            .iter()
+            .map(|(p, _)| p.clone())
+            .collect();
+        for pattern in patterns {
+            self.subscribe(pattern).await?;
+        }
+
+        let permits = Arc::new(Semaphore::new(self.handlers.concurrency));
+        let mut tasks = JoinSet::new();
+        let result = loop {
+            while tasks.try_join_next().is_some() {}
+            let msg = match self.read_message().await {
+                Ok(msg) => msg,
+                Err(e) if !is_connection_error(&e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+                Err(e) => break Err(e),
+            };
+            if msg.header.pkt_type != PktType::PUBLISH {
+                continue;
+            }
+            for (pattern, handler) in &self.handlers.routes {
+                if !acl::topic_matches(pattern, &msg.topic) {
+                    continue;
+                }
+                let permit = permits.clone().acquire_owned().await?;
+                let handling = tokio::spawn(handler(msg.clone()));
+                let on_error = self.handlers.on_error.clone();
+                let pattern = pattern.clone();
+                let topic = msg.topic.clone();
+                tasks.spawn(async move {
+                    let result = match handling.await {
+                        Ok(result) => result,
+                        Err(e) => Err(anyhow::anyhow!("{}", e)),
+                    };
+                    drop(permit);
+                    if let Err(error) = result {
+                        report(
+                            on_error,
+                            HandlerError {
+                                pattern,
+                                topic,
+                                error,
+                            },
+                        );
+                    }
+                });
+            }
+        };
+        while tasks.join_next().await.is_some() {}
+        result
+    }
+}
+
+fn report(on_error: Option<ErrorHandler>, error: HandlerError) {
+    match on_error {
+        Some(on_error) => on_error(error),
+        None => error!("{}", error),
+    }
+}
@@ -1,20 +1,27 @@
-use crate::error::PubSubError::ClientNotConnected;
+mod handler;
+mod reconnect;
+mod split;
+mod tls;
+use crate::admin::{AdminRequest, ClientInfo};
+use crate::auth::{ConnectAck, ConnectRequest, Credentials};
+use crate::embedded::Broker;
+use crate::error::PubSubError::{self, ClientNotConnected};
 use crate::message;
 use crate::message::Msg;
 use crate::stream;
+use crate::stream::WebSocketStream;
 use crate::Header;
 use crate::PktType;
 use anyhow::Result;
-use log::{info, trace};
-use std::fs::File;
-use std::io::Read;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    net::UnixStream,
-};
-use tokio_native_tls::native_tls::{Certificate, TlsConnector};
-use tokio_native_tls::TlsStream;
+pub use handler::{AsyncHandler, Handler, HandlerError, HandlerResult, SyncHandler};
+pub use reconnect::{ConnectionEvent, ReconnectPolicy};
+pub use split::{Publisher, Subscriber, Subscription};
+use std::collections::{BTreeSet, VecDeque};
+pub use tls::TlsStream;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::broadcast;
+use tracing::{debug, info, trace, warn};
 
 /// Simple pub sub Client for Tcp connection
 #[derive(Debug, Clone)]
@@ -26,8 +33,13 @@ pub struct PubSubTcpClient {
     pub port: u16,
     /// tls certificate (`.pem`) file
     pub cert: Option<String>,
-    /// password for the tls certificate
+    /// password for the PKCS#12 client certificate
     pub cert_password: Option<String>,
+    /// client certificate for mutual tls,
+    /// a `.pem` file when `client_key` is set, a PKCS#12 (`.pfx`) file otherwise.
+    pub client_cert: Option<String>,
+    /// private key (`.pem`) for the client certificate
+    pub client_key: Option<String>,
 }
 
 /// Simple pub sub Client for Unix connection
@@ -45,15 +57,11 @@ pub enum PubSubClient {
     Tcp(PubSubTcpClient),
     /// unix socket client for the simple pub sub
     Unix(PubSubUnixClient),
-}
-
-impl PubSubClient {
-    fn server(&self) -> &str {
-        match self {
-            PubSubClient::Tcp(pub_sub_tcp_client) => &pub_sub_tcp_client.server,
-            PubSubClient::Unix(pub_sub_unix_client) => &pub_sub_unix_client.path,
-        }
-    }
+    /// websocket client for the simple pub sub, connects to `ws://server:port/`,
+    /// or `wss://server:port/` when the tls certificates are set.
+    WebSocket(PubSubTcpClient),
+    /// in-memory client for the embedded broker
+    Memory(Broker),
 }
 
 /// Stream for Tcp and Unix connection
@@ -62,9 +70,15 @@ pub enum StreamType {
     /// tcp stream
     Tcp(TcpStream),
     /// tls stream
-    Tls(Box<TlsStream<TcpStream>>),
+    Tls(Box<TlsStream>),
     /// unix socket stream
     Unix(UnixStream),
+    /// websocket stream
+    WebSocket(Box<WebSocketStream<TcpStream>>),
+    /// websocket stream over tls
+    WebSocketTls(Box<WebSocketStream<TlsStream>>),
+    /// in-memory stream to the embedded broker
+    Memory(DuplexStream),
 }
 
 impl StreamType {
@@ -73,32 +87,26 @@ impl StreamType {
             StreamType::Tcp(stream) => Ok(stream::read_message(stream).await?),
             StreamType::Tls(stream) => Ok(stream::read_message(stream).await?),
             StreamType::Unix(stream) => Ok(stream::read_message(stream).await?),
+            StreamType::WebSocket(stream) => Ok(stream::read_message(stream).await?),
+            StreamType::WebSocketTls(stream) => Ok(stream::read_message(stream).await?),
+            StreamType::Memory(stream) => Ok(stream::read_message(stream).await?),
         }
     }
 
-    async fn read_buf(&mut self, message: &mut Vec<u8>) -> Result<usize> {
-        let size = match self {
-            StreamType::Tls(ref mut tls_stream) => tls_stream.read_buf(message).await?,
-            StreamType::Tcp(ref mut tcp_stream) => tcp_stream.read_buf(message).await?,
-            StreamType::Unix(ref mut unix_stream) => unix_stream.read_buf(message).await?,
-        };
-        Ok(size)
-    }
-
-    async fn read(&mut self, message: &mut [u8]) -> Result<usize> {
-        let size = match self {
-            StreamType::Tls(ref mut tls_stream) => tls_stream.read(message).await?,
-            StreamType::Tcp(ref mut tcp_stream) => tcp_stream.read(message).await?,
-            StreamType::Unix(ref mut unix_stream) => unix_stream.read(message).await?,
-        };
-        Ok(size)
-    }
-
     async fn write_all(&mut self, message: Vec<u8>) -> Result<()> {
         match self {
             StreamType::Tls(tls_stream) => tls_stream.write_all(&message).await?,
             StreamType::Tcp(ref mut tcp_stream) => tcp_stream.write_all(&message).await?,
             StreamType::Unix(ref mut unix_stream) => unix_stream.write_all(&message).await?,
+            StreamType::Memory(ref mut stream) => stream.write_all(&message).await?,
+            StreamType::WebSocket(ws_stream) => {
+                ws_stream.write_all(&message).await?;
+                ws_stream.flush().await?;
+            }
+            StreamType::WebSocketTls(ws_stream) => {
+                ws_stream.write_all(&message).await?;
+                ws_stream.flush().await?;
+            }
         };
         Ok(())
     }
@@ -108,6 +116,28 @@ impl StreamType {
 #[derive(Debug)]
 pub struct Client {
     pub client_type: PubSubClient,
+    credentials: Option<Credentials>,
+    /// connects as a bridge to another broker
+    bridge: bool,
+    /// id of the persistent session
+    session: Option<String>,
+    /// the broker resumed the persistent session on the last connect
+    session_present: bool,
+    /// the broker accepted the client as a bridge on the last connect
+    bridge_granted: bool,
+    /// connects again when the connection is lost
+    reconnect: Option<ReconnectPolicy>,
+    /// subscribed topics, subscribed again after a reconnect
+    subscriptions: BTreeSet<String>,
+    /// publishes kept while the client could not reconnect, up to the outbox limit
+    outbox: VecDeque<Msg>,
+    /// messages published while the client waited for a response after a reconnect
+    received: VecDeque<Msg>,
+    /// capacity of the channels of the split client
+    split_capacity: usize,
+    events: broadcast::Sender<ConnectionEvent>,
+    /// handlers of the subscribed topics, called by `run`
+    handlers: handler::Handlers,
     stream: Option<StreamType>,
 }
 
@@ -132,6 +162,8 @@ impl Client {
     ///        port: 6480,
     ///        cert: None,
     ///        cert_password: None,
+    ///        client_cert: None,
+    ///        client_key: None,
     /// };
     ///
     /// // initialize the client.
@@ -142,30 +174,85 @@ impl Client {
     pub fn new(client_type: PubSubClient) -> Self {
         Client {
             client_type,
+            credentials: None,
+            bridge: false,
+            session: None,
+            session_present: false,
+            bridge_granted: false,
+            reconnect: None,
+            subscriptions: BTreeSet::new(),
+            outbox: VecDeque::new(),
+            received: VecDeque::new(),
+            split_capacity: 1024,
+            events: broadcast::channel(16).0,
+            handlers: Default::default(),
             stream: None,
         }
     }
 
-    async fn connect_tls(&mut self, url: String, cert: String) -> Result<()> {
-        // Load CA certificate
-        let mut file = File::open(cert)?;
-        let mut ca_cert = vec![];
-        file.read_to_end(&mut ca_cert)?;
-        let ca_cert = Certificate::from_pem(&ca_cert)?;
+    /// sets the credentials, sent to the server in a `CONNECT` packet on connect.
+    /// ```
+    /// use simple_pub_sub::auth::Credentials;
+    /// use simple_pub_sub::client::{PubSubClient, PubSubUnixClient, Client};
+    /// let mut pub_sub_client = Client::new(PubSubClient::Unix(PubSubUnixClient {
+    ///     path: "/tmp/simple.sock".to_string(),
+    /// }));
+    /// pub_sub_client.credentials(Credentials::Password {
+    ///     username: "alice".to_string(),
+    ///     password: "password".to_string(),
+    /// });
+    /// ```
+    pub fn credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
 
-        // Configure TLS
-        let connector = TlsConnector::builder()
-            .add_root_certificate(ca_cert)
-            .build()?;
+    /// marks the connection as a bridge to another broker, the broker does not
+    /// send the messages published by the other bridges to this connection.
+    pub fn bridge(&mut self) {
+        self.bridge = true;
+    }
 
-        let connector = tokio_native_tls::TlsConnector::from(connector);
+    /// connects with a persistent session, the broker keeps the subscriptions and
+    /// queues the messages of the session while the client is disconnected, they are
+    /// delivered when the client connects again with the same session id.
+    /// ```
+    /// use simple_pub_sub::client::{PubSubClient, PubSubUnixClient, Client};
+    /// let mut pub_sub_client = Client::new(PubSubClient::Unix(PubSubUnixClient {
+    ///     path: "/tmp/simple.sock".to_string(),
+    /// }));
+    /// pub_sub_client.persistent_session("billing-worker-1".to_string());
+    /// ```
+    pub fn persistent_session(&mut self, session_id: String) {
+        self.session = Some(session_id);
+    }
+
+    /// returns true if the broker resumed the persistent session on connect,
+    /// the subscriptions of the session are still active.
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
 
-        // Connect to the server
-        let stream = TcpStream::connect(&url).await?;
+    /// returns true if the broker accepted the client as a bridge on connect,
+    /// the messages it publishes are not sent to the other bridges.
+    pub fn bridge_granted(&self) -> bool {
+        self.bridge_granted
+    }
 
-        // create the StreamType::Tls
-        let connector = connector.connect(self.client_type.server(), stream).await?;
-        self.stream = Some(StreamType::Tls(Box::new(connector)));
+    /// sends the `CONNECT` packet with the credentials and waits for the ack.
+    async fn authenticate(&mut self, request: ConnectRequest) -> Result<()> {
+        let request = serde_json::to_vec(&request)?;
+        let msg = Msg::new(PktType::CONNECT, "".to_string(), Some(request));
+        self.write(msg.bytes()).await?;
+        let resp = self.read_response().await?;
+        if resp.header.pkt_type != PktType::CONNECTACK {
+            anyhow::bail!(PubSubError::ServerError(format!(
+                "unexpected response to connect: {}",
+                resp.header.pkt_type
+            )));
+        }
+        let ack = serde_json::from_slice::<ConnectAck>(&resp.message).unwrap_or_default();
+        self.session_present = ack.session_present;
+        self.bridge_granted = ack.bridge;
         Ok(())
     }
 
@@ -177,6 +264,8 @@ impl Client {
     ///        port: 6480,
     ///        cert: None,
     ///        cert_password: None,
+    ///        client_cert: None,
+    ///        client_key: None,
     /// };
     ///
     /// // initialize the client.
@@ -189,22 +278,179 @@ impl Client {
         match self.client_type.clone() {
             PubSubClient::Tcp(tcp_client) => {
                 let server_url: String = format!("{}:{}", tcp_client.server, tcp_client.port);
-                if let Some(cert) = tcp_client.cert {
-                    self.connect_tls(server_url, cert).await?;
+                if tcp_client.cert.is_some() || tcp_client.client_cert.is_some() {
+                    let stream = tls::connect(&server_url, &tcp_client).await?;
+                    self.stream = Some(StreamType::Tls(Box::new(stream)));
                 } else {
                     let stream = TcpStream::connect(server_url).await?;
                     self.stream = Some(StreamType::Tcp(stream));
                 }
             }
+            PubSubClient::WebSocket(tcp_client) => {
+                let server_url: String = format!("{}:{}", tcp_client.server, tcp_client.port);
+                if tcp_client.cert.is_some() || tcp_client.client_cert.is_some() {
+                    let stream = tls::connect(&server_url, &tcp_client).await?;
+                    let (ws, _) =
+                        tokio_tungstenite::client_async(format!("wss://{server_url}/"), stream)
+                            .await?;
+                    self.stream =
+                        Some(StreamType::WebSocketTls(Box::new(WebSocketStream::new(ws))));
+                } else {
+                    let stream = TcpStream::connect(&server_url).await?;
+                    let (ws, _) =
+                        tokio_tungstenite::client_async(format!("ws://{server_url}/"), stream)
+                            .await?;
+                    self.stream = Some(StreamType::WebSocket(Box::new(WebSocketStream::new(ws))));
+                }
+            }
             PubSubClient::Unix(unix_stream) => {
                 let path = unix_stream.path;
                 let stream = UnixStream::connect(path).await?;
                 self.stream = Some(StreamType::Unix(stream));
             }
+            PubSubClient::Memory(broker) => {
+                self.stream = Some(StreamType::Memory(broker.connect().await));
+            }
+        }
+        let mut request = self
+            .credentials
+            .as_ref()
+            .map(ConnectRequest::from)
+            .unwrap_or_default();
+        request.bridge = self.bridge;
+        request.session_id = self.session.clone();
+        request.persistent = self.session.is_some();
+        if self.credentials.is_some() || self.bridge || self.session.is_some() {
+            self.authenticate(request).await?;
+        }
+        let _ = self.events.send(ConnectionEvent::Connected);
+        Ok(())
+    }
+
+    /// connects again after the connection was lost with the backoff of the
+    /// reconnect policy, subscribes again to the topics and sends the publishes
+    /// kept in the outbox. Their responses are read before returning, the messages
+    /// published meanwhile are returned by `read_message`.
+    async fn recover(&mut self, mut error: anyhow::Error) -> Result<()> {
+        let Some(policy) = self.reconnect.clone() else {
+            return Err(error);
+        };
+        let mut attempt = 1;
+        loop {
+            warn!("Connection lost: {}", error);
+            self.stream = None;
+            let _ = self
+                .events
+                .send(ConnectionEvent::Disconnected(error.to_string()));
+            loop {
+                if !policy.allows(attempt) {
+                    let _ = self.events.send(ConnectionEvent::Closed);
+                    return Err(error);
+                }
+                let delay = policy.delay(attempt);
+                let _ = self
+                    .events
+                    .send(ConnectionEvent::Reconnecting { attempt, delay });
+                tokio::time::sleep(delay).await;
+                match self.connect().await {
+                    Ok(()) => break,
+                    Err(e) => {
+                        debug!("Reconnect attempt {} failed: {}", attempt, e);
+                        self.stream = None;
+                    }
+                }
+                attempt += 1;
+            }
+            info!("Reconnected after {} attempts", attempt);
+            match self.restore().await {
+                Ok(()) => return Ok(()),
+                // lost again, the remaining requests are sent after the next reconnect.
+                Err(e) if is_connection_error(&e) => error = e,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// subscribes again to the topics and sends the publishes kept in the outbox,
+    /// one at a time, each one waits for its response.
+    async fn restore(&mut self) -> Result<()> {
+        for topic in self.subscriptions.clone() {
+            let msg = Msg::new(PktType::SUBSCRIBE, topic, None);
+            self.write(msg.bytes()).await?;
+            self.read_ack().await?;
+        }
+        while let Some(msg) = self.outbox.front() {
+            self.write(msg.bytes()).await?;
+            self.read_ack().await?;
+            self.outbox.pop_front();
         }
         Ok(())
     }
 
+    /// reads the response to the request sent last, the error packets are logged.
+    /// The messages published before the response are kept for `read_message`.
+    async fn read_ack(&mut self) -> Result<()> {
+        loop {
+            match self.read_response().await {
+                Ok(msg) if msg.header.pkt_type == PktType::PUBLISH => self.received.push_back(msg),
+                Ok(msg) => {
+                    trace!("Resp: {:?}", msg.header);
+                    return Ok(());
+                }
+                Err(e) if is_connection_error(&e) => return Err(e),
+                Err(e) => {
+                    warn!("{}", e);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sets the reconnect policy, the client connects again with a backoff when
+    /// the connection is lost and subscribes again to its topics.
+    /// ```
+    /// use simple_pub_sub::client::{PubSubClient, Client, ReconnectPolicy};
+    /// let client_type = simple_pub_sub::client::PubSubTcpClient {
+    ///        server: "localhost".to_string(),
+    ///        port: 6480,
+    ///        cert: None,
+    ///        cert_password: None,
+    ///        client_cert: None,
+    ///        client_key: None,
+    /// };
+    /// let mut client = Client::new(PubSubClient::Tcp(client_type));
+    /// client.reconnect(ReconnectPolicy {
+    ///     max_attempts: Some(20),
+    ///     outbox: 100,
+    ///     ..Default::default()
+    /// });
+    /// ```
+    pub fn reconnect(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = Some(policy);
+    }
+
+    /// sets the capacity of the channels of the split client, 1024 by default.
+    /// The requests of the handles wait while the I/O task has `capacity` of them
+    /// to send, and a subscription keeps at most `capacity` messages not read yet:
+    /// the messages published meanwhile are dropped for it, the other subscriptions
+    /// and the responses are not held up by a slow reader.
+    /// ```
+    /// use simple_pub_sub::client::{PubSubClient, PubSubUnixClient, Client};
+    /// let mut pub_sub_client = Client::new(PubSubClient::Unix(PubSubUnixClient {
+    ///     path: "/tmp/simple.sock".to_string(),
+    /// }));
+    /// pub_sub_client.split_capacity(64);
+    /// ```
+    pub fn split_capacity(&mut self, capacity: usize) {
+        // the channels hold at least one message.
+        self.split_capacity = capacity.max(1);
+    }
+
+    /// returns the receiver of the connection state changes.
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
     /// Sends the message to the given server and returns the ack
     /// the server could be either a tcp or unix server
     ///```
@@ -217,6 +463,8 @@ impl Client {
     ///          port: 6480,
     ///          cert: None,
     ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
     ///   };
     ///
     /// // initialize the client.
@@ -230,20 +478,17 @@ impl Client {
     /// ```
     pub async fn post(&mut self, msg: Msg) -> Result<Vec<u8>> {
         self.write(msg.bytes()).await?;
-        let mut response_message_buffer: Vec<u8>;
-        response_message_buffer = vec![0; 8];
-        self.read(&mut response_message_buffer).await?;
-        trace!("Buf: {:?}", response_message_buffer);
-        let response_header = Header::try_from(response_message_buffer.clone())?;
-        trace!("Resp: {:?}", response_header);
-        let mut response_body = Vec::with_capacity(response_header.message_length as usize);
-        trace!("Reading remaining bytes");
-        self.read_buf(&mut response_body).await?;
-        response_message_buffer.extend(response_body);
-        Ok(response_message_buffer)
+        let response = self.read_response().await?;
+        trace!("Resp: {:?}", response);
+        Ok(response.bytes())
     }
 
     /// Publishes the message to the given topic
+    ///
+    /// With a reconnect policy a lost connection is recovered first, the publish
+    /// is sent again when the outbox is not 0. Once the client gives up, the
+    /// publish fails and is kept in the outbox up to its limit, it is sent on the
+    /// next reconnect.
     /// ```
     /// use simple_pub_sub::client::{PubSubClient, Client};
     /// async fn publish_msg(){
@@ -252,6 +497,8 @@ impl Client {
     ///          port: 6480,
     ///          cert: None,
     ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
     ///   };
     ///
     /// // initialize the client.
@@ -270,7 +517,25 @@ impl Client {
     pub async fn publish(&mut self, topic: String, message: Vec<u8>) -> Result<()> {
         let msg: Msg = Msg::new(PktType::PUBLISH, topic, Some(message));
         trace!("Msg: {:?}", msg);
-        let buf = self.post(msg).await?;
+        let buf = match self.post(msg.clone()).await {
+            Ok(buf) => buf,
+            Err(e) if self.reconnect.is_some() && is_connection_error(&e) => {
+                let outbox = self.reconnect.as_ref().map_or(0, |policy| policy.outbox);
+                if let Err(e) = self.recover(e).await {
+                    // sent after the next reconnect, dropped over the limit.
+                    if self.outbox.len() < outbox {
+                        self.outbox.push_back(msg);
+                    }
+                    return Err(e);
+                }
+                if outbox == 0 {
+                    anyhow::bail!(ClientNotConnected);
+                }
+                // the publish was not acknowledged, it is sent again.
+                self.post(msg).await?
+            }
+            Err(e) => return Err(e),
+        };
         trace!("The raw buffer is: {:?}", buf);
         let resp_: Header = Header::try_from(buf)?;
         trace!("{:?}", resp_);
@@ -286,6 +551,8 @@ impl Client {
     ///          port: 6480,
     ///          cert: None,
     ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
     ///   };
     ///
     /// // initialize the client.
@@ -305,10 +572,51 @@ impl Client {
         trace!("Msg: {:?}", msg);
 
         self.write(msg.bytes()).await?;
-        let msg = self.read_message().await?;
+        let msg = self.read_response().await?;
         Ok(String::from_utf8(msg.message)?)
     }
 
+    /// Sends the admin request to the server, returns the json result.
+    /// ```
+    /// use simple_pub_sub::admin::AdminRequest;
+    /// use simple_pub_sub::client::{self, PubSubClient, Client};
+    /// async fn kick(){
+    ///   let client_type = simple_pub_sub::client::PubSubTcpClient {
+    ///          server: "localhost".to_string(),
+    ///          port: 6480,
+    ///          cert: None,
+    ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
+    ///   };
+    ///
+    /// // initialize the client.
+    /// let mut pub_sub_client = simple_pub_sub::client::Client::new(
+    ///     simple_pub_sub::client::PubSubClient::Tcp(client_type),
+    /// );
+    /// pub_sub_client.connect().await.unwrap();
+    /// pub_sub_client.admin(AdminRequest::Kick { client_id: "abc".to_string() }).await.unwrap();
+    /// }
+    /// ```
+    pub async fn admin(&mut self, request: AdminRequest) -> Result<String> {
+        let msg: Msg = Msg::new(
+            PktType::ADMIN,
+            request.operation().to_string(),
+            Some(serde_json::to_vec(&request)?),
+        );
+        trace!("Msg: {:?}", msg);
+
+        self.write(msg.bytes()).await?;
+        let msg = self.read_response().await?;
+        Ok(String::from_utf8(msg.message)?)
+    }
+
+    /// lists the clients connected to the server.
+    pub async fn clients(&mut self) -> Result<Vec<ClientInfo>> {
+        let resp = self.admin(AdminRequest::Clients).await?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+
     /// subscribes to the given topic
     ///```
     /// use simple_pub_sub::client::{self, PubSubClient, Client};
@@ -317,6 +625,8 @@ impl Client {
     ///        port: 6480,
     ///        cert: None,
     ///        cert_password: None,
+    ///        client_cert: None,
+    ///        client_key: None,
     /// };
     /// // initialize the client.
     /// let mut pub_sub_client = simple_pub_sub::client::Client::new(
@@ -324,33 +634,46 @@ impl Client {
     /// pub_sub_client.subscribe("Test".to_string());
     /// ```
     pub async fn subscribe(&mut self, topic: String) -> Result<()> {
+        self.subscriptions.insert(topic.clone());
         let msg: message::Msg = message::Msg::new(PktType::SUBSCRIBE, topic, None);
         trace!("Msg: {:?}", msg);
         self.write(msg.bytes()).await
     }
 
-    async fn write(&mut self, message: Vec<u8>) -> Result<()> {
-        if let Some(stream) = &mut self.stream {
-            stream.write_all(message).await?;
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!(ClientNotConnected))
-        }
+    /// Moves the connection to a background I/O task, returns the cloneable
+    /// handles to publish and to subscribe from any task.
+    /// The responses are routed to the callers, the published messages to
+    /// the [`Subscription`] streams.
+    /// ```
+    /// use simple_pub_sub::client::{PubSubClient, Client};
+    /// use tokio_stream::StreamExt;
+    /// async fn split(){
+    ///   let client_type = simple_pub_sub::client::PubSubTcpClient {
+    ///          server: "localhost".to_string(),
+    ///          port: 6480,
+    ///          cert: None,
+    ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
+    ///   };
+    ///   let mut client = Client::new(PubSubClient::Tcp(client_type));
+    ///   client.connect().await.unwrap();
+    ///   let (publisher, subscriber) = client.split().unwrap();
+    ///   let mut subscription = subscriber.subscribe("Test".to_string()).await.unwrap();
+    ///   publisher.publish("Test".to_string(), b"hello".to_vec()).await.unwrap();
+    ///   let msg = subscription.next().await.unwrap();
+    /// }
+    /// ```
+    pub fn split(mut self) -> Result<(Publisher, Subscriber)> {
+        let Some(stream) = self.stream.take() else {
+            anyhow::bail!(ClientNotConnected);
+        };
+        Ok(split::spawn(stream, self))
     }
 
-    async fn read(&mut self, message: &mut [u8]) -> Result<()> {
-        if let Some(stream) = &mut self.stream {
-            let size = stream.read(message).await?;
-            trace!("Read: {} bytes", size);
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!(ClientNotConnected))
-        }
-    }
-    async fn read_buf(&mut self, message: &mut Vec<u8>) -> Result<()> {
+    async fn write(&mut self, message: Vec<u8>) -> Result<()> {
         if let Some(stream) = &mut self.stream {
-            let size = stream.read_buf(message).await?;
-            trace!("Read: {} bytes", size);
+            stream.write_all(message).await?;
             Ok(())
         } else {
             Err(anyhow::anyhow!(ClientNotConnected))
@@ -368,6 +691,8 @@ impl Client {
     ///          port: 6480,
     ///          cert: None,
     ///          cert_password: None,
+    ///          client_cert: None,
+    ///          client_key: None,
     ///   };
     ///   // initialize the client.
     ///   let mut pub_sub_client = simple_pub_sub::client::Client::new(
@@ -389,10 +714,40 @@ impl Client {
     /// }
     /// ```
     pub async fn read_message(&mut self) -> Result<Msg> {
+        loop {
+            if let Some(msg) = self.received.pop_front() {
+                return Ok(msg);
+            }
+            match self.read_response().await {
+                Err(e) if self.reconnect.is_some() && is_connection_error(&e) => {
+                    self.recover(e).await?
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// reads the next message without reconnecting, the error packets are returned as errors.
+    async fn read_response(&mut self) -> Result<Msg> {
         if let Some(stream) = &mut self.stream {
-            stream.read_message().await
+            let msg = stream.read_message().await?;
+            if msg.header.pkt_type == PktType::ERROR {
+                anyhow::bail!(PubSubError::ServerError(
+                    String::from_utf8_lossy(&msg.message).to_string()
+                ));
+            }
+            Ok(msg)
         } else {
             Err(anyhow::anyhow!(ClientNotConnected))
         }
     }
 }
+
+/// returns true if the error is not an error packet from the server,
+/// the connection is lost or was not established.
+fn is_connection_error(e: &anyhow::Error) -> bool {
+    !matches!(
+        e.downcast_ref::<PubSubError>(),
+        Some(PubSubError::ServerError(_))
+    )
+}
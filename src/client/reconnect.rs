@@ -0,0 +1,87 @@
+//! Reconnect policy of the client.
+//!
+//! With a policy set, a client that loses the connection connects again after an
+//! exponential backoff with jitter, authenticates and subscribes again to its
+//! topics. The publishes that were not acknowledged when the connection was lost
+//! and the ones made while the client is disconnected are kept in a bounded outbox
+//! and sent again once it is connected, so a message can be delivered twice.
+//! The publishes over the outbox limit fail and are dropped.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Reconnect policy, see [`Client::reconnect`](super::Client::reconnect).
+/// ```
+/// use simple_pub_sub::client::ReconnectPolicy;
+/// let policy = ReconnectPolicy {
+///     max_attempts: Some(10),
+///     outbox: 1000,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// delay before the first attempt, doubled after every failed one
+    pub initial_backoff: Duration,
+    /// maximum delay between the attempts
+    pub max_backoff: Duration,
+    /// attempts before giving up, unlimited when not set
+    pub max_attempts: Option<u32>,
+    /// waits a random part of the delay, so the clients of a restarted broker
+    /// do not reconnect all at once
+    pub jitter: bool,
+    /// publishes kept while the client is disconnected, none when 0
+    pub outbox: usize,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+            jitter: true,
+            outbox: 0,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// delay before the attempt, counted from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        // between the half and the full delay.
+        backoff / 2 + backoff.mul_f64(random() / 2.0)
+    }
+
+    /// returns true if the attempt is allowed.
+    pub(crate) fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+}
+
+/// random number in [0, 1).
+fn random() -> f64 {
+    // every `RandomState` has new random keys.
+    let value = RandomState::new().build_hasher().finish();
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Change of the connection state, see [`Client::events`](super::Client::events).
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// connected or connected again to the server
+    Connected,
+    /// the connection was lost, with the reason
+    Disconnected(String),
+    /// waiting before the next attempt to connect
+    Reconnecting { attempt: u32, delay: Duration },
+    /// the attempts are exhausted, the client stays disconnected
+    Closed,
+}
@@ -0,0 +1,667 @@
+//! Client connection driven by a background I/O task.
+//!
+//! [`Client::split`](super::Client::split) moves the connection to a task that
+//! reads and writes the socket, the [`Publisher`] and the [`Subscriber`] handles
+//! send their requests to it and can be cloned and used from any task. The task
+//! sends one request at a time and routes its response (the ack, the query or the
+//! admin response or the error) back to the caller, the published messages are
+//! routed to the [`Subscription`] streams matching their topic and never mixed
+//! with the responses.
+//!
+//! With a [`ReconnectPolicy`](super::ReconnectPolicy) the task connects again when
+//! the connection is lost, subscribes again to the topics of the subscriptions and
+//! sends the publishes kept in the outbox, the streams stay open meanwhile.
+//!
+//! The channels are bounded by [`Client::split_capacity`](super::Client::split_capacity):
+//! the requests wait for room in the channel of the I/O task, the connection is not
+//! read while the task is behind, and the messages that do not fit in the channel
+//! of a subscription are dropped for it with a warning, so a slow subscription
+//! holds up neither the other ones nor the responses.
+use super::{Client, ConnectionEvent, StreamType};
+use crate::acl::topic_matches;
+use crate::admin::{AdminRequest, ClientInfo};
+use crate::error::PubSubError;
+use crate::message::Msg;
+use crate::stream;
+use crate::PktType;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tokio_stream::Stream;
+use tracing::{debug, info, trace, warn};
+
+/// byte stream of any of the connection types.
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+impl StreamType {
+    fn into_io(self) -> Box<dyn Io> {
+        match self {
+            StreamType::Tcp(stream) => Box::new(stream),
+            StreamType::Tls(stream) => stream,
+            StreamType::Unix(stream) => Box::new(stream),
+            StreamType::WebSocket(stream) => stream,
+            StreamType::WebSocketTls(stream) => stream,
+            StreamType::Memory(stream) => Box::new(stream),
+        }
+    }
+}
+
+/// request to the I/O task.
+enum Command {
+    /// sends the packet, the response is returned when there is a caller waiting
+    Request(Msg, Option<oneshot::Sender<Result<Msg>>>),
+    /// adds the subscription, the topic is subscribed when it is the first one for it
+    Subscribe(String, u64, mpsc::Sender<Msg>, oneshot::Sender<Result<()>>),
+    /// removes the subscription, the topic is unsubscribed when it was the last one for it
+    Unsubscribe(String, u64),
+}
+
+/// caller waiting for the response to its request.
+enum Waiter {
+    Response(oneshot::Sender<Result<Msg>>),
+    /// the subscription is removed when the subscribe fails
+    Subscribe(String, u64, oneshot::Sender<Result<()>>),
+    None,
+}
+
+/// starts the I/O task for the stream of the connected client.
+pub(super) fn spawn(stream: StreamType, client: Client) -> (Publisher, Subscriber) {
+    let capacity = client.split_capacity;
+    let (commands, rx) = mpsc::channel(capacity);
+    let events = client.events.clone();
+    let task = IoTask {
+        connection: Some(Connection::new(stream, capacity)),
+        client,
+        pending: VecDeque::new(),
+        in_flight: None,
+        subscriptions: HashMap::new(),
+    };
+    tokio::spawn(task.run(rx));
+    (
+        Publisher {
+            commands: commands.clone(),
+            events,
+        },
+        Subscriber {
+            commands,
+            next_id: Arc::new(AtomicU64::new(0)),
+            capacity,
+        },
+    )
+}
+
+/// connected stream, split in the read and the write half.
+struct Connection {
+    writer: WriteHalf<Box<dyn Io>>,
+    incoming: mpsc::Receiver<Result<Msg>>,
+    reading: JoinHandle<()>,
+}
+
+impl Connection {
+    fn new(stream: StreamType, capacity: usize) -> Connection {
+        let (reader, writer) = tokio::io::split(stream.into_io());
+        let (incoming, rx) = mpsc::channel(capacity);
+        // the reads are never cancelled halfway through a message,
+        // so they run in their own task.
+        let reading = tokio::spawn(read(reader, incoming));
+        Connection {
+            writer,
+            incoming: rx,
+            reading,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reading.abort();
+    }
+}
+
+/// reads the messages until the connection is closed, waits while the channel is full.
+async fn read(mut reader: ReadHalf<Box<dyn Io>>, incoming: mpsc::Sender<Result<Msg>>) {
+    loop {
+        let msg = stream::read_message(&mut reader).await;
+        let failed = msg.is_err();
+        if incoming.send(msg).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// what woke up the I/O task.
+enum Event {
+    Command(Option<Command>),
+    Incoming(Option<Result<Msg>>),
+}
+
+struct IoTask {
+    /// `None` while disconnected
+    connection: Option<Connection>,
+    /// the client without the stream, to connect again
+    client: Client,
+    /// requests waiting for the one in flight, or for the connection
+    pending: VecDeque<(Msg, Waiter)>,
+    in_flight: Option<(Msg, Waiter)>,
+    /// subscriptions by topic or pattern
+    subscriptions: HashMap<String, Vec<(u64, mpsc::Sender<Msg>)>>,
+}
+
+impl IoTask {
+    /// runs until the connection is closed for good or all the handles are dropped.
+    async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        let reason = loop {
+            let Some(connection) = &mut self.connection else {
+                match self.reconnect(&mut commands).await {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(e) => break e,
+                }
+                if let Err(e) = self.restore().await {
+                    if let Err(e) = self.lost(e) {
+                        break e;
+                    }
+                }
+                continue;
+            };
+            let event = tokio::select! {
+                command = commands.recv() => Event::Command(command),
+                msg = connection.incoming.recv() => Event::Incoming(msg),
+            };
+            let result = match event {
+                Event::Command(Some(command)) => self.command(command).await,
+                Event::Command(None) => {
+                    debug!("All the client handles are dropped, closing the connection");
+                    if let Some(connection) = &mut self.connection {
+                        let _ = connection.writer.shutdown().await;
+                    }
+                    return;
+                }
+                Event::Incoming(Some(Ok(msg))) => self.incoming(msg).await,
+                Event::Incoming(Some(Err(e))) => Err(e),
+                Event::Incoming(None) => Err(anyhow!(PubSubError::ClientNotConnected)),
+            };
+            if let Err(e) = result {
+                if let Err(e) = self.lost(e) {
+                    break e;
+                }
+            }
+        };
+        debug!("Client connection closed: {}", reason);
+        // the waiting callers get the error, the subscription streams end.
+        let error = reason.to_string();
+        let waiters = self
+            .in_flight
+            .take()
+            .into_iter()
+            .chain(self.pending.drain(..))
+            .map(|(_, waiter)| waiter);
+        for waiter in waiters {
+            fail(waiter, anyhow!(PubSubError::ServerError(error.clone())));
+        }
+        // the requests sent later fail as the channel is closed.
+        commands.close();
+        while let Some(command) = commands.recv().await {
+            match command {
+                Command::Request(_, Some(reply)) => {
+                    let _ = reply.send(Err(anyhow!(PubSubError::ClientNotConnected)));
+                }
+                Command::Subscribe(_, _, _, reply) => {
+                    let _ = reply.send(Err(anyhow!(PubSubError::ClientNotConnected)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// handles the lost connection, returns the error when the client does not reconnect.
+    fn lost(&mut self, e: anyhow::Error) -> Result<()> {
+        if self.client.reconnect.is_none() {
+            return Err(e);
+        }
+        warn!("Connection lost: {}", e);
+        self.connection = None;
+        let _ = self
+            .client
+            .events
+            .send(ConnectionEvent::Disconnected(e.to_string()));
+        // the request without a response is sent again, when it can be.
+        let requests = self
+            .in_flight
+            .take()
+            .into_iter()
+            .chain(self.pending.drain(..));
+        for (msg, waiter) in requests.collect::<Vec<_>>() {
+            self.hold(msg, waiter);
+        }
+        Ok(())
+    }
+
+    /// connects again with the backoff of the policy, the commands are handled meanwhile.
+    /// Returns false when all the handles are dropped.
+    async fn reconnect(&mut self, commands: &mut mpsc::Receiver<Command>) -> Result<bool> {
+        let Some(policy) = self.client.reconnect.clone() else {
+            bail!(PubSubError::ClientNotConnected);
+        };
+        let mut attempt = 1;
+        while policy.allows(attempt) {
+            let delay = policy.delay(attempt);
+            let _ = self
+                .client
+                .events
+                .send(ConnectionEvent::Reconnecting { attempt, delay });
+            let wake = Instant::now() + delay;
+            loop {
+                tokio::select! {
+                    _ = sleep_until(wake) => break,
+                    command = commands.recv() => match command {
+                        // not connected, the request is kept or fails.
+                        Some(command) => self.command(command).await?,
+                        None => return Ok(false),
+                    },
+                }
+            }
+            match self.client.connect().await {
+                Ok(()) => {
+                    if let Some(stream) = self.client.stream.take() {
+                        info!("Reconnected after {} attempts", attempt);
+                        let capacity = self.client.split_capacity;
+                        self.connection = Some(Connection::new(stream, capacity));
+                        return Ok(true);
+                    }
+                }
+                Err(e) => {
+                    debug!("Reconnect attempt {} failed: {}", attempt, e);
+                    self.client.stream = None;
+                }
+            }
+            attempt += 1;
+        }
+        let _ = self.client.events.send(ConnectionEvent::Closed);
+        bail!("Could not reconnect after {} attempts", attempt - 1)
+    }
+
+    /// subscribes again to the topics and sends the pending requests.
+    async fn restore(&mut self) -> Result<()> {
+        // the topics with a new subscription waiting are subscribed by it.
+        let waiting = self
+            .pending
+            .iter()
+            .filter_map(|(_, waiter)| match waiter {
+                Waiter::Subscribe(topic, _, _) => Some(topic.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for topic in self.subscriptions.keys() {
+            if !waiting.contains(topic) {
+                let msg = Msg::new(PktType::SUBSCRIBE, topic.clone(), None);
+                self.pending.push_front((msg, Waiter::None));
+            }
+        }
+        self.send_next().await
+    }
+
+    /// keeps the request while disconnected: the publishes up to the outbox limit and
+    /// the new subscriptions, the other requests fail.
+    fn hold(&mut self, msg: Msg, waiter: Waiter) {
+        let outbox = self
+            .client
+            .reconnect
+            .as_ref()
+            .map_or(0, |policy| policy.outbox);
+        let held = match (&msg.header.pkt_type, &waiter) {
+            (PktType::PUBLISH, _) => {
+                let publishes = self
+                    .pending
+                    .iter()
+                    .filter(|(msg, _)| msg.header.pkt_type == PktType::PUBLISH)
+                    .count();
+                publishes < outbox
+            }
+            (PktType::SUBSCRIBE, Waiter::Subscribe(..)) => true,
+            // the topics are subscribed again from the subscriptions.
+            _ => false,
+        };
+        if held {
+            self.pending.push_back((msg, waiter));
+        } else {
+            fail(waiter, anyhow!(PubSubError::ClientNotConnected));
+        }
+    }
+
+    async fn command(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Request(msg, reply) => {
+                let waiter = reply.map_or(Waiter::None, Waiter::Response);
+                self.request(msg, waiter).await
+            }
+            Command::Subscribe(topic, id, channel, reply) => {
+                let subscriptions = self.subscriptions.entry(topic.clone()).or_default();
+                subscriptions.push((id, channel));
+                if subscriptions.len() > 1 {
+                    // the topic is already subscribed on the connection.
+                    let _ = reply.send(Ok(()));
+                    return Ok(());
+                }
+                let msg = Msg::new(PktType::SUBSCRIBE, topic.clone(), None);
+                self.request(msg, Waiter::Subscribe(topic, id, reply)).await
+            }
+            Command::Unsubscribe(topic, id) => {
+                if self.remove(&topic, id) {
+                    let msg = Msg::new(PktType::UNSUBSCRIBE, topic, None);
+                    self.request(msg, Waiter::None).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// sends the request when none is in flight, queues it otherwise.
+    async fn request(&mut self, msg: Msg, waiter: Waiter) -> Result<()> {
+        let Some(connection) = &mut self.connection else {
+            self.hold(msg, waiter);
+            return Ok(());
+        };
+        if self.in_flight.is_some() {
+            self.pending.push_back((msg, waiter));
+            return Ok(());
+        }
+        trace!("Sending: {:?}", msg.header);
+        let bytes = msg.bytes();
+        // kept until the response, to be sent again after a reconnect.
+        self.in_flight = Some((msg, waiter));
+        connection.writer.write_all(&bytes).await?;
+        connection.writer.flush().await?;
+        Ok(())
+    }
+
+    /// sends the first pending request.
+    async fn send_next(&mut self) -> Result<()> {
+        match self.pending.pop_front() {
+            Some((msg, waiter)) => self.request(msg, waiter).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn incoming(&mut self, msg: Msg) -> Result<()> {
+        if msg.header.pkt_type == PktType::PUBLISH {
+            // the dropped subscriptions the task was not told about are removed.
+            for (topic, id) in self.dispatch(msg) {
+                self.command(Command::Unsubscribe(topic, id)).await?;
+            }
+            return Ok(());
+        }
+        let Some((_, waiter)) = self.in_flight.take() else {
+            if msg.header.pkt_type == PktType::ERROR {
+                warn!(
+                    "Error from the server: {}",
+                    String::from_utf8_lossy(&msg.message)
+                );
+            } else {
+                warn!("Unexpected {} from the server", msg.header.pkt_type);
+            }
+            return Ok(());
+        };
+        match waiter {
+            Waiter::Response(reply) => {
+                let _ = reply.send(response(msg));
+            }
+            Waiter::Subscribe(topic, id, reply) => {
+                let result = response(msg).map(|_| ());
+                if result.is_err() {
+                    self.remove(&topic, id);
+                }
+                let _ = reply.send(result);
+            }
+            Waiter::None => {
+                if let Err(e) = response(msg) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        self.send_next().await
+    }
+
+    /// sends the message to the subscriptions matching its topic, the message is
+    /// dropped for the subscriptions with a full channel.
+    /// Returns the subscriptions with a closed channel.
+    fn dispatch(&mut self, msg: Msg) -> Vec<(String, u64)> {
+        let mut delivered = false;
+        let mut closed = vec![];
+        for (topic, subscriptions) in &self.subscriptions {
+            if !topic_matches(topic, &msg.topic) {
+                continue;
+            }
+            for (id, channel) in subscriptions {
+                match channel.try_send(msg.clone()) {
+                    Ok(()) => delivered = true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!(
+                            "The subscription to '{}' is full, dropping the message on '{}'",
+                            topic, msg.topic
+                        );
+                    }
+                    Err(TrySendError::Closed(_)) => closed.push((topic.clone(), *id)),
+                }
+            }
+        }
+        if !delivered {
+            debug!("No subscription for the message on '{}'", msg.topic);
+        }
+        closed
+    }
+
+    /// removes the subscription, returns true if it was the last one for the topic.
+    fn remove(&mut self, topic: &str, id: u64) -> bool {
+        let Some(subscriptions) = self.subscriptions.get_mut(topic) else {
+            return false;
+        };
+        subscriptions.retain(|(subscription, _)| *subscription != id);
+        if subscriptions.is_empty() {
+            self.subscriptions.remove(topic);
+            return true;
+        }
+        false
+    }
+}
+
+/// the response, or the error sent by the server.
+fn response(msg: Msg) -> Result<Msg> {
+    if msg.header.pkt_type == PktType::ERROR {
+        bail!(PubSubError::ServerError(
+            String::from_utf8_lossy(&msg.message).to_string()
+        ));
+    }
+    Ok(msg)
+}
+
+fn fail(waiter: Waiter, e: anyhow::Error) {
+    match waiter {
+        Waiter::Response(reply) => {
+            let _ = reply.send(Err(e));
+        }
+        Waiter::Subscribe(_, _, reply) => {
+            let _ = reply.send(Err(e));
+        }
+        Waiter::None => {}
+    }
+}
+
+/// sends the request to the I/O task and waits for the response.
+async fn request(commands: &mpsc::Sender<Command>, msg: Msg) -> Result<Msg> {
+    let (reply, response) = oneshot::channel();
+    commands
+        .send(Command::Request(msg, Some(reply)))
+        .await
+        .map_err(|_| PubSubError::ClientNotConnected)?;
+    response
+        .await
+        .map_err(|_| PubSubError::ClientNotConnected)?
+}
+
+/// Cloneable handle to publish and to send the queries and the admin requests.
+/// ```
+/// use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient};
+/// async fn publish() {
+///   let mut client = Client::new(PubSubClient::Tcp(PubSubTcpClient {
+///     server: "localhost".to_string(),
+///     port: 6480,
+///     cert: None,
+///     cert_password: None,
+///     client_cert: None,
+///     client_key: None,
+///   }));
+///   client.connect().await.unwrap();
+///   let (publisher, _subscriber) = client.split().unwrap();
+///   for i in 0..4 {
+///     let publisher = publisher.clone();
+///     tokio::spawn(async move {
+///       publisher
+///         .publish("abc".to_string(), format!("from task {i}").into_bytes())
+///         .await
+///         .unwrap();
+///     });
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+impl Publisher {
+    /// returns the receiver of the connection state changes.
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// publishes the message, returns once the server acknowledged it.
+    pub async fn publish(&self, topic: String, message: Vec<u8>) -> Result<()> {
+        let msg = Msg::new(PktType::PUBLISH, topic, Some(message));
+        request(&self.commands, msg).await?;
+        Ok(())
+    }
+
+    /// returns the response of the server to the query.
+    pub async fn query(&self, topic: String) -> Result<String> {
+        let msg = Msg::new(PktType::QUERY, topic, Some(b" ".to_vec()));
+        let msg = request(&self.commands, msg).await?;
+        Ok(String::from_utf8(msg.message)?)
+    }
+
+    /// sends the admin request, returns the json result.
+    pub async fn admin(&self, request: AdminRequest) -> Result<String> {
+        let msg = Msg::new(
+            PktType::ADMIN,
+            request.operation().to_string(),
+            Some(serde_json::to_vec(&request)?),
+        );
+        let msg = self::request(&self.commands, msg).await?;
+        Ok(String::from_utf8(msg.message)?)
+    }
+
+    /// returns the clients connected to the server.
+    pub async fn clients(&self) -> Result<Vec<ClientInfo>> {
+        let resp = self.admin(AdminRequest::Clients).await?;
+        Ok(serde_json::from_str(&resp)?)
+    }
+}
+
+/// Cloneable handle to subscribe, every subscription is a separate stream.
+/// ```
+/// use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient};
+/// use tokio_stream::StreamExt;
+/// async fn subscribe() {
+///   let mut client = Client::new(PubSubClient::Tcp(PubSubTcpClient {
+///     server: "localhost".to_string(),
+///     port: 6480,
+///     cert: None,
+///     cert_password: None,
+///     client_cert: None,
+///     client_key: None,
+///   }));
+///   client.connect().await.unwrap();
+///   let (_publisher, subscriber) = client.split().unwrap();
+///   let mut temperatures = subscriber.subscribe("sensors/+/temp".to_string()).await.unwrap();
+///   while let Some(msg) = temperatures.next().await {
+///     println!("{}: {:?}", msg.topic, msg.message);
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    commands: mpsc::Sender<Command>,
+    next_id: Arc<AtomicU64>,
+    /// capacity of the channels of the subscriptions
+    capacity: usize,
+}
+
+impl Subscriber {
+    /// subscribes to the topic or the pattern, returns once the server acknowledged it.
+    pub async fn subscribe(&self, topic: String) -> Result<Subscription> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (channel, rx) = mpsc::channel(self.capacity);
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe(topic.clone(), id, channel, reply))
+            .await
+            .map_err(|_| PubSubError::ClientNotConnected)?;
+        response
+            .await
+            .map_err(|_| PubSubError::ClientNotConnected)??;
+        Ok(Subscription {
+            topic,
+            id,
+            rx,
+            commands: self.commands.clone(),
+        })
+    }
+}
+
+/// Stream of the messages published on the subscribed topic or pattern,
+/// the topic is unsubscribed when the last subscription to it is dropped.
+/// The stream ends when the connection is closed. The messages published while
+/// the stream holds [`Client::split_capacity`](super::Client::split_capacity) messages
+/// not read yet are dropped for it.
+#[derive(Debug)]
+pub struct Subscription {
+    topic: String,
+    id: u64,
+    rx: mpsc::Receiver<Msg>,
+    commands: mpsc::Sender<Command>,
+}
+
+impl Subscription {
+    /// the subscribed topic or pattern.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Msg;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Msg>> {
+        self.get_mut().rx.poll_recv(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // with a full channel the I/O task removes the subscription
+        // when it finds its channel closed.
+        let _ = self
+            .commands
+            .try_send(Command::Unsubscribe(self.topic.clone(), self.id));
+    }
+}
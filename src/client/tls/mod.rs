@@ -0,0 +1,11 @@
+//! tls backends for the client, `rustls` takes precedence over `openssl`
+//! when both the features are enabled.
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+mod native;
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub use native::{connect, TlsStream};
+
+#[cfg(feature = "rustls")]
+mod rustls;
+#[cfg(feature = "rustls")]
+pub use self::rustls::{connect, TlsStream};
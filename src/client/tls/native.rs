@@ -0,0 +1,56 @@
+use super::super::PubSubTcpClient;
+use anyhow::Result;
+use std::fs::File;
+use std::io::Read;
+use tokio::net::TcpStream;
+use tokio_native_tls::native_tls::{Certificate, Identity, TlsConnector};
+
+/// tls stream for the native-tls backend
+pub type TlsStream = tokio_native_tls::TlsStream<TcpStream>;
+
+/// reads the client identity from a `.pem` certificate and key,
+/// or from a PKCS#12 file when no key is given.
+fn client_identity(cert: &str, key: Option<&str>, password: Option<&str>) -> Result<Identity> {
+    let mut cert_buf = vec![];
+    File::open(cert)?.read_to_end(&mut cert_buf)?;
+    match key {
+        Some(key) => {
+            let mut key_buf = vec![];
+            File::open(key)?.read_to_end(&mut key_buf)?;
+            Ok(Identity::from_pkcs8(&cert_buf, &key_buf)?)
+        }
+        None => Ok(Identity::from_pkcs12(&cert_buf, password.unwrap_or(""))?),
+    }
+}
+
+/// Connects to the server over tls.
+pub async fn connect(url: &str, tcp_client: &PubSubTcpClient) -> Result<TlsStream> {
+    let mut builder = TlsConnector::builder();
+
+    // Load CA certificate
+    if let Some(cert) = &tcp_client.cert {
+        let mut file = File::open(cert)?;
+        let mut ca_cert = vec![];
+        file.read_to_end(&mut ca_cert)?;
+        builder.add_root_certificate(Certificate::from_pem(&ca_cert)?);
+    }
+
+    // Load the client certificate for mutual tls
+    if let Some(client_cert) = &tcp_client.client_cert {
+        builder.identity(client_identity(
+            client_cert,
+            tcp_client.client_key.as_deref(),
+            tcp_client.cert_password.as_deref(),
+        )?);
+    }
+
+    // Configure TLS
+    let connector = builder.build()?;
+
+    let connector = tokio_native_tls::TlsConnector::from(connector);
+
+    // Connect to the server
+    let stream = TcpStream::connect(url).await?;
+
+    Ok(connector.connect(&tcp_client.server, stream).await?)
+}
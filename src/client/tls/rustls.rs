@@ -0,0 +1,50 @@
+use super::super::PubSubTcpClient;
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+/// tls stream for the rustls backend
+pub type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+/// Connects to the server over tls.
+/// the webpki roots are used when no CA certificate is given.
+pub async fn connect(url: &str, tcp_client: &PubSubTcpClient) -> Result<TlsStream> {
+    // Load CA certificate
+    let mut roots = RootCertStore::empty();
+    if let Some(cert) = &tcp_client.cert {
+        for ca in CertificateDer::pem_file_iter(cert).context("Error while opening the CA")? {
+            roots.add(ca.context("Error while loading the CA")?)?;
+        }
+    } else {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+
+    // Load the client certificate for mutual tls
+    let config = match (&tcp_client.client_cert, &tcp_client.client_key) {
+        (Some(client_cert), Some(client_key)) => {
+            let certs = CertificateDer::pem_file_iter(client_cert)
+                .context("Error while opening the client certificate")?
+                .collect::<Result<Vec<_>, _>>()
+                .context("Error while loading the client certificate")?;
+            let key = PrivateKeyDer::from_pem_file(client_key)
+                .context("Error while loading the client key")?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        (Some(_), None) => {
+            bail!("PKCS#12 client certificates are not supported with rustls, use a PEM certificate and key");
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    let connector = TlsConnector::from(Arc::new(config));
+
+    // Connect to the server
+    let stream = TcpStream::connect(url).await?;
+
+    let server_name = ServerName::try_from(tcp_client.server.clone())?;
+    Ok(connector.connect(server_name, stream).await?)
+}
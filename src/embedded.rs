@@ -0,0 +1,103 @@
+//! Embedded broker, running in the same process without any listeners.
+//!
+//! The in-memory clients talk to the broker over an in-process pipe with the
+//! same packets as the socket clients, the authentication, the acl, the rate
+//! limits and the topic routing work the same way.
+//! ```
+//! use simple_pub_sub::embedded::Broker;
+//! use simple_pub_sub::PktType;
+//! async fn run() {
+//!   let broker = Broker::start(1024, &Default::default()).await.unwrap();
+//!   let mut subscriber = broker.client();
+//!   subscriber.connect().await.unwrap();
+//!   subscriber.subscribe("abc".to_string()).await.unwrap();
+//!   // the subscription is registered once it is acknowledged.
+//!   let ack = subscriber.read_message().await.unwrap();
+//!   assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+//!
+//!   let mut publisher = broker.client();
+//!   publisher.connect().await.unwrap();
+//!   publisher.publish("abc".to_string(), b"message".to_vec()).await.unwrap();
+//!   let msg = subscriber.read_message().await.unwrap();
+//!   assert_eq!(msg.message, b"message");
+//! }
+//! ```
+use crate::client::{Client, PubSubClient};
+use crate::metrics::metrics;
+use crate::server::broker::Broker as BrokerState;
+use crate::server::client_handler;
+use crate::server::{BrokerConfig, Listeners};
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::io::DuplexStream;
+
+/// buffer size of the in-memory connections.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Handle to the embedded broker, the broker runs until [`Broker::shutdown`]
+/// is called on any of its clones or the last clone is dropped. The clients
+/// returned by [`Broker::client`] hold a clone.
+#[derive(Clone)]
+pub struct Broker {
+    running: Arc<Running>,
+}
+
+/// the started broker, shut down when the last handle is dropped.
+struct Running {
+    state: Arc<BrokerState>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.state.shutdown();
+    }
+}
+
+impl std::fmt::Debug for Broker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Broker").finish_non_exhaustive()
+    }
+}
+
+impl Broker {
+    /// starts the broker with the given queue capacity and config,
+    /// the http gateway, the metrics endpoint and the MQTT, Redis and NATS listeners
+    /// are started when they are configured.
+    pub async fn start(capacity: usize, config: &BrokerConfig) -> Result<Broker> {
+        let state = BrokerState::start(capacity, config).await?;
+        Ok(Broker {
+            running: Arc::new(Running { state }),
+        })
+    }
+
+    /// stops the topic manager and the listeners of the broker and disconnects
+    /// the clients, the later connections are closed right away.
+    pub fn shutdown(&self) {
+        self.running.state.shutdown();
+    }
+
+    /// returns the addresses of the configured listeners,
+    /// with the ports chosen by the system for the port 0.
+    pub fn listeners(&self) -> Listeners {
+        self.running.state.listeners()
+    }
+
+    /// returns an in-memory client for the broker, not connected yet.
+    pub fn client(&self) -> Client {
+        Client::new(PubSubClient::Memory(self.clone()))
+    }
+
+    /// opens an in-memory connection, the other end is handled by the broker.
+    pub(crate) async fn connect(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(PIPE_CAPACITY);
+        metrics().connection("memory");
+        client_handler::handle_client(
+            server,
+            self.running.state.clone(),
+            None,
+            "memory".to_string(),
+        )
+        .await;
+        client
+    }
+}
@@ -5,4 +5,40 @@ pub enum PubSubError {
     /// client is not connected yet
     #[error("Client is not connected")]
     ClientNotConnected,
+    /// client must authenticate before publishing/subscribing
+    #[error("Client is not authenticated")]
+    NotAuthenticated,
+    /// the body of the `CONNECT` packet is not a valid connect request
+    #[error("Invalid connect request: {0}")]
+    InvalidConnect(String),
+    /// the credentials sent by the client are not valid
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    /// the token of the client expired during the session
+    #[error("Token expired")]
+    TokenExpired,
+    /// the acl does not allow the action on the topic
+    #[error("Not authorized to {0}")]
+    NotAuthorized(String),
+    /// the topic or the pattern is malformed, or a publish topic has wildcards
+    #[error("Invalid topic: {0}")]
+    InvalidTopic(String),
+    /// the publish is over the rate limit
+    #[error("Rate limit exceeded for the topic: {0}")]
+    RateLimited(String),
+    /// the admin request refers to a client that is not connected
+    #[error("Unknown client: {0}")]
+    UnknownClient(String),
+    /// the client was disconnected by an admin
+    #[error("Disconnected by the admin")]
+    Kicked,
+    /// the messages held by the broker are over the memory limit
+    #[error("Memory limit exceeded")]
+    MemoryLimitExceeded,
+    /// the persistent session is used by a connection that could not be disconnected
+    #[error("Session in use: {0}")]
+    SessionInUse(String),
+    /// error packet received from the server
+    #[error("Error from the server: {0}")]
+    ServerError(String),
 }
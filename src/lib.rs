@@ -1,7 +1,19 @@
+pub mod acl;
+pub mod admin;
+pub mod auth;
+pub mod bench;
+pub mod bridge;
 pub mod client;
+pub mod embedded;
 pub mod error;
+pub(crate) mod memory;
+pub mod metrics;
+pub mod rate_limit;
 pub mod server;
+pub(crate) mod spans;
 pub mod stream;
+pub mod systemd;
+pub mod token;
 pub mod topics;
 pub use simple_pub_sub_message::header::Header;
 pub use simple_pub_sub_message::message;
@@ -1,118 +1,110 @@
 pub mod cli;
-use crate::cli::{Cli, ClientType, Commands, LogLevel, ServerType};
+use crate::cli::{
+    AdminAction, AuthArgs, Cli, ClientType, Commands, LogFormat, LogLevel, LogRotation,
+    PasswdAction, RateLimitMode, ServerType, TcpArgs,
+};
 use clap::{Parser, ValueEnum};
-use log::{error, info};
+use simple_pub_sub::admin::AdminRequest;
+use simple_pub_sub::bench::Bench;
+use simple_pub_sub::bridge::{self, BridgeTopic, Direction};
+use simple_pub_sub::rate_limit::RateLimitAction;
 use simple_pub_sub::server::ServerTrait as _;
-use simple_pub_sub::{client, server};
-use std::env;
+use simple_pub_sub::{auth, client, server, systemd, token};
 use std::error::Error;
+use std::io::IsTerminal as _;
+use std::path::Path;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    let log_level: &str = match cli.log_level {
-        Some(LogLevel::Trace) => "trace",
-        Some(LogLevel::Warn) => "warn",
-        Some(LogLevel::Info) => "info",
-        Some(LogLevel::Error) => "error",
-        Some(LogLevel::Debug) => "debug",
-        None => "info",
-    };
-    env::set_var("RUST_LOG", log_level);
-    env_logger::init();
+    // the logs are flushed to the file when the guard is dropped.
+    let _log_guard = init_logging(&cli)?;
 
     let queue_capacity = cli.capacity.unwrap_or(1024);
 
     match &cli.command {
-        Commands::Server { server_type } => match server_type {
-            ServerType::Tcp {
-                host,
-                port,
-                cert,
-                cert_password,
-            } => {
-                let server = server::Tcp {
-                    host: host.to_string(),
-                    port: *port,
-                    cert: cert.clone(),
-                    cert_password: cert_password.clone(),
-                    capacity: queue_capacity,
-                };
-                match server.start().await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("{:?}", e);
-                    }
-                };
-            }
-            ServerType::Unix { path } => {
-                let server = server::Unix {
+        Commands::Server {
+            server_type,
+            broker,
+        } => {
+            let config = server::BrokerConfig {
+                credentials: broker.credentials.clone(),
+                acl: broker.acl.clone(),
+                audit_log: broker.audit_log.clone(),
+                token_secret: broker.token_secret.clone(),
+                token_public_key: broker.token_public_key.clone(),
+                rate_limits: broker.rate_limits.clone(),
+                rate_limit_action: match broker.rate_limit_action {
+                    RateLimitMode::Throttle => RateLimitAction::Throttle,
+                    RateLimitMode::Reject => RateLimitAction::Reject,
+                },
+                http: broker.http.clone(),
+                metrics: broker.metrics.clone(),
+                metrics_topics: broker.metrics_topics.clone(),
+                mqtt: broker.mqtt.clone(),
+                redis: broker.redis.clone(),
+                nats: broker.nats.clone(),
+                session_expiry: broker.session_expiry.map(Duration::from_secs),
+                session_queue: broker.session_queue,
+                memory_limit: broker.memory_limit,
+                client_memory_limit: broker.client_memory_limit,
+            };
+            let server = match server_type {
+                ServerType::Tcp(args) => {
+                    server::ServerType::Tcp(tcp_server(args, queue_capacity, config))
+                }
+                ServerType::WebSocket(args) => {
+                    server::ServerType::WebSocket(tcp_server(args, queue_capacity, config))
+                }
+                ServerType::Unix {
+                    path,
+                    mode,
+                    owner,
+                    group,
+                } => server::ServerType::Unix(server::Unix {
                     path: path.clone(),
+                    mode: *mode,
+                    owner: owner.clone(),
+                    group: group.clone(),
                     capacity: queue_capacity,
-                };
-                match server.start().await {
-                    Ok(_) => {}
-                    Err(e) => {
+                    config,
+                }),
+            };
+            // the handlers are installed before the server is ready, a signal sent
+            // right after the readiness notification is not lost.
+            let shutdown = shutdown_signal()?;
+            tokio::select! {
+                result = server.start() => {
+                    if let Err(e) = result {
                         error!("{:?}", e);
                     }
-                };
+                }
+                _ = shutdown => {
+                    info!("Shutting down");
+                    if let Err(e) = systemd::notify("STOPPING=1") {
+                        error!("Could not notify systemd: {:?}", e);
+                    }
+                }
             }
-        },
+        }
         Commands::Client {
             client_type,
             topic,
             message,
             server_tyepe,
+            session,
+            auth,
         } => {
-            let (server, port, socket, cert, cert_password): (
-                &String,
-                Option<&u16>,
-                String,
-                Option<String>,
-                Option<String>,
-            ) = match server_tyepe {
-                ServerType::Tcp {
-                    host,
-                    port,
-                    cert,
-                    cert_password,
-                } => (
-                    host,
-                    Some(port),
-                    "tcp".to_string(),
-                    cert.clone(),
-                    cert_password.clone(),
-                ),
-                ServerType::Unix { path } => (path, Some(&0), "unix".to_string(), None, None),
-            };
-
-            let client_: client::PubSubClient;
-
-            if socket == "unix" {
-                client_ = client::PubSubClient::Unix(client::PubSubUnixClient {
-                    path: server.clone(),
-                });
-            } else if socket == "tcp" {
-                let port = match port {
-                    Some(port) => *port,
-                    None => 6480,
-                };
-                let addr = format!("{server}:{port}");
-                info!("Connecting to: {addr}");
-                client_ = client::PubSubClient::Tcp(client::PubSubTcpClient {
-                    server: server.clone(),
-                    port,
-                    cert,
-                    cert_password,
-                });
-            } else {
-                return Err("Socket type not supported".into());
-            }
-
-            let mut client = client::Client::new(client_);
-            match client.connect().await {
-                Ok(()) => {}
+            let mut client = match connect(server_tyepe, auth, session.as_deref()).await {
+                Ok(client) => client,
                 Err(e) => {
                     error!("{:?}", e);
 
@@ -162,6 +154,172 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
             }
         }
+        Commands::Bench {
+            server_type,
+            publishers,
+            subscribers,
+            topics,
+            message_size,
+            rate,
+            duration,
+            json,
+            auth,
+        } => {
+            let bench = Bench {
+                client_type: client_type(server_type),
+                credentials: credentials(&auth.username, &auth.password, &auth.token),
+                publishers: *publishers,
+                subscribers: *subscribers,
+                topics: *topics,
+                message_size: *message_size,
+                rate: *rate,
+                duration: Duration::from_secs(*duration),
+            };
+            let report = bench.run().await?;
+            if *json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                println!("{report}");
+            }
+        }
+        Commands::Admin { action, auth } => {
+            let (server_type, request) = match action {
+                AdminAction::Clients { server_type } => (server_type, AdminRequest::Clients),
+                AdminAction::Kick {
+                    client_id,
+                    server_type,
+                } => (
+                    server_type,
+                    AdminRequest::Kick {
+                        client_id: client_id.clone(),
+                    },
+                ),
+                AdminAction::Unsubscribe {
+                    client_id,
+                    topic,
+                    server_type,
+                } => (
+                    server_type,
+                    AdminRequest::Unsubscribe {
+                        client_id: client_id.clone(),
+                        topic: topic.clone(),
+                    },
+                ),
+                AdminAction::DropTopic { topic, server_type } => (
+                    server_type,
+                    AdminRequest::DropTopic {
+                        topic: topic.clone(),
+                    },
+                ),
+            };
+            let mut client = connect(server_type, auth, None).await?;
+            if request == AdminRequest::Clients {
+                for info in client.clients().await? {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        info.id,
+                        info.address,
+                        info.identity.as_deref().unwrap_or("-"),
+                        info.connected_at,
+                        info.memory,
+                        info.subscriptions.join(",")
+                    );
+                }
+            } else {
+                println!("{}", client.admin(request).await?);
+            }
+        }
+        Commands::Bridge {
+            local,
+            remote,
+            outgoing,
+            incoming,
+            both,
+            local_cert,
+            local_username,
+            local_password,
+            local_token,
+            remote_cert,
+            remote_username,
+            remote_password,
+            remote_token,
+            max_backoff,
+        } => {
+            let topics = [
+                (outgoing, Direction::Out),
+                (incoming, Direction::In),
+                (both, Direction::Both),
+            ]
+            .into_iter()
+            .flat_map(|(patterns, direction)| {
+                patterns.iter().map(move |pattern| BridgeTopic {
+                    pattern: pattern.clone(),
+                    direction,
+                })
+            })
+            .collect::<Vec<_>>();
+            if topics.is_empty() {
+                return Err("At least one of --out, --in or --both is required".into());
+            }
+            let bridge = bridge::Bridge {
+                local: endpoint(
+                    local,
+                    local_cert,
+                    credentials(local_username, local_password, local_token),
+                )?,
+                remote: endpoint(
+                    remote,
+                    remote_cert,
+                    credentials(remote_username, remote_password, remote_token),
+                )?,
+                topics,
+                max_backoff: Duration::from_secs(*max_backoff),
+            };
+            bridge.run().await;
+        }
+        Commands::Passwd { action, file } => match action {
+            PasswdAction::Add { username, password } => {
+                let password = match password {
+                    Some(password) => password.clone(),
+                    None => read_password()?,
+                };
+                auth::add_user(file, username, &password)?;
+                info!("User '{}' added to {}", username, file);
+            }
+            PasswdAction::Remove { username } => {
+                if auth::remove_user(file, username)? {
+                    info!("User '{}' removed from {}", username, file);
+                } else {
+                    error!("User '{}' not found in {}", username, file);
+                }
+            }
+            PasswdAction::List => {
+                for username in auth::list_users(file)? {
+                    println!("{}", username);
+                }
+            }
+        },
+        Commands::Token {
+            subject,
+            publish,
+            subscribe,
+            expires_in,
+            secret,
+            private_key,
+        } => {
+            let claims = token::Claims::new(
+                subject.clone(),
+                Duration::from_secs(*expires_in),
+                publish.clone(),
+                subscribe.clone(),
+            );
+            let token = match (secret, private_key) {
+                (Some(secret), _) => token::mint_with_secret(secret, &claims)?,
+                (None, Some(private_key)) => token::mint_with_private_key(private_key, &claims)?,
+                (None, None) => return Err("Either --secret or --private-key is required".into()),
+            };
+            println!("{}", token);
+        }
         Commands::Completion { shell } => {
             completion(shell);
         }
@@ -169,6 +327,193 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// connects the client for the cli options.
+async fn connect(
+    server_type: &ServerType,
+    args: &AuthArgs,
+    session: Option<&str>,
+) -> anyhow::Result<client::Client> {
+    let mut client = client::Client::new(client_type(server_type));
+    if let Some(username) = &args.username {
+        client.credentials(auth::Credentials::Password {
+            username: username.clone(),
+            password: args.password.clone().unwrap_or_default(),
+        });
+    }
+    if let Some(token) = &args.token {
+        client.credentials(auth::Credentials::Token(token.clone()));
+    }
+    if let Some(session) = session {
+        client.persistent_session(session.to_string());
+    }
+    client.connect().await?;
+    Ok(client)
+}
+
+/// client type for the server type of the cli.
+fn client_type(server_type: &ServerType) -> client::PubSubClient {
+    match server_type {
+        ServerType::Tcp(args) => client::PubSubClient::Tcp(tcp_client(args)),
+        ServerType::WebSocket(args) => client::PubSubClient::WebSocket(tcp_client(args)),
+        ServerType::Unix { path, .. } => {
+            client::PubSubClient::Unix(client::PubSubUnixClient { path: path.clone() })
+        }
+    }
+}
+
+/// credentials for the cli options.
+fn credentials(
+    username: &Option<String>,
+    password: &Option<String>,
+    token: &Option<String>,
+) -> Option<auth::Credentials> {
+    match (username, token) {
+        (Some(username), _) => Some(auth::Credentials::Password {
+            username: username.clone(),
+            password: password.clone().unwrap_or_default(),
+        }),
+        (None, Some(token)) => Some(auth::Credentials::Token(token.clone())),
+        (None, None) => None,
+    }
+}
+
+/// bridge endpoint for the broker url: `tcp://host:port`, `ws://host:port` or `unix:///path`.
+fn endpoint(
+    url: &str,
+    cert: &Option<String>,
+    credentials: Option<auth::Credentials>,
+) -> Result<bridge::Endpoint, Box<dyn Error>> {
+    let Some((scheme, address)) = url.split_once("://") else {
+        return Err(format!("Invalid broker url: {url}").into());
+    };
+    let tcp_client = || -> Result<client::PubSubTcpClient, Box<dyn Error>> {
+        let Some((host, port)) = address.rsplit_once(':') else {
+            return Err(format!("Port missing in the broker url: {url}").into());
+        };
+        Ok(client::PubSubTcpClient {
+            server: host.to_string(),
+            port: port.parse()?,
+            cert: cert.clone(),
+            cert_password: None,
+            client_cert: None,
+            client_key: None,
+        })
+    };
+    let client_type = match scheme {
+        "tcp" => client::PubSubClient::Tcp(tcp_client()?),
+        "ws" => client::PubSubClient::WebSocket(tcp_client()?),
+        "unix" => client::PubSubClient::Unix(client::PubSubUnixClient {
+            path: address.to_string(),
+        }),
+        _ => return Err(format!("Unsupported scheme in the broker url: {url}").into()),
+    };
+    Ok(bridge::Endpoint {
+        client_type,
+        credentials,
+    })
+}
+
+/// tcp/websocket server for the cli options.
+fn tcp_server(args: &TcpArgs, capacity: usize, config: server::BrokerConfig) -> server::Tcp {
+    server::Tcp {
+        host: args.host.clone(),
+        port: args.port,
+        cert: args.cert.clone(),
+        cert_password: args.cert_password.clone(),
+        key: args.key.clone(),
+        client_ca: args.client_ca.clone(),
+        capacity,
+        config,
+    }
+}
+
+/// tcp/websocket client for the cli options.
+fn tcp_client(args: &TcpArgs) -> client::PubSubTcpClient {
+    info!("Connecting to: {}:{}", args.host, args.port);
+    client::PubSubTcpClient {
+        server: args.host.clone(),
+        port: args.port,
+        cert: args.cert.clone(),
+        cert_password: args.cert_password.clone(),
+        client_cert: args.client_cert.clone(),
+        client_key: args.client_key.clone(),
+    }
+}
+
+/// sets up the tracing subscriber with the log level, the format and the output of the cli,
+/// `RUST_LOG` is used when the log level is not set.
+fn init_logging(cli: &Cli) -> Result<Option<WorkerGuard>, Box<dyn Error>> {
+    let filter = match cli.log_level {
+        Some(LogLevel::Trace) => EnvFilter::new("trace"),
+        Some(LogLevel::Warn) => EnvFilter::new("warn"),
+        Some(LogLevel::Info) => EnvFilter::new("info"),
+        Some(LogLevel::Error) => EnvFilter::new("error"),
+        Some(LogLevel::Debug) => EnvFilter::new("debug"),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let (writer, guard) = match &cli.log_file {
+        Some(path) => {
+            let path = Path::new(path);
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| format!("Invalid log file: {}", path.display()))?;
+            let directory = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let mut appender = RollingFileAppender::builder()
+                .rotation(match cli.log_rotation {
+                    LogRotation::Minutely => Rotation::MINUTELY,
+                    LogRotation::Hourly => Rotation::HOURLY,
+                    LogRotation::Daily => Rotation::DAILY,
+                    LogRotation::Never => Rotation::NEVER,
+                })
+                .filename_prefix(file_name.to_string_lossy());
+            if let Some(max_files) = cli.log_max_files {
+                appender = appender.max_log_files(max_files);
+            }
+            let (writer, guard) = tracing_appender::non_blocking(appender.build(directory)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), None),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match cli.log_format {
+        LogFormat::Text => subscriber
+            .with_ansi(cli.log_file.is_none() && std::io::stderr().is_terminal())
+            .init(),
+        LogFormat::Json => subscriber.json().with_span_list(true).init(),
+    }
+    Ok(guard)
+}
+
+/// installs the SIGINT and SIGTERM handlers, the returned future completes on
+/// the first of them.
+fn shutdown_signal() -> std::io::Result<impl std::future::Future<Output = ()>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+/// reads the password from the stdin.
+fn read_password() -> Result<String, Box<dyn Error>> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        return Err("Password can not be empty".into());
+    }
+    Ok(password)
+}
+
 fn completion(shell: &str) {
     use clap::CommandFactory;
     let mut cmd = Cli::command();
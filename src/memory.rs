@@ -0,0 +1,138 @@
+//! Accounting of the memory held by the messages in the broker.
+//!
+//! A published message waiting for the topic manager is charged to the publishing
+//! client, every copy queued for a subscriber (including the persistent session
+//! queues) is charged to the subscriber. The bytes are released when the message is
+//! dropped. Over the high watermark of the global or the client limit the reads from
+//! the publishing client are delayed, over the limit the publishes are rejected and
+//! the copies for the subscribers are dropped.
+use crate::message::Msg;
+use crate::metrics::metrics;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// share of the limit from which the publishing clients are slowed down, in percent.
+const HIGH_WATERMARK: usize = 80;
+/// delay of the next read from a client over the high watermark.
+pub(crate) const BACKPRESSURE_DELAY: Duration = Duration::from_millis(10);
+
+/// bytes accounted for the message.
+pub(crate) fn size(msg: &Msg) -> usize {
+    msg.topic.len() + msg.message.len()
+}
+
+/// memory limits in bytes, `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct MemoryLimits {
+    /// limit for all the messages held by the broker
+    pub(crate) global: Option<usize>,
+    /// limit for the messages charged to one client
+    pub(crate) client: Option<usize>,
+}
+
+/// bytes held by the broker, in total and by client id.
+#[derive(Debug)]
+pub(crate) struct MemoryAccountant {
+    limits: MemoryLimits,
+    used: AtomicUsize,
+    clients: Mutex<HashMap<String, usize>>,
+}
+
+impl MemoryAccountant {
+    pub(crate) fn new(limits: MemoryLimits) -> Arc<MemoryAccountant> {
+        Arc::new(MemoryAccountant {
+            limits,
+            used: AtomicUsize::new(0),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// charges the bytes to the client, `None` when it would go over the global
+    /// or the client limit.
+    pub(crate) fn reserve(self: &Arc<Self>, client_id: &str, bytes: usize) -> Option<Reservation> {
+        let mut clients = self.clients.lock().ok()?;
+        let client_used = clients.get(client_id).copied().unwrap_or_default();
+        if self
+            .limits
+            .client
+            .is_some_and(|limit| client_used + bytes > limit)
+        {
+            return None;
+        }
+        let reserved =
+            self.used
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                    match self.limits.global {
+                        Some(limit) if used + bytes > limit => None,
+                        _ => Some(used + bytes),
+                    }
+                });
+        if reserved.is_err() {
+            return None;
+        }
+        clients.insert(client_id.to_string(), client_used + bytes);
+        metrics().memory.inc_by(bytes as i64);
+        Some(Reservation {
+            accountant: self.clone(),
+            client_id: client_id.to_string(),
+            bytes,
+        })
+    }
+
+    /// returns true if the global usage or the usage of the client
+    /// is over the high watermark of its limit.
+    pub(crate) fn over_watermark(&self, client_id: &str) -> bool {
+        let over = |used: usize, limit: Option<usize>| {
+            limit.is_some_and(|limit| used * 100 >= limit * HIGH_WATERMARK)
+        };
+        over(self.used(), self.limits.global)
+            || over(self.client_used(client_id), self.limits.client)
+    }
+
+    /// bytes held for all the clients.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// bytes charged to the client.
+    pub(crate) fn client_used(&self, client_id: &str) -> usize {
+        self.clients
+            .lock()
+            .ok()
+            .and_then(|clients| clients.get(client_id).copied())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn limits(&self) -> MemoryLimits {
+        self.limits
+    }
+
+    fn release(&self, client_id: &str, bytes: usize) {
+        if let Ok(mut clients) = self.clients.lock() {
+            if let Some(used) = clients.get_mut(client_id) {
+                *used = used.saturating_sub(bytes);
+                if *used == 0 {
+                    clients.remove(client_id);
+                }
+            }
+        }
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+        metrics().memory.dec_by(bytes as i64);
+    }
+}
+
+/// bytes charged to a client, released when dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    accountant: Arc<MemoryAccountant>,
+    client_id: String,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.accountant.release(&self.client_id, self.bytes);
+    }
+}
@@ -0,0 +1,244 @@
+//! Prometheus/OpenMetrics metrics of the broker.
+//!
+//! The metrics are process wide, see [`metrics`], and are served
+//! in the OpenMetrics text format on `/metrics` when the metrics
+//! address is set in the broker config.
+//!
+//! The messages are counted by the first configured topic pattern matching
+//! the topic, the topics matching none of them are counted under `other`.
+use crate::acl::topic_matches;
+use crate::topics::Envelope;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::sync::{OnceLock, RwLock};
+
+/// label of the topics not matching any of the configured patterns.
+const OTHER_TOPICS: &str = "other";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TransportLabels {
+    /// tcp, tls, unix, websocket, mqtt, redis, nats or memory
+    pub transport: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TopicLabels {
+    /// configured topic pattern or `other`
+    pub topic: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DropLabels {
+    /// why the message was dropped
+    pub reason: String,
+}
+
+/// the metrics of the broker.
+pub struct Metrics {
+    registry: Registry,
+    /// connections accepted by the listeners
+    pub connections: Family<TransportLabels, Counter>,
+    /// currently connected clients
+    pub clients: Gauge,
+    /// messages published on the topic
+    pub messages_in: Family<TopicLabels, Counter>,
+    /// bytes published on the topic
+    pub bytes_in: Family<TopicLabels, Counter>,
+    /// messages delivered to the subscribers of the topic
+    pub messages_out: Family<TopicLabels, Counter>,
+    /// bytes delivered to the subscribers of the topic
+    pub bytes_out: Family<TopicLabels, Counter>,
+    /// dropped messages, by reason
+    pub dropped: Family<DropLabels, Counter>,
+    /// publishes delayed by the rate limits
+    pub throttled: Counter,
+    /// time from receiving a publish to writing it to a subscriber
+    pub delivery_latency: Histogram,
+    /// messages waiting in the broker queue
+    pub queue_depth: Gauge,
+    /// topics with at least one subscriber
+    pub topics: Gauge,
+    /// subscriptions over all the topics
+    pub subscriptions: Gauge,
+    /// bytes of the messages held by the broker
+    pub memory: Gauge,
+    /// topic patterns counted separately
+    topic_patterns: RwLock<Vec<String>>,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let mut metrics = Metrics {
+            registry: Registry::with_prefix("simple_pub_sub"),
+            connections: Family::default(),
+            clients: Gauge::default(),
+            messages_in: Family::default(),
+            bytes_in: Family::default(),
+            messages_out: Family::default(),
+            bytes_out: Family::default(),
+            dropped: Family::default(),
+            throttled: Counter::default(),
+            // 50us to ~1.6s
+            delivery_latency: Histogram::new(exponential_buckets(0.00005, 2.0, 16)),
+            queue_depth: Gauge::default(),
+            topics: Gauge::default(),
+            subscriptions: Gauge::default(),
+            memory: Gauge::default(),
+            topic_patterns: RwLock::new(vec![]),
+        };
+        metrics.registry.register(
+            "connections",
+            "Connections accepted by the listeners",
+            metrics.connections.clone(),
+        );
+        metrics.registry.register(
+            "clients",
+            "Currently connected clients",
+            metrics.clients.clone(),
+        );
+        metrics.registry.register(
+            "messages_in",
+            "Messages published on the topic",
+            metrics.messages_in.clone(),
+        );
+        metrics.registry.register(
+            "bytes_in",
+            "Bytes published on the topic",
+            metrics.bytes_in.clone(),
+        );
+        metrics.registry.register(
+            "messages_out",
+            "Messages delivered to the subscribers of the topic",
+            metrics.messages_out.clone(),
+        );
+        metrics.registry.register(
+            "bytes_out",
+            "Bytes delivered to the subscribers of the topic",
+            metrics.bytes_out.clone(),
+        );
+        metrics.registry.register(
+            "dropped_messages",
+            "Messages dropped by the broker",
+            metrics.dropped.clone(),
+        );
+        metrics.registry.register(
+            "throttled",
+            "Publishes delayed by the rate limits",
+            metrics.throttled.clone(),
+        );
+        metrics.registry.register(
+            "delivery_latency_seconds",
+            "Time from receiving a publish to writing it to a subscriber",
+            metrics.delivery_latency.clone(),
+        );
+        metrics.registry.register(
+            "queue_depth",
+            "Messages waiting in the broker queue",
+            metrics.queue_depth.clone(),
+        );
+        metrics.registry.register(
+            "topics",
+            "Topics with at least one subscriber",
+            metrics.topics.clone(),
+        );
+        metrics.registry.register(
+            "subscriptions",
+            "Subscriptions over all the topics",
+            metrics.subscriptions.clone(),
+        );
+        metrics.registry.register(
+            "memory_bytes",
+            "Bytes of the messages held by the broker",
+            metrics.memory.clone(),
+        );
+        metrics
+    }
+
+    /// counts an accepted connection.
+    pub fn connection(&self, transport: &str) {
+        self.connections
+            .get_or_create(&TransportLabels {
+                transport: transport.to_string(),
+            })
+            .inc();
+    }
+
+    /// sets the topic patterns counted separately.
+    pub fn count_topics(&self, patterns: &[String]) {
+        if let Ok(mut topic_patterns) = self.topic_patterns.write() {
+            *topic_patterns = patterns.to_vec();
+        }
+    }
+
+    /// returns the labels of the first pattern matching the topic.
+    fn topic_labels(&self, topic: &str) -> TopicLabels {
+        let pattern = self.topic_patterns.read().ok().and_then(|patterns| {
+            patterns
+                .iter()
+                .find(|pattern| topic_matches(pattern, topic))
+                .cloned()
+        });
+        TopicLabels {
+            topic: pattern.unwrap_or_else(|| OTHER_TOPICS.to_string()),
+        }
+    }
+
+    /// counts a message published on the topic.
+    pub fn published(&self, topic: &str, bytes: usize) {
+        let labels = self.topic_labels(topic);
+        self.messages_in.get_or_create(&labels).inc();
+        self.bytes_in.get_or_create(&labels).inc_by(bytes as u64);
+    }
+
+    /// counts a message written to a subscriber, with the latency
+    /// since the publish was received.
+    pub fn delivered(&self, msg: &Envelope) {
+        let labels = self.topic_labels(&msg.topic);
+        self.messages_out.get_or_create(&labels).inc();
+        self.bytes_out
+            .get_or_create(&labels)
+            .inc_by(msg.message.len() as u64);
+        if let Some(received_at) = msg.received_at {
+            self.delivery_latency
+                .observe(received_at.elapsed().as_secs_f64());
+        }
+    }
+
+    /// counts the dropped messages.
+    pub fn dropped(&self, reason: &str, count: u64) {
+        self.dropped
+            .get_or_create(&DropLabels {
+                reason: reason.to_string(),
+            })
+            .inc_by(count);
+    }
+
+    /// encodes the metrics in the OpenMetrics text format.
+    /// ```
+    /// let metrics = simple_pub_sub::metrics::metrics();
+    /// metrics.count_topics(&["sensors/#".to_string()]);
+    /// metrics.published("sensors/kitchen", 10);
+    /// metrics.published("abc", 10);
+    /// let text = metrics.encode();
+    /// assert!(text.contains("simple_pub_sub_messages_in_total{topic=\"sensors/#\"}"));
+    /// assert!(text.contains("simple_pub_sub_messages_in_total{topic=\"other\"}"));
+    /// assert!(text.ends_with("# EOF\n"));
+    /// ```
+    pub fn encode(&self) -> String {
+        let mut buffer = String::new();
+        if let Err(e) = prometheus_client::encoding::text::encode(&mut buffer, &self.registry) {
+            tracing::error!("Error while encoding the metrics: {:?}", e);
+        }
+        buffer
+    }
+}
+
+/// returns the process wide metrics.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
@@ -0,0 +1,352 @@
+//! Token bucket rate limiting of the published messages.
+//!
+//! The rate limit file contains one rule per line:
+//! ```text
+//! # <client|topic> <identity|topic pattern|*> <messages/s|-> <bytes/s|->
+//! client ingest-svc 10000 -
+//! client *          1000  1048576
+//! topic  logs/#     500   -
+//! ```
+//! The first matching client rule limits all the connections of the identity
+//! together, the first matching topic rule limits all the topics matching its
+//! pattern together. `-` disables the limit, the buckets allow a burst of one
+//! second and a full bucket admits a single larger message.
+//! The idle buckets are removed, a full bucket is the same as a new one.
+use crate::acl::topic_matches;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// interval between the removals of the idle buckets.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+/// longest throttle of a single message, the rest of the debt delays the next ones.
+const MAX_THROTTLE: Duration = Duration::from_secs(3600);
+
+/// what happens to the publishes over the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RateLimitAction {
+    /// the message is accepted and the reads from the client are delayed.
+    #[default]
+    Throttle,
+    /// the message is dropped and an error is sent to the client.
+    Reject,
+}
+
+/// result of the rate limit check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// the message is within the limits.
+    Allow,
+    /// the message is accepted, the next read must wait for the duration.
+    Throttle(Duration),
+    /// the message is over the limit and must be dropped.
+    Reject,
+}
+
+/// number of the limited messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitCounters {
+    pub throttled: u64,
+    pub rejected: u64,
+}
+
+/// messages/s and bytes/s limit.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limit {
+    messages: Option<f64>,
+    bytes: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+enum Scope {
+    /// identity of the client, `None` matches any client.
+    Client(Option<String>),
+    Topic(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    scope: Scope,
+    limit: Limit,
+}
+
+/// token bucket, the tokens can go negative while throttling.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// a full bucket admits the messages larger than the burst, they leave a debt.
+    fn available(&self, n: f64) -> bool {
+        self.tokens >= n.min(self.rate)
+    }
+
+    fn full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+
+    /// takes the tokens, returns the time until the bucket is out of the debt,
+    /// at most [`MAX_THROTTLE`].
+    fn take(&mut self, n: f64) -> Duration {
+        self.tokens -= n;
+        if self.tokens < 0.0 {
+            Duration::try_from_secs_f64(-self.tokens / self.rate)
+                .map_or(MAX_THROTTLE, |wait| wait.min(MAX_THROTTLE))
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// message and byte buckets for a single client or topic.
+#[derive(Debug, Default)]
+struct Buckets {
+    messages: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Buckets {
+    fn new(limit: Limit, now: Instant) -> Buckets {
+        Buckets {
+            messages: limit.messages.map(|rate| Bucket::new(rate, now)),
+            bytes: limit.bytes.map(|rate| Bucket::new(rate, now)),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.messages.iter_mut().for_each(|b| b.refill(now));
+        self.bytes.iter_mut().for_each(|b| b.refill(now));
+    }
+
+    fn available(&self, bytes: f64) -> bool {
+        self.messages.as_ref().is_none_or(|b| b.available(1.0))
+            && self.bytes.as_ref().is_none_or(|b| b.available(bytes))
+    }
+
+    fn take(&mut self, bytes: f64) -> Duration {
+        let messages = self.messages.as_mut().map(|b| b.take(1.0));
+        let bytes = self.bytes.as_mut().map(|b| b.take(bytes));
+        messages.max(bytes).unwrap_or_default()
+    }
+
+    fn full(&self) -> bool {
+        self.messages.as_ref().is_none_or(Bucket::full)
+            && self.bytes.as_ref().is_none_or(Bucket::full)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// by the identity, or the client id of the anonymous clients.
+    clients: HashMap<String, Buckets>,
+    /// by the pattern of the topic rule.
+    topics: HashMap<String, Buckets>,
+    /// last removal of the idle buckets
+    swept: Option<Instant>,
+}
+
+impl State {
+    /// removes the buckets refilled since their last message.
+    fn sweep(&mut self, now: Instant) {
+        for buckets in [&mut self.clients, &mut self.topics] {
+            buckets.retain(|_, b| {
+                b.refill(now);
+                !b.full()
+            });
+        }
+        self.swept = Some(now);
+    }
+}
+
+/// Rate limiter for the published messages, loaded from the rate limit file.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    action: RateLimitAction,
+    state: Mutex<State>,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl RateLimiter {
+    /// loads the rules from the given file.
+    pub fn load(path: &str, action: RateLimitAction) -> Result<RateLimiter> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Error while reading the rate limit file: {path}"))?;
+        RateLimiter::parse(&content, action)
+            .with_context(|| format!("Error while parsing the rate limit file: {path}"))
+    }
+
+    /// parses the rules.
+    pub fn parse(content: &str, action: RateLimitAction) -> Result<RateLimiter> {
+        let mut rules = vec![];
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [scope, target, messages, bytes] = fields[..] else {
+                bail!("Invalid rule at line {}: {}", n + 1, line);
+            };
+            let scope = match (scope, target) {
+                ("client", "*") => Scope::Client(None),
+                ("client", identity) => Scope::Client(Some(identity.to_string())),
+                ("topic", pattern) => Scope::Topic(pattern.to_string()),
+                _ => bail!("Invalid scope at line {}: {}", n + 1, scope),
+            };
+            let limit = Limit {
+                messages: parse_rate(messages).with_context(|| format!("line {}", n + 1))?,
+                bytes: parse_rate(bytes).with_context(|| format!("line {}", n + 1))?,
+            };
+            rules.push(Rule { scope, limit });
+        }
+        Ok(RateLimiter {
+            rules,
+            action,
+            ..Default::default()
+        })
+    }
+
+    /// checks the message of `bytes` length published by the client on the topic.
+    /// the client buckets are shared by the identity, `client` (the client id)
+    /// is used for the anonymous clients.
+    /// ```
+    /// use simple_pub_sub::rate_limit::{Decision, RateLimitAction, RateLimiter};
+    /// let limiter = RateLimiter::parse("topic logs/# 2 -", RateLimitAction::Reject).unwrap();
+    /// assert_eq!(limiter.check("alice", None, "logs/app", 10), Decision::Allow);
+    /// assert_eq!(limiter.check("alice", None, "logs/db", 10), Decision::Allow);
+    /// // the topics matching the pattern share the bucket.
+    /// assert_eq!(limiter.check("alice", None, "logs/app", 10), Decision::Reject);
+    /// assert_eq!(limiter.check("alice", None, "metrics", 10), Decision::Allow);
+    /// assert_eq!(limiter.counters().rejected, 1);
+    ///
+    /// // a message larger than the burst is admitted by a full bucket.
+    /// let limiter = RateLimiter::parse("client * - 100", RateLimitAction::Reject).unwrap();
+    /// assert_eq!(limiter.check("alice", None, "files", 1000), Decision::Allow);
+    /// assert_eq!(limiter.check("alice", None, "files", 10), Decision::Reject);
+    ///
+    /// // the throttle of a message is at most an hour, even with a tiny rate.
+    /// let limiter = RateLimiter::parse("client * 1e-300 -", RateLimitAction::Throttle).unwrap();
+    /// assert_eq!(
+    ///     limiter.check("alice", None, "logs", 10),
+    ///     Decision::Throttle(std::time::Duration::from_secs(3600))
+    /// );
+    /// ```
+    pub fn check(
+        &self,
+        client: &str,
+        identity: Option<&str>,
+        topic: &str,
+        bytes: usize,
+    ) -> Decision {
+        let client_limit = self.rules.iter().find_map(|rule| match &rule.scope {
+            Scope::Client(None) => Some(rule.limit),
+            Scope::Client(Some(id)) if Some(id.as_str()) == identity => Some(rule.limit),
+            _ => None,
+        });
+        let topic_limit = self.rules.iter().find_map(|rule| match &rule.scope {
+            Scope::Topic(pattern) if topic_matches(pattern, topic) => Some((pattern, rule.limit)),
+            _ => None,
+        });
+        if client_limit.is_none() && topic_limit.is_none() {
+            return Decision::Allow;
+        }
+
+        let now = Instant::now();
+        let bytes = bytes as f64;
+        let Ok(mut state) = self.state.lock() else {
+            return Decision::Allow;
+        };
+        if state
+            .swept
+            .is_none_or(|at| now.saturating_duration_since(at) >= SWEEP_INTERVAL)
+        {
+            state.sweep(now);
+        }
+        let State {
+            clients, topics, ..
+        } = &mut *state;
+        let mut buckets = vec![];
+        if let Some(limit) = client_limit {
+            let key = identity.unwrap_or(client);
+            buckets.push(
+                clients
+                    .entry(key.to_string())
+                    .or_insert_with(|| Buckets::new(limit, now)),
+            );
+        }
+        if let Some((pattern, limit)) = topic_limit {
+            buckets.push(
+                topics
+                    .entry(pattern.clone())
+                    .or_insert_with(|| Buckets::new(limit, now)),
+            );
+        }
+        buckets.iter_mut().for_each(|b| b.refill(now));
+
+        if self.action == RateLimitAction::Reject {
+            if !buckets.iter().all(|b| b.available(bytes)) {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Decision::Reject;
+            }
+            buckets.iter_mut().for_each(|b| {
+                b.take(bytes);
+            });
+            return Decision::Allow;
+        }
+
+        let wait = buckets
+            .iter_mut()
+            .map(|b| b.take(bytes))
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            Decision::Allow
+        } else {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            Decision::Throttle(wait)
+        }
+    }
+
+    /// returns the number of the throttled and rejected messages.
+    pub fn counters(&self) -> RateLimitCounters {
+        RateLimitCounters {
+            throttled: self.throttled.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// parses the rate, `-` is no limit.
+fn parse_rate(rate: &str) -> Result<Option<f64>> {
+    if rate == "-" {
+        return Ok(None);
+    }
+    let rate: f64 = rate
+        .parse()
+        .with_context(|| format!("Invalid rate: {rate}"))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        bail!("Invalid rate: {rate}");
+    }
+    Ok(Some(rate))
+}
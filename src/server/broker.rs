@@ -0,0 +1,522 @@
+use super::session::{
+    self, ResumedSession, Sessions, Taken, DEFAULT_SESSION_EXPIRY, DEFAULT_SESSION_QUEUE,
+};
+use super::{http, mqtt, nats, redis, BrokerConfig, Listeners};
+use crate::acl::{Acl, Action, AuditLog};
+use crate::admin::{AdminRequest, ClientInfo};
+use crate::auth::{ConnectRequest, CredentialsFile};
+use crate::error::PubSubError;
+use crate::memory::{self, MemoryAccountant, MemoryLimits, BACKPRESSURE_DELAY};
+use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::rate_limit::{Decision, RateLimiter};
+use crate::token::{Claims, TokenVerifier};
+use crate::topics::{self, Envelope};
+use crate::PktType;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, Sender};
+use tokio::sync::{watch, Notify};
+use tracing::{info, warn};
+
+/// time to wait for the topic manager to answer a query or an admin request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// how often the expired sessions are removed.
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// time to wait for the connection using a session to be disconnected.
+const SESSION_TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Authenticated client.
+pub(crate) struct Session {
+    /// username or the subject of the token
+    pub(crate) identity: String,
+    /// claims of the token, restrict the topics of the client
+    pub(crate) claims: Option<Claims>,
+}
+
+/// Connected client, kept for the admin operations.
+struct ConnectedClient {
+    address: String,
+    identity: Option<String>,
+    /// seconds since the unix epoch
+    connected_at: u64,
+    /// notified to disconnect the client
+    kick: Arc<Notify>,
+}
+
+/// State of the broker, shared by all the connected clients.
+pub(crate) struct Broker {
+    /// channel to the topic manager
+    pub(crate) tx: Sender<Envelope>,
+    /// credentials for the password authentication
+    pub(crate) credentials: Option<CredentialsFile>,
+    /// key for the token authentication
+    pub(crate) tokens: Option<TokenVerifier>,
+    /// access control for the topics
+    pub(crate) acl: Option<Acl>,
+    /// audit log for the denied requests
+    pub(crate) audit: Option<AuditLog>,
+    /// rate limits for the published messages
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    /// connected clients, by client id
+    clients: Mutex<HashMap<String, ConnectedClient>>,
+    /// persistent sessions of the native clients
+    pub(crate) sessions: Sessions,
+    /// memory held by the queued messages
+    pub(crate) memory: Arc<MemoryAccountant>,
+    /// set when the broker shuts down
+    stop: watch::Sender<bool>,
+    /// addresses of the listeners started with the broker
+    listeners: OnceLock<Listeners>,
+}
+
+impl Broker {
+    /// creates the broker for the given config and starts the topic manager.
+    /// the http gateway, the metrics endpoint and the MQTT, Redis and NATS listeners are started
+    /// as well when they are configured.
+    pub(crate) async fn start(capacity: usize, config: &BrokerConfig) -> Result<Arc<Broker>> {
+        if config.session_queue == Some(0) {
+            bail!("The session queue must hold at least one message");
+        }
+        let credentials = config
+            .credentials
+            .as_deref()
+            .map(CredentialsFile::open)
+            .transpose()?;
+        let tokens = match (&config.token_secret, &config.token_public_key) {
+            (Some(_), Some(_)) => bail!("Only one of the token secret and public key can be set"),
+            (Some(secret), None) => Some(TokenVerifier::from_secret_file(secret)?),
+            (None, Some(public_key)) => Some(TokenVerifier::from_public_key_file(public_key)?),
+            (None, None) => None,
+        };
+        let acl = config.acl.as_deref().map(Acl::load).transpose()?;
+        let audit = config
+            .audit_log
+            .as_deref()
+            .map(AuditLog::open)
+            .transpose()?;
+        let rate_limiter = config
+            .rate_limits
+            .as_deref()
+            .map(|path| RateLimiter::load(path, config.rate_limit_action))
+            .transpose()?;
+
+        let tx = topics::get_global_broadcaster(capacity);
+        let memory = MemoryAccountant::new(MemoryLimits {
+            global: config.memory_limit,
+            client: config.client_memory_limit,
+        });
+        let broker = Arc::new(Broker {
+            tx,
+            credentials,
+            tokens,
+            acl,
+            audit,
+            rate_limiter,
+            clients: Mutex::new(HashMap::new()),
+            sessions: Sessions::new(
+                config.session_queue.unwrap_or(DEFAULT_SESSION_QUEUE),
+                config.session_expiry.unwrap_or(DEFAULT_SESSION_EXPIRY),
+            ),
+            memory,
+            stop: watch::Sender::new(false),
+            listeners: OnceLock::new(),
+        });
+        let topic_manager = broker.clone();
+        let _topic_handler = tokio::spawn(async move {
+            tokio::select! {
+                _ = topics::topic_manager(topic_manager.tx.clone(), topic_manager.memory.clone()) => {},
+                _ = topic_manager.stopped() => {},
+            }
+        });
+        let sweeper = broker.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => sweeper.expire_sessions(),
+                    _ = sweeper.stopped() => return,
+                }
+            }
+        });
+        let mut listeners = Listeners::default();
+        if let Some(addr) = &config.http {
+            listeners.http = Some(http::start(addr, broker.clone()).await?);
+        }
+        if let Some(addr) = &config.metrics {
+            metrics().count_topics(&config.metrics_topics);
+            listeners.metrics = Some(http::start_metrics(addr, broker.clone()).await?);
+        }
+        if let Some(addr) = &config.mqtt {
+            listeners.mqtt = Some(mqtt::start(addr, broker.clone()).await?);
+        }
+        if let Some(addr) = &config.redis {
+            listeners.redis = Some(redis::start(addr, broker.clone()).await?);
+        }
+        if let Some(addr) = &config.nats {
+            listeners.nats = Some(nats::start(addr, broker.clone()).await?);
+        }
+        let _ = broker.listeners.set(listeners);
+        Ok(broker)
+    }
+
+    /// returns the addresses of the listeners started with the broker.
+    pub(crate) fn listeners(&self) -> Listeners {
+        self.listeners.get().cloned().unwrap_or_default()
+    }
+
+    /// stops the topic manager and the listeners, and disconnects the clients.
+    pub(crate) fn shutdown(&self) {
+        info!("Shutting down the broker");
+        self.stop.send_replace(true);
+    }
+
+    /// returns true once the broker is shut down.
+    pub(crate) fn is_stopped(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    /// completes once the broker is shut down.
+    pub(crate) async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+        let _ = stop.wait_for(|stopped| *stopped).await;
+    }
+
+    /// returns true if the clients must authenticate.
+    pub(crate) fn auth_required(&self) -> bool {
+        self.credentials.is_some() || self.tokens.is_some()
+    }
+
+    /// checks the acl and the token claims for the given message,
+    /// the denied requests are audited.
+    pub(crate) fn authorize(&self, msg: &Envelope, claims: Option<&Claims>) -> Result<()> {
+        let action = Action::try_from(&msg.header.pkt_type)?;
+        let identity = msg.identity.as_deref();
+        if self.allows(identity, claims, action, &msg.topic) {
+            return Ok(());
+        }
+        let client_id = msg.client_id.as_deref().unwrap_or_default();
+        warn!(
+            target: "simple_pub_sub::audit",
+            "Denied {} on '{}' for the client {} ({})",
+            action,
+            msg.topic,
+            client_id,
+            identity.unwrap_or("anonymous")
+        );
+        if let Some(audit) = &self.audit {
+            audit.denied(client_id, identity, action, &msg.topic);
+        }
+        bail!(PubSubError::NotAuthorized(format!(
+            "{} '{}'",
+            action, msg.topic
+        )))
+    }
+
+    /// returns true if the acl and the token claims allow the action on the topic.
+    fn allows(
+        &self,
+        identity: Option<&str>,
+        claims: Option<&Claims>,
+        action: Action,
+        topic: &str,
+    ) -> bool {
+        // the admin operations are only allowed by an acl granting them.
+        (match &self.acl {
+            Some(acl) => acl.is_allowed(identity, action, topic),
+            None => action != Action::Admin,
+        }) && claims.is_none_or(|claims| claims.is_allowed(action, topic))
+    }
+
+    /// returns true if the authenticated client can connect as a bridge,
+    /// the acl must allow the `bridge` action on `#`.
+    pub(crate) fn allows_bridge(&self, identity: Option<&str>, claims: Option<&Claims>) -> bool {
+        identity.is_some()
+            && self
+                .acl
+                .as_ref()
+                .is_none_or(|acl| acl.is_allowed(identity, Action::Bridge, "#"))
+            && claims.is_none_or(|claims| claims.is_allowed(Action::Bridge, "#"))
+    }
+
+    /// checks the rate limits for the published message.
+    pub(crate) fn rate_limit(&self, msg: &Envelope) -> Decision {
+        let Some(rate_limiter) = &self.rate_limiter else {
+            return Decision::Allow;
+        };
+        let client_id = msg.client_id.as_deref().unwrap_or_default();
+        let identity = msg.identity.as_deref();
+        let decision = rate_limiter.check(client_id, identity, &msg.topic, msg.message.len());
+        match decision {
+            Decision::Allow => {}
+            Decision::Throttle(_) => {
+                metrics().throttled.inc();
+            }
+            Decision::Reject => {
+                metrics().dropped("rate_limited", 1);
+                let counters = rate_limiter.counters();
+                warn!(
+                    "Rejected the publish on '{}' from the client {}, rejected: {}",
+                    msg.topic, client_id, counters.rejected
+                );
+            }
+        }
+        decision
+    }
+
+    /// charges the published message to the client while it waits for the topic manager,
+    /// returns the delay of the next read from the client over the high watermark.
+    pub(crate) fn reserve_memory(&self, msg: &mut Envelope) -> Result<Option<Duration>> {
+        let client_id = msg.client_id.as_deref().unwrap_or_default();
+        let Some(reservation) = self.memory.reserve(client_id, memory::size(msg)) else {
+            metrics().dropped("memory_limit", 1);
+            warn!(
+                "Rejected the publish on '{}' from the client {}, used: {} bytes",
+                msg.topic,
+                client_id,
+                self.memory.used()
+            );
+            bail!(PubSubError::MemoryLimitExceeded);
+        };
+        let backpressure = self
+            .memory
+            .over_watermark(client_id)
+            .then_some(BACKPRESSURE_DELAY);
+        msg.reservation = Some(Arc::new(reservation));
+        Ok(backpressure)
+    }
+
+    /// authenticates the client with a password or a token,
+    /// returns `None` when the authentication is not enabled.
+    pub(crate) async fn authenticate_request(
+        &self,
+        request: ConnectRequest,
+    ) -> Result<Option<Session>> {
+        if !self.auth_required() {
+            return Ok(None);
+        }
+        if let Some(token) = request.token {
+            let Some(tokens) = &self.tokens else {
+                bail!(PubSubError::InvalidCredentials)
+            };
+            return match tokens.verify(&token) {
+                Ok(claims) => Ok(Some(Session {
+                    identity: claims.sub.clone(),
+                    claims: Some(claims),
+                })),
+                Err(e) => {
+                    warn!("Invalid token: {}", e);
+                    bail!(PubSubError::InvalidCredentials)
+                }
+            };
+        }
+        match (&self.credentials, request.username, request.password) {
+            (Some(credentials), Some(username), Some(password)) => {
+                if credentials.verify(&username, &password).await? {
+                    Ok(Some(Session {
+                        identity: username,
+                        claims: None,
+                    }))
+                } else {
+                    bail!(PubSubError::InvalidCredentials)
+                }
+            }
+            _ => bail!(PubSubError::InvalidCredentials),
+        }
+    }
+
+    /// registers the connected client, the returned `Notify`
+    /// is notified when the client is kicked.
+    pub(crate) fn register(
+        &self,
+        client_id: &str,
+        address: String,
+        identity: Option<String>,
+    ) -> Result<Arc<Notify>> {
+        let kick = Arc::new(Notify::new());
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut clients = self.clients.lock().map_err(|e| anyhow!(e.to_string()))?;
+        if clients.contains_key(client_id) {
+            bail!("The client {client_id} is already connected");
+        }
+        clients.insert(
+            client_id.to_string(),
+            ConnectedClient {
+                address,
+                identity,
+                connected_at,
+                kick: kick.clone(),
+            },
+        );
+        Ok(kick)
+    }
+
+    /// updates the identity of the client after the authentication.
+    pub(crate) fn set_identity(&self, client_id: &str, identity: String) {
+        if let Ok(mut clients) = self.clients.lock() {
+            if let Some(client) = clients.get_mut(client_id) {
+                client.identity = Some(identity);
+            }
+        }
+    }
+
+    /// removes the disconnected client.
+    pub(crate) fn unregister(&self, client_id: &str) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.remove(client_id);
+        }
+    }
+
+    /// disconnects the client, returns false if it is not connected.
+    pub(crate) fn kick(&self, client_id: &str) -> bool {
+        let Ok(clients) = self.clients.lock() else {
+            return false;
+        };
+        let Some(client) = clients.get(client_id) else {
+            return false;
+        };
+        client.kick.notify_one();
+        true
+    }
+
+    /// takes the persistent session for the connection of the client,
+    /// the connection already using the session is disconnected.
+    pub(crate) async fn resume_session(
+        &self,
+        id: &str,
+        identity: Option<&str>,
+    ) -> Result<ResumedSession> {
+        let deadline = tokio::time::Instant::now() + SESSION_TAKEOVER_TIMEOUT;
+        loop {
+            let returned = match self.sessions.take(id, identity) {
+                Taken::Resumed(session) => return Ok(session),
+                Taken::Denied => bail!(PubSubError::NotAuthorized(format!(
+                    "resume the session {id}"
+                ))),
+                Taken::InUse(returned) => returned,
+            };
+            info!("Disconnecting the connection using the session {}", id);
+            if let Ok(clients) = self.clients.lock() {
+                if let Some(client) = clients.get(&session::client_id(id)) {
+                    client.kick.notify_one();
+                }
+            }
+            if tokio::time::timeout_at(deadline, returned.notified())
+                .await
+                .is_err()
+            {
+                bail!(PubSubError::SessionInUse(id.to_string()));
+            }
+        }
+    }
+
+    /// removes the persistent session and its subscriptions,
+    /// only the identity owning the session can remove it.
+    pub(crate) fn discard_session(&self, id: &str, identity: Option<&str>) -> Result<()> {
+        let Some(topics) = self.sessions.discard(id, identity) else {
+            bail!(PubSubError::NotAuthorized(format!(
+                "discard the session {id}"
+            )));
+        };
+        self.unsubscribe_session(id, topics);
+        Ok(())
+    }
+
+    /// removes the expired sessions and their subscriptions.
+    fn expire_sessions(&self) {
+        for (id, topics) in self.sessions.expire() {
+            info!("Session {} expired", id);
+            self.unsubscribe_session(&id, topics);
+        }
+    }
+
+    /// unsubscribes the session from the topics.
+    fn unsubscribe_session(&self, id: &str, topics: Vec<String>) {
+        for topic in topics {
+            let mut msg = Msg::new(PktType::UNSUBSCRIBE, topic, None);
+            msg.client_id(session::client_id(id));
+            let _ = self.tx.send(msg.into());
+        }
+    }
+
+    /// runs the admin request, returns the json result.
+    pub(crate) async fn admin(&self, request: AdminRequest) -> Result<Value> {
+        info!("Admin request: {:?}", request);
+        match request {
+            AdminRequest::Kick { client_id } => {
+                if !self.kick(&client_id) {
+                    bail!(PubSubError::UnknownClient(client_id));
+                }
+                Ok(json!({ "kicked": client_id }))
+            }
+            AdminRequest::Clients => {
+                let subscriptions: HashMap<String, Vec<String>> =
+                    serde_json::from_value(self.topic_manager_admin(&request).await?)?;
+                let clients = self.clients.lock().map_err(|e| anyhow!(e.to_string()))?;
+                let mut clients = clients
+                    .iter()
+                    .map(|(id, client)| ClientInfo {
+                        id: id.clone(),
+                        address: client.address.clone(),
+                        identity: client.identity.clone(),
+                        connected_at: client.connected_at,
+                        subscriptions: subscriptions.get(id).cloned().unwrap_or_default(),
+                        memory: self.memory.client_used(id),
+                    })
+                    .collect::<Vec<_>>();
+                clients.sort_by(|a, b| (a.connected_at, &a.id).cmp(&(b.connected_at, &b.id)));
+                Ok(serde_json::to_value(clients)?)
+            }
+            AdminRequest::Unsubscribe { .. } | AdminRequest::DropTopic { .. } => {
+                self.topic_manager_admin(&request).await
+            }
+        }
+    }
+
+    /// forwards the admin request to the topic manager and waits for the result.
+    async fn topic_manager_admin(&self, request: &AdminRequest) -> Result<Value> {
+        let msg = Msg::new(
+            PktType::ADMIN,
+            request.operation().to_string(),
+            Some(serde_json::to_vec(request)?),
+        );
+        self.request(msg.into()).await
+    }
+
+    /// runs the `QUERY` message of a client, returns the json result.
+    /// The query of all the topics (`*`) lists only the topics the client can query.
+    pub(crate) async fn query(&self, msg: Envelope, claims: Option<&Claims>) -> Result<Value> {
+        let identity = msg.identity.clone();
+        let all = msg.topic == "*";
+        let mut resp = self.request(msg).await?;
+        if let Some(topics) = resp["*"].as_array_mut().filter(|_| all) {
+            // the entries are `topic: subscribers`.
+            topics.retain(|entry| {
+                entry
+                    .as_str()
+                    .and_then(|entry| entry.rsplit_once(": "))
+                    .is_some_and(|(topic, _)| {
+                        self.allows(identity.as_deref(), claims, Action::Query, topic)
+                    })
+            });
+        }
+        Ok(resp)
+    }
+
+    /// sends the message to the topic manager and waits for the json response.
+    async fn request(&self, mut msg: Envelope) -> Result<Value> {
+        let (tx, mut rx) = broadcast::channel(1);
+        msg.channel(tx);
+        self.tx.send(msg)?;
+        let resp = tokio::time::timeout(REQUEST_TIMEOUT, rx.recv())
+            .await
+            .map_err(|_| anyhow!("No response from the topic manager"))??;
+        Ok(serde_json::from_slice(&resp.message)?)
+    }
+}
@@ -1,72 +1,388 @@
+use super::broker::Broker;
+use super::session;
+use crate::acl::is_valid_request_topic;
+use crate::admin::AdminRequest;
+use crate::auth::{ConnectAck, ConnectRequest};
+use crate::error::PubSubError;
 use crate::message;
+use crate::metrics::metrics;
+use crate::rate_limit::Decision;
+use crate::spans::{connection_span, message_span, record_identity};
 use crate::stream;
+use crate::token::Claims;
+use crate::topics::Envelope;
 use crate::PktType;
-use anyhow::Result;
-use log::{error, info, warn};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::broadcast::Sender;
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn, Instrument};
 use uuid;
 
-/// reads a `Msg` from the channel.
-pub async fn read_channel_msg(
-    chan: Sender<message::Msg>,
-) -> Result<message::Msg, tokio::sync::broadcast::error::RecvError> {
-    let mut rx = chan.subscribe();
-    rx.recv().await
+/// writes the bytes and flushes the socket, the websocket
+/// messages are only sent out completely on flush.
+pub(super) async fn write_all<W>(socket: &mut W, bytes: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    socket.write_all(bytes).await?;
+    socket.flush().await
 }
 
-/// Handles the communication between a client and the broker.
-pub async fn handle_client<S>(mut socket: S, chan: Sender<message::Msg>)
+/// writes an `ERROR` packet with the reason to the client.
+async fn write_error<W>(socket: &mut W, topic: &str, reason: String)
 where
-    S: AsyncWriteExt + Unpin + Send + tokio::io::AsyncReadExt + 'static,
+    W: AsyncWriteExt + Unpin,
 {
-    let client_chan = tokio::sync::broadcast::channel(1).0;
-    let client_id = uuid::Uuid::new_v4().to_string();
+    let msg = message::Msg::new(PktType::ERROR, topic.to_string(), Some(reason.into_bytes()));
+    if let Err(e) = write_all(socket, &msg.bytes()).await {
+        error!("Could not write the error to the socket: {:?}", e);
+    }
+}
+
+/// completes at the deadline, never when there is no deadline.
+pub(super) async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// runs the admin request of the client, returns the `ADMIN_RESP` packet.
+async fn admin(broker: &Broker, m: &message::Msg) -> anyhow::Result<message::Msg> {
+    let request: AdminRequest = serde_json::from_slice(&m.message)?;
+    // the acl is checked for the topic, it must be the requested operation.
+    if request.operation() != m.topic {
+        anyhow::bail!("Invalid admin request for the operation: {}", m.topic);
+    }
+    let resp = broker.admin(request).await?;
+    m.response_msg(resp.to_string().into_bytes())
+}
 
+/// Handles the communication between a client and the broker.
+/// `identity` is the verified identity of the client, if the transport provides one,
+/// `address` is the peer address listed by the admin operations.
+pub async fn handle_client<S>(
+    socket: S,
+    broker: Arc<Broker>,
+    identity: Option<String>,
+    address: String,
+) where
+    S: AsyncWriteExt + Unpin + Send + AsyncReadExt + 'static,
+{
+    if broker.is_stopped() {
+        debug!(
+            "Closing the connection from {}, the broker is shut down",
+            address
+        );
+        return;
+    }
+    let (mut client_chan, mut client_rx) = tokio::sync::broadcast::channel(1);
+    let mut client_id = uuid::Uuid::new_v4().to_string();
+    let (mut reader, mut socket) = tokio::io::split(socket);
+    let connection = connection_span(&client_id, &address);
+    if let Some(identity) = &identity {
+        record_identity(&connection, identity);
+    }
+
+    // the messages are read in a separate task, a partially read message
+    // must not be dropped when the client channel receives a message.
+    let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(1);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let msg = stream::read_message(&mut reader).await;
+            let disconnected = msg.is_err();
+            if msg_tx.send(msg).await.is_err() || disconnected {
+                return;
+            }
+        }
+    });
+
+    let span = connection.clone();
     tokio::spawn(async move {
+        let Ok(mut kick) = broker.register(&client_id, address.clone(), identity.clone()) else {
+            reader_task.abort();
+            return;
+        };
+        metrics().clients.inc();
+        let mut identity = identity;
+        let mut claims: Option<Claims> = None;
+        let mut deadline = None;
+        // reads are paused until then when the client is throttled.
+        let mut resume = None;
+        // the transport level identity (client certificate) is enough to authenticate.
+        let mut authenticated = !broker.auth_required() || identity.is_some();
+        // the messages published by a bridge are not sent to the other bridges.
+        let mut bridge = false;
+        // the persistent session resumed by the client, the client id is derived from it.
+        let mut persistent: Option<String> = None;
         loop {
             tokio::select! {
-                msg = stream::read_message(&mut socket) => {
+                _ = wait_until(resume), if resume.is_some() => {
+                    resume = None;
+                },
+                Some(msg) = msg_rx.recv(), if resume.is_none() => {
                     match msg {
-                        Ok(mut m) => {
-                            m.client_id(client_id.clone());
-                            if !m.topic.is_empty() {
-                                info!("Topic: {}", m.topic);
-                                match m.header.pkt_type {
-                                    PktType::PUBLISH | PktType::SUBSCRIBE | PktType::UNSUBSCRIBE | PktType::QUERY => {
-                                        m.channel(client_chan.clone());
-                                        if let Err(e) = chan.send(m.clone()) {
-                                            error!("Error while sending message: {:?}", e);
+                        Ok(m) => {
+                            let mut m = Envelope::new(m);
+                            m.received_at = Some(std::time::Instant::now());
+                            let span = message_span(&m);
+                            let flow = async {
+                                if m.header.pkt_type == PktType::CONNECT {
+                                    let request = match serde_json::from_slice::<ConnectRequest>(&m.message) {
+                                        Ok(request) => request,
+                                        Err(e) => {
+                                            warn!("Invalid connect request from the client {}: {}", client_id, e);
+                                            write_error(&mut socket, &m.topic, PubSubError::InvalidConnect(e.to_string()).to_string()).await;
+                                            return ControlFlow::Break(());
+                                        }
+                                    };
+                                    // the transport level identity is kept when no credentials are sent.
+                                    let session = if identity.is_some() && !request.has_credentials() {
+                                        Ok(None)
+                                    } else {
+                                        broker.authenticate_request(request.clone()).await
+                                    };
+                                    match session {
+                                        Ok(session) => {
+                                            if let Some(session) = session {
+                                                info!("Client {} authenticated as: {}", client_id, session.identity);
+                                                record_identity(&connection, &session.identity);
+                                                broker.set_identity(&client_id, session.identity.clone());
+                                                identity = Some(session.identity);
+                                                deadline = session.claims.as_ref().map(|c| Instant::now() + c.expires_in());
+                                                claims = session.claims;
+                                            }
+                                            authenticated = true;
+                                            // only the authenticated clients allowed by the acl are bridges.
+                                            bridge = request.bridge && broker.allows_bridge(identity.as_deref(), claims.as_ref());
+                                            if request.bridge && !bridge {
+                                                warn!("Client {} is not allowed to connect as a bridge", client_id);
+                                            }
+                                            let mut session_present = false;
+                                            if let (Some(session_id), None) = (&request.session_id, &persistent) {
+                                                if request.persistent {
+                                                    match broker.resume_session(session_id, identity.as_deref()).await {
+                                                        Ok(session) => {
+                                                            info!("Client {} resumed the session {} (present: {})", client_id, session_id, session.present);
+                                                            let session_client_id = session::client_id(session_id);
+                                                            match broker.register(&session_client_id, address.clone(), identity.clone()) {
+                                                                Ok(session_kick) => kick = session_kick,
+                                                                Err(e) => {
+                                                                    // the queue goes back to the session for the next connection.
+                                                                    broker.sessions.suspend(session_id, session.rx);
+                                                                    warn!("Could not resume the session {} for the client {}: {}", session_id, client_id, e);
+                                                                    write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                                    return ControlFlow::Break(());
+                                                                }
+                                                            }
+                                                            broker.unregister(&client_id);
+                                                            client_id = session_client_id;
+                                                            client_chan = session.channel;
+                                                            client_rx = session.rx;
+                                                            session_present = session.present;
+                                                            persistent = Some(session_id.clone());
+                                                        }
+                                                        Err(e) => {
+                                                            warn!("Could not resume the session {} for the client {}: {}", session_id, client_id, e);
+                                                            write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                            return ControlFlow::Break(());
+                                                        }
+                                                    }
+                                                } else if let Err(e) = broker.discard_session(session_id, identity.as_deref()) {
+                                                    warn!("Could not discard the session {} for the client {}: {}", session_id, client_id, e);
+                                                    write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                    return ControlFlow::Break(());
+                                                }
+                                            }
+                                            let mut ack = vec![];
+                                            if request.session_id.is_some() || request.bridge {
+                                                ack = serde_json::to_vec(&ConnectAck { session_present, bridge }).unwrap_or_default();
+                                            }
+                                            match m.response_msg(ack) {
+                                                Ok(ack) => {
+                                                    if let Err(e) = write_all(&mut socket, &ack.bytes()).await {
+                                                        error!("Could not write the data to the socket: {:?}", e);
+                                                    }
+                                                }
+                                                Err(e) => error!("Error while generating the connect ack: {:?}", e),
+                                            }
+                                        }
+                                        Err(e) => {
+                                            warn!("Authentication failed for the client {}: {}", client_id, e);
+                                            write_error(&mut socket, &m.topic, e.to_string()).await;
+                                            return ControlFlow::Break(());
                                         }
-                                    },
-                                    _ => {}
+                                    }
+                                    return ControlFlow::Continue(());
                                 }
-                            }
-                            if m.header.pkt_type != PktType::QUERY {
-                                if let Ok(v) = message::get_msg_response(m.clone()) {
-                                    if let Err(e) = socket.write_all(&v).await {
-                                        error!("Could not write the data to the socket: {:?}", e);
+                                if !authenticated {
+                                    warn!("Rejecting {} from the unauthenticated client: {}", m.header.pkt_type, client_id);
+                                    write_error(&mut socket, &m.topic, PubSubError::NotAuthenticated.to_string()).await;
+                                    return ControlFlow::Break(());
+                                }
+
+                                m.client_id(client_id.clone());
+                                if let Some(identity) = &identity {
+                                    m.identity(identity.clone());
+                                }
+                                if !m.topic.is_empty() {
+                                    info!("Message received");
+                                    match m.header.pkt_type {
+                                        PktType::ADMIN => {
+                                            if let Err(e) = broker.authorize(&m, claims.as_ref()) {
+                                                write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                return ControlFlow::Continue(());
+                                            }
+                                            match admin(&broker, &m).await {
+                                                Ok(resp) => {
+                                                    if let Err(e) = write_all(&mut socket, &resp.bytes()).await {
+                                                        error!("Could not write the data to the socket: {:?}", e);
+                                                    }
+                                                }
+                                                Err(e) => {
+                                                    warn!("Admin request from the client {} failed: {}", client_id, e);
+                                                    write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                }
+                                            }
+                                            return ControlFlow::Continue(());
+                                        }
+                                        PktType::PUBLISH | PktType::SUBSCRIBE | PktType::UNSUBSCRIBE | PktType::QUERY => {
+                                            if !is_valid_request_topic(&m.header.pkt_type, &m.topic) {
+                                                warn!("Invalid topic from the client {}: {}", client_id, m.topic);
+                                                write_error(&mut socket, &m.topic, PubSubError::InvalidTopic(m.topic.clone()).to_string()).await;
+                                                return ControlFlow::Continue(());
+                                            }
+                                            if let Err(e) = broker.authorize(&m, claims.as_ref()) {
+                                                write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                return ControlFlow::Continue(());
+                                            }
+                                            if m.header.pkt_type == PktType::QUERY {
+                                                let resp = broker.query(m.clone(), claims.as_ref()).await.and_then(|resp| m.response_msg(resp.to_string().into_bytes()));
+                                                match resp {
+                                                    Ok(resp) => {
+                                                        if let Err(e) = write_all(&mut socket, &resp.bytes()).await {
+                                                            error!("Could not write the data to the socket: {:?}", e);
+                                                        }
+                                                    }
+                                                    Err(e) => {
+                                                        error!("Query failed: {:?}", e);
+                                                        write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                    }
+                                                }
+                                                return ControlFlow::Continue(());
+                                            }
+                                            if m.header.pkt_type == PktType::PUBLISH {
+                                                match broker.rate_limit(&m) {
+                                                    Decision::Allow => {}
+                                                    Decision::Throttle(wait) => {
+                                                        debug!("Throttling the client {} for {:?}", client_id, wait);
+                                                        resume = Some(Instant::now() + wait);
+                                                    }
+                                                    Decision::Reject => {
+                                                        write_error(&mut socket, &m.topic, PubSubError::RateLimited(m.topic.clone()).to_string()).await;
+                                                        return ControlFlow::Continue(());
+                                                    }
+                                                }
+                                                match broker.reserve_memory(&mut m) {
+                                                    Ok(Some(wait)) => {
+                                                        debug!("Slowing down the client {} over the memory watermark", client_id);
+                                                        resume = resume.max(Some(Instant::now() + wait));
+                                                    }
+                                                    Ok(None) => {}
+                                                    Err(e) => {
+                                                        write_error(&mut socket, &m.topic, e.to_string()).await;
+                                                        return ControlFlow::Continue(());
+                                                    }
+                                                }
+                                            }
+                                            m.channel(client_chan.clone());
+                                            m.bridged = bridge;
+                                            if let Err(e) = broker.tx.send(m.clone()) {
+                                                error!("Error while sending message: {:?}", e);
+                                            }
+                                            if let Some(session_id) = &persistent {
+                                                match m.header.pkt_type {
+                                                    PktType::SUBSCRIBE => broker.sessions.subscribed(session_id, &m.topic),
+                                                    PktType::UNSUBSCRIBE => broker.sessions.unsubscribed(session_id, &m.topic),
+                                                    _ => {}
+                                                }
+                                            }
+                                        },
+                                        _ => {}
                                     }
-                                } else {
-                                    error!("Error while writing the data to the socket");
                                 }
+                                if m.header.pkt_type != PktType::QUERY {
+                                    if let Ok(v) = message::get_msg_response(m.msg.clone()) {
+                                        if let Err(e) = write_all(&mut socket, &v).await {
+                                            error!("Could not write the data to the socket: {:?}", e);
+                                        }
+                                    } else {
+                                        error!("Error while writing the data to the socket");
+                                    }
+                                }
+                                ControlFlow::Continue(())
+                            }
+                            .instrument(span)
+                            .await;
+                            if flow.is_break() {
+                                break;
                             }
                         },
                         Err(_e) => {
-                            warn!("Client disconnected: {}", client_id);
-                            return;
+                            warn!("Client disconnected: {} ({})", client_id, identity.as_deref().unwrap_or("anonymous"));
+                            break;
                         }
                     }
                 },
-                chan_msg = read_channel_msg(client_chan.clone()) => {
-                    if let Ok(m) = chan_msg {
-                        info!("Message received: {:?}, {}", m.topic.clone(), m.message.len());
-                        if let Err(e) = socket.write_all(&m.bytes()).await {
-                            error!("Failed to write data to socket: {:?}", e);
+                _ = kick.notified() => {
+                    warn!("Client {} disconnected by the admin", client_id);
+                    write_error(&mut socket, "", PubSubError::Kicked.to_string()).await;
+                    break;
+                },
+                _ = broker.stopped() => {
+                    info!("Client {} disconnected, the broker is shutting down", client_id);
+                    break;
+                },
+                _ = wait_until(deadline) => {
+                    warn!("Token expired for the client: {}", client_id);
+                    write_error(&mut socket, "", PubSubError::TokenExpired.to_string()).await;
+                    break;
+                },
+                chan_msg = client_rx.recv() => {
+                    match chan_msg {
+                        Ok(m) if bridge && m.bridged => {
+                            debug!("Not forwarding the bridged message on {} to the bridge {}", m.topic, client_id);
                         }
+                        Ok(m) => {
+                            let span = message_span(&m);
+                            async {
+                                if let Err(e) = write_all(&mut socket, &m.bytes()).await {
+                                    error!("Failed to write data to socket: {:?}", e);
+                                } else if m.header.pkt_type == PktType::PUBLISH {
+                                    info!("Message delivered");
+                                    metrics().delivered(&m);
+                                }
+                            }
+                            .instrument(span)
+                            .await;
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                            warn!("Client {} missed {} messages", client_id, n);
+                            metrics().dropped("client_lagged", n);
+                        }
+                        Err(_) => {}
                     }
                 }
             }
         }
-    });
+        reader_task.abort();
+        broker.unregister(&client_id);
+        if let Some(session_id) = &persistent {
+            // the messages are queued until the client resumes the session.
+            broker.sessions.suspend(session_id, client_rx);
+        }
+        metrics().clients.dec();
+        let _ = socket.shutdown().await;
+    }.instrument(span));
 }
@@ -0,0 +1,468 @@
+//! HTTP gateway for the broker.
+//!
+//! - `POST /topics/{topic}` publishes the request body on the topic.
+//! - `GET /topics/{topic}` returns the `QUERY` result as json, `GET /topics` queries all the topics.
+//! - `GET /events/{topic}` subscribes to the topic or pattern with Server-Sent Events.
+//!   The event data is the json `{"topic": ..., "message": ...}` with the topic the
+//!   message was published on, the payloads that are not UTF-8 are sent base64
+//!   encoded in `base64` events.
+//! - `GET /poll/{topic}?timeout=<seconds>` waits for the next message on the topic,
+//!   responds with the same json as the events, with `"base64": true` when the
+//!   payload is base64 encoded, or with `204 No Content` when no message arrives in time.
+//!   Every poll subscribes again, the messages published between two polls are
+//!   not delivered (at most once), the Server-Sent Events receive all of them.
+//!
+//! - `POST /admin` runs the json encoded [`AdminRequest`] in the body,
+//!   e.g. `{"op":"kick","client_id":"abc"}`, and returns its result as json.
+//!   The acl must allow the `admin` action on the operation, as for the native clients.
+//!
+//! The packets carry only the topic and the payload, the gateway does not read
+//! message properties from the query parameters or the headers.
+//!
+//! When the authentication is enabled the clients send `Authorization: Basic`
+//! (checked against the credentials file) or `Authorization: Bearer <token>`,
+//! the acl, the token claims and the rate limits apply as for the native clients.
+//!
+//! The metrics endpoint (`GET /metrics`) is served on its own address,
+//! without the authentication.
+use super::broker::{Broker, Session};
+use crate::acl::is_valid_request_topic;
+use crate::admin::AdminRequest;
+use crate::auth::ConnectRequest;
+use crate::error::PubSubError;
+use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::rate_limit::Decision;
+use crate::topics::Envelope;
+use crate::PktType;
+use anyhow::Result;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+/// messages buffered for a slow http subscriber.
+const SUBSCRIPTION_CAPACITY: usize = 64;
+/// default and maximum wait of a long-poll request, in seconds.
+const DEFAULT_POLL_TIMEOUT: u64 = 30;
+const MAX_POLL_TIMEOUT: u64 = 300;
+
+/// starts the http gateway on the given address.
+pub(crate) async fn start(addr: &str, broker: Arc<Broker>) -> Result<SocketAddr> {
+    let listener = super::bind_tcp(addr, Some("http")).await?;
+    let local_addr = listener.local_addr()?;
+    info!("HTTP gateway listening on: {}", local_addr);
+    let app = Router::new()
+        .route("/topics", get(query_all))
+        .route("/topics/{*topic}", get(query).post(publish))
+        .route("/events/{*topic}", get(events))
+        .route("/poll/{*topic}", get(poll))
+        .route("/admin", post(admin))
+        .layer(DefaultBodyLimit::max(usize::from(u16::MAX)))
+        .with_state(broker.clone())
+        .into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move {
+        let stopped = async move { broker.stopped().await };
+        if let Err(e) = axum::serve(listener, app)
+            .with_graceful_shutdown(stopped)
+            .await
+        {
+            error!("HTTP gateway stopped: {:?}", e);
+        }
+    });
+    Ok(local_addr)
+}
+
+/// starts the prometheus metrics endpoint on the given address.
+pub(crate) async fn start_metrics(addr: &str, broker: Arc<Broker>) -> Result<SocketAddr> {
+    let listener = super::bind_tcp(addr, Some("metrics")).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Metrics endpoint listening on: {}", local_addr);
+    let app = Router::new()
+        .route("/metrics", get(encode_metrics))
+        .with_state(broker.clone());
+    tokio::spawn(async move {
+        let stopped = async move { broker.stopped().await };
+        if let Err(e) = axum::serve(listener, app)
+            .with_graceful_shutdown(stopped)
+            .await
+        {
+            error!("Metrics endpoint stopped: {:?}", e);
+        }
+    });
+    Ok(local_addr)
+}
+
+/// returns the metrics in the OpenMetrics text format.
+async fn encode_metrics(State(broker): State<Arc<Broker>>) -> Response {
+    metrics().queue_depth.set(broker.tx.len() as i64);
+    (
+        [(
+            CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics().encode(),
+    )
+        .into_response()
+}
+
+/// error response, the reason is sent as json.
+struct HttpError(StatusCode, String);
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({ "error": self.1 }));
+        if self.0 == StatusCode::UNAUTHORIZED {
+            (
+                self.0,
+                [(WWW_AUTHENTICATE, "Basic realm=\"simple-pub-sub\"")],
+                body,
+            )
+                .into_response()
+        } else {
+            (self.0, body).into_response()
+        }
+    }
+}
+
+/// a single http request, authenticated with the `Authorization` header.
+struct HttpClient {
+    id: String,
+    session: Option<Session>,
+}
+
+impl HttpClient {
+    async fn authenticate(broker: &Broker, headers: &HeaderMap) -> Result<HttpClient, HttpError> {
+        let id = uuid::Uuid::new_v4().to_string();
+        if !broker.auth_required() {
+            return Ok(HttpClient { id, session: None });
+        }
+        let Some(request) = connect_request(headers) else {
+            return Err(HttpError(
+                StatusCode::UNAUTHORIZED,
+                PubSubError::NotAuthenticated.to_string(),
+            ));
+        };
+        match broker.authenticate_request(request).await {
+            Ok(session) => Ok(HttpClient { id, session }),
+            Err(e) => {
+                warn!("Authentication failed for the http client {}: {}", id, e);
+                Err(HttpError(StatusCode::UNAUTHORIZED, e.to_string()))
+            }
+        }
+    }
+
+    /// creates the message for the request, checks the acl and the token claims.
+    fn msg(
+        &self,
+        broker: &Broker,
+        pkt_type: PktType,
+        topic: String,
+        message: Vec<u8>,
+    ) -> Result<Envelope, HttpError> {
+        if topic.is_empty() || topic.len() > usize::from(u8::MAX) {
+            return Err(HttpError(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid topic length: {}", topic.len()),
+            ));
+        }
+        if !is_valid_request_topic(&pkt_type, &topic) {
+            return Err(HttpError(
+                StatusCode::BAD_REQUEST,
+                PubSubError::InvalidTopic(topic).to_string(),
+            ));
+        }
+        let mut msg = Envelope::new(Msg::new(pkt_type, topic, Some(message)));
+        msg.client_id(self.id.clone());
+        if let Some(session) = &self.session {
+            msg.identity(session.identity.clone());
+        }
+        let claims = self.session.as_ref().and_then(|s| s.claims.as_ref());
+        if let Err(e) = broker.authorize(&msg, claims) {
+            return Err(HttpError(StatusCode::FORBIDDEN, e.to_string()));
+        }
+        Ok(msg)
+    }
+
+    /// time left before the token of the client expires.
+    fn expires_in(&self) -> Option<Duration> {
+        self.session
+            .as_ref()
+            .and_then(|s| s.claims.as_ref())
+            .map(|claims| claims.expires_in())
+    }
+}
+
+/// reads the credentials from the `Authorization` header.
+fn connect_request(headers: &HeaderMap) -> Option<ConnectRequest> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "basic" => {
+            let decoded = BASE64_STANDARD.decode(credentials.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            Some(ConnectRequest {
+                username: Some(username.to_string()),
+                password: Some(password.to_string()),
+                ..Default::default()
+            })
+        }
+        "bearer" => Some(ConnectRequest {
+            token: Some(credentials.trim().to_string()),
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+fn send(broker: &Broker, msg: Envelope) -> Result<(), HttpError> {
+    broker.tx.send(msg).map(|_| ()).map_err(|e| {
+        error!("Error while sending message: {:?}", e);
+        HttpError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })
+}
+
+/// publishes the request body on the topic.
+async fn publish(
+    State(broker): State<Arc<Broker>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(topic): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    let client = HttpClient::authenticate(&broker, &headers).await?;
+    let mut msg = client.msg(&broker, PktType::PUBLISH, topic.clone(), body.to_vec())?;
+    // every request is a new client, the anonymous publishers
+    // are rate limited by their address instead.
+    msg.client_id(format!("http:{}", addr.ip()));
+    msg.received_at = Some(std::time::Instant::now());
+    match broker.rate_limit(&msg) {
+        Decision::Allow => {}
+        Decision::Throttle(wait) => tokio::time::sleep(wait).await,
+        Decision::Reject => {
+            return Err(HttpError(
+                StatusCode::TOO_MANY_REQUESTS,
+                PubSubError::RateLimited(topic).to_string(),
+            ))
+        }
+    }
+    match broker.reserve_memory(&mut msg) {
+        Ok(Some(wait)) => tokio::time::sleep(wait).await,
+        Ok(None) => {}
+        Err(e) => return Err(HttpError(StatusCode::SERVICE_UNAVAILABLE, e.to_string())),
+    }
+    info!("Topic: {}, http client: {}", topic, client.id);
+    send(&broker, msg)?;
+    Ok(Json(json!({ "topic": topic, "published": body.len() })))
+}
+
+async fn query_all(
+    State(broker): State<Arc<Broker>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    query(State(broker), Path("*".to_string()), headers).await
+}
+
+/// returns the `QUERY` response of the topic manager.
+async fn query(
+    State(broker): State<Arc<Broker>>,
+    Path(topic): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    let client = HttpClient::authenticate(&broker, &headers).await?;
+    let msg = client.msg(&broker, PktType::QUERY, topic, vec![])?;
+    let claims = client.session.as_ref().and_then(|s| s.claims.as_ref());
+    match broker.query(msg, claims).await {
+        Ok(resp) => Ok(Json(resp)),
+        Err(e) => {
+            error!("Query failed: {:?}", e);
+            Err(HttpError(
+                StatusCode::GATEWAY_TIMEOUT,
+                "No response to the query".to_string(),
+            ))
+        }
+    }
+}
+
+/// runs the admin request of the body.
+async fn admin(
+    State(broker): State<Arc<Broker>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    let client = HttpClient::authenticate(&broker, &headers).await?;
+    let request: AdminRequest = serde_json::from_slice(&body)
+        .map_err(|e| HttpError(StatusCode::BAD_REQUEST, e.to_string()))?;
+    // the acl is checked for the operation.
+    client.msg(
+        &broker,
+        PktType::ADMIN,
+        request.operation().to_string(),
+        vec![],
+    )?;
+    match broker.admin(request).await {
+        Ok(resp) => Ok(Json(resp)),
+        Err(e) => {
+            warn!(
+                "Admin request from the http client {} failed: {}",
+                client.id, e
+            );
+            let status = match e.downcast_ref::<PubSubError>() {
+                Some(PubSubError::UnknownClient(_)) => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err(HttpError(status, e.to_string()))
+        }
+    }
+}
+
+/// subscription of a http client, unsubscribed when dropped.
+struct Subscription {
+    broker: Arc<Broker>,
+    topic: String,
+    client_id: String,
+    rx: broadcast::Receiver<Envelope>,
+}
+
+impl Subscription {
+    fn new(
+        broker: Arc<Broker>,
+        client: &HttpClient,
+        topic: String,
+    ) -> Result<Subscription, HttpError> {
+        let mut msg = client.msg(&broker, PktType::SUBSCRIBE, topic.clone(), vec![])?;
+        let (tx, rx) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+        msg.channel(tx);
+        send(&broker, msg)?;
+        Ok(Subscription {
+            broker,
+            topic,
+            client_id: client.id.clone(),
+            rx,
+        })
+    }
+
+    /// waits for the next message on the topic, `None` once the broker shuts down.
+    async fn next(&mut self) -> Option<Envelope> {
+        loop {
+            let msg = tokio::select! {
+                msg = self.rx.recv() => msg,
+                _ = self.broker.stopped() => return None,
+            };
+            match msg {
+                Ok(msg) => {
+                    metrics().delivered(&msg);
+                    return Some(msg);
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Http client {} missed {} messages", self.client_id, n);
+                    metrics().dropped("client_lagged", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut msg = Envelope::new(Msg::new(PktType::UNSUBSCRIBE, self.topic.clone(), None));
+        msg.client_id(self.client_id.clone());
+        let _ = self.broker.tx.send(msg);
+    }
+}
+
+/// streams the messages on the topic as Server-Sent Events,
+/// the stream ends when the token of the client expires.
+async fn events(
+    State(broker): State<Arc<Broker>>,
+    Path(topic): Path<String>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, HttpError> {
+    let client = HttpClient::authenticate(&broker, &headers).await?;
+    let expires_in = client.expires_in();
+    let subscription = Subscription::new(broker, &client, topic)?;
+    let stream = futures_util::stream::unfold(subscription, |mut subscription| async move {
+        let msg = subscription.next().await?;
+        Some((Ok(event(&msg)), subscription))
+    });
+    let expiry = async move {
+        match expires_in {
+            Some(expires_in) => tokio::time::sleep(expires_in).await,
+            None => std::future::pending().await,
+        }
+    };
+    Ok(Sse::new(stream.take_until(expiry)).keep_alive(KeepAlive::default()))
+}
+
+/// the event with the json of the message, a `base64` event when the payload is encoded.
+fn event(msg: &Msg) -> Event {
+    let (base64, message) = message_json(msg);
+    let event = if base64 {
+        Event::default().event("base64")
+    } else {
+        Event::default()
+    };
+    event.data(message.to_string())
+}
+
+/// the json of the message with its topic, the payload is base64 encoded when
+/// it is not UTF-8. Returns true if the payload is encoded.
+fn message_json(msg: &Msg) -> (bool, serde_json::Value) {
+    match std::str::from_utf8(&msg.message) {
+        Ok(text) => (false, json!({ "topic": msg.topic, "message": text })),
+        Err(_) => (
+            true,
+            json!({
+                "topic": msg.topic,
+                "message": BASE64_STANDARD.encode(&msg.message),
+                "base64": true,
+            }),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct PollParams {
+    /// seconds to wait for a message
+    timeout: Option<u64>,
+}
+
+/// waits for the next message on the topic.
+async fn poll(
+    State(broker): State<Arc<Broker>>,
+    Path(topic): Path<String>,
+    Query(params): Query<PollParams>,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    let client = HttpClient::authenticate(&broker, &headers).await?;
+    let mut subscription = Subscription::new(broker, &client, topic)?;
+    let mut timeout = Duration::from_secs(
+        params
+            .timeout
+            .unwrap_or(DEFAULT_POLL_TIMEOUT)
+            .min(MAX_POLL_TIMEOUT),
+    );
+    if let Some(expires_in) = client.expires_in() {
+        timeout = timeout.min(expires_in);
+    }
+    match tokio::time::timeout(timeout, subscription.next()).await {
+        Ok(Some(msg)) => Ok(Json(message_json(&msg).1).into_response()),
+        _ => Ok(StatusCode::NO_CONTENT.into_response()),
+    }
+}
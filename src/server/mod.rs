@@ -1,22 +1,209 @@
-mod client_handler;
-use crate::topics;
-use anyhow::Result;
-use log::info;
-use std::fs::File;
-use std::io::Read;
+pub(crate) mod broker;
+pub(crate) mod client_handler;
+mod http;
+mod mqtt;
+mod nats;
+mod redis;
+mod session;
+mod tls;
+mod unix;
+use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::rate_limit::RateLimitAction;
+use crate::stream::WebSocketStream;
+use crate::systemd;
+use crate::PktType;
+use anyhow::{anyhow, bail, Result};
+use broker::Broker;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::net::UnixListener;
-use tokio_native_tls::native_tls::{Identity, TlsAcceptor};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// topic queried to check the topic manager before notifying the systemd watchdog.
+const WATCHDOG_TOPIC: &str = "$watchdog";
+
+/// Configuration of the broker, common for all the server types.
+#[derive(Debug, Clone, Default)]
+pub struct BrokerConfig {
+    /// credentials file with the salted password hashes,
+    /// the clients must authenticate with a `CONNECT` packet when set.
+    pub credentials: Option<String>,
+    /// acl file with the allow/deny rules for the topics.
+    pub acl: Option<String>,
+    /// file to record the denied requests.
+    pub audit_log: Option<String>,
+    /// file with the HMAC secret to verify the `HS256` tokens.
+    pub token_secret: Option<String>,
+    /// Ed25519 public key (.pem) to verify the `EdDSA` tokens.
+    pub token_public_key: Option<String>,
+    /// rate limit file with the per client and per topic limits.
+    pub rate_limits: Option<String>,
+    /// what happens to the publishes over the rate limit.
+    pub rate_limit_action: RateLimitAction,
+    /// address (`host:port`) of the http gateway, disabled when not set.
+    pub http: Option<String>,
+    /// address (`host:port`) of the prometheus metrics endpoint, disabled when not set.
+    pub metrics: Option<String>,
+    /// topic patterns counted separately in the metrics, the other topics are counted together.
+    pub metrics_topics: Vec<String>,
+    /// address (`host:port`) of the MQTT 3.1.1 listener, disabled when not set.
+    pub mqtt: Option<String>,
+    /// address (`host:port`) of the Redis pub/sub listener, disabled when not set.
+    pub redis: Option<String>,
+    /// address (`host:port`) of the NATS listener, disabled when not set.
+    pub nats: Option<String>,
+    /// time a disconnected persistent session is kept, one hour when not set.
+    pub session_expiry: Option<Duration>,
+    /// messages queued for a disconnected persistent session, 1024 when not set.
+    /// the broker does not start when it is 0.
+    /// the limit is rounded up to a power of two, the oldest messages are dropped first.
+    pub session_queue: Option<usize>,
+    /// bytes of the messages held by the broker for all the clients, unlimited when not set.
+    pub memory_limit: Option<usize>,
+    /// bytes of the messages held by the broker for one client, unlimited when not set.
+    pub client_memory_limit: Option<usize>,
+}
 
 pub trait ServerTrait {
-    fn start(&self) -> impl std::future::Future<Output = Result<()>> + Send;
+    /// binds the listener and starts the server in the background,
+    /// the returned handle reports the bound address and the readiness.
+    fn bind(&self) -> impl Future<Output = Result<ServerHandle>> + Send;
+    /// runs the server until it stops.
+    fn start(&self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Addresses of the configured http gateway, metrics endpoint and MQTT, Redis
+/// and NATS listeners, with the ports chosen by the system for the port 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listeners {
+    pub http: Option<SocketAddr>,
+    pub metrics: Option<SocketAddr>,
+    pub mqtt: Option<SocketAddr>,
+    pub redis: Option<SocketAddr>,
+    pub nats: Option<SocketAddr>,
+}
+
+/// Address the server is listening on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl LocalAddr {
+    /// returns the port of the tcp address, `None` for the unix socket.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            LocalAddr::Tcp(addr) => Some(addr.port()),
+            LocalAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalAddr::Tcp(addr) => write!(f, "{addr}"),
+            LocalAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Running server returned by [`ServerTrait::bind`]. The listener is bound when
+/// the handle is returned, the broker starts in the background and the server keeps
+/// running when the handle is dropped.
+/// ```
+/// use simple_pub_sub::server::ServerTrait as _;
+/// async fn run() {
+///   let server = simple_pub_sub::server::Tcp {
+///     host: "127.0.0.1".to_string(),
+///     // any free port
+///     port: 0,
+///     cert: None,
+///     cert_password: None,
+///     key: None,
+///     client_ca: None,
+///     capacity: 1024,
+///     config: Default::default(),
+///   };
+///   let server = server.bind().await.unwrap();
+///   server.ready().await.unwrap();
+///   println!("Listening on {}", server.local_addr());
+///   server.shutdown().await.unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: LocalAddr,
+    /// `None` while the broker is starting, the error when it could not start.
+    ready: watch::Receiver<Option<Result<Listeners, String>>>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// returns the address the server is listening on,
+    /// the port chosen by the system when the port 0 is bound.
+    pub fn local_addr(&self) -> &LocalAddr {
+        &self.local_addr
+    }
+
+    /// waits until the broker is started and the connections are accepted,
+    /// fails when the broker could not start.
+    pub async fn ready(&self) -> Result<()> {
+        let mut ready = self.ready.clone();
+        let state = ready
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| anyhow!("The server stopped before it was ready"))?
+            .clone();
+        match state {
+            Some(Err(e)) => bail!("The server could not start: {e}"),
+            _ => Ok(()),
+        }
+    }
+
+    /// returns the addresses of the other listeners of the broker,
+    /// none of them is set before the server is ready.
+    pub fn listeners(&self) -> Listeners {
+        match &*self.ready.borrow() {
+            Some(Ok(listeners)) => listeners.clone(),
+            _ => Listeners::default(),
+        }
+    }
+
+    /// waits until the server stops, returns the error of the broker or the listener.
+    pub async fn join(self) -> Result<()> {
+        self.task.await?
+    }
+
+    /// stops accepting the connections, disconnects the clients, stops the
+    /// listeners of the broker and waits until the server is stopped.
+    pub async fn shutdown(self) -> Result<()> {
+        self.stop.send_replace(true);
+        self.join().await
+    }
 }
 pub struct Tcp {
     pub host: String,
     pub port: u16,
     pub cert: Option<String>,
     pub cert_password: Option<String>,
+    /// private key (.pem) for the certificate, when set the `cert`
+    /// is read as a PEM certificate chain instead of a PKCS#12 identity.
+    pub key: Option<String>,
+    /// CA certificate (.pem) used to verify the client certificates,
+    /// enables mutual tls when set.
+    pub client_ca: Option<String>,
     pub capacity: usize,
+    pub config: BrokerConfig,
 }
 
 impl ServerTrait for Tcp {
@@ -30,7 +217,10 @@ impl ServerTrait for Tcp {
     ///     port: 6480,
     ///     cert: None,
     ///     cert_password: None,
+    ///     key: None,
+    ///     client_ca: None,
     ///     capacity: 1024,
+    ///     config: Default::default(),
     ///   });
     ///   let _ = server.start().await;
     /// }
@@ -41,29 +231,35 @@ impl ServerTrait for Tcp {
     ///     port: 6480,
     ///     cert: Some("certs/cert.pem".to_string()),
     ///     cert_password: Some("password".to_string()),
+    ///     key: None,
+    ///     client_ca: None,
     ///     capacity: 1024,
+    ///     config: Default::default(),
     ///   });
     ///   let _ = server.start().await;
     /// }
     /// ```
     async fn start(&self) -> Result<()> {
-        if let Some(cert) = &self.cert {
-            start_tls_server(
-                self.host.clone(),
-                self.port,
-                cert.clone(),
-                self.cert_password.clone(),
-                self.capacity,
-            )
-            .await
-        } else {
-            start_tcp_server(format!("{}:{}", self.host, self.port), self.capacity).await
+        self.bind().await?.join().await
+    }
+
+    async fn bind(&self) -> Result<ServerHandle> {
+        match &self.cert {
+            Some(cert) => bind_tls_server(self, cert).await,
+            None => bind_tcp_server(self).await,
         }
     }
 }
 pub struct Unix {
     pub path: String,
+    /// permissions of the socket file, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// owner of the socket file, user name or uid.
+    pub owner: Option<String>,
+    /// group of the socket file, group name or gid.
+    pub group: Option<String>,
     pub capacity: usize,
+    pub config: BrokerConfig,
 }
 
 impl ServerTrait for Unix {
@@ -72,25 +268,29 @@ impl ServerTrait for Unix {
     /// use crate::simple_pub_sub::server::ServerTrait as _;
     /// let server = simple_pub_sub::server::ServerType::Unix(simple_pub_sub::server::Unix {
     ///   path: "/tmp/sample.sock".to_string(),
+    ///   mode: None,
+    ///   owner: None,
+    ///   group: None,
     ///   capacity: 1024,
+    ///   config: Default::default(),
     /// });
     /// let result = server.start();
     ///```
     async fn start(&self) -> Result<()> {
-        start_unix_server(self.path.clone(), self.capacity).await
+        self.bind().await?.join().await
     }
-}
-impl Drop for Unix {
-    fn drop(&mut self) {
-        if std::path::Path::new(&self.path).exists() {
-            std::fs::remove_file(&self.path).unwrap();
-        }
+
+    async fn bind(&self) -> Result<ServerHandle> {
+        bind_unix_server(self).await
     }
 }
 
 pub enum ServerType {
     Tcp(Tcp),
     Unix(Unix),
+    /// websocket server, the `Msg` frames are sent as the binary messages.
+    /// uses tls (`wss://`) when the `cert` is set.
+    WebSocket(Tcp),
 }
 impl ServerTrait for ServerType {
     /// starts the simple-pub-sub server on the given server type
@@ -103,7 +303,10 @@ impl ServerTrait for ServerType {
     ///     port: 6480,
     ///     cert: None,
     ///     cert_password: None,
+    ///     key: None,
+    ///     client_ca: None,
     ///     capacity: 1024,
+    ///     config: Default::default(),
     ///   });
     ///   server.start();
     ///
@@ -114,7 +317,10 @@ impl ServerTrait for ServerType {
     ///     port: 6480,
     ///     cert: Some("certs/cert.pem".to_string()),
     ///     cert_password: Some("password".to_string()),
+    ///     key: None,
+    ///     client_ca: None,
     ///     capacity: 1024,
+    ///     config: Default::default(),
     ///   });
     ///   server.start();
     ///
@@ -122,14 +328,23 @@ impl ServerTrait for ServerType {
     /// use crate::simple_pub_sub::server::ServerTrait as _;
     /// let server = simple_pub_sub::server::ServerType::Unix(simple_pub_sub::server::Unix {
     ///   path: "/tmp/sample.sock".to_string(),
+    ///   mode: None,
+    ///   owner: None,
+    ///   group: None,
     ///   capacity: 1024,
+    ///   config: Default::default(),
     /// });
     /// let result = server.start();
     ///```
     async fn start(&self) -> Result<()> {
+        self.bind().await?.join().await
+    }
+
+    async fn bind(&self) -> Result<ServerHandle> {
         match self {
-            ServerType::Tcp(tcp) => tcp.start().await,
-            ServerType::Unix(unix) => unix.start().await,
+            ServerType::Tcp(tcp) => tcp.bind().await,
+            ServerType::Unix(unix) => unix.bind().await,
+            ServerType::WebSocket(server) => bind_websocket_server(server).await,
         }
     }
 }
@@ -142,75 +357,276 @@ impl Server {
     pub async fn start(&self) -> Result<()> {
         self.server_type.start().await
     }
+
+    /// binds the listener and starts the server in the background, see [`ServerHandle`].
+    pub async fn bind(&self) -> Result<ServerHandle> {
+        self.server_type.bind().await
+    }
 }
 
-/// Started a tls server on the given address with the given certificate (.pfx file)
-async fn start_tls_server(
-    host: String,
-    port: u16,
-    cert: String,
-    cert_password: Option<String>,
-    capacity: usize,
-) -> Result<()> {
-    // Load TLS identity (certificate and private key)
-    let mut file = File::open(&cert)?;
-    let mut identity_vec = vec![];
-    file.read_to_end(&mut identity_vec)?;
+/// returns the TCP listening socket passed by systemd with the name (see
+/// [`crate::systemd`]), or binds the address when there is none.
+async fn bind_tcp(addr: &str, name: Option<&str>) -> Result<TcpListener> {
+    match systemd::tcp_listener(name) {
+        Some(listener) => {
+            info!(
+                "Using the socket {} passed by systemd instead of {}",
+                listener.local_addr()?,
+                addr
+            );
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener)?)
+        }
+        None => Ok(TcpListener::bind(addr).await?),
+    }
+}
 
-    let identity: Identity;
-    if let Some(cert_password) = cert_password {
-        identity = Identity::from_pkcs12(&identity_vec, cert_password.as_str())?;
-    } else {
-        identity = Identity::from_pkcs12(&identity_vec, "")?;
+/// notifies systemd that the broker is ready, and notifies the watchdog
+/// while the topic manager answers when the watchdog is enabled.
+fn notify_ready(broker: &Arc<Broker>) {
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!("Could not notify systemd: {:?}", e);
     }
+    let Some(interval) = systemd::watchdog_interval() else {
+        return;
+    };
+    let broker = broker.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = broker.stopped() => return,
+            }
+            let query = Msg::new(PktType::QUERY, WATCHDOG_TOPIC.to_string(), None);
+            match broker.query(query.into(), None).await {
+                Ok(_) => {
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        warn!("Could not notify the systemd watchdog: {:?}", e);
+                    }
+                }
+                Err(e) => warn!("No watchdog notification: {}", e),
+            }
+        }
+    });
+}
 
-    let acceptor = TlsAcceptor::builder(identity).build()?;
-    let acceptor = tokio_native_tls::TlsAcceptor::from(acceptor);
+/// starts the broker and serves the connections in the background,
+/// until the server is shut down or the listener fails.
+fn spawn_server<F, Fut>(
+    local_addr: LocalAddr,
+    capacity: usize,
+    config: BrokerConfig,
+    serve: F,
+) -> ServerHandle
+where
+    F: FnOnce(Arc<Broker>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let (ready_tx, ready) = watch::channel(None);
+    let (stop, mut stop_rx) = watch::channel(false);
+    let task = tokio::spawn(async move {
+        info!("Getting global broadcaster");
+        let broker = match Broker::start(capacity, &config).await {
+            Ok(broker) => broker,
+            Err(e) => {
+                ready_tx.send_replace(Some(Err(format!("{e:?}"))));
+                return Err(e);
+            }
+        };
+        notify_ready(&broker);
+        ready_tx.send_replace(Some(Ok(broker.listeners())));
+        let stopped = async move {
+            // the server keeps running when the handle is dropped.
+            if stop_rx.wait_for(|stop| *stop).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+        let result = tokio::select! {
+            result = serve(broker.clone()) => result,
+            _ = stopped => Ok(()),
+        };
+        broker.shutdown();
+        result
+    });
+    ServerHandle {
+        local_addr,
+        ready,
+        stop,
+        task,
+    }
+}
 
-    // Bind TCP listener
-    let listener = TcpListener::bind(format!("{host}:{port}")).await?;
+/// Started a tls server on the given address with the given certificate,
+/// either a PKCS#12 identity (.pfx file) or a PEM certificate with the `key`.
+/// when `client_ca` is given, only the clients with a certificate signed by it are accepted.
+async fn bind_tls_server(server: &Tcp, cert: &str) -> Result<ServerHandle> {
+    // Load TLS identity (certificate and private key)
+    let acceptor = tls::acceptor(
+        cert,
+        server.key.as_deref(),
+        server.cert_password.as_deref(),
+        server.client_ca.as_deref(),
+    )?;
 
-    info!("Server listening on port {}:{}", host, port);
-    let tx = topics::get_global_broadcaster(capacity);
-    let _topic_handler = tokio::spawn(topics::topic_manager(tx.clone()));
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("Accepted connection from {:?}", addr);
-        let acceptor = acceptor.clone();
-        let tls_stream = acceptor.accept(stream).await?;
-        client_handler::handle_client(tls_stream, tx.clone()).await;
-    }
+    // Bind TCP listener
+    let listener = bind_tcp(&format!("{}:{}", server.host, server.port), None).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Server listening on {}", local_addr);
+    let serve = move |broker: Arc<Broker>| async move {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!("Accepted connection from {:?}", addr);
+            metrics().connection("tls");
+            let acceptor = acceptor.clone();
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                match tls::accept(&acceptor, stream).await {
+                    Ok((tls_stream, identity)) => {
+                        if let Some(identity) = &identity {
+                            info!("Client {:?} authenticated as: {}", addr, identity);
+                        }
+                        client_handler::handle_client(
+                            tls_stream,
+                            broker,
+                            identity,
+                            addr.to_string(),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        error!("Rejected the connection from {:?}: {:?}", addr, e);
+                    }
+                }
+            });
+        }
+    };
+    Ok(spawn_server(
+        LocalAddr::Tcp(local_addr),
+        server.capacity,
+        server.config.clone(),
+        serve,
+    ))
 }
 
-/// Starts a tcp server on the given address
-async fn start_tcp_server(addr: String, capacity: usize) -> Result<()> {
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on: {}", addr);
-    info!("Getting global broadcaster");
+/// Starts a websocket server, the clients can connect on any path.
+async fn bind_websocket_server(server: &Tcp) -> Result<ServerHandle> {
+    let acceptor = match &server.cert {
+        Some(cert) => Some(tls::acceptor(
+            cert,
+            server.key.as_deref(),
+            server.cert_password.as_deref(),
+            server.client_ca.as_deref(),
+        )?),
+        None => None,
+    };
 
-    let tx = topics::get_global_broadcaster(capacity);
-    let _topic_handler = tokio::spawn(topics::topic_manager(tx.clone()));
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("Addr is: {addr}");
-        client_handler::handle_client(socket, tx.clone()).await;
-    }
+    let listener = bind_tcp(&format!("{}:{}", server.host, server.port), None).await?;
+    let local_addr = listener.local_addr()?;
+    info!("WebSocket server listening on {}", local_addr);
+    let serve = move |broker: Arc<Broker>| async move {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            info!("Accepted connection from {:?}", addr);
+            metrics().connection("websocket");
+            let acceptor = acceptor.clone();
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                let result: Result<()> = async {
+                    match &acceptor {
+                        Some(acceptor) => {
+                            let (tls_stream, identity) = tls::accept(acceptor, stream).await?;
+                            let ws = tokio_tungstenite::accept_async(tls_stream).await?;
+                            client_handler::handle_client(
+                                WebSocketStream::new(ws),
+                                broker,
+                                identity,
+                                addr.to_string(),
+                            )
+                            .await;
+                        }
+                        None => {
+                            let ws = tokio_tungstenite::accept_async(stream).await?;
+                            client_handler::handle_client(
+                                WebSocketStream::new(ws),
+                                broker,
+                                None,
+                                addr.to_string(),
+                            )
+                            .await;
+                        }
+                    }
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = result {
+                    error!("Rejected the connection from {:?}: {:?}", addr, e);
+                }
+            });
+        }
+    };
+    Ok(spawn_server(
+        LocalAddr::Tcp(local_addr),
+        server.capacity,
+        server.config.clone(),
+        serve,
+    ))
 }
 
-/// Starts a unix server on the given path
-async fn start_unix_server(path: String, capacity: usize) -> Result<()> {
-    if std::path::Path::new(&path).exists() {
-        std::fs::remove_file(path.clone())?;
-    }
+/// Starts a tcp server on the given address
+async fn bind_tcp_server(server: &Tcp) -> Result<ServerHandle> {
+    let listener = bind_tcp(&format!("{}:{}", server.host, server.port), None).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Listening on: {}", local_addr);
+    let serve = move |broker: Arc<Broker>| async move {
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            info!("Addr is: {addr}");
+            metrics().connection("tcp");
+            client_handler::handle_client(socket, broker.clone(), None, addr.to_string()).await;
+        }
+    };
+    Ok(spawn_server(
+        LocalAddr::Tcp(local_addr),
+        server.capacity,
+        server.config.clone(),
+        serve,
+    ))
+}
 
-    let listener = UnixListener::bind(&path)?;
-    info!("Listening on: {}", path);
-    info!("Getting global broadcaster");
-    let tx = topics::get_global_broadcaster(capacity);
-    let _topic_handler = tokio::spawn(topics::topic_manager(tx.clone()));
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("Addr is: {:?}", addr.as_pathname());
-        client_handler::handle_client(socket, tx.clone()).await;
+/// Starts a unix server on the given path,
+/// the clients are identified by the user of the connected process.
+async fn bind_unix_server(server: &Unix) -> Result<ServerHandle> {
+    let (listener, socket_file) = unix::bind(server)?;
+    // the socket of the broker may have been bound elsewhere and moved to the path.
+    let path = match listener.local_addr()?.as_pathname() {
+        Some(path) if socket_file.is_none() => path.to_path_buf(),
+        _ => PathBuf::from(&server.path),
+    };
+    if socket_file.is_some() {
+        info!("Listening on: {}", server.path);
     }
+    let address = format!("unix:{}", server.path);
+    let serve = move |broker: Arc<Broker>| async move {
+        // the socket file is removed when the server stops.
+        let _socket_file = socket_file;
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            info!("Addr is: {:?}", addr.as_pathname());
+            metrics().connection("unix");
+            let broker = broker.clone();
+            let address = address.clone();
+            // the accept loop does not wait for the user lookup.
+            tokio::spawn(async move {
+                let identity = unix::peer_identity(&socket).await;
+                client_handler::handle_client(socket, broker, identity, address).await;
+            });
+        }
+    };
+    Ok(spawn_server(
+        LocalAddr::Unix(path),
+        server.capacity,
+        server.config.clone(),
+        serve,
+    ))
 }
@@ -0,0 +1,852 @@
+//! MQTT 3.1.1 listener.
+//!
+//! The MQTT clients share the topics with the native clients, `CONNECT`,
+//! `PUBLISH`, `SUBSCRIBE`, `UNSUBSCRIBE` and `PINGREQ` are translated to the
+//! topic manager messages. The `+` and `#` wildcards of the topic filters
+//! match the same way as the native subscription patterns, except that a filter
+//! starting with a wildcard does not match the topics starting with `$`.
+//!
+//! - The subscriptions are granted QoS 0, the messages are delivered at most once.
+//!   A QoS 1 publish is acknowledged once it is handed to the broker, the QoS 2
+//!   publishes are accepted with the `PUBREC`/`PUBREL`/`PUBCOMP` handshake.
+//! - Only the clean sessions are supported, the subscriptions end with the connection.
+//!   A client connecting with the client identifier of a connected client with the
+//!   same identity takes over, the other connection is closed. The `CONNECT` of
+//!   another identity is rejected with `identifier rejected`.
+//! - The retained messages are not stored.
+//! - The username and password are checked against the credentials file,
+//!   with an empty username the password is verified as a token.
+//!
+//! The acl, the token claims and the rate limits apply as for the native clients,
+//! the denied and the rejected publishes are dropped, MQTT 3.1.1 can not report them.
+use super::broker::{Broker, Session};
+use super::client_handler::{wait_until, write_all};
+use crate::acl::{is_pattern, is_valid_pattern, topic_matches};
+use crate::auth::ConnectRequest;
+use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::rate_limit::Decision;
+use crate::spans::{connection_span, message_span, record_identity};
+use crate::token::Claims;
+use crate::topics::Envelope;
+use crate::PktType;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn, Instrument, Span};
+
+/// time to wait for the `CONNECT` packet of a new connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// messages buffered for a slow MQTT subscriber.
+const SUBSCRIPTION_CAPACITY: usize = 64;
+/// largest accepted packet, a publish with the longest topic and message.
+const MAX_PACKET_SIZE: usize = 2 + 255 + 2 + 65535;
+
+// control packet types
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+// `CONNACK` return codes
+const ACCEPTED: u8 = 0;
+const UNACCEPTABLE_PROTOCOL: u8 = 1;
+const IDENTIFIER_REJECTED: u8 = 2;
+const BAD_CREDENTIALS: u8 = 4;
+const NOT_AUTHORIZED: u8 = 5;
+/// `SUBACK` return code of a rejected topic filter.
+const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+/// connected MQTT clients, the broker client id and the identity by MQTT client identifier.
+type ClientIds = Arc<Mutex<HashMap<String, (String, Option<String>)>>>;
+
+/// takes the MQTT client identifier for the connection, returns the connection
+/// it is taken over from. Only a client with the same identity takes it over.
+fn take_client_id(
+    client_ids: &ClientIds,
+    mqtt_id: &str,
+    client_id: &str,
+    identity: &Option<String>,
+) -> Result<Option<String>> {
+    let mut ids = client_ids
+        .lock()
+        .map_err(|_| anyhow!("The client ids are poisoned"))?;
+    if let Some((_, owner)) = ids.get(mqtt_id) {
+        if owner != identity {
+            bail!(
+                "The client identifier {} is used by another identity",
+                mqtt_id
+            );
+        }
+    }
+    let entry = (client_id.to_string(), identity.clone());
+    Ok(ids
+        .insert(mqtt_id.to_string(), entry)
+        .map(|(taken, _)| taken))
+}
+
+/// releases the MQTT client identifier, unless another connection took it over.
+fn release_client_id(client_ids: &ClientIds, mqtt_id: &str, client_id: &str) {
+    if let Ok(mut ids) = client_ids.lock() {
+        if ids
+            .get(mqtt_id)
+            .is_some_and(|(owner, _)| owner == client_id)
+        {
+            ids.remove(mqtt_id);
+        }
+    }
+}
+
+/// `PUBLISH` packet, also used for the will message.
+#[derive(Debug)]
+struct Publish {
+    topic: String,
+    payload: Vec<u8>,
+    qos: u8,
+    retain: bool,
+    /// packet identifier, 0 for QoS 0
+    pkid: u16,
+}
+
+#[derive(Debug)]
+struct Connect {
+    client_id: String,
+    clean_session: bool,
+    /// seconds, 0 disables the keep alive
+    keep_alive: u16,
+    username: Option<String>,
+    password: Option<Vec<u8>>,
+    will: Option<Publish>,
+}
+
+/// packets sent by the MQTT clients.
+#[derive(Debug)]
+enum Packet {
+    Connect(Connect),
+    /// `CONNECT` with an other protocol name or level
+    UnsupportedProtocol(String, u8),
+    Publish(Publish),
+    PubAck(u16),
+    PubRel(u16),
+    Subscribe {
+        pkid: u16,
+        filters: Vec<(String, u8)>,
+    },
+    Unsubscribe {
+        pkid: u16,
+        filters: Vec<String>,
+    },
+    PingReq,
+    Disconnect,
+}
+
+/// reads the fields of a packet body.
+struct Body<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Body<'a> {
+    fn u8(&mut self) -> Result<u8> {
+        let Some(&byte) = self.buf.get(self.pos) else {
+            bail!("Malformed packet: unexpected end of the packet");
+        };
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    /// length prefixed binary data.
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = usize::from(self.u16()?);
+        let Some(bytes) = self.buf.get(self.pos..self.pos + len) else {
+            bail!("Malformed packet: unexpected end of the packet");
+        };
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// length prefixed utf-8 string.
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?.to_vec())?)
+    }
+
+    /// non-zero packet identifier.
+    fn pkid(&mut self) -> Result<u16> {
+        match self.u16()? {
+            0 => bail!("Malformed packet: packet identifier 0"),
+            pkid => Ok(pkid),
+        }
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+}
+
+/// reads a packet from the client.
+async fn read_packet<R>(reader: &mut R) -> Result<Packet>
+where
+    R: AsyncRead + Unpin,
+{
+    let first = reader.read_u8().await?;
+    let mut len = 0;
+    for i in 0..4 {
+        let byte = reader.read_u8().await?;
+        len |= usize::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if i == 3 {
+            bail!("Malformed packet: invalid remaining length");
+        }
+    }
+    if len > MAX_PACKET_SIZE {
+        bail!("Packet too large: {} bytes", len);
+    }
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf).await?;
+    parse(first, &buf)
+}
+
+fn parse(first: u8, buf: &[u8]) -> Result<Packet> {
+    let flags = first & 0x0f;
+    let mut body = Body { buf, pos: 0 };
+    let packet = match (first >> 4, flags) {
+        (CONNECT, 0) => parse_connect(&mut body)?,
+        (PUBLISH, _) => Packet::Publish(parse_publish(flags, &mut body)?),
+        (PUBACK, 0) => Packet::PubAck(body.pkid()?),
+        (PUBREL, 2) => Packet::PubRel(body.pkid()?),
+        (SUBSCRIBE, 2) => {
+            let pkid = body.pkid()?;
+            let mut filters = vec![];
+            while !body.is_empty() {
+                filters.push((body.string()?, body.u8()?));
+            }
+            if filters.is_empty() {
+                bail!("Malformed packet: SUBSCRIBE without topic filters");
+            }
+            Packet::Subscribe { pkid, filters }
+        }
+        (UNSUBSCRIBE, 2) => {
+            let pkid = body.pkid()?;
+            let mut filters = vec![];
+            while !body.is_empty() {
+                filters.push(body.string()?);
+            }
+            if filters.is_empty() {
+                bail!("Malformed packet: UNSUBSCRIBE without topic filters");
+            }
+            Packet::Unsubscribe { pkid, filters }
+        }
+        (PINGREQ, 0) => Packet::PingReq,
+        (DISCONNECT, 0) => Packet::Disconnect,
+        (kind, flags) => bail!(
+            "Unexpected packet type {} with the flags {:#x}",
+            kind,
+            flags
+        ),
+    };
+    Ok(packet)
+}
+
+fn parse_connect(body: &mut Body) -> Result<Packet> {
+    let protocol = body.string()?;
+    let level = body.u8()?;
+    // the rest of the packet differs for the other versions.
+    if protocol != "MQTT" || level != 4 {
+        return Ok(Packet::UnsupportedProtocol(protocol, level));
+    }
+    let flags = body.u8()?;
+    if flags & 0x01 != 0 {
+        bail!("Malformed packet: reserved CONNECT flag is set");
+    }
+    let keep_alive = body.u16()?;
+    let client_id = body.string()?;
+    let will = if flags & 0x04 != 0 {
+        Some(Publish {
+            topic: body.string()?,
+            payload: body.bytes()?.to_vec(),
+            qos: (flags >> 3) & 0x03,
+            retain: flags & 0x20 != 0,
+            pkid: 0,
+        })
+    } else {
+        None
+    };
+    let username = (flags & 0x80 != 0).then(|| body.string()).transpose()?;
+    let password = (flags & 0x40 != 0)
+        .then(|| body.bytes().map(<[u8]>::to_vec))
+        .transpose()?;
+    Ok(Packet::Connect(Connect {
+        client_id,
+        clean_session: flags & 0x02 != 0,
+        keep_alive,
+        username,
+        password,
+        will,
+    }))
+}
+
+fn parse_publish(flags: u8, body: &mut Body) -> Result<Publish> {
+    let qos = (flags >> 1) & 0x03;
+    if qos == 3 {
+        bail!("Malformed packet: QoS 3");
+    }
+    let topic = body.string()?;
+    let pkid = if qos > 0 { body.pkid()? } else { 0 };
+    Ok(Publish {
+        topic,
+        payload: body.rest().to_vec(),
+        qos,
+        retain: flags & 0x01 != 0,
+        pkid,
+    })
+}
+
+/// encodes a packet with the fixed header.
+fn encode(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(body.len() + 5);
+    bytes.push(first);
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+fn connack(code: u8) -> Vec<u8> {
+    encode(CONNACK << 4, &[0, code])
+}
+
+/// `PUBACK`, `PUBREC`, `PUBCOMP` or `UNSUBACK` for the packet identifier.
+fn ack(kind: u8, pkid: u16) -> Vec<u8> {
+    encode(kind << 4, &pkid.to_be_bytes())
+}
+
+fn suback(pkid: u16, codes: &[u8]) -> Vec<u8> {
+    let mut body = pkid.to_be_bytes().to_vec();
+    body.extend_from_slice(codes);
+    encode(SUBACK << 4, &body)
+}
+
+/// QoS 0 `PUBLISH`.
+fn publish(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    body.extend_from_slice(topic.as_bytes());
+    body.extend_from_slice(payload);
+    encode(PUBLISH << 4, &body)
+}
+
+/// returns true if the topic matches the filter, the filters starting with a
+/// wildcard do not match the topics starting with `$`.
+fn filter_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    topic_matches(filter, topic)
+}
+
+/// checks the topic of a publish, it must fit in a native packet and has no wildcards.
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && topic.len() <= usize::from(u8::MAX) && !is_pattern(topic)
+}
+
+/// authenticates the `CONNECT`, returns the `CONNACK` code when it fails.
+async fn authenticate(broker: &Broker, connect: &Connect) -> Result<Option<Session>, u8> {
+    if !broker.auth_required() {
+        return Ok(None);
+    }
+    let Some(password) = &connect.password else {
+        return Err(NOT_AUTHORIZED);
+    };
+    let password = String::from_utf8(password.clone()).map_err(|_| BAD_CREDENTIALS)?;
+    let request = match connect.username.as_deref() {
+        Some(username) if !username.is_empty() => ConnectRequest {
+            username: Some(username.to_string()),
+            password: Some(password),
+            ..Default::default()
+        },
+        _ => ConnectRequest {
+            token: Some(password),
+            ..Default::default()
+        },
+    };
+    broker.authenticate_request(request).await.map_err(|e| {
+        warn!(
+            "Authentication failed for the MQTT client {}: {}",
+            connect.client_id, e
+        );
+        BAD_CREDENTIALS
+    })
+}
+
+/// starts the MQTT listener on the given address.
+pub(crate) async fn start(addr: &str, broker: Arc<Broker>) -> Result<SocketAddr> {
+    let listener = super::bind_tcp(addr, Some("mqtt")).await?;
+    let local_addr = listener.local_addr()?;
+    info!("MQTT listener on: {}", local_addr);
+    let client_ids = ClientIds::default();
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = broker.stopped() => return,
+            };
+            match accepted {
+                Ok((socket, addr)) => {
+                    info!("Accepted MQTT connection from {:?}", addr);
+                    metrics().connection("mqtt");
+                    let client_id = uuid::Uuid::new_v4().to_string();
+                    let address = format!("mqtt:{addr}");
+                    let span = connection_span(&client_id, &address);
+                    tokio::spawn(
+                        handle_client(
+                            socket,
+                            broker.clone(),
+                            client_ids.clone(),
+                            client_id,
+                            address,
+                        )
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    error!("MQTT listener stopped: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// connected MQTT client.
+struct MqttClient<S> {
+    broker: Arc<Broker>,
+    socket: WriteHalf<S>,
+    client_id: String,
+    identity: Option<String>,
+    claims: Option<Claims>,
+    /// channel for the messages on the subscribed topics
+    channel: broadcast::Sender<Envelope>,
+    /// subscribed topic filters
+    subscriptions: HashSet<String>,
+    /// QoS 2 publishes received but not released yet
+    unreleased: HashSet<u16>,
+}
+
+impl<S> MqttClient<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(write_all(&mut self.socket, bytes).await?)
+    }
+
+    fn msg(&self, pkt_type: PktType, topic: String, message: Vec<u8>) -> Envelope {
+        let mut msg = Envelope::new(Msg::new(pkt_type, topic, Some(message)));
+        msg.client_id(self.client_id.clone());
+        if let Some(identity) = &self.identity {
+            msg.identity(identity.clone());
+        }
+        msg
+    }
+
+    /// handles a packet of the client, returns the time to pause the reads
+    /// when the client is throttled.
+    async fn handle(&mut self, packet: Packet) -> Result<Option<Duration>> {
+        match packet {
+            Packet::Publish(publish) => return self.publish(publish).await,
+            Packet::PubAck(pkid) => debug!("PUBACK {} from the client {}", pkid, self.client_id),
+            Packet::PubRel(pkid) => {
+                self.unreleased.remove(&pkid);
+                self.write(&ack(PUBCOMP, pkid)).await?;
+            }
+            Packet::Subscribe { pkid, filters } => self.subscribe(pkid, filters).await?,
+            Packet::Unsubscribe { pkid, filters } => self.unsubscribe(pkid, filters).await?,
+            Packet::PingReq => self.write(&encode(PINGRESP << 4, &[])).await?,
+            Packet::Connect(_) | Packet::UnsupportedProtocol(..) => {
+                bail!("Unexpected CONNECT on a connected session")
+            }
+            // handled by the caller.
+            Packet::Disconnect => {}
+        }
+        Ok(None)
+    }
+
+    async fn publish(&mut self, publish: Publish) -> Result<Option<Duration>> {
+        if !valid_topic(&publish.topic) {
+            bail!("Invalid topic for a publish: {}", publish.topic);
+        }
+        if publish.payload.len() > usize::from(u16::MAX) {
+            bail!("Message too large: {} bytes", publish.payload.len());
+        }
+        if publish.retain {
+            debug!(
+                "The retained messages are not stored, topic: {}",
+                publish.topic
+            );
+        }
+        // a resent QoS 2 publish is acknowledged again, but not forwarded.
+        let duplicate = publish.qos == 2 && !self.unreleased.insert(publish.pkid);
+        let throttle = if duplicate {
+            None
+        } else {
+            self.forward(&publish)
+        };
+        match publish.qos {
+            1 => self.write(&ack(PUBACK, publish.pkid)).await?,
+            2 => self.write(&ack(PUBREC, publish.pkid)).await?,
+            _ => {}
+        }
+        Ok(throttle)
+    }
+
+    /// sends the publish to the topic manager, returns the throttling delay.
+    fn forward(&self, publish: &Publish) -> Option<Duration> {
+        let mut msg = self.msg(
+            PktType::PUBLISH,
+            publish.topic.clone(),
+            publish.payload.clone(),
+        );
+        msg.received_at = Some(std::time::Instant::now());
+        if let Err(e) = self.broker.authorize(&msg, self.claims.as_ref()) {
+            warn!(
+                "Dropped the publish of the MQTT client {}: {}",
+                self.client_id, e
+            );
+            return None;
+        }
+        let throttle = match self.broker.rate_limit(&msg) {
+            Decision::Allow => None,
+            Decision::Throttle(wait) => Some(wait),
+            Decision::Reject => return None,
+        };
+        let backpressure = match self.broker.reserve_memory(&mut msg) {
+            Ok(backpressure) => backpressure,
+            Err(e) => {
+                warn!(
+                    "Dropped the publish of the MQTT client {}: {}",
+                    self.client_id, e
+                );
+                return None;
+            }
+        };
+        info!("Topic: {}, mqtt client: {}", publish.topic, self.client_id);
+        if let Err(e) = self.broker.tx.send(msg) {
+            error!("Error while sending message: {:?}", e);
+        }
+        throttle.max(backpressure)
+    }
+
+    async fn subscribe(&mut self, pkid: u16, filters: Vec<(String, u8)>) -> Result<()> {
+        let mut codes = Vec::with_capacity(filters.len());
+        for (filter, qos) in filters {
+            if qos > 2 {
+                bail!("Malformed packet: QoS {} for the filter {}", qos, filter);
+            }
+            if !is_valid_pattern(&filter) {
+                warn!(
+                    "Invalid topic filter from the client {}: {}",
+                    self.client_id, filter
+                );
+                codes.push(SUBSCRIBE_FAILURE);
+                continue;
+            }
+            let mut msg = self.msg(PktType::SUBSCRIBE, filter.clone(), vec![]);
+            if self.broker.authorize(&msg, self.claims.as_ref()).is_err() {
+                codes.push(SUBSCRIBE_FAILURE);
+                continue;
+            }
+            msg.channel(self.channel.clone());
+            if let Err(e) = self.broker.tx.send(msg) {
+                error!("Error while sending message: {:?}", e);
+                codes.push(SUBSCRIBE_FAILURE);
+                continue;
+            }
+            // the deliveries are not retried, every subscription is granted QoS 0.
+            info!("Topic: {}, mqtt client: {}", filter, self.client_id);
+            self.subscriptions.insert(filter);
+            codes.push(0);
+        }
+        self.write(&suback(pkid, &codes)).await
+    }
+
+    async fn unsubscribe(&mut self, pkid: u16, filters: Vec<String>) -> Result<()> {
+        for filter in filters {
+            if self.subscriptions.remove(&filter) {
+                let msg = self.msg(PktType::UNSUBSCRIBE, filter, vec![]);
+                let _ = self.broker.tx.send(msg);
+            }
+        }
+        self.write(&ack(UNSUBACK, pkid)).await
+    }
+
+    /// removes the subscriptions of the disconnected client.
+    fn unsubscribe_all(&mut self) {
+        for filter in self.subscriptions.drain() {
+            let mut msg = Envelope::new(Msg::new(PktType::UNSUBSCRIBE, filter, None));
+            msg.client_id(self.client_id.clone());
+            let _ = self.broker.tx.send(msg);
+        }
+    }
+
+    /// writes a message on the subscribed topics.
+    async fn deliver(&mut self, msg: Envelope) -> Result<()> {
+        if msg.header.pkt_type != PktType::PUBLISH
+            || !self
+                .subscriptions
+                .iter()
+                .any(|filter| filter_matches(filter, &msg.topic))
+        {
+            return Ok(());
+        }
+        self.write(&publish(&msg.topic, &msg.message)).await?;
+        metrics().delivered(&msg);
+        Ok(())
+    }
+}
+
+/// Handles an MQTT connection, the will message is published
+/// when the connection ends without a `DISCONNECT`.
+async fn handle_client<S>(
+    socket: S,
+    broker: Arc<Broker>,
+    client_ids: ClientIds,
+    client_id: String,
+    address: String,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut reader, mut socket) = tokio::io::split(socket);
+    let connect = match tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader)).await {
+        Ok(Ok(Packet::Connect(connect))) => connect,
+        Ok(Ok(Packet::UnsupportedProtocol(protocol, level))) => {
+            warn!(
+                "Unsupported protocol from {}: {} level {}",
+                address, protocol, level
+            );
+            let _ = write_all(&mut socket, &connack(UNACCEPTABLE_PROTOCOL)).await;
+            return;
+        }
+        Ok(Ok(packet)) => {
+            warn!("Expected CONNECT from {}, received: {:?}", address, packet);
+            return;
+        }
+        Ok(Err(e)) => {
+            warn!("Could not read the CONNECT from {}: {}", address, e);
+            return;
+        }
+        Err(_) => {
+            warn!("No CONNECT from {} in {:?}", address, CONNECT_TIMEOUT);
+            return;
+        }
+    };
+    if connect.client_id.is_empty() && !connect.clean_session {
+        let _ = write_all(&mut socket, &connack(IDENTIFIER_REJECTED)).await;
+        return;
+    }
+    if connect
+        .will
+        .as_ref()
+        .is_some_and(|will| !valid_topic(&will.topic))
+    {
+        warn!("Invalid will topic from {}", address);
+        return;
+    }
+    let session = match authenticate(&broker, &connect).await {
+        Ok(session) => session,
+        Err(code) => {
+            let _ = write_all(&mut socket, &connack(code)).await;
+            return;
+        }
+    };
+    let identity = session.as_ref().map(|s| s.identity.clone());
+    // the clients without an identifier never take over.
+    let taken_over = if connect.client_id.is_empty() {
+        None
+    } else {
+        match take_client_id(&client_ids, &connect.client_id, &client_id, &identity) {
+            Ok(taken_over) => taken_over,
+            Err(e) => {
+                warn!("Rejected the MQTT client from {}: {}", address, e);
+                let _ = write_all(&mut socket, &connack(IDENTIFIER_REJECTED)).await;
+                return;
+            }
+        }
+    };
+    if let Err(e) = write_all(&mut socket, &connack(ACCEPTED)).await {
+        error!("Could not write the data to the socket: {:?}", e);
+        release_client_id(&client_ids, &connect.client_id, &client_id);
+        return;
+    }
+
+    info!(
+        "MQTT client {} connected from {} as {} ({})",
+        connect.client_id,
+        address,
+        client_id,
+        identity.as_deref().unwrap_or("anonymous")
+    );
+    if let Some(identity) = &identity {
+        record_identity(&Span::current(), identity);
+    }
+    let Ok(kick) = broker.register(&client_id, address, identity.clone()) else {
+        release_client_id(&client_ids, &connect.client_id, &client_id);
+        return;
+    };
+    metrics().clients.inc();
+    if let Some(taken_over) = taken_over {
+        info!(
+            "MQTT client {} took over the connection {}",
+            connect.client_id, taken_over
+        );
+        broker.kick(&taken_over);
+    }
+    let stopping = broker.clone();
+    let claims = session.and_then(|s| s.claims);
+    let deadline = claims.as_ref().map(|c| Instant::now() + c.expires_in());
+    // the client is disconnected after one and a half keep alive periods without a packet.
+    let keep_alive = (connect.keep_alive > 0)
+        .then(|| Duration::from_millis(u64::from(connect.keep_alive) * 1500));
+    let mut will = connect.will;
+
+    // the packets are read in a separate task, a partially read packet
+    // must not be dropped when the client channel receives a message.
+    let (packet_tx, mut packet_rx) = tokio::sync::mpsc::channel(1);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let packet = read_packet(&mut reader).await;
+            let disconnected = packet.is_err();
+            if packet_tx.send(packet).await.is_err() || disconnected {
+                return;
+            }
+        }
+    });
+
+    let (channel, mut client_rx) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+    let mut client = MqttClient {
+        broker: broker.clone(),
+        socket,
+        client_id: client_id.clone(),
+        identity,
+        claims,
+        channel,
+        subscriptions: HashSet::new(),
+        unreleased: HashSet::new(),
+    };
+    let mut expires = keep_alive.map(|keep_alive| Instant::now() + keep_alive);
+    // reads are paused until then when the client is throttled.
+    let mut resume = None;
+    loop {
+        tokio::select! {
+            _ = wait_until(resume), if resume.is_some() => {
+                resume = None;
+                expires = keep_alive.map(|keep_alive| Instant::now() + keep_alive);
+            },
+            Some(packet) = packet_rx.recv(), if resume.is_none() => {
+                expires = keep_alive.map(|keep_alive| Instant::now() + keep_alive);
+                match packet {
+                    Ok(Packet::Disconnect) => {
+                        info!("MQTT client {} disconnected", client_id);
+                        will = None;
+                        break;
+                    }
+                    Ok(packet) => match client.handle(packet).await {
+                        Ok(throttle) => {
+                            if let Some(wait) = throttle {
+                                debug!("Throttling the client {} for {:?}", client_id, wait);
+                                resume = Some(Instant::now() + wait);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Closing the MQTT client {}: {}", client_id, e);
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("MQTT client disconnected: {} ({})", client_id, e);
+                        break;
+                    }
+                }
+            },
+            _ = wait_until(expires) => {
+                warn!("Keep alive expired for the MQTT client: {}", client_id);
+                break;
+            },
+            _ = kick.notified() => {
+                warn!("Client {} disconnected by the admin", client_id);
+                break;
+            },
+            _ = stopping.stopped() => {
+                info!("Client {} disconnected, the broker is shutting down", client_id);
+                break;
+            },
+            _ = wait_until(deadline) => {
+                warn!("Token expired for the client: {}", client_id);
+                break;
+            },
+            chan_msg = client_rx.recv() => {
+                match chan_msg {
+                    Ok(m) => {
+                        let span = message_span(&m);
+                        if let Err(e) = client.deliver(m).instrument(span).await {
+                            error!("Failed to write data to socket: {:?}", e);
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Client {} missed {} messages", client_id, n);
+                        metrics().dropped("client_lagged", n);
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    reader_task.abort();
+    if let Some(will) = will {
+        info!("Publishing the will of the MQTT client {}", client_id);
+        client.forward(&will);
+    }
+    client.unsubscribe_all();
+    release_client_id(&client_ids, &connect.client_id, &client_id);
+    broker.unregister(&client_id);
+    metrics().clients.dec();
+    let _ = client.socket.shutdown().await;
+}
@@ -0,0 +1,865 @@
+//! NATS text protocol listener.
+//!
+//! The NATS clients (the `nats` cli and the client libraries) share the topics
+//! with the native clients, the subject tokens are the topic levels:
+//! `sensors.kitchen.temp` is the topic `sensors/kitchen/temp`. The wildcards `*`
+//! (a token) and `>` (the remaining tokens) are subscribed as `+` and `#`, the
+//! subjects can not contain `/`, `+` or `#`.
+//!
+//! - `INFO`, `CONNECT`, `PUB`, `SUB`, `UNSUB`, `PING`, `PONG` and `MSG` are supported,
+//!   the headers (`HPUB`/`HMSG`) are not.
+//! - The queue groups share one broker subscription per subject and queue,
+//!   each message goes to one of the members in turn.
+//! - The reply subject is only delivered to the NATS subscribers.
+//!
+//! The `user`/`pass` of `CONNECT` are checked against the credentials file and the
+//! `auth_token` is verified as a token. The acl, the token claims and the rate limits
+//! apply as for the native clients, over the rate limit the messages are dropped.
+use super::broker::Broker;
+use super::client_handler::{wait_until, write_all};
+use crate::acl::is_valid_pattern;
+use crate::auth::ConnectRequest;
+use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::rate_limit::Decision;
+use crate::spans::{connection_span, message_span, record_identity};
+use crate::token::Claims;
+use crate::topics::Envelope;
+use crate::PktType;
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    WriteHalf,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn, Instrument, Span};
+
+/// messages buffered for a slow NATS subscriber.
+const SUBSCRIPTION_CAPACITY: usize = 64;
+/// longest accepted protocol line.
+const MAX_LINE: usize = 4096;
+/// largest message, the largest message of a native packet.
+const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// options of the `CONNECT` operation.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConnectOptions {
+    /// `+OK` after every operation
+    verbose: bool,
+    user: Option<String>,
+    pass: Option<String>,
+    auth_token: Option<String>,
+    name: Option<String>,
+    /// the own messages are delivered, `true` when not set
+    echo: Option<bool>,
+}
+
+/// operations sent by the NATS clients.
+#[derive(Debug)]
+enum Op {
+    Connect(ConnectOptions),
+    Pub {
+        subject: String,
+        reply_to: Option<String>,
+        payload: Vec<u8>,
+    },
+    Sub {
+        subject: String,
+        queue: Option<String>,
+        sid: String,
+    },
+    Unsub {
+        sid: String,
+        max: Option<u64>,
+    },
+    Ping,
+    Pong,
+}
+
+/// reads a line without the line ending.
+async fn read_line<R>(reader: &mut R) -> Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+    }
+    if line.last() != Some(&b'\n') {
+        bail!("Maximum Control Line Exceeded");
+    }
+    Ok(String::from_utf8(line)?.trim_end().to_string())
+}
+
+/// reads an operation of the client.
+async fn read_op<R>(reader: &mut R) -> Result<Op>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let line = read_line(reader).await?;
+        let line = line.trim_start();
+        let (op, args) = line.split_once([' ', '\t']).unwrap_or((line, ""));
+        let fields = args.split_whitespace().collect::<Vec<_>>();
+        let op = match (op.to_ascii_uppercase().as_str(), fields.as_slice()) {
+            ("", _) => continue,
+            ("CONNECT", _) => match serde_json::from_str(args) {
+                Ok(options) => Op::Connect(options),
+                Err(_) => bail!("Invalid CONNECT options"),
+            },
+            ("PUB", [subject, size] | [subject, _, size]) => {
+                let Ok(size) = size.parse::<usize>() else {
+                    bail!("Invalid Message Size");
+                };
+                if size > MAX_PAYLOAD {
+                    bail!("Maximum Payload Violation");
+                }
+                let mut payload = vec![0; size + 2];
+                reader.read_exact(&mut payload).await?;
+                if !payload.ends_with(b"\r\n") {
+                    bail!("Invalid Message Size");
+                }
+                payload.truncate(size);
+                Op::Pub {
+                    subject: subject.to_string(),
+                    reply_to: (fields.len() == 3).then(|| fields[1].to_string()),
+                    payload,
+                }
+            }
+            ("SUB", [subject, sid]) => Op::Sub {
+                subject: subject.to_string(),
+                queue: None,
+                sid: sid.to_string(),
+            },
+            ("SUB", [subject, queue, sid]) => Op::Sub {
+                subject: subject.to_string(),
+                queue: Some(queue.to_string()),
+                sid: sid.to_string(),
+            },
+            ("UNSUB", [sid]) => Op::Unsub {
+                sid: sid.to_string(),
+                max: None,
+            },
+            ("UNSUB", [sid, max]) => match max.parse() {
+                Ok(max) => Op::Unsub {
+                    sid: sid.to_string(),
+                    max: Some(max),
+                },
+                Err(_) => bail!("Invalid UNSUB maximum"),
+            },
+            ("PING", []) => Op::Ping,
+            ("PONG", []) => Op::Pong,
+            _ => bail!("Unknown Protocol Operation"),
+        };
+        return Ok(op);
+    }
+}
+
+/// checks a subject, the `*` and `>` wildcards are only allowed in the subscriptions.
+fn valid_subject(subject: &str, wildcards: bool) -> bool {
+    let tokens = subject.split('.').collect::<Vec<_>>();
+    let last = tokens.len() - 1;
+    tokens.iter().enumerate().all(|(i, token)| match *token {
+        "" => false,
+        "*" => wildcards,
+        ">" => wildcards && i == last,
+        token => !token.contains(['/', '+', '#']) && !token.contains(char::is_whitespace),
+    }) && is_valid_pattern(&topic(subject))
+}
+
+/// returns the topic for the subject, the wildcards are translated to `+` and `#`.
+fn topic(subject: &str) -> String {
+    subject
+        .split('.')
+        .map(|token| match token {
+            "*" => "+",
+            ">" => "#",
+            token => token,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// returns true if the subject matches the subscription, `>` needs at least one token.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match token {
+            ">" => return tokens.next().is_some(),
+            "*" => {
+                if tokens.next().is_none() {
+                    return false;
+                }
+            }
+            token => {
+                if tokens.next() != Some(token) {
+                    return false;
+                }
+            }
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// member of a queue group, a subscription of a connection.
+struct Member {
+    client_id: String,
+    sid: String,
+    /// channel of the connection for the queue group messages
+    tx: mpsc::Sender<(String, Envelope)>,
+}
+
+struct QueueGroup {
+    /// client id of the group subscription in the topic manager
+    id: String,
+    members: Vec<Member>,
+    /// next member to receive a message
+    next: usize,
+}
+
+/// queue groups of the listener, by the topic and the queue name.
+#[derive(Default)]
+struct QueueGroups {
+    groups: Mutex<HashMap<(String, String), QueueGroup>>,
+}
+
+impl QueueGroups {
+    /// adds the member, the first member subscribes the group to the topic.
+    fn join(self: &Arc<Self>, broker: &Broker, topic: &str, queue: &str, member: Member) {
+        let Ok(mut groups) = self.groups.lock() else {
+            return;
+        };
+        let key = (topic.to_string(), queue.to_string());
+        if let Some(group) = groups.get_mut(&key) {
+            group.members.push(member);
+            return;
+        }
+        let id = format!("nats-queue:{}", uuid::Uuid::new_v4());
+        groups.insert(
+            key.clone(),
+            QueueGroup {
+                id: id.clone(),
+                members: vec![member],
+                next: 0,
+            },
+        );
+        let (tx, rx) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+        let mut msg = Envelope::new(Msg::new(PktType::SUBSCRIBE, topic.to_string(), None));
+        msg.client_id(id.clone());
+        msg.channel(tx);
+        if let Err(e) = broker.tx.send(msg) {
+            error!("Error while sending message: {:?}", e);
+        }
+        tokio::spawn(self.clone().dispatch(key, id, rx));
+    }
+
+    /// removes the member, the group is unsubscribed when the last member leaves.
+    fn leave(&self, broker: &Broker, topic: &str, queue: &str, client_id: &str, sid: &str) {
+        let Ok(mut groups) = self.groups.lock() else {
+            return;
+        };
+        let key = (topic.to_string(), queue.to_string());
+        let Some(group) = groups.get_mut(&key) else {
+            return;
+        };
+        group
+            .members
+            .retain(|m| !(m.client_id == client_id && m.sid == sid));
+        if group.members.is_empty() {
+            if let Some(group) = groups.remove(&key) {
+                let mut msg =
+                    Envelope::new(Msg::new(PktType::UNSUBSCRIBE, topic.to_string(), None));
+                msg.client_id(group.id);
+                let _ = broker.tx.send(msg);
+            }
+        }
+    }
+
+    /// sends the messages of the group to the members in turn,
+    /// the members that can not take more messages are skipped.
+    async fn dispatch(
+        self: Arc<Self>,
+        key: (String, String),
+        id: String,
+        mut rx: broadcast::Receiver<Envelope>,
+    ) {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => {
+                    let Ok(mut groups) = self.groups.lock() else {
+                        return;
+                    };
+                    let Some(group) = groups.get_mut(&key).filter(|g| g.id == id) else {
+                        return;
+                    };
+                    let members = group.members.len();
+                    let delivered = (0..members).map(|i| (group.next + i) % members).find(|&i| {
+                        let member = &group.members[i];
+                        member
+                            .tx
+                            .try_send((member.sid.clone(), msg.clone()))
+                            .is_ok()
+                    });
+                    match delivered {
+                        Some(i) => group.next = (i + 1) % members,
+                        None => {
+                            warn!("No member of the queue group {} took the message", key.1);
+                            metrics().dropped("client_lagged", 1);
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Queue group {} missed {} messages", key.1, n);
+                    metrics().dropped("client_lagged", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// starts the NATS listener on the given address.
+pub(crate) async fn start(addr: &str, broker: Arc<Broker>) -> Result<SocketAddr> {
+    let listener = super::bind_tcp(addr, Some("nats")).await?;
+    let local_addr = listener.local_addr()?;
+    info!("NATS listener on: {}", local_addr);
+    let server_info = json!({
+        "server_id": uuid::Uuid::new_v4().to_string(),
+        "server_name": "simple-pub-sub",
+        "version": env!("CARGO_PKG_VERSION"),
+        "proto": 1,
+        "host": local_addr.ip().to_string(),
+        "port": local_addr.port(),
+        "headers": false,
+        "max_payload": MAX_PAYLOAD,
+        "auth_required": broker.auth_required(),
+    });
+    let server_info: Arc<str> = format!("INFO {server_info}\r\n").into();
+    let queues = Arc::new(QueueGroups::default());
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = broker.stopped() => return,
+            };
+            match accepted {
+                Ok((socket, addr)) => {
+                    info!("Accepted NATS connection from {:?}", addr);
+                    metrics().connection("nats");
+                    let client_id = uuid::Uuid::new_v4().to_string();
+                    let address = format!("nats:{addr}");
+                    let span = connection_span(&client_id, &address);
+                    tokio::spawn(
+                        handle_client(
+                            socket,
+                            broker.clone(),
+                            queues.clone(),
+                            server_info.clone(),
+                            client_id,
+                            address,
+                        )
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    error!("NATS listener stopped: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// what happens after an operation.
+enum Flow {
+    Continue,
+    /// reads are paused for the throttled client
+    Throttle(Duration),
+    Close,
+}
+
+struct Subscription {
+    subject: String,
+    topic: String,
+    queue: Option<String>,
+    /// messages delivered to the subscription
+    delivered: u64,
+    /// the subscription ends after this many messages
+    max: Option<u64>,
+}
+
+/// connected NATS client.
+struct NatsClient<S> {
+    broker: Arc<Broker>,
+    queues: Arc<QueueGroups>,
+    socket: WriteHalf<S>,
+    client_id: String,
+    identity: Option<String>,
+    claims: Option<Claims>,
+    authenticated: bool,
+    /// expiry of the token
+    deadline: Option<Instant>,
+    verbose: bool,
+    echo: bool,
+    /// channel for the messages on the subscribed topics
+    channel: broadcast::Sender<Envelope>,
+    /// channel for the queue group messages, with the sid
+    queue_tx: mpsc::Sender<(String, Envelope)>,
+    /// subscriptions by sid
+    subscriptions: HashMap<String, Subscription>,
+}
+
+impl<S> NatsClient<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(write_all(&mut self.socket, bytes).await?)
+    }
+
+    async fn error(&mut self, reason: &str) -> Result<()> {
+        self.write(format!("-ERR '{reason}'\r\n").as_bytes()).await
+    }
+
+    async fn ok(&mut self) -> Result<()> {
+        if self.verbose {
+            self.write(b"+OK\r\n").await?;
+        }
+        Ok(())
+    }
+
+    fn msg(&self, pkt_type: PktType, topic: String, message: Vec<u8>) -> Envelope {
+        let mut msg = Envelope::new(Msg::new(pkt_type, topic, Some(message)));
+        msg.client_id(self.client_id.clone());
+        if let Some(identity) = &self.identity {
+            msg.identity(identity.clone());
+        }
+        msg
+    }
+
+    /// handles an operation of the client.
+    async fn handle(&mut self, op: Op) -> Result<Flow> {
+        if let Op::Connect(options) = op {
+            return self.connect(options).await;
+        }
+        if !self.authenticated {
+            self.error("Authorization Violation").await?;
+            return Ok(Flow::Close);
+        }
+        match op {
+            Op::Pub {
+                subject,
+                reply_to,
+                payload,
+            } => return self.publish(subject, reply_to, payload).await,
+            Op::Sub {
+                subject,
+                queue,
+                sid,
+            } => self.subscribe(subject, queue, sid).await?,
+            Op::Unsub { sid, max } => {
+                match (self.subscriptions.get_mut(&sid), max) {
+                    (Some(subscription), Some(max)) if subscription.delivered < max => {
+                        subscription.max = Some(max);
+                    }
+                    _ => self.unsubscribe(&sid),
+                }
+                self.ok().await?;
+            }
+            Op::Ping => self.write(b"PONG\r\n").await?,
+            Op::Pong | Op::Connect(_) => {}
+        }
+        Ok(Flow::Continue)
+    }
+
+    async fn connect(&mut self, options: ConnectOptions) -> Result<Flow> {
+        self.verbose = options.verbose;
+        self.echo = options.echo.unwrap_or(true);
+        if let Some(name) = &options.name {
+            info!("NATS client {} is: {}", self.client_id, name);
+        }
+        if self.broker.auth_required() {
+            let request = match options {
+                ConnectOptions {
+                    auth_token: Some(token),
+                    ..
+                } => ConnectRequest {
+                    token: Some(token),
+                    ..Default::default()
+                },
+                ConnectOptions { user, pass, .. } => ConnectRequest {
+                    username: user,
+                    password: pass,
+                    ..Default::default()
+                },
+            };
+            match self.broker.authenticate_request(request).await {
+                Ok(Some(session)) => {
+                    info!(
+                        "Client {} authenticated as: {}",
+                        self.client_id, session.identity
+                    );
+                    record_identity(&Span::current(), &session.identity);
+                    self.broker
+                        .set_identity(&self.client_id, session.identity.clone());
+                    self.identity = Some(session.identity);
+                    self.deadline = session
+                        .claims
+                        .as_ref()
+                        .map(|c| Instant::now() + c.expires_in());
+                    self.claims = session.claims;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(
+                        "Authentication failed for the client {}: {}",
+                        self.client_id, e
+                    );
+                    self.error("Authorization Violation").await?;
+                    return Ok(Flow::Close);
+                }
+            }
+        }
+        self.authenticated = true;
+        self.ok().await?;
+        Ok(Flow::Continue)
+    }
+
+    async fn publish(
+        &mut self,
+        subject: String,
+        reply_to: Option<String>,
+        payload: Vec<u8>,
+    ) -> Result<Flow> {
+        if !valid_subject(&subject, false) {
+            self.error("Invalid Publish Subject").await?;
+            return Ok(Flow::Continue);
+        }
+        let mut msg = self.msg(PktType::PUBLISH, topic(&subject), payload);
+        msg.reply_to = reply_to;
+        msg.received_at = Some(std::time::Instant::now());
+        if let Err(e) = self.broker.authorize(&msg, self.claims.as_ref()) {
+            debug!("Publish denied for the client {}: {}", self.client_id, e);
+            self.error(&format!(
+                "Permissions Violation for Publish to \"{subject}\""
+            ))
+            .await?;
+            return Ok(Flow::Continue);
+        }
+        let flow = match self.broker.rate_limit(&msg) {
+            Decision::Allow => Flow::Continue,
+            Decision::Throttle(wait) => Flow::Throttle(wait),
+            Decision::Reject => return Ok(Flow::Continue),
+        };
+        let flow = match self.broker.reserve_memory(&mut msg) {
+            Ok(Some(wait)) => match flow {
+                Flow::Throttle(throttle) => Flow::Throttle(throttle.max(wait)),
+                _ => Flow::Throttle(wait),
+            },
+            Ok(None) => flow,
+            Err(e) => {
+                self.error(&e.to_string()).await?;
+                return Ok(Flow::Continue);
+            }
+        };
+        info!("Topic: {}, nats client: {}", msg.topic, self.client_id);
+        if let Err(e) = self.broker.tx.send(msg) {
+            error!("Error while sending message: {:?}", e);
+        }
+        self.ok().await?;
+        Ok(flow)
+    }
+
+    async fn subscribe(
+        &mut self,
+        subject: String,
+        queue: Option<String>,
+        sid: String,
+    ) -> Result<()> {
+        if !valid_subject(&subject, true) {
+            return self.error("Invalid Subject").await;
+        }
+        let topic = topic(&subject);
+        let msg = self.msg(PktType::SUBSCRIBE, topic.clone(), vec![]);
+        // the wildcards are authorized as patterns, they must not include a denied topic.
+        if let Err(e) = self.broker.authorize(&msg, self.claims.as_ref()) {
+            debug!("Subscribe denied for the client {}: {}", self.client_id, e);
+            return self
+                .error(&format!(
+                    "Permissions Violation for Subscription to \"{subject}\""
+                ))
+                .await;
+        }
+        // a sid is replaced when it is used again.
+        self.unsubscribe(&sid);
+        info!("Topic: {}, nats client: {}", topic, self.client_id);
+        match &queue {
+            Some(queue) => self.queues.join(
+                &self.broker,
+                &topic,
+                queue,
+                Member {
+                    client_id: self.client_id.clone(),
+                    sid: sid.clone(),
+                    tx: self.queue_tx.clone(),
+                },
+            ),
+            None if !self.subscribed(&topic) => {
+                let mut msg = msg;
+                msg.channel(self.channel.clone());
+                if let Err(e) = self.broker.tx.send(msg) {
+                    error!("Error while sending message: {:?}", e);
+                }
+            }
+            None => {}
+        }
+        self.subscriptions.insert(
+            sid,
+            Subscription {
+                subject,
+                topic,
+                queue,
+                delivered: 0,
+                max: None,
+            },
+        );
+        self.ok().await
+    }
+
+    /// returns true if the connection is subscribed to the topic, without a queue group.
+    fn subscribed(&self, topic: &str) -> bool {
+        self.subscriptions
+            .values()
+            .any(|s| s.queue.is_none() && s.topic == topic)
+    }
+
+    fn unsubscribe(&mut self, sid: &str) {
+        let Some(subscription) = self.subscriptions.remove(sid) else {
+            return;
+        };
+        match &subscription.queue {
+            Some(queue) => {
+                self.queues.leave(
+                    &self.broker,
+                    &subscription.topic,
+                    queue,
+                    &self.client_id,
+                    sid,
+                );
+            }
+            None if !self.subscribed(&subscription.topic) => {
+                let msg = self.msg(PktType::UNSUBSCRIBE, subscription.topic, vec![]);
+                let _ = self.broker.tx.send(msg);
+            }
+            None => {}
+        }
+    }
+
+    /// removes the subscriptions of the disconnected client.
+    fn unsubscribe_all(&mut self) {
+        let sids = self.subscriptions.keys().cloned().collect::<Vec<_>>();
+        for sid in sids {
+            self.unsubscribe(&sid);
+        }
+    }
+
+    /// returns the `MSG` for the subscription, counts the delivered messages.
+    fn msg_op(&mut self, sid: &str, subject: &str, msg: &Envelope) -> Option<Vec<u8>> {
+        let subscription = self.subscriptions.get_mut(sid)?;
+        subscription.delivered += 1;
+        let mut bytes = match &msg.reply_to {
+            Some(reply_to) => format!("MSG {subject} {sid} {reply_to} {}\r\n", msg.message.len()),
+            None => format!("MSG {subject} {sid} {}\r\n", msg.message.len()),
+        }
+        .into_bytes();
+        bytes.extend_from_slice(&msg.message);
+        bytes.extend_from_slice(b"\r\n");
+        Some(bytes)
+    }
+
+    /// writes the message to the matching subscriptions, or to the given
+    /// queue group subscription.
+    async fn deliver(&mut self, msg: Envelope, queue_sid: Option<String>) -> Result<()> {
+        if msg.header.pkt_type != PktType::PUBLISH {
+            return Ok(());
+        }
+        if !self.echo && msg.client_id.as_deref() == Some(&self.client_id) {
+            return Ok(());
+        }
+        let subject = msg.topic.replace('/', ".");
+        if subject.contains(char::is_whitespace) {
+            debug!("Not delivering the topic {} as a NATS subject", msg.topic);
+            return Ok(());
+        }
+        let sids = match queue_sid {
+            Some(sid) => vec![sid],
+            None => self
+                .subscriptions
+                .iter()
+                .filter(|(_, s)| s.queue.is_none() && subject_matches(&s.subject, &subject))
+                .map(|(sid, _)| sid.clone())
+                .collect(),
+        };
+        let mut bytes = Vec::new();
+        for sid in sids {
+            if let Some(op) = self.msg_op(&sid, &subject, &msg) {
+                bytes.extend(op);
+            }
+            // the auto unsubscribe after the maximum messages.
+            if self
+                .subscriptions
+                .get(&sid)
+                .is_some_and(|s| s.max.is_some_and(|max| s.delivered >= max))
+            {
+                self.unsubscribe(&sid);
+            }
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.write(&bytes).await?;
+        metrics().delivered(&msg);
+        Ok(())
+    }
+}
+
+/// Handles a NATS connection.
+async fn handle_client<S>(
+    socket: S,
+    broker: Arc<Broker>,
+    queues: Arc<QueueGroups>,
+    server_info: Arc<str>,
+    client_id: String,
+    address: String,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (reader, mut socket) = tokio::io::split(socket);
+    if let Err(e) = write_all(&mut socket, server_info.as_bytes()).await {
+        error!("Could not write the data to the socket: {:?}", e);
+        return;
+    }
+    info!("NATS client {} connected from {}", client_id, address);
+
+    // the operations are read in a separate task, a partially read operation
+    // must not be dropped when the client channel receives a message.
+    let (op_tx, mut op_rx) = mpsc::channel(1);
+    let reader_task = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let op = read_op(&mut reader).await;
+            let disconnected = op.is_err();
+            if op_tx.send(op).await.is_err() || disconnected {
+                return;
+            }
+        }
+    });
+
+    let Ok(kick) = broker.register(&client_id, address, None) else {
+        return;
+    };
+    metrics().clients.inc();
+    let stopping = broker.clone();
+    let (channel, mut client_rx) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+    let (queue_tx, mut queue_rx) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+    let mut client = NatsClient {
+        broker: broker.clone(),
+        queues,
+        socket,
+        client_id: client_id.clone(),
+        identity: None,
+        claims: None,
+        authenticated: !broker.auth_required(),
+        deadline: None,
+        verbose: false,
+        echo: true,
+        channel,
+        queue_tx,
+        subscriptions: HashMap::new(),
+    };
+    // reads are paused until then when the client is throttled.
+    let mut resume = None;
+    loop {
+        tokio::select! {
+            _ = wait_until(resume), if resume.is_some() => {
+                resume = None;
+            },
+            Some(op) = op_rx.recv(), if resume.is_none() => {
+                match op {
+                    Ok(op) => match client.handle(op).await {
+                        Ok(Flow::Continue) => {}
+                        Ok(Flow::Throttle(wait)) => {
+                            debug!("Throttling the client {} for {:?}", client_id, wait);
+                            resume = Some(Instant::now() + wait);
+                        }
+                        Ok(Flow::Close) => break,
+                        Err(e) => {
+                            error!("Failed to write data to socket: {:?}", e);
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("NATS client disconnected: {} ({})", client_id, e);
+                        if e.downcast_ref::<std::io::Error>().is_none() {
+                            let _ = client.error(&e.to_string()).await;
+                        }
+                        break;
+                    }
+                }
+            },
+            _ = kick.notified() => {
+                warn!("Client {} disconnected by the admin", client_id);
+                break;
+            },
+            _ = stopping.stopped() => {
+                info!("Client {} disconnected, the broker is shutting down", client_id);
+                break;
+            },
+            _ = wait_until(client.deadline) => {
+                warn!("Token expired for the client: {}", client_id);
+                let _ = client.error("User Authentication Expired").await;
+                break;
+            },
+            Some((sid, m)) = queue_rx.recv() => {
+                let span = message_span(&m);
+                        if let Err(e) = client.deliver(m, Some(sid)).instrument(span).await {
+                    error!("Failed to write data to socket: {:?}", e);
+                    break;
+                }
+            },
+            chan_msg = client_rx.recv() => {
+                match chan_msg {
+                    Ok(m) => {
+                        if let Err(e) = client.deliver(m, None).await {
+                            error!("Failed to write data to socket: {:?}", e);
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Client {} missed {} messages", client_id, n);
+                        metrics().dropped("client_lagged", n);
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    reader_task.abort();
+    client.unsubscribe_all();
+    broker.unregister(&client_id);
+    metrics().clients.dec();
+    let _ = client.socket.shutdown().await;
+}
@@ -0,0 +1,804 @@
+//! Redis RESP2 pub/sub listener.
+//!
+//! The Redis clients (`redis-cli` and the client libraries) can publish and
+//! subscribe to the broker topics, the channels are the topics:
+//!
+//! - `PUBLISH channel message`, the reply is always 0, the broker does not count the receivers.
+//! - `SUBSCRIBE`/`UNSUBSCRIBE` on the channels.
+//! - `PSUBSCRIBE`/`PUNSUBSCRIBE` on the patterns. The broker patterns (`+`, `#`) are used,
+//!   a `*` filling a whole level is translated to `#` when it is the last level and to `+`
+//!   otherwise, the other glob patterns are not supported.
+//! - `PUBSUB CHANNELS [pattern]`, `PUBSUB NUMSUB [channel ...]` and `PUBSUB NUMPAT`,
+//!   answered from the `QUERY` of the topic manager.
+//! - `AUTH [username] password`, `PING [message]` and `QUIT`.
+//!
+//! `AUTH` with only the password verifies it as a token. The acl, the token claims
+//! and the rate limits apply as for the native clients.
+use super::broker::Broker;
+use super::client_handler::{wait_until, write_all};
+use crate::acl::{is_pattern, is_valid_pattern, topic_matches};
+use crate::auth::ConnectRequest;
+use crate::error::PubSubError;
+use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::rate_limit::Decision;
+use crate::spans::{connection_span, message_span, record_identity};
+use crate::token::Claims;
+use crate::topics::Envelope;
+use crate::PktType;
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    WriteHalf,
+};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn, Instrument, Span};
+
+/// messages buffered for a slow Redis subscriber.
+const SUBSCRIPTION_CAPACITY: usize = 64;
+/// longest accepted line, an inline command or a length header.
+const MAX_LINE: usize = 64 * 1024;
+/// most arguments accepted in a command.
+const MAX_ARGS: usize = 1024;
+/// longest accepted argument, the largest message of a native packet.
+const MAX_BULK: usize = u16::MAX as usize;
+
+/// RESP2 reply.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(value.as_bytes().to_vec())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(value) => out.extend_from_slice(format!("+{value}\r\n").as_bytes()),
+            Reply::Error(reason) => {
+                // the line breaks would end the reply early.
+                let reason = reason.replace(['\r', '\n'], " ");
+                out.extend_from_slice(format!("-{reason}\r\n").as_bytes());
+            }
+            Reply::Integer(value) => out.extend_from_slice(format!(":{value}\r\n").as_bytes()),
+            Reply::Bulk(value) => {
+                out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                out.extend_from_slice(value);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// what happens after a command.
+enum Flow {
+    Continue,
+    /// reads are paused for the throttled client
+    Throttle(Duration),
+    Quit,
+}
+
+/// reads a line without the line ending.
+async fn read_line<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        bail!("Connection closed");
+    }
+    if line.pop() != Some(b'\n') {
+        bail!("Protocol error: too big inline request");
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn parse_len(bytes: &[u8]) -> Result<usize> {
+    std::str::from_utf8(bytes)?
+        .parse()
+        .map_err(|_| anyhow!("Protocol error: invalid length"))
+}
+
+/// reads a command, an array of bulk strings or an inline command.
+async fn read_command<R>(reader: &mut R) -> Result<Vec<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let line = read_line(reader).await?;
+    let Some(count) = line.strip_prefix(b"*") else {
+        // inline command, e.g. from telnet.
+        return Ok(line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect());
+    };
+    let count = parse_len(count)?;
+    if count > MAX_ARGS {
+        bail!("Protocol error: too many arguments: {}", count);
+    }
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader).await?;
+        let Some(len) = line.strip_prefix(b"$") else {
+            bail!(
+                "Protocol error: expected '$', got '{}'",
+                String::from_utf8_lossy(&line)
+            );
+        };
+        let len = parse_len(len)?;
+        if len > MAX_BULK {
+            bail!("Protocol error: invalid bulk length: {}", len);
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            bail!("Protocol error: invalid bulk string");
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// translates a glob pattern to a broker pattern, only `*` filling a whole level
+/// is supported, it is `#` as the last level and `+` otherwise.
+fn topic_pattern(pattern: &str) -> Option<String> {
+    let levels = pattern.split('/').collect::<Vec<_>>();
+    let last = levels.len() - 1;
+    let topic = levels
+        .iter()
+        .enumerate()
+        .map(|(i, level)| match *level {
+            "*" if i == last => "#",
+            "*" => "+",
+            level => level,
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    (is_valid_pattern(&topic) && !topic.contains(['*', '?', '[', '\\'])).then_some(topic)
+}
+
+fn wrong_arguments(command: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{command}' command"
+    ))
+}
+
+fn utf8(arg: &[u8]) -> Result<String, Reply> {
+    String::from_utf8(arg.to_vec())
+        .map_err(|_| Reply::Error("ERR invalid channel name".to_string()))
+}
+
+/// starts the Redis listener on the given address.
+pub(crate) async fn start(addr: &str, broker: Arc<Broker>) -> Result<SocketAddr> {
+    let listener = super::bind_tcp(addr, Some("redis")).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Redis listener on: {}", local_addr);
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = broker.stopped() => return,
+            };
+            match accepted {
+                Ok((socket, addr)) => {
+                    info!("Accepted Redis connection from {:?}", addr);
+                    metrics().connection("redis");
+                    let client_id = uuid::Uuid::new_v4().to_string();
+                    let address = format!("redis:{addr}");
+                    let span = connection_span(&client_id, &address);
+                    tokio::spawn(
+                        handle_client(socket, broker.clone(), client_id, address).instrument(span),
+                    );
+                }
+                Err(e) => {
+                    error!("Redis listener stopped: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// connected Redis client.
+struct RedisClient<S> {
+    broker: Arc<Broker>,
+    socket: WriteHalf<S>,
+    client_id: String,
+    identity: Option<String>,
+    claims: Option<Claims>,
+    authenticated: bool,
+    /// expiry of the token
+    deadline: Option<Instant>,
+    /// channel for the messages on the subscribed topics
+    channel: broadcast::Sender<Envelope>,
+    /// subscribed channels
+    channels: BTreeSet<String>,
+    /// subscribed glob patterns and their broker patterns
+    patterns: BTreeMap<String, String>,
+}
+
+impl<S> RedisClient<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    async fn write(&mut self, replies: &[Reply]) -> Result<()> {
+        let mut bytes = Vec::new();
+        for reply in replies {
+            reply.encode(&mut bytes);
+        }
+        Ok(write_all(&mut self.socket, &bytes).await?)
+    }
+
+    fn msg(&self, pkt_type: PktType, topic: String, message: Vec<u8>) -> Envelope {
+        let mut msg = Envelope::new(Msg::new(pkt_type, topic, Some(message)));
+        msg.client_id(self.client_id.clone());
+        if let Some(identity) = &self.identity {
+            msg.identity(identity.clone());
+        }
+        msg
+    }
+
+    fn authorize(&self, msg: &Envelope) -> Result<(), Reply> {
+        self.broker
+            .authorize(msg, self.claims.as_ref())
+            .map_err(|e| Reply::Error(format!("NOPERM {e}")))
+    }
+
+    /// number of the subscribed channels and patterns.
+    fn subscriptions(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+
+    /// returns true if the topic is registered with the topic manager,
+    /// by a channel or a pattern.
+    fn registered(&self, topic: &str) -> bool {
+        self.channels.contains(topic) || self.patterns.values().any(|t| t == topic)
+    }
+
+    fn register(&self, topic: &str) {
+        if self.registered(topic) {
+            return;
+        }
+        let mut msg = self.msg(PktType::SUBSCRIBE, topic.to_string(), vec![]);
+        msg.channel(self.channel.clone());
+        if let Err(e) = self.broker.tx.send(msg) {
+            error!("Error while sending message: {:?}", e);
+        }
+    }
+
+    fn unregister(&self, topic: &str) {
+        if !self.registered(topic) {
+            let msg = self.msg(PktType::UNSUBSCRIBE, topic.to_string(), vec![]);
+            let _ = self.broker.tx.send(msg);
+        }
+    }
+
+    /// runs the command and writes the replies.
+    async fn handle(&mut self, command: Vec<Vec<u8>>) -> Result<Flow> {
+        let Some((name, args)) = command.split_first() else {
+            return Ok(Flow::Continue);
+        };
+        let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+        debug!("Command {} from the client {}", name, self.client_id);
+        let subscribed = self.subscriptions() > 0;
+        let replies = match name.as_str() {
+            "quit" => {
+                self.write(&[Reply::Simple("OK")]).await?;
+                return Ok(Flow::Quit);
+            }
+            "auth" => vec![self.auth(args).await],
+            _ if !self.authenticated => {
+                vec![Reply::Error("NOAUTH Authentication required.".to_string())]
+            }
+            "ping" => vec![self.ping(args)],
+            "subscribe" => self.subscribe(args),
+            "psubscribe" => self.psubscribe(args),
+            "unsubscribe" => self.unsubscribe(args),
+            "punsubscribe" => self.punsubscribe(args),
+            _ if subscribed => vec![Reply::Error(format!(
+                "ERR Can't execute '{name}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context"
+            ))],
+            "publish" => {
+                let (reply, throttle) = self.publish(args);
+                self.write(&[reply]).await?;
+                return Ok(throttle.map_or(Flow::Continue, Flow::Throttle));
+            }
+            "pubsub" => vec![self.pubsub(args).await],
+            _ => vec![Reply::Error(format!("ERR unknown command '{name}'"))],
+        };
+        self.write(&replies).await?;
+        Ok(Flow::Continue)
+    }
+
+    async fn auth(&mut self, args: &[Vec<u8>]) -> Reply {
+        if !self.broker.auth_required() {
+            return Reply::Error(
+                "ERR AUTH called without any password configured for the default user".to_string(),
+            );
+        }
+        let text = |arg: &Vec<u8>| String::from_utf8_lossy(arg).into_owned();
+        let request = match args {
+            [password] => ConnectRequest {
+                token: Some(text(password)),
+                ..Default::default()
+            },
+            [username, password] => ConnectRequest {
+                username: Some(text(username)),
+                password: Some(text(password)),
+                ..Default::default()
+            },
+            _ => return wrong_arguments("auth"),
+        };
+        match self.broker.authenticate_request(request).await {
+            Ok(session) => {
+                if let Some(session) = session {
+                    info!(
+                        "Client {} authenticated as: {}",
+                        self.client_id, session.identity
+                    );
+                    record_identity(&Span::current(), &session.identity);
+                    self.broker
+                        .set_identity(&self.client_id, session.identity.clone());
+                    self.identity = Some(session.identity);
+                    self.deadline = session
+                        .claims
+                        .as_ref()
+                        .map(|c| Instant::now() + c.expires_in());
+                    self.claims = session.claims;
+                }
+                self.authenticated = true;
+                Reply::Simple("OK")
+            }
+            Err(e) => {
+                warn!(
+                    "Authentication failed for the client {}: {}",
+                    self.client_id, e
+                );
+                Reply::Error(
+                    "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                )
+            }
+        }
+    }
+
+    fn ping(&self, args: &[Vec<u8>]) -> Reply {
+        match args {
+            [] if self.subscriptions() > 0 => {
+                Reply::Array(vec![Reply::bulk("pong"), Reply::bulk("")])
+            }
+            [] => Reply::Simple("PONG"),
+            [message] if self.subscriptions() > 0 => {
+                Reply::Array(vec![Reply::bulk("pong"), Reply::Bulk(message.clone())])
+            }
+            [message] => Reply::Bulk(message.clone()),
+            _ => wrong_arguments("ping"),
+        }
+    }
+
+    /// publishes the message, returns the throttling delay with the reply.
+    fn publish(&self, args: &[Vec<u8>]) -> (Reply, Option<Duration>) {
+        let [channel, message] = args else {
+            return (wrong_arguments("publish"), None);
+        };
+        let channel = match utf8(channel) {
+            Ok(channel) if !channel.is_empty() && channel.len() <= usize::from(u8::MAX) => channel,
+            _ => return (Reply::Error("ERR invalid channel name".to_string()), None),
+        };
+        let mut msg = self.msg(PktType::PUBLISH, channel.clone(), message.clone());
+        msg.received_at = Some(std::time::Instant::now());
+        if let Err(reply) = self.authorize(&msg) {
+            return (reply, None);
+        }
+        let throttle = match self.broker.rate_limit(&msg) {
+            Decision::Allow => None,
+            Decision::Throttle(wait) => Some(wait),
+            Decision::Reject => {
+                let reason = PubSubError::RateLimited(channel).to_string();
+                return (Reply::Error(format!("ERR {reason}")), None);
+            }
+        };
+        let backpressure = match self.broker.reserve_memory(&mut msg) {
+            Ok(backpressure) => backpressure,
+            Err(e) => return (Reply::Error(format!("ERR {e}")), None),
+        };
+        info!("Topic: {}, redis client: {}", channel, self.client_id);
+        if let Err(e) = self.broker.tx.send(msg) {
+            error!("Error while sending message: {:?}", e);
+            return (Reply::Error(format!("ERR {e}")), None);
+        }
+        (Reply::Integer(0), throttle.max(backpressure))
+    }
+
+    /// checks the channels or patterns and their broker topics,
+    /// none of them is subscribed when one is not valid or not allowed.
+    fn topics(
+        &self,
+        args: &[Vec<u8>],
+        topic: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<(String, String)>, Reply> {
+        let mut topics = Vec::with_capacity(args.len());
+        for arg in args {
+            let name = utf8(arg)?;
+            let Some(topic) = topic(&name) else {
+                return Err(Reply::Error(format!(
+                    "ERR unsupported channel or pattern '{name}'"
+                )));
+            };
+            self.authorize(&self.msg(PktType::SUBSCRIBE, topic.clone(), vec![]))?;
+            topics.push((name, topic));
+        }
+        Ok(topics)
+    }
+
+    fn subscribe(&mut self, args: &[Vec<u8>]) -> Vec<Reply> {
+        if args.is_empty() {
+            return vec![wrong_arguments("subscribe")];
+        }
+        // the patterns are subscribed with PSUBSCRIBE.
+        let channel =
+            |name: &str| (is_valid_pattern(name) && !is_pattern(name)).then(|| name.to_string());
+        let channels = match self.topics(args, channel) {
+            Ok(channels) => channels,
+            Err(reply) => return vec![reply],
+        };
+        channels
+            .into_iter()
+            .map(|(channel, _)| {
+                self.register(&channel);
+                info!("Topic: {}, redis client: {}", channel, self.client_id);
+                self.channels.insert(channel.clone());
+                Reply::Array(vec![
+                    Reply::bulk("subscribe"),
+                    Reply::bulk(&channel),
+                    Reply::Integer(self.subscriptions()),
+                ])
+            })
+            .collect()
+    }
+
+    fn psubscribe(&mut self, args: &[Vec<u8>]) -> Vec<Reply> {
+        if args.is_empty() {
+            return vec![wrong_arguments("psubscribe")];
+        }
+        let patterns = match self.topics(args, topic_pattern) {
+            Ok(patterns) => patterns,
+            Err(reply) => return vec![reply],
+        };
+        patterns
+            .into_iter()
+            .map(|(pattern, topic)| {
+                self.register(&topic);
+                info!("Topic: {}, redis client: {}", topic, self.client_id);
+                self.patterns.insert(pattern.clone(), topic);
+                Reply::Array(vec![
+                    Reply::bulk("psubscribe"),
+                    Reply::bulk(&pattern),
+                    Reply::Integer(self.subscriptions()),
+                ])
+            })
+            .collect()
+    }
+
+    fn unsubscribe(&mut self, args: &[Vec<u8>]) -> Vec<Reply> {
+        let channels = if args.is_empty() {
+            self.channels.iter().cloned().collect::<Vec<_>>()
+        } else {
+            args.iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect()
+        };
+        if channels.is_empty() {
+            return vec![Reply::Array(vec![
+                Reply::bulk("unsubscribe"),
+                Reply::Nil,
+                Reply::Integer(self.subscriptions()),
+            ])];
+        }
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.channels.remove(&channel) {
+                    self.unregister(&channel);
+                }
+                Reply::Array(vec![
+                    Reply::bulk("unsubscribe"),
+                    Reply::bulk(&channel),
+                    Reply::Integer(self.subscriptions()),
+                ])
+            })
+            .collect()
+    }
+
+    fn punsubscribe(&mut self, args: &[Vec<u8>]) -> Vec<Reply> {
+        let patterns = if args.is_empty() {
+            self.patterns.keys().cloned().collect::<Vec<_>>()
+        } else {
+            args.iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect()
+        };
+        if patterns.is_empty() {
+            return vec![Reply::Array(vec![
+                Reply::bulk("punsubscribe"),
+                Reply::Nil,
+                Reply::Integer(self.subscriptions()),
+            ])];
+        }
+        patterns
+            .into_iter()
+            .map(|pattern| {
+                if let Some(topic) = self.patterns.remove(&pattern) {
+                    self.unregister(&topic);
+                }
+                Reply::Array(vec![
+                    Reply::bulk("punsubscribe"),
+                    Reply::bulk(&pattern),
+                    Reply::Integer(self.subscriptions()),
+                ])
+            })
+            .collect()
+    }
+
+    /// removes the subscriptions of the disconnected client.
+    fn unsubscribe_all(&mut self) {
+        let topics = std::mem::take(&mut self.channels)
+            .into_iter()
+            .chain(std::mem::take(&mut self.patterns).into_values())
+            .collect::<BTreeSet<_>>();
+        for topic in topics {
+            self.unregister(&topic);
+        }
+    }
+
+    /// runs the `QUERY` of the topic manager.
+    async fn query(&self, topic: &str) -> Result<Value, Reply> {
+        let msg = self.msg(PktType::QUERY, topic.to_string(), vec![]);
+        self.authorize(&msg)?;
+        self.broker
+            .query(msg, self.claims.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Query failed: {:?}", e);
+                Reply::Error(format!("ERR {e}"))
+            })
+    }
+
+    /// the subscribed topics and their subscriber counts, from the query of all the topics.
+    async fn subscribed_topics(&self) -> Result<Vec<(String, i64)>, Reply> {
+        let resp = self.query("*").await?;
+        Ok(resp["*"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|topic| {
+                let (topic, count) = topic.as_str()?.rsplit_once(": ")?;
+                Some((topic.to_string(), count.parse().ok()?))
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect())
+    }
+
+    async fn pubsub(&self, args: &[Vec<u8>]) -> Reply {
+        let Some((subcommand, args)) = args.split_first() else {
+            return wrong_arguments("pubsub");
+        };
+        let subcommand = String::from_utf8_lossy(subcommand).to_ascii_lowercase();
+        let result = match (subcommand.as_str(), args) {
+            ("channels", [] | [_]) => {
+                let pattern = match args.first().map(|arg| utf8(arg)) {
+                    Some(Ok(pattern)) => match topic_pattern(&pattern) {
+                        Some(topic) => Some(topic),
+                        None => {
+                            return Reply::Error(format!("ERR unsupported pattern '{pattern}'"))
+                        }
+                    },
+                    Some(Err(reply)) => return reply,
+                    None => None,
+                };
+                self.subscribed_topics().await.map(|topics| {
+                    Reply::Array(
+                        topics
+                            .into_iter()
+                            .filter(|(topic, _)| !is_pattern(topic))
+                            .filter(|(topic, _)| {
+                                pattern.as_ref().is_none_or(|p| topic_matches(p, topic))
+                            })
+                            .map(|(topic, _)| Reply::bulk(&topic))
+                            .collect(),
+                    )
+                })
+            }
+            ("numsub", channels) => {
+                let mut replies = Vec::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    let channel = String::from_utf8_lossy(channel).into_owned();
+                    let count = match self.query(&channel).await {
+                        Ok(resp) => resp[&channel][0]
+                            .as_str()
+                            .and_then(|count| count.parse().ok())
+                            .unwrap_or_default(),
+                        Err(reply) => return reply,
+                    };
+                    replies.push(Reply::bulk(&channel));
+                    replies.push(Reply::Integer(count));
+                }
+                Ok(Reply::Array(replies))
+            }
+            ("numpat", []) => self.subscribed_topics().await.map(|topics| {
+                Reply::Integer(topics.iter().filter(|(topic, _)| is_pattern(topic)).count() as i64)
+            }),
+            ("channels" | "numpat", _) => Err(Reply::Error(format!(
+                "ERR wrong number of arguments for 'pubsub|{subcommand}' command"
+            ))),
+            _ => Err(Reply::Error(format!(
+                "ERR unknown subcommand '{subcommand}'. Try PUBSUB HELP."
+            ))),
+        };
+        result.unwrap_or_else(|reply| reply)
+    }
+
+    /// writes a message on the subscribed channel and on the matching patterns.
+    async fn deliver(&mut self, msg: Envelope) -> Result<()> {
+        if msg.header.pkt_type != PktType::PUBLISH {
+            return Ok(());
+        }
+        let mut replies = Vec::new();
+        if self.channels.contains(&msg.topic) {
+            replies.push(Reply::Array(vec![
+                Reply::bulk("message"),
+                Reply::bulk(&msg.topic),
+                Reply::Bulk(msg.message.clone()),
+            ]));
+        }
+        for (pattern, topic) in &self.patterns {
+            if topic_matches(topic, &msg.topic) {
+                replies.push(Reply::Array(vec![
+                    Reply::bulk("pmessage"),
+                    Reply::bulk(pattern),
+                    Reply::bulk(&msg.topic),
+                    Reply::Bulk(msg.message.clone()),
+                ]));
+            }
+        }
+        if replies.is_empty() {
+            return Ok(());
+        }
+        self.write(&replies).await?;
+        metrics().delivered(&msg);
+        Ok(())
+    }
+}
+
+/// Handles a Redis connection.
+async fn handle_client<S>(socket: S, broker: Arc<Broker>, client_id: String, address: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (reader, socket) = tokio::io::split(socket);
+    info!("Redis client {} connected from {}", client_id, address);
+
+    // the commands are read in a separate task, a partially read command
+    // must not be dropped when the client channel receives a message.
+    let (command_tx, mut command_rx) = tokio::sync::mpsc::channel(1);
+    let reader_task = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let command = read_command(&mut reader).await;
+            let disconnected = command.is_err();
+            if command_tx.send(command).await.is_err() || disconnected {
+                return;
+            }
+        }
+    });
+
+    let Ok(kick) = broker.register(&client_id, address, None) else {
+        return;
+    };
+    metrics().clients.inc();
+    let stopping = broker.clone();
+    let (channel, mut client_rx) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+    let mut client = RedisClient {
+        broker: broker.clone(),
+        socket,
+        client_id: client_id.clone(),
+        identity: None,
+        claims: None,
+        authenticated: !broker.auth_required(),
+        deadline: None,
+        channel,
+        channels: BTreeSet::new(),
+        patterns: BTreeMap::new(),
+    };
+    // reads are paused until then when the client is throttled.
+    let mut resume = None;
+    loop {
+        tokio::select! {
+            _ = wait_until(resume), if resume.is_some() => {
+                resume = None;
+            },
+            Some(command) = command_rx.recv(), if resume.is_none() => {
+                match command {
+                    Ok(command) => match client.handle(command).await {
+                        Ok(Flow::Continue) => {}
+                        Ok(Flow::Throttle(wait)) => {
+                            debug!("Throttling the client {} for {:?}", client_id, wait);
+                            resume = Some(Instant::now() + wait);
+                        }
+                        Ok(Flow::Quit) => break,
+                        Err(e) => {
+                            error!("Failed to write data to socket: {:?}", e);
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Redis client disconnected: {} ({})", client_id, e);
+                        break;
+                    }
+                }
+            },
+            _ = kick.notified() => {
+                warn!("Client {} disconnected by the admin", client_id);
+                break;
+            },
+            _ = stopping.stopped() => {
+                info!("Client {} disconnected, the broker is shutting down", client_id);
+                break;
+            },
+            _ = wait_until(client.deadline) => {
+                warn!("Token expired for the client: {}", client_id);
+                break;
+            },
+            chan_msg = client_rx.recv() => {
+                match chan_msg {
+                    Ok(m) => {
+                        let span = message_span(&m);
+                        if let Err(e) = client.deliver(m).instrument(span).await {
+                            error!("Failed to write data to socket: {:?}", e);
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Client {} missed {} messages", client_id, n);
+                        metrics().dropped("client_lagged", n);
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+    reader_task.abort();
+    client.unsubscribe_all();
+    broker.unregister(&client_id);
+    metrics().clients.dec();
+    let _ = client.socket.shutdown().await;
+}
@@ -0,0 +1,192 @@
+//! Persistent sessions of the native clients.
+//!
+//! A client connecting with a session id and the persistent flag gets a channel
+//! registered for its subscriptions under the session id. The channel is kept while
+//! the client is disconnected, the messages published meanwhile stay in the channel
+//! up to the queue limit and are delivered when the client resumes the session.
+use crate::topics::Envelope;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// time a disconnected session is kept by default.
+pub(crate) const DEFAULT_SESSION_EXPIRY: Duration = Duration::from_secs(3600);
+/// messages queued by default for a disconnected session.
+pub(crate) const DEFAULT_SESSION_QUEUE: usize = 1024;
+
+/// returns the client id of the connections using the session. The session ids
+/// are unique among the sessions, the prefix keeps them apart from the connection ids.
+pub(crate) fn client_id(session_id: &str) -> String {
+    format!("session:{session_id}")
+}
+
+/// session kept by the broker.
+struct StoredSession {
+    /// identity of the client that created the session
+    identity: Option<String>,
+    /// subscribed topics, unsubscribed when the session expires
+    topics: BTreeSet<String>,
+    /// channel registered for the subscriptions of the session
+    channel: Sender<Envelope>,
+    /// messages queued while the client is disconnected, `None` while it is connected
+    queue: Option<Receiver<Envelope>>,
+    /// when the client disconnected
+    disconnected_at: Option<Instant>,
+    /// notified when the connection using the session returns the queue
+    returned: Arc<Notify>,
+}
+
+/// session taken by a connection.
+pub(crate) struct ResumedSession {
+    /// channel to register for the subscriptions
+    pub(crate) channel: Sender<Envelope>,
+    /// messages for the client, the queued ones first
+    pub(crate) rx: Receiver<Envelope>,
+    /// the session existed before the connection
+    pub(crate) present: bool,
+}
+
+/// result of taking a session.
+pub(crate) enum Taken {
+    Resumed(ResumedSession),
+    /// the session is used by another connection,
+    /// notified once the connection returns the queue.
+    InUse(Arc<Notify>),
+    /// the session belongs to another identity
+    Denied,
+}
+
+/// persistent sessions, by session id.
+pub(crate) struct Sessions {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+    queue_limit: usize,
+    expiry: Duration,
+}
+
+impl Sessions {
+    pub(crate) fn new(queue_limit: usize, expiry: Duration) -> Sessions {
+        Sessions {
+            sessions: Mutex::new(HashMap::new()),
+            queue_limit,
+            expiry,
+        }
+    }
+
+    /// takes the session for a connection, creates it when there is none.
+    pub(crate) fn take(&self, id: &str, identity: Option<&str>) -> Taken {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return Taken::Denied;
+        };
+        let Some(session) = sessions.get_mut(id) else {
+            // the channel capacity is the limit of the queued messages.
+            let (channel, rx) = broadcast::channel(self.queue_limit);
+            sessions.insert(
+                id.to_string(),
+                StoredSession {
+                    identity: identity.map(str::to_string),
+                    topics: BTreeSet::new(),
+                    channel: channel.clone(),
+                    queue: None,
+                    disconnected_at: None,
+                    returned: Arc::new(Notify::new()),
+                },
+            );
+            return Taken::Resumed(ResumedSession {
+                channel,
+                rx,
+                present: false,
+            });
+        };
+        if session.identity.as_deref() != identity {
+            return Taken::Denied;
+        }
+        match session.queue.take() {
+            Some(rx) => {
+                session.disconnected_at = None;
+                Taken::Resumed(ResumedSession {
+                    channel: session.channel.clone(),
+                    rx,
+                    present: true,
+                })
+            }
+            None => Taken::InUse(session.returned.clone()),
+        }
+    }
+
+    /// keeps the queue of the disconnected client until the session expires.
+    pub(crate) fn suspend(&self, id: &str, rx: Receiver<Envelope>) {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return;
+        };
+        if let Some(session) = sessions.get_mut(id) {
+            session.queue = Some(rx);
+            session.disconnected_at = Some(Instant::now());
+            session.returned.notify_one();
+        }
+    }
+
+    /// records the subscription of the session.
+    pub(crate) fn subscribed(&self, id: &str, topic: &str) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            if let Some(session) = sessions.get_mut(id) {
+                session.topics.insert(topic.to_string());
+            }
+        }
+    }
+
+    /// records the unsubscription of the session.
+    pub(crate) fn unsubscribed(&self, id: &str, topic: &str) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            if let Some(session) = sessions.get_mut(id) {
+                session.topics.remove(topic);
+            }
+        }
+    }
+
+    /// removes the session, returns its topics or `None` when the session
+    /// belongs to another identity.
+    pub(crate) fn discard(&self, id: &str, identity: Option<&str>) -> Option<Vec<String>> {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return None;
+        };
+        if sessions
+            .get(id)
+            .is_some_and(|session| session.identity.as_deref() != identity)
+        {
+            return None;
+        }
+        Some(
+            sessions
+                .remove(id)
+                .map(|session| session.topics.into_iter().collect())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// removes the sessions disconnected for longer than the expiry,
+    /// returns their ids and topics.
+    pub(crate) fn expire(&self) -> Vec<(String, Vec<String>)> {
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return vec![];
+        };
+        let expired = sessions
+            .iter()
+            .filter(|(_, session)| {
+                session
+                    .disconnected_at
+                    .is_some_and(|at| at.elapsed() >= self.expiry)
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .filter_map(|id| {
+                let session = sessions.remove(&id)?;
+                Some((id, session.topics.into_iter().collect()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,14 @@
+//! tls backends for the server, `rustls` takes precedence over `openssl`
+//! when both the features are enabled.
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+mod native;
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub(crate) use native::{accept, acceptor};
+
+#[cfg(feature = "rustls")]
+mod rustls;
+#[cfg(feature = "rustls")]
+pub(crate) use self::rustls::{accept, acceptor};
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+compile_error!("either the `openssl` or the `rustls` feature must be enabled");
@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Ref;
+use std::fs::File;
+use std::io::Read;
+use std::pin::Pin;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+/// Builds the `SslAcceptor` from the given certificate.
+/// `cert` is read as a PEM certificate chain when the `key` is given,
+/// as a PKCS#12 identity (.pfx file) otherwise.
+/// When `client_ca` is given, the clients must present a certificate signed by it.
+pub(crate) fn acceptor(
+    cert: &str,
+    key: Option<&str>,
+    cert_password: Option<&str>,
+    client_ca: Option<&str>,
+) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+
+    if let Some(key) = key {
+        builder
+            .set_certificate_chain_file(cert)
+            .context("Error while loading the certificate")?;
+        builder
+            .set_private_key_file(key, SslFiletype::PEM)
+            .context("Error while loading the private key")?;
+    } else {
+        let mut file = File::open(cert).context("Error while opening the certificate")?;
+        let mut identity_vec = vec![];
+        file.read_to_end(&mut identity_vec)?;
+
+        let identity = Pkcs12::from_der(&identity_vec)?
+            .parse2(cert_password.unwrap_or(""))
+            .context("Error while parsing the PKCS#12 identity")?;
+
+        if let Some(pkey) = identity.pkey {
+            builder.set_private_key(&pkey)?;
+        }
+        if let Some(cert) = identity.cert {
+            builder.set_certificate(&cert)?;
+        }
+        if let Some(chain) = identity.ca {
+            for cert in chain {
+                builder.add_extra_chain_cert(cert)?;
+            }
+        }
+    }
+    builder.check_private_key()?;
+
+    if let Some(client_ca) = client_ca {
+        builder
+            .set_ca_file(client_ca)
+            .context("Error while loading the client CA")?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(builder.build())
+}
+
+/// Performs the tls handshake on the given stream.
+/// returns the stream along with the identity from the verified client certificate, if any.
+pub(crate) async fn accept(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+) -> Result<(SslStream<TcpStream>, Option<String>)> {
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, stream)?;
+    Pin::new(&mut stream)
+        .accept()
+        .await
+        .context("Error during the tls handshake")?;
+
+    let identity = stream
+        .ssl()
+        .peer_certificate()
+        .and_then(|c| peer_identity(&c));
+    Ok((stream, identity))
+}
+
+/// returns the common name of the certificate, or the first subject alternative name.
+fn peer_identity(cert: &X509Ref) -> Option<String> {
+    if let Some(cn) = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next() {
+        if let Ok(cn) = cn.data().as_utf8() {
+            return Some(cn.to_string());
+        }
+    }
+    cert.subject_alt_names()?.iter().find_map(|name| {
+        name.dnsname()
+            .or_else(|| name.email())
+            .or_else(|| name.uri())
+            .map(|n| n.to_string())
+    })
+}
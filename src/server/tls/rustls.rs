@@ -0,0 +1,81 @@
+use anyhow::{bail, Context, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::extensions::GeneralName;
+
+/// Builds the `TlsAcceptor` from the given PEM certificate chain and the private key.
+/// PKCS#12 identities are not supported by the rustls backend.
+/// When `client_ca` is given, the clients must present a certificate signed by it.
+pub(crate) fn acceptor(
+    cert: &str,
+    key: Option<&str>,
+    _cert_password: Option<&str>,
+    client_ca: Option<&str>,
+) -> Result<TlsAcceptor> {
+    let Some(key) = key else {
+        bail!("PKCS#12 identities are not supported with rustls, use a PEM certificate and key");
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .context("Error while opening the certificate")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Error while loading the certificate")?;
+    let key = PrivateKeyDer::from_pem_file(key).context("Error while loading the private key")?;
+
+    let builder = ServerConfig::builder();
+    let builder = if let Some(client_ca) = client_ca {
+        let mut roots = RootCertStore::empty();
+        for ca in
+            CertificateDer::pem_file_iter(client_ca).context("Error while opening the client CA")?
+        {
+            roots.add(ca.context("Error while loading the client CA")?)?;
+        }
+        builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+    } else {
+        builder.with_no_client_auth()
+    };
+    let config = builder.with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Performs the tls handshake on the given stream.
+/// returns the stream along with the identity from the verified client certificate, if any.
+pub(crate) async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+) -> Result<(TlsStream<TcpStream>, Option<String>)> {
+    let stream = acceptor
+        .accept(stream)
+        .await
+        .context("Error during the tls handshake")?;
+
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|c| peer_identity(c));
+    Ok((stream, identity))
+}
+
+/// returns the common name of the certificate, or the first subject alternative name.
+fn peer_identity(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    if let Some(cn) = cert.subject().iter_common_name().next() {
+        if let Ok(cn) = cn.as_str() {
+            return Some(cn.to_string());
+        }
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(n) | GeneralName::RFC822Name(n) | GeneralName::URI(n) => {
+            Some(n.to_string())
+        }
+        _ => None,
+    })
+}
@@ -0,0 +1,148 @@
+//! Unix socket specific parts of the server: the peer credentials
+//! and the permissions of the socket file.
+use anyhow::{bail, Context, Result};
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use std::fs::DirBuilder;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::info;
+
+use super::Unix;
+use crate::systemd;
+
+/// Removes the socket file when the listener is dropped.
+pub(crate) struct SocketFile {
+    path: String,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// binds the listener on the path of the server, the existing socket file is
+/// only removed when no other broker is listening on it.
+/// the mode, owner and group of the socket file are set from the server options,
+/// with any of them the socket is created in a private directory and moved to the
+/// path once they are set, it is never reachable with the default permissions.
+/// the socket passed by systemd is used instead when there is one, its file
+/// belongs to systemd and is not removed.
+pub(crate) fn bind(server: &Unix) -> Result<(UnixListener, Option<SocketFile>)> {
+    if let Some(listener) = systemd::unix_listener() {
+        info!(
+            "Using the socket {:?} passed by systemd instead of {}",
+            listener.local_addr()?.as_pathname(),
+            server.path
+        );
+        listener.set_nonblocking(true)?;
+        return Ok((UnixListener::from_std(listener)?, None));
+    }
+    remove_stale_socket(&server.path)?;
+    if server.mode.is_none() && server.owner.is_none() && server.group.is_none() {
+        let listener = UnixListener::bind(&server.path)?;
+        let socket_file = SocketFile {
+            path: server.path.clone(),
+        };
+        return Ok((listener, Some(socket_file)));
+    }
+
+    // the private directory is next to the path, on the same file system for the rename.
+    let path = Path::new(&server.path);
+    let private = path.with_file_name(format!(".{}", uuid::Uuid::new_v4().simple()));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("Error while creating {}", private.display()))?;
+    let private = PrivateDir(private);
+    let temp_path = private.0.join("socket");
+    let listener = UnixListener::bind(&temp_path)?;
+
+    // the owner is changed first, so the old group never gets the new mode.
+    if server.owner.is_some() || server.group.is_some() {
+        let owner = server.owner.as_deref().map(uid).transpose()?;
+        let group = server.group.as_deref().map(gid).transpose()?;
+        chown(&temp_path, owner, group)
+            .with_context(|| format!("Error while changing the owner of {}", server.path))?;
+    }
+    if let Some(mode) = server.mode {
+        std::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Error while setting the mode of {}", server.path))?;
+    }
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("Error while moving the socket to {}", server.path))?;
+    let socket_file = SocketFile {
+        path: server.path.clone(),
+    };
+    Ok((listener, Some(socket_file)))
+}
+
+/// Directory only accessible by the broker, removed with what is left in it when dropped.
+struct PrivateDir(PathBuf);
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// removes the socket file left behind by a broker that is not running anymore.
+fn remove_stale_socket(path: &str) -> Result<()> {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{path} exists and is not a socket");
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => bail!("Another broker is already listening on {path}"),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            info!("Removing the stale socket: {path}");
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("Error while checking the socket {path}")),
+    }
+}
+
+/// returns the identity of the connected process, the name of its user
+/// or the uid if the user can not be resolved.
+pub(crate) async fn peer_identity(socket: &UnixStream) -> Option<String> {
+    let cred = socket.peer_cred().ok()?;
+    info!(
+        "Peer credentials uid: {}, gid: {}, pid: {:?}",
+        cred.uid(),
+        cred.gid(),
+        cred.pid()
+    );
+    let uid = Uid::from_raw(cred.uid());
+    // the lookup in the user database (NSS) may block.
+    match tokio::task::spawn_blocking(move || User::from_uid(uid)).await {
+        Ok(Ok(Some(user))) => Some(user.name),
+        _ => Some(uid.to_string()),
+    }
+}
+
+/// resolves the user name or the numeric uid.
+fn uid(owner: &str) -> Result<Uid> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+    match User::from_name(owner)? {
+        Some(user) => Ok(user.uid),
+        None => bail!("No such user: {owner}"),
+    }
+}
+
+/// resolves the group name or the numeric gid.
+fn gid(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(group)? {
+        Some(group) => Ok(group.gid),
+        None => bail!("No such group: {group}"),
+    }
+}
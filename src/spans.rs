@@ -0,0 +1,27 @@
+//! Tracing spans of the connections and the messages.
+//!
+//! The events logged while a connection is handled carry the client id, the peer
+//! address and the identity once the client is authenticated, the events logged
+//! while a message is handled carry its topic, size and packet type.
+use crate::message::Msg;
+use tracing::{field, info_span, Span};
+
+/// span of a client connection, the identity is recorded with [`record_identity`].
+pub(crate) fn connection_span(client_id: &str, peer: &str) -> Span {
+    info_span!("connection", client_id, peer, identity = field::Empty)
+}
+
+/// records the verified identity of the client on the connection span.
+pub(crate) fn record_identity(span: &Span, identity: &str) {
+    span.record("identity", identity);
+}
+
+/// span of a message, in or out of the broker.
+pub(crate) fn message_span(msg: &Msg) -> Span {
+    info_span!(
+        "message",
+        topic = %msg.topic,
+        size = msg.message.len(),
+        pkt_type = %msg.header.pkt_type
+    )
+}
@@ -1,55 +1,138 @@
 use crate::message;
 use crate::Header;
-use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use log::{debug, trace};
-use tokio::io::AsyncReadExt;
+use futures_util::{Sink, Stream};
+use simple_pub_sub_message::constants::HEADER_LEN;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, trace};
 
 /// reads a data from a `TcpStream` and returns a `Msg`.
+/// the header is read first, followed by exactly the topic and the message,
+/// so the consecutive messages on the stream are not mixed up.
 pub(crate) async fn read_message<S>(s: &mut S) -> Result<message::Msg>
 where
     S: AsyncReadExt + Unpin + Send,
 {
-    let mut pkt_buf: Vec<u8>;
-    pkt_buf = vec![0; 512];
+    let mut header_buf = [0; HEADER_LEN];
+    s.read_exact(&mut header_buf)
+        .await
+        .context("Error while reading data from the socket")?;
+    debug!("Incoming pkt: {:?}", header_buf);
+    let header: Header = Header::try_from(&header_buf[..])?;
+    debug!("{:?}", header);
 
+    let topic_length = usize::from(header.topic_length);
+    let mut pkt_buf: Vec<u8> = vec![0; topic_length + usize::from(header.message_length)];
     trace!("The size of buffer is: {}", pkt_buf.len());
-    let n = s
-        .read(&mut pkt_buf)
+    s.read_exact(&mut pkt_buf)
         .await
-        .context("Error while reading data")?;
-    if n == 0 {
-        bail!("Error while reading data from the socket");
-    }
-    debug!("Incoming pkt: {:?}", pkt_buf[..8].to_vec().clone());
-    let header: Header = Header::try_from(&pkt_buf[..8])?;
-    debug!("{:?}", header);
+        .context("Error while reading the data from socket")?;
 
-    let topic: String = String::from_utf8(pkt_buf[8..(8 + header.topic_length).into()].to_vec())
+    let topic: String = String::from_utf8(pkt_buf[..topic_length].to_vec())
         .context("Error while parsing the topic string")?;
-    let message_position: usize = ((8 + header.topic_length) as u16 + header.message_length).into();
-
-    if 504 - u16::from(header.topic_length) < header.message_length {
-        let bytes_remaining = header.message_length - (504 - u16::from(header.topic_length));
-        trace!("The message is bigger, reading the remaining chunk");
-        trace!("{} bytes remaining", bytes_remaining);
-
-        let mut buf: Vec<u8> = Vec::with_capacity(bytes_remaining.into());
-        trace!("Reading next bytes");
-
-        let n = s.read_buf(&mut buf).await?;
-        if n == 0 {
-            bail!("Error while reading the data from socket");
-        }
-
-        pkt_buf.extend(buf);
-    }
     Ok(message::Msg {
         header: header.clone(),
         topic,
-        message: pkt_buf[(8 + header.topic_length).into()..message_position].to_vec(),
+        message: pkt_buf[topic_length..].to_vec(),
         channel: None,
         client_id: None,
     })
 }
+
+/// Byte stream over a websocket connection.
+/// every write is sent as a single binary message,
+/// the binary messages are read back as a continuous stream.
+pub struct WebSocketStream<S> {
+    inner: tokio_tungstenite::WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<S> WebSocketStream<S> {
+    pub fn new(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        WebSocketStream {
+            inner,
+            read_buf: vec![],
+            read_pos: 0,
+        }
+    }
+}
+
+impl<S> std::fmt::Debug for WebSocketStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketStream")
+            .field("buffered", &(self.read_buf.len() - self.read_pos))
+            .finish()
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.read_buf = data.into();
+                    this.read_pos = 0;
+                }
+                // the connection is closed, end of the stream.
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // the pings are answered by tungstenite, the text messages are not a part of the protocol.
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let inner = &mut self.get_mut().inner;
+        ready!(Pin::new(&mut *inner).poll_ready(cx)).map_err(io::Error::other)?;
+        Pin::new(&mut *inner)
+            .start_send(Message::binary(buf.to_vec()))
+            .map_err(io::Error::other)?;
+        // the message is queued, the pending data is sent by the next flush.
+        if let Poll::Ready(Err(e)) = Pin::new(inner).poll_flush(cx) {
+            return Poll::Ready(Err(io::Error::other(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,143 @@
+//! systemd integration: the socket activation (`LISTEN_FDS`) and the service
+//! notifications (`NOTIFY_SOCKET`).
+//!
+//! With the socket activation systemd opens the listening sockets and passes them to
+//! the broker from the fd 3 on, so the restarts do not close the listening sockets and
+//! the privileged ports do not need the privileges. The main server (tcp, tls,
+//! websocket or unix) takes the first socket of its kind that is not named after one
+//! of the [`LISTENER_NAMES`], the http, metrics, MQTT, Redis and NATS listeners take
+//! the TCP socket named after them with `FileDescriptorName=` when they are enabled.
+//!
+//! ```ini
+//! # simple-pub-sub-mqtt.socket, the name applies to all the sockets of the unit
+//! [Socket]
+//! ListenStream=1883
+//! FileDescriptorName=mqtt
+//! Service=simple-pub-sub.service
+//! ```
+use anyhow::Result;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+use std::env;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::warn;
+
+/// first socket passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// names of the sockets of the http, metrics, MQTT, Redis and NATS listeners.
+pub const LISTENER_NAMES: [&str; 5] = ["http", "metrics", "mqtt", "redis", "nats"];
+
+/// socket passed by systemd with its `FileDescriptorName=`.
+struct ListenFd {
+    fd: OwnedFd,
+    name: String,
+}
+
+/// sockets passed by systemd that were not taken yet.
+static LISTEN_FDS: OnceLock<Mutex<Vec<ListenFd>>> = OnceLock::new();
+
+/// reads the sockets from `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`, the fds
+/// are closed on exec and `LISTEN_PID` does not match the child processes.
+fn listen_fds_from_env() -> Vec<ListenFd> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    if pid != Some(std::process::id()) || count <= 0 {
+        return vec![];
+    }
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd passed the fds to this process, nothing else owns them.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            if let Err(e) = fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+                warn!(
+                    "Could not set FD_CLOEXEC on the fd {}: {}",
+                    fd.as_raw_fd(),
+                    e
+                );
+            }
+            let name = names
+                .next()
+                .filter(|name| !name.is_empty())
+                .unwrap_or("unknown");
+            ListenFd {
+                fd,
+                name: name.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// takes the first socket passed by systemd in one of the address families,
+/// with the name or, when there is no name, not named after one of the listeners.
+fn take(name: Option<&str>, families: &[AddressFamily]) -> Option<OwnedFd> {
+    let fds = LISTEN_FDS.get_or_init(|| Mutex::new(listen_fds_from_env()));
+    let mut fds = fds.lock().ok()?;
+    let index = fds.iter().position(|listen_fd| {
+        let family = getsockname::<SockaddrStorage>(listen_fd.fd.as_raw_fd())
+            .ok()
+            .and_then(|addr| addr.family());
+        let name_matches = match name {
+            Some(name) => listen_fd.name == name,
+            None => !LISTENER_NAMES.contains(&listen_fd.name.as_str()),
+        };
+        name_matches && family.is_some_and(|family| families.contains(&family))
+    })?;
+    Some(fds.remove(index).fd)
+}
+
+/// takes the TCP listening socket passed by systemd, see [`take`] for the name.
+pub(crate) fn tcp_listener(name: Option<&str>) -> Option<std::net::TcpListener> {
+    take(name, &[AddressFamily::Inet, AddressFamily::Inet6]).map(std::net::TcpListener::from)
+}
+
+/// takes the unix listening socket passed by systemd.
+pub(crate) fn unix_listener() -> Option<UnixListener> {
+    take(None, &[AddressFamily::Unix]).map(UnixListener::from)
+}
+
+/// sends the state (`READY=1`, `STOPPING=1`, `WATCHDOG=1`, `STATUS=...`) to the
+/// service manager, does nothing when `NOTIFY_SOCKET` is not set.
+/// ```
+/// simple_pub_sub::systemd::notify("READY=1").unwrap();
+/// ```
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt as _;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => anyhow::bail!("The abstract notify sockets are only supported on linux"),
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// returns how often `WATCHDOG=1` must be sent, half of `WATCHDOG_USEC`,
+/// `None` when the watchdog is not enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
@@ -0,0 +1,168 @@
+//! Bearer token authentication for the broker.
+//!
+//! The tokens are JWTs signed with a shared HMAC secret (`HS256`)
+//! or an Ed25519 key (`EdDSA`), the claims carry the topic patterns
+//! the client is allowed to publish and subscribe to.
+//! the topic patterns use the same syntax as the acl, see [`crate::acl::topic_matches`].
+use crate::acl::{topic_matches, Action};
+use anyhow::{Context, Result};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// claims of the token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    /// identity of the client
+    pub sub: String,
+    /// expiry, seconds since the unix epoch
+    pub exp: u64,
+    /// topic patterns the client can publish to
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// topic patterns the client can subscribe to and query
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+}
+
+impl Claims {
+    /// creates the claims for the subject, valid for the given duration.
+    pub fn new(
+        sub: String,
+        valid_for: Duration,
+        publish: Vec<String>,
+        subscribe: Vec<String>,
+    ) -> Claims {
+        Claims {
+            sub,
+            exp: (SystemTime::now() + valid_for)
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            publish,
+            subscribe,
+        }
+    }
+
+    /// returns true if the claims allow the action on the topic.
+    /// ```
+    /// use simple_pub_sub::acl::Action;
+    /// use simple_pub_sub::token::Claims;
+    /// let claims = Claims::new(
+    ///     "batch-job".to_string(),
+    ///     std::time::Duration::from_secs(60),
+    ///     vec!["jobs/+/result".to_string()],
+    ///     vec!["jobs/#".to_string()],
+    /// );
+    /// assert!(claims.is_allowed(Action::Publish, "jobs/42/result"));
+    /// assert!(!claims.is_allowed(Action::Publish, "jobs/42"));
+    /// assert!(claims.is_allowed(Action::Subscribe, "jobs/42"));
+    /// // a pattern must be covered by a claim.
+    /// assert!(claims.is_allowed(Action::Subscribe, "jobs/+/result"));
+    /// assert!(!claims.is_allowed(Action::Subscribe, "#"));
+    /// assert!(!claims.is_allowed(Action::Subscribe, "+/42"));
+    /// ```
+    pub fn is_allowed(&self, action: Action, topic: &str) -> bool {
+        let patterns = match action {
+            Action::Publish => &self.publish,
+            Action::Subscribe | Action::Unsubscribe | Action::Query => &self.subscribe,
+            // the tokens can not be used for the admin operations and the bridges.
+            Action::Admin | Action::Bridge => return false,
+        };
+        // the subscription patterns are covered only by the wider patterns.
+        patterns.iter().any(|pattern| topic_matches(pattern, topic))
+    }
+
+    /// returns the time left before the token expires.
+    pub fn expires_in(&self) -> Duration {
+        (UNIX_EPOCH + Duration::from_secs(self.exp))
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+/// Key used by the broker to verify the tokens.
+#[derive(Clone)]
+pub struct TokenVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl TokenVerifier {
+    /// reads the HMAC secret for the `HS256` tokens from the file.
+    pub fn from_secret_file(path: &str) -> Result<TokenVerifier> {
+        let secret = read_secret(path)?;
+        Ok(TokenVerifier::new(
+            DecodingKey::from_secret(&secret),
+            Algorithm::HS256,
+        ))
+    }
+
+    /// reads the Ed25519 public key (.pem) for the `EdDSA` tokens.
+    pub fn from_public_key_file(path: &str) -> Result<TokenVerifier> {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Error while reading the public key: {path}"))?;
+        Ok(TokenVerifier::new(
+            DecodingKey::from_ed_pem(&pem)?,
+            Algorithm::EdDSA,
+        ))
+    }
+
+    fn new(key: DecodingKey, algorithm: Algorithm) -> TokenVerifier {
+        let mut validation = Validation::new(algorithm);
+        // the connection is closed exactly at the expiry, accept no leeway.
+        validation.leeway = 0;
+        TokenVerifier { key, validation }
+    }
+
+    /// verifies the signature and the expiry, returns the claims.
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        Ok(jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)?.claims)
+    }
+}
+
+/// signs the claims with the HMAC secret in the file.
+/// ```
+/// use simple_pub_sub::token::{mint_with_secret, Claims, TokenVerifier};
+/// let path = "/tmp/simple-pub-sub-doc.secret";
+/// std::fs::write(path, "secret").unwrap();
+/// let claims = Claims::new(
+///     "batch-job".to_string(),
+///     std::time::Duration::from_secs(60),
+///     vec!["jobs/#".to_string()],
+///     vec![],
+/// );
+/// let token = mint_with_secret(path, &claims).unwrap();
+/// let verifier = TokenVerifier::from_secret_file(path).unwrap();
+/// assert_eq!(verifier.verify(&token).unwrap(), claims);
+/// ```
+pub fn mint_with_secret(path: &str, claims: &Claims) -> Result<String> {
+    let secret = read_secret(path)?;
+    Ok(jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        claims,
+        &EncodingKey::from_secret(&secret),
+    )?)
+}
+
+/// signs the claims with the Ed25519 private key (.pem, PKCS#8).
+pub fn mint_with_private_key(path: &str, claims: &Claims) -> Result<String> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Error while reading the private key: {path}"))?;
+    Ok(jsonwebtoken::encode(
+        &Header::new(Algorithm::EdDSA),
+        claims,
+        &EncodingKey::from_ed_pem(&pem)?,
+    )?)
+}
+
+/// reads the secret, the trailing newline is not a part of the secret.
+fn read_secret(path: &str) -> Result<Vec<u8>> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("Error while reading the token secret: {path}"))?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        anyhow::bail!("The token secret is empty: {path}");
+    }
+    Ok(secret.as_bytes().to_vec())
+}
@@ -1,40 +1,139 @@
+use crate::acl::{is_pattern, topic_matches};
+use crate::admin::AdminRequest;
+use crate::memory::{self, MemoryAccountant, Reservation};
 use crate::message::Msg;
+use crate::metrics::metrics;
+use crate::spans::message_span;
 use crate::PktType;
-use log::{error, info, trace};
-use serde_json::json;
-use std::collections::{BTreeMap, HashMap};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Instant;
 use tokio;
 use tokio::sync::broadcast::Sender;
+use tracing::{debug, error, info, trace, warn};
 
-type ClientChannelMap = HashMap<String, Sender<Msg>>;
+type ClientChannelMap = HashMap<String, Sender<Envelope>>;
+
+/// Message routed by the broker, with the metadata that is not part of the packet.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub(crate) msg: Msg,
+    /// channel of the client, for the subscribed topics and the responses.
+    pub(crate) channel: Option<Sender<Envelope>>,
+    /// the authenticated identity of the client, if any.
+    pub(crate) identity: Option<String>,
+    /// time the message was received by the broker, for the delivery latency.
+    pub(crate) received_at: Option<Instant>,
+    /// the message was published by a bridge, it is not forwarded to the other bridges.
+    pub(crate) bridged: bool,
+    /// subject for the replies of the NATS clients.
+    pub(crate) reply_to: Option<String>,
+    /// memory reserved for the message, released when the last copy sharing it is dropped.
+    pub(crate) reservation: Option<Arc<Reservation>>,
+}
+
+impl Envelope {
+    pub(crate) fn new(msg: Msg) -> Envelope {
+        Envelope {
+            msg,
+            channel: None,
+            identity: None,
+            received_at: None,
+            bridged: false,
+            reply_to: None,
+            reservation: None,
+        }
+    }
+
+    /// sets the channel of the client.
+    pub(crate) fn channel(&mut self, channel: Sender<Envelope>) {
+        self.channel = Some(channel);
+    }
+
+    /// sets the authenticated identity of the client.
+    pub(crate) fn identity(&mut self, identity: String) {
+        self.identity = Some(identity);
+    }
+}
+
+impl From<Msg> for Envelope {
+    fn from(msg: Msg) -> Envelope {
+        Envelope::new(msg)
+    }
+}
+
+impl Deref for Envelope {
+    type Target = Msg;
+
+    fn deref(&self) -> &Msg {
+        &self.msg
+    }
+}
+
+impl DerefMut for Envelope {
+    fn deref_mut(&mut self) -> &mut Msg {
+        &mut self.msg
+    }
+}
 
 /// The `TopicMap` struct is used to store the channels for a given topic.
 #[derive(Debug, Clone)]
 pub struct TopicMap {
     pub map: BTreeMap<String, ClientChannelMap>,
+    /// identities of the subscribed clients, by client id.
+    pub identities: HashMap<String, String>,
+    /// subscribed patterns with `+` or `#`, matched against the published topics.
+    pub patterns: BTreeSet<String>,
+    /// memory held by the messages queued for the subscribers.
+    memory: Arc<MemoryAccountant>,
 }
 impl TopicMap {
-    /// Returns the number of connected clients for a given topic.
+    /// Returns the number of connected clients for a given topic,
+    /// with the memory held by the broker.
     fn query(&self, topic: String) -> String {
         let v: Vec<String>;
+        let memory = json!({
+            "used": self.memory.used(),
+            "limit": self.memory.limits().global,
+        });
         if topic == "*" {
             v = self
                 .map
                 .iter()
                 .map(|(k, v)| format!("{}: {}", k, v.len()))
                 .collect();
-            json!({topic: v}).to_string()
+            json!({topic: v, "memory": memory}).to_string()
         } else {
-            let clients = match self.map.get(&topic) {
-                Some(clients) => clients.len(),
-                None => 0,
+            let (clients, identities) = match self.map.get(&topic) {
+                Some(clients) => (
+                    clients.len(),
+                    clients
+                        .keys()
+                        .filter_map(|client_id| self.identities.get(client_id))
+                        .collect::<Vec<_>>(),
+                ),
+                None => (0, vec![]),
             };
             v = vec![format!("{}", clients)];
-            json!({topic: v}).to_string()
+            json!({topic: v, "identities": identities, "memory": memory}).to_string()
         }
     }
     /// Adds a channel to the map.
-    fn add_channel(&mut self, topic: String, client_id: String, channel: Sender<Msg>) {
+    fn add_channel(
+        &mut self,
+        topic: String,
+        client_id: String,
+        identity: Option<String>,
+        channel: Sender<Envelope>,
+    ) {
+        if let Some(identity) = identity {
+            self.identities.insert(client_id.clone(), identity);
+        }
+        if is_pattern(&topic) {
+            self.patterns.insert(topic.clone());
+        }
         if self.map.contains_key(&topic.clone()) {
             if let Some(channels) = self.map.get_mut(&topic.clone()) {
                 channels.entry(client_id).or_insert(channel);
@@ -51,120 +150,217 @@ impl TopicMap {
         if self.map.contains_key(&topic) {
             if let Some(channels) = self.map.get_mut(&topic) {
                 channels.remove(&client_id);
+                // the pattern is no longer matched once nobody holds it.
+                if channels.is_empty() {
+                    self.patterns.remove(&topic);
+                }
+            }
+            if !self.map.values().any(|c| c.contains_key(&client_id)) {
+                self.identities.remove(&client_id);
             }
             trace!("Channels: {:?}", self.map);
         }
     }
 
-    /// Publishes the message to the channels.
-    async fn publish(&mut self, msg: Msg) {
-        if !self.map.contains_key(&msg.topic) {
-            return;
+    /// runs the admin request forwarded by the broker.
+    fn admin(&mut self, request: AdminRequest) -> Value {
+        match request {
+            AdminRequest::Clients => {
+                let mut subscriptions: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+                for (topic, channels) in &self.map {
+                    for client_id in channels.keys() {
+                        subscriptions.entry(client_id).or_default().push(topic);
+                    }
+                }
+                json!(subscriptions)
+            }
+            AdminRequest::Unsubscribe { client_id, topic } => {
+                let subscribed = self
+                    .map
+                    .get(&topic)
+                    .is_some_and(|c| c.contains_key(&client_id));
+                self.remove_channel(topic, client_id);
+                json!({ "unsubscribed": subscribed })
+            }
+            AdminRequest::DropTopic { topic } => {
+                let channels = self.map.remove(&topic).unwrap_or_default();
+                self.patterns.remove(&topic);
+                for client_id in channels.keys() {
+                    if !self.map.values().any(|c| c.contains_key(client_id)) {
+                        self.identities.remove(client_id);
+                    }
+                }
+                json!({ "dropped": channels.len() })
+            }
+            // the clients are disconnected by the broker.
+            AdminRequest::Kick { .. } => Value::Null,
         }
-        let topic = msg.topic.clone();
+    }
+
+    /// updates the topic and subscription gauges.
+    fn update_metrics(&self) {
+        let subscribed = self.map.values().filter(|c| !c.is_empty());
+        metrics().topics.set(subscribed.clone().count() as i64);
+        metrics()
+            .subscriptions
+            .set(subscribed.map(|c| c.len()).sum::<usize>() as i64);
+    }
 
-        if let Some(channels) = self.map.get_mut(&topic.clone()) {
-            let dead_channels = channels
+    /// Publishes the message to the channels of the topic and of the matching patterns,
+    /// a client subscribed to more than one of them receives the message once.
+    fn publish(&mut self, mut msg: Envelope) {
+        metrics().published(&msg.topic, msg.message.len());
+        // the publisher is no longer charged once the message left the queue.
+        msg.reservation = None;
+        let topics = std::iter::once(&msg.topic).chain(
+            self.patterns
                 .iter()
-                .map(|(client_id, channel)| {
-                    info!("Sending msg to the {}", client_id);
-                    match channel.send(msg.clone()) {
-                        Ok(_n) => "".to_string(),
-                        Err(e) => {
-                            error!(
-                                "Error occurred: {} while sending the message to the channel {}",
-                                e.to_string(),
-                                client_id
-                            );
-                            error!("Cleaning up");
-                            client_id.clone()
-                        }
+                .filter(|pattern| topic_matches(pattern, &msg.topic)),
+        );
+        let mut subscribers: HashMap<&String, (&String, &Sender<Envelope>)> = HashMap::new();
+        for topic in topics {
+            if let Some(channels) = self.map.get(topic) {
+                for (client_id, channel) in channels {
+                    subscribers.entry(client_id).or_insert((topic, channel));
+                }
+            }
+        }
+        if subscribers.is_empty() {
+            metrics().dropped("no_subscribers", 1);
+            return;
+        }
+
+        let size = memory::size(&msg);
+        let dead_channels = subscribers
+            .into_iter()
+            .filter_map(|(client_id, (topic, channel))| {
+                debug!("Sending msg to the {}", client_id);
+                // the copy queued for the subscriber is charged to the subscriber.
+                let Some(reservation) = self.memory.reserve(client_id, size) else {
+                    warn!(
+                        "Dropped the message for the client {}, over the memory limit",
+                        client_id
+                    );
+                    metrics().dropped("memory_limit", 1);
+                    return None;
+                };
+                let mut copy = msg.clone();
+                copy.reservation = Some(Arc::new(reservation));
+                match channel.send(copy) {
+                    Ok(_n) => None,
+                    Err(e) => {
+                        error!(
+                            "Error occurred: {} while sending the message to the channel {}",
+                            e, client_id
+                        );
+                        Some((topic.clone(), client_id.clone()))
                     }
-                })
-                .collect::<Vec<_>>();
-            info!("Dead_channels: {:?}", dead_channels);
-            let _ = dead_channels
-                .iter()
-                .map(|client_id| {
-                    self.remove_channel(topic.clone(), client_id.clone());
-                })
-                .collect::<Vec<_>>();
+                }
+            })
+            .collect::<Vec<_>>();
+        for (topic, client_id) in dead_channels {
+            metrics().dropped("dead_channel", 1);
+            self.remove_channel(topic, client_id);
         }
+        self.update_metrics();
     }
 }
 
 /// returns a global broadcaster.
-pub(crate) fn get_global_broadcaster(capacity: usize) -> tokio::sync::broadcast::Sender<Msg> {
+pub(crate) fn get_global_broadcaster(capacity: usize) -> Sender<Envelope> {
     info!("Creating broadcast channel");
     let (glob_tx, _) = tokio::sync::broadcast::channel(capacity);
     glob_tx
 }
 
 /// Handles the incoming and out-going messages for each topic.
-pub(crate) async fn topic_manager(chan: Sender<Msg>) {
+pub(crate) async fn topic_manager(chan: Sender<Envelope>, memory: Arc<MemoryAccountant>) {
     // NOTE: this MSG must always have the client_id and channel
     // it should not be None
     let mut map: TopicMap = TopicMap {
         map: BTreeMap::new(),
+        identities: HashMap::new(),
+        patterns: BTreeSet::new(),
+        memory,
     };
     let mut rx = chan.subscribe();
     loop {
         match rx.recv().await {
             Ok(msg) => {
                 if !msg.topic.is_empty() {
-                    info!("Topic received: {}", msg.topic);
+                    let _span = message_span(&msg).entered();
+                    debug!("Message received by the topic manager");
                     match msg.header.pkt_type {
                         PktType::PUBLISH => {
                             trace!("Publishing to map:{:?}", map);
-                            map.publish(msg).await;
+                            map.publish(msg);
                         }
                         PktType::SUBSCRIBE => {
                             map.add_channel(
-                                msg.topic,
-                                msg.client_id.unwrap(),
+                                msg.msg.topic,
+                                msg.msg.client_id.unwrap(),
+                                msg.identity,
                                 msg.channel.unwrap(),
                             );
+                            map.update_metrics();
                             trace!("Map: {:?}", map);
                         }
                         PktType::UNSUBSCRIBE => {
-                            info!("Unsubscribing:");
-                            map.remove_channel(msg.topic, msg.client_id.unwrap());
+                            map.remove_channel(msg.msg.topic, msg.msg.client_id.unwrap());
+                            map.update_metrics();
                         }
                         PktType::QUERY => {
-                            info!("Querying");
                             let query_resp = map.query(msg.topic.clone());
-                            info!("Query_resp: {}", query_resp.clone());
+                            debug!("Query response: {}", query_resp);
                             let resp_msg = match msg.response_msg(query_resp.into_bytes()) {
-                                Ok(rm) => rm,
+                                Ok(rm) => Envelope::new(rm),
                                 Err(e) => {
                                     error!(
                                         "Error while getting the response to the query message: {}",
-                                        e.to_string()
+                                        e
                                     );
                                     continue;
                                 }
                             };
-                            info!("Generated query resp: {:?}", resp_msg);
                             match msg.channel.unwrap().send(resp_msg) {
                                 Ok(n) => n,
                                 Err(e) => {
-                                    error!(
-                                        "Error while sending the query response: {}",
-                                        e.to_string()
-                                    );
+                                    error!("Error while sending the query response: {}", e);
                                     0
                                 }
                             };
                         }
+                        PktType::ADMIN => {
+                            let request: AdminRequest = match serde_json::from_slice(&msg.message) {
+                                Ok(request) => request,
+                                Err(e) => {
+                                    error!("Invalid admin request: {}", e);
+                                    continue;
+                                }
+                            };
+                            info!("Admin request: {:?}", request);
+                            let resp = map.admin(request);
+                            map.update_metrics();
+                            match msg.response_msg(resp.to_string().into_bytes()) {
+                                Ok(resp_msg) => {
+                                    if let Some(channel) = msg.channel {
+                                        let _ = channel.send(Envelope::new(resp_msg));
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("Error while generating the admin response: {}", e);
+                                }
+                            }
+                        }
                         _ => {}
                     };
                 }
             }
             Err(e) => {
-                error!(
-                    "Error occurred while receiving the topic: {}",
-                    e.to_string()
-                );
+                if let tokio::sync::broadcast::error::RecvError::Lagged(n) = e {
+                    metrics().dropped("broker_lagged", n);
+                }
+                error!("Error occurred while receiving the topic: {}", e);
                 // "".to_string()
             }
         };
@@ -0,0 +1,115 @@
+mod common;
+#[cfg(test)]
+mod tests {
+
+    use crate::common;
+    use simple_pub_sub::client::Client;
+    use simple_pub_sub::server::ServerHandle;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-acl-test.passwd";
+    const ACL: &str = "/tmp/simple-pub-sub-test.acl";
+    const AUDIT_LOG: &str = "/tmp/simple-pub-sub-test-audit.log";
+
+    async fn client(server: &ServerHandle, username: &str) -> Client {
+        let mut client = common::password_client(server, username, "secret");
+        client.connect().await.unwrap();
+        client
+    }
+
+    async fn allowed_publish(server: &ServerHandle) {
+        let mut client = client(server, "billing-svc").await;
+        let result = client
+            .publish("billing/invoices".to_string(), b"invoice".to_vec())
+            .await;
+        assert!(result.is_ok());
+    }
+
+    async fn denied_publish(server: &ServerHandle) {
+        let mut client = client(server, "alice").await;
+        let result = client
+            .publish("billing/invoices".to_string(), b"invoice".to_vec())
+            .await;
+        assert!(result.is_err());
+
+        // the connection stays open after a denied request.
+        let result = client
+            .publish("alice/notes".to_string(), b"note".to_vec())
+            .await;
+        assert!(result.is_ok());
+    }
+
+    async fn denied_wildcard_subscribe(server: &ServerHandle) {
+        let mut client = client(server, "alice").await;
+        // the patterns including the denied topics are denied.
+        for pattern in ["#", "+/invoices"] {
+            client.subscribe(pattern.to_string()).await.unwrap();
+            let result = client.read_message().await;
+            assert!(result.is_err(), "{pattern}");
+        }
+        client.subscribe("alice/+".to_string()).await.unwrap();
+        assert!(client.read_message().await.is_ok());
+    }
+
+    async fn filtered_query_all(server: &ServerHandle) {
+        let mut billing = client(server, "billing-svc").await;
+        billing
+            .subscribe("billing/invoices".to_string())
+            .await
+            .unwrap();
+        billing.read_message().await.unwrap();
+        let mut client = client(server, "alice").await;
+        client.subscribe("alice/inbox".to_string()).await.unwrap();
+        client.read_message().await.unwrap();
+
+        // the query of all the topics lists only the topics alice can query.
+        let response = client.query("*".to_string()).await.unwrap();
+        assert!(response.contains("alice/inbox: 1"), "{response}");
+        assert!(!response.contains("billing"), "{response}");
+        let response = billing.query("*".to_string()).await.unwrap();
+        assert!(response.contains("billing/invoices: 1"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_all() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        let _ = std::fs::remove_file(AUDIT_LOG);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        simple_pub_sub::auth::add_user(CREDENTIALS, "billing-svc", "secret").unwrap();
+        std::fs::write(
+            ACL,
+            "allow billing-svc publish,subscribe,query billing/#\n\
+             deny * publish billing/#\n\
+             deny * query billing/#\n\
+             deny * subscribe billing/#\n\
+             allow * all #\n",
+        )
+        .unwrap();
+
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            acl: Some(ACL.to_string()),
+            audit_log: Some(AUDIT_LOG.to_string()),
+            ..Default::default()
+        })
+        .await;
+        allowed_publish(&server).await;
+        denied_publish(&server).await;
+        denied_wildcard_subscribe(&server).await;
+
+        let audit = std::fs::read_to_string(AUDIT_LOG).unwrap();
+        let entries: Vec<serde_json::Value> = audit
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["identity"], "alice");
+        assert_eq!(entries[0]["action"], "publish");
+        assert_eq!(entries[0]["topic"], "billing/invoices");
+        assert_eq!(entries[0]["result"], "denied");
+        assert_eq!(entries[1]["action"], "subscribe");
+        assert_eq!(entries[1]["topic"], "#");
+        assert_eq!(entries[2]["topic"], "+/invoices");
+        filtered_query_all(&server).await;
+        server.shutdown().await.unwrap();
+    }
+}
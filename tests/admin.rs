@@ -0,0 +1,120 @@
+mod common;
+use tokio::time::{sleep, Duration};
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::admin::AdminRequest;
+    use simple_pub_sub::client::Client;
+    use simple_pub_sub::server::ServerHandle;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-admin-test.passwd";
+    const ACL: &str = "/tmp/simple-pub-sub-admin-test.acl";
+
+    async fn client(server: &ServerHandle, username: &str) -> Client {
+        let mut client = common::password_client(server, username, "secret");
+        client.connect().await.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        for user in ["alice", "bob", "ops"] {
+            simple_pub_sub::auth::add_user(CREDENTIALS, user, "secret").unwrap();
+        }
+        std::fs::write(ACL, "allow ops admin #\nallow * all #\n").unwrap();
+
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            acl: Some(ACL.to_string()),
+            ..Default::default()
+        })
+        .await;
+
+        let mut alice = client(&server, "alice").await;
+        alice.subscribe("news".to_string()).await.unwrap();
+        let mut bob = client(&server, "bob").await;
+        bob.subscribe("news".to_string()).await.unwrap();
+        bob.subscribe("sports".to_string()).await.unwrap();
+
+        // `all` does not allow the admin operations.
+        let error = client(&server, "alice").await.clients().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Not authorized to admin 'clients'"));
+        sleep(Duration::from_millis(200)).await;
+
+        let mut ops = client(&server, "ops").await;
+        let clients = ops.clients().await.unwrap();
+        assert_eq!(clients.len(), 3);
+        let alice_info = clients
+            .iter()
+            .find(|c| c.identity.as_deref() == Some("alice"))
+            .unwrap();
+        assert_eq!(alice_info.subscriptions, vec!["news"]);
+        assert!(alice_info.address.starts_with("127.0.0.1:") || alice_info.address.contains("::1"));
+        let bob_info = clients
+            .iter()
+            .find(|c| c.identity.as_deref() == Some("bob"))
+            .unwrap();
+        assert_eq!(bob_info.subscriptions, vec!["news", "sports"]);
+
+        let resp = ops
+            .admin(AdminRequest::Unsubscribe {
+                client_id: bob_info.id.clone(),
+                topic: "sports".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp, r#"{"unsubscribed":true}"#);
+
+        let resp = ops
+            .admin(AdminRequest::DropTopic {
+                topic: "news".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp, r#"{"dropped":2}"#);
+        let clients = ops.clients().await.unwrap();
+        assert!(clients.iter().all(|c| c.subscriptions.is_empty()));
+
+        ops.admin(AdminRequest::Kick {
+            client_id: alice_info.id.clone(),
+        })
+        .await
+        .unwrap();
+        let error = loop {
+            // skip the subscription ack.
+            if let Err(e) = alice.read_message().await {
+                break e;
+            }
+        };
+        assert!(error.to_string().contains("Disconnected by the admin"));
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(ops.clients().await.unwrap().len(), 2);
+
+        let result = ops
+            .admin(AdminRequest::Kick {
+                client_id: "unknown".to_string(),
+            })
+            .await;
+        assert!(result.is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_without_acl() {
+        let broker = simple_pub_sub::embedded::Broker::start(1024, &Default::default())
+            .await
+            .unwrap();
+        let mut client = broker.client();
+        client.connect().await.unwrap();
+
+        // the anonymous clients can not use the admin operations without an acl.
+        let error = client.clients().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Not authorized to admin 'clients'"));
+    }
+}
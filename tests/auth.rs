@@ -0,0 +1,98 @@
+mod common;
+#[cfg(test)]
+mod tests {
+
+    use crate::common;
+    use simple_pub_sub::auth::Credentials;
+    use simple_pub_sub::client::Client;
+    use simple_pub_sub::server::ServerHandle;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-test.passwd";
+
+    fn client(server: &ServerHandle, credentials: Option<Credentials>) -> Client {
+        let mut client = common::client(server);
+        if let Some(credentials) = credentials {
+            client.credentials(credentials);
+        }
+        client
+    }
+
+    fn password(password: &str) -> Option<Credentials> {
+        Some(Credentials::Password {
+            username: "alice".to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    async fn authenticated_publish(server: &ServerHandle) {
+        let mut client = client(server, password("secret"));
+        client.connect().await.unwrap();
+        let result = client
+            .publish(
+                "abc".to_string(),
+                "test message".to_string().into_bytes().to_vec(),
+            )
+            .await;
+        assert!(result.is_ok());
+    }
+
+    async fn invalid_password_rejected(server: &ServerHandle) {
+        let mut client = client(server, password("wrong"));
+        assert!(client.connect().await.is_err());
+    }
+
+    async fn unauthenticated_publish_rejected(server: &ServerHandle) {
+        let mut client = client(server, None);
+        client.connect().await.unwrap();
+        let result = client
+            .publish(
+                "abc".to_string(),
+                "test message".to_string().into_bytes().to_vec(),
+            )
+            .await;
+        assert!(result.is_err());
+    }
+
+    async fn invalid_connect_rejected(server: &ServerHandle) {
+        use simple_pub_sub::message::Msg;
+        use simple_pub_sub::PktType;
+        let mut client = client(server, password("secret"));
+        client.connect().await.unwrap();
+        let msg = Msg::new(
+            PktType::CONNECT,
+            "".to_string(),
+            Some(br#"{"username":"#.to_vec()),
+        );
+        let error = client.post(msg).await.unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid connect request"),
+            "{error}"
+        );
+        // the connection is closed after the malformed request.
+        let result = client
+            .publish("abc".to_string(), b"test message".to_vec())
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_all() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        assert_eq!(
+            simple_pub_sub::auth::list_users(CREDENTIALS).unwrap(),
+            vec!["alice".to_string()]
+        );
+
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            ..Default::default()
+        })
+        .await;
+        authenticated_publish(&server).await;
+        invalid_password_rejected(&server).await;
+        unauthenticated_publish_rejected(&server).await;
+        invalid_connect_rejected(&server).await;
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+mod common;
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::bench::{Bench, BenchReport};
+    use simple_pub_sub::client::PubSubClient;
+
+    #[tokio::test]
+    async fn test_bench() {
+        let server = common::start_server(Default::default()).await;
+        let bench = Bench {
+            client_type: PubSubClient::Tcp(common::tcp_client(&server)),
+            credentials: None,
+            publishers: 2,
+            subscribers: 2,
+            topics: 2,
+            message_size: 64,
+            rate: Some(200),
+            duration: Duration::from_secs(1),
+        };
+        let report = bench.run().await.unwrap();
+        println!("{report}");
+
+        // every message is received by the single subscriber of its topic.
+        assert!(
+            report.published > 150 && report.published <= 202,
+            "{report:?}"
+        );
+        assert_eq!(report.received, report.published);
+        assert!(report.msgs_per_sec > 0.0);
+        assert!(report.latency.p50 <= report.latency.p99);
+        assert!(report.latency.p99 <= report.latency.p999);
+        assert!(report.latency.p999 <= report.latency.max);
+
+        // the JSON report can be read back.
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<BenchReport>(&json).unwrap(), report);
+        server.shutdown().await.unwrap();
+    }
+}
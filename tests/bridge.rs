@@ -0,0 +1,288 @@
+mod common;
+use tokio::time::{sleep, timeout, Duration};
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::auth::Credentials;
+    use simple_pub_sub::bridge::{Bridge, BridgeTopic, Direction, Endpoint};
+    use simple_pub_sub::client::{Client, PubSubClient};
+    use simple_pub_sub::embedded::Broker;
+    use simple_pub_sub::PktType;
+    use std::sync::OnceLock;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-bridge-test.passwd";
+    const ACL: &str = "/tmp/simple-pub-sub-bridge-test.acl";
+
+    async fn broker() -> Broker {
+        Broker::start(1024, &Default::default()).await.unwrap()
+    }
+
+    /// broker with the credentials and the acl, only `bridge` can connect as a bridge.
+    async fn secured_broker() -> Broker {
+        static FILES: OnceLock<()> = OnceLock::new();
+        FILES.get_or_init(|| {
+            let _ = std::fs::remove_file(CREDENTIALS);
+            for username in ["bridge", "reader", "alice"] {
+                simple_pub_sub::auth::add_user(CREDENTIALS, username, "secret").unwrap();
+            }
+            std::fs::write(ACL, "allow bridge bridge #\nallow * all #\n").unwrap();
+        });
+        let config = simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            acl: Some(ACL.to_string()),
+            ..Default::default()
+        };
+        Broker::start(1024, &config).await.unwrap()
+    }
+
+    fn credentials(username: &str) -> Credentials {
+        Credentials::Password {
+            username: username.to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    fn endpoint(broker: &Broker) -> Endpoint {
+        Endpoint {
+            client_type: PubSubClient::Memory(broker.clone()),
+            credentials: Some(credentials("bridge")),
+        }
+    }
+
+    fn start_bridge(local: Endpoint, remote: Endpoint, topics: &[(&str, Direction)]) {
+        let bridge = Bridge {
+            local,
+            remote,
+            topics: topics
+                .iter()
+                .map(|(pattern, direction)| BridgeTopic {
+                    pattern: pattern.to_string(),
+                    direction: *direction,
+                })
+                .collect(),
+            max_backoff: Duration::from_millis(500),
+        };
+        tokio::spawn(async move { bridge.run().await });
+    }
+
+    /// connects, the credentials are ignored by the brokers without authentication.
+    async fn connect(client_type: PubSubClient) -> Client {
+        let mut client = Client::new(client_type);
+        client.credentials(credentials("reader"));
+        client.connect().await.unwrap();
+        client
+    }
+
+    async fn subscriber(client_type: PubSubClient, topic: &str) -> Client {
+        let mut client = connect(client_type).await;
+        client.subscribe(topic.to_string()).await.unwrap();
+        let ack = client.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+        client
+    }
+
+    /// waits until the bridge subscribed to the pattern.
+    async fn wait_subscribed(client_type: PubSubClient, pattern: &str) {
+        let mut client = connect(client_type).await;
+        let subscribed = format!("\"{pattern}\":[\"1\"]");
+        timeout(Duration::from_secs(10), async {
+            while !client
+                .query(pattern.to_string())
+                .await
+                .unwrap()
+                .contains(&subscribed)
+            {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn next_publish(client: &mut Client) -> (String, Vec<u8>) {
+        let msg = timeout(Duration::from_secs(5), client.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.header.pkt_type, PktType::PUBLISH);
+        (msg.topic, msg.message)
+    }
+
+    #[tokio::test]
+    async fn forward_patterns() {
+        let (local, remote) = (secured_broker().await, secured_broker().await);
+        start_bridge(
+            endpoint(&local),
+            endpoint(&remote),
+            &[("sensors/#", Direction::Out), ("commands/+", Direction::In)],
+        );
+        wait_subscribed(PubSubClient::Memory(local.clone()), "sensors/#").await;
+        wait_subscribed(PubSubClient::Memory(remote.clone()), "commands/+").await;
+
+        let mut remote_sub = subscriber(PubSubClient::Memory(remote.clone()), "sensors/#").await;
+        let mut local_sub = subscriber(PubSubClient::Memory(local.clone()), "commands/+").await;
+
+        let mut local_pub = connect(PubSubClient::Memory(local.clone())).await;
+        local_pub
+            .publish("sensors/kitchen/temp".to_string(), b"21.5".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            next_publish(&mut remote_sub).await,
+            ("sensors/kitchen/temp".to_string(), b"21.5".to_vec())
+        );
+
+        let mut remote_pub = connect(PubSubClient::Memory(remote.clone())).await;
+        remote_pub
+            .publish("commands/restart".to_string(), b"now".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            next_publish(&mut local_sub).await,
+            ("commands/restart".to_string(), b"now".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn no_loops() {
+        let (local, remote) = (secured_broker().await, secured_broker().await);
+        start_bridge(
+            endpoint(&local),
+            endpoint(&remote),
+            &[("shared/#", Direction::Both)],
+        );
+        wait_subscribed(PubSubClient::Memory(local.clone()), "shared/#").await;
+        wait_subscribed(PubSubClient::Memory(remote.clone()), "shared/#").await;
+
+        let mut local_sub = subscriber(PubSubClient::Memory(local.clone()), "shared/#").await;
+        let mut remote_sub = subscriber(PubSubClient::Memory(remote.clone()), "shared/#").await;
+
+        let mut local_pub = connect(PubSubClient::Memory(local.clone())).await;
+        local_pub
+            .publish("shared/1".to_string(), b"first".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next_publish(&mut remote_sub).await.1, b"first");
+        local_pub
+            .publish("shared/2".to_string(), b"second".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next_publish(&mut remote_sub).await.1, b"second");
+
+        // the first message did not come back to the local broker.
+        assert_eq!(next_publish(&mut local_sub).await.1, b"first");
+        assert_eq!(next_publish(&mut local_sub).await.1, b"second");
+    }
+
+    #[tokio::test]
+    async fn no_loops_unauthenticated() {
+        let (local, remote) = (broker().await, broker().await);
+        start_bridge(
+            endpoint(&local),
+            endpoint(&remote),
+            &[("shared/#", Direction::Both)],
+        );
+        wait_subscribed(PubSubClient::Memory(local.clone()), "shared/#").await;
+
+        let mut local_sub = subscriber(PubSubClient::Memory(local.clone()), "shared/#").await;
+        let mut remote_sub = subscriber(PubSubClient::Memory(remote.clone()), "shared/#").await;
+
+        // the brokers did not accept the bridge, only the outgoing topics are forwarded.
+        let mut remote_pub = connect(PubSubClient::Memory(remote.clone())).await;
+        remote_pub
+            .publish("shared/remote".to_string(), b"stays".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next_publish(&mut remote_sub).await.1, b"stays");
+
+        let mut local_pub = connect(PubSubClient::Memory(local.clone())).await;
+        local_pub
+            .publish("shared/1".to_string(), b"first".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next_publish(&mut remote_sub).await.1, b"first");
+        assert_eq!(next_publish(&mut local_sub).await.1, b"first");
+
+        // the message did not come back.
+        assert!(
+            timeout(Duration::from_millis(300), local_sub.read_message())
+                .await
+                .is_err()
+        );
+        assert!(
+            timeout(Duration::from_millis(300), remote_sub.read_message())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn bridge_not_allowed() {
+        let (local, remote) = (secured_broker().await, secured_broker().await);
+        start_bridge(
+            endpoint(&local),
+            endpoint(&remote),
+            &[("shared/#", Direction::Out)],
+        );
+        wait_subscribed(PubSubClient::Memory(local.clone()), "shared/#").await;
+        let mut remote_sub = subscriber(PubSubClient::Memory(remote.clone()), "shared/#").await;
+
+        // a client the acl does not allow as a bridge is an ordinary client,
+        // its messages are still forwarded by the bridges.
+        let mut spoofed = local.client();
+        spoofed.credentials(credentials("alice"));
+        spoofed.bridge();
+        spoofed.connect().await.unwrap();
+        spoofed
+            .publish("shared/1".to_string(), b"forwarded".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next_publish(&mut remote_sub).await.1, b"forwarded");
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let local = broker().await;
+        let start_remote = |port: u16| {
+            common::start(simple_pub_sub::server::Tcp {
+                port,
+                ..common::tcp(Default::default())
+            })
+        };
+        // the remote broker is not running yet, its port is picked by a first run.
+        let server = start_remote(0).await;
+        let port = server.local_addr().port().unwrap();
+        server.shutdown().await.unwrap();
+        let remote = simple_pub_sub::client::PubSubTcpClient {
+            server: "127.0.0.1".to_string(),
+            port,
+            cert: None,
+            cert_password: None,
+            client_cert: None,
+            client_key: None,
+        };
+        start_bridge(
+            endpoint(&local),
+            Endpoint {
+                client_type: PubSubClient::Tcp(remote.clone()),
+                credentials: None,
+            },
+            &[("alerts", Direction::Out)],
+        );
+        sleep(Duration::from_millis(300)).await;
+
+        let server = start_remote(port).await;
+        let mut remote_sub = subscriber(PubSubClient::Tcp(remote), "alerts").await;
+        wait_subscribed(PubSubClient::Memory(local.clone()), "alerts").await;
+        // the destination is connected before the source subscribes.
+        let mut local_pub = local.client();
+        local_pub.connect().await.unwrap();
+        local_pub
+            .publish("alerts".to_string(), b"disk full".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next_publish(&mut remote_sub).await.1, b"disk full");
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! fixture shared by the integration tests: a broker on a free port and the
+//! clients of it, each test file keeps only its behaviour checks.
+#![allow(dead_code)]
+
+use simple_pub_sub::auth::Credentials;
+use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient};
+use simple_pub_sub::server::{BrokerConfig, ServerHandle, ServerTrait, Tcp};
+
+/// tcp server settings on a free port of the loopback interface.
+pub fn tcp(config: BrokerConfig) -> Tcp {
+    Tcp {
+        host: "127.0.0.1".to_string(),
+        port: 0,
+        cert: None,
+        cert_password: None,
+        key: None,
+        client_ca: None,
+        capacity: 1024,
+        config,
+    }
+}
+
+/// binds the server and waits until it is ready.
+pub async fn start(server: impl ServerTrait) -> ServerHandle {
+    let server = server.bind().await.unwrap();
+    server.ready().await.unwrap();
+    server
+}
+
+/// starts a tcp server on a free port with the given broker config.
+pub async fn start_server(config: BrokerConfig) -> ServerHandle {
+    start(tcp(config)).await
+}
+
+/// tcp client settings for the server.
+pub fn tcp_client(server: &ServerHandle) -> PubSubTcpClient {
+    PubSubTcpClient {
+        server: "127.0.0.1".to_string(),
+        port: server.local_addr().port().unwrap(),
+        cert: None,
+        cert_password: None,
+        client_cert: None,
+        client_key: None,
+    }
+}
+
+/// tcp client of the server, not connected yet.
+pub fn client(server: &ServerHandle) -> Client {
+    Client::new(PubSubClient::Tcp(tcp_client(server)))
+}
+
+/// tcp client connected to the server.
+pub async fn connected_client(server: &ServerHandle) -> Client {
+    let mut client = client(server);
+    client.connect().await.unwrap();
+    client
+}
+
+/// tcp client of the server with the password credentials, not connected yet.
+pub fn password_client(server: &ServerHandle, username: &str, password: &str) -> Client {
+    let mut client = client(server);
+    client.credentials(Credentials::Password {
+        username: username.to_string(),
+        password: password.to_string(),
+    });
+    client
+}
@@ -0,0 +1,166 @@
+#[cfg(test)]
+mod tests {
+
+    use simple_pub_sub::auth::Credentials;
+    use simple_pub_sub::embedded::Broker;
+    use simple_pub_sub::PktType;
+    use std::time::Duration;
+
+    async fn subscriber(broker: &Broker, topic: &str) -> simple_pub_sub::client::Client {
+        let mut client = broker.client();
+        client.connect().await.unwrap();
+        client.subscribe(topic.to_string()).await.unwrap();
+        // the subscription is registered once it is acknowledged.
+        let ack = client.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+        client
+    }
+
+    #[tokio::test]
+    async fn publish_subscribe() {
+        let broker = Broker::start(1024, &Default::default()).await.unwrap();
+        let mut client_sub = subscriber(&broker, "abc").await;
+        let mut other_sub = subscriber(&broker, "xyz").await;
+
+        let mut client_pub = broker.client();
+        client_pub.connect().await.unwrap();
+        client_pub
+            .publish("abc".to_string(), b"test message".to_vec())
+            .await
+            .unwrap();
+        client_pub
+            .publish("xyz".to_string(), b"other message".to_vec())
+            .await
+            .unwrap();
+
+        let msg = client_sub.read_message().await.unwrap();
+        assert_eq!(msg.topic, "abc");
+        assert_eq!(msg.message, b"test message");
+        let msg = other_sub.read_message().await.unwrap();
+        assert_eq!(msg.topic, "xyz");
+        assert_eq!(msg.message, b"other message");
+
+        let resp = client_pub.query("abc".to_string()).await.unwrap();
+        assert!(resp.contains("\"abc\":[\"1\"]"));
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let broker = Broker::start(1024, &Default::default()).await.unwrap();
+        let mut client_sub = subscriber(&broker, "abc").await;
+
+        // the connected clients are disconnected, the new ones can not publish.
+        broker.clone().shutdown();
+        assert!(client_sub.read_message().await.is_err());
+        let mut client_pub = broker.client();
+        let _ = client_pub.connect().await;
+        assert!(client_pub
+            .publish("abc".to_string(), b"test message".to_vec())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_on_drop() {
+        let tasks = || {
+            tokio::runtime::Handle::current()
+                .metrics()
+                .num_alive_tasks()
+        };
+        let before = tasks();
+        let broker = Broker::start(1024, &Default::default()).await.unwrap();
+        let client_sub = subscriber(&broker, "abc").await;
+        assert!(tasks() > before);
+
+        // the broker stops once the handle and the clients holding a clone are dropped.
+        drop(broker);
+        drop(client_sub);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while tasks() > before {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn separate_brokers() {
+        let broker = Broker::start(1024, &Default::default()).await.unwrap();
+        let other = Broker::start(1024, &Default::default()).await.unwrap();
+        let _client_sub = subscriber(&broker, "abc").await;
+
+        let mut client = other.client();
+        client.connect().await.unwrap();
+        let resp = client.query("abc".to_string()).await.unwrap();
+        assert!(resp.contains("\"abc\":[\"0\"]"));
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        const CREDENTIALS: &str = "/tmp/simple-pub-sub-embedded-test.passwd";
+        let _ = std::fs::remove_file(CREDENTIALS);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        let config = simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            ..Default::default()
+        };
+        let broker = Broker::start(1024, &config).await.unwrap();
+
+        let mut client = broker.client();
+        client.credentials(Credentials::Password {
+            username: "alice".to_string(),
+            password: "wrong".to_string(),
+        });
+        assert!(client.connect().await.is_err());
+
+        let mut client = broker.client();
+        client.credentials(Credentials::Password {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        });
+        client.connect().await.unwrap();
+        client
+            .publish("abc".to_string(), b"message".to_vec())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_topics() {
+        let broker = Broker::start(1024, &Default::default()).await.unwrap();
+        let mut client = broker.client();
+        client.connect().await.unwrap();
+        for pattern in ["a/#/b", "#/b"] {
+            client.subscribe(pattern.to_string()).await.unwrap();
+            let error = client.read_message().await.unwrap_err();
+            assert!(error.to_string().contains("Invalid topic"), "{pattern}");
+        }
+        // the published topics can not have wildcards.
+        for topic in ["a/+", "a/#", "+"] {
+            let result = client.publish(topic.to_string(), b"message".to_vec()).await;
+            assert!(result.is_err(), "{topic}");
+        }
+        client.subscribe("a/+/b".to_string()).await.unwrap();
+        assert!(client.read_message().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn wildcard_characters_in_names() {
+        let broker = Broker::start(1024, &Default::default()).await.unwrap();
+        let mut client = broker.client();
+        client.connect().await.unwrap();
+        // `+` and `#` inside a level are plain characters of the name.
+        for topic in ["c++", "chat#1", "a/b#"] {
+            client.subscribe(topic.to_string()).await.unwrap();
+            assert!(client.read_message().await.is_ok(), "{topic}");
+            client
+                .publish(topic.to_string(), b"message".to_vec())
+                .await
+                .unwrap();
+            let msg = client.read_message().await.unwrap();
+            assert_eq!(msg.topic, topic);
+            assert_eq!(msg.message, b"message");
+        }
+    }
+}
@@ -0,0 +1,143 @@
+mod common;
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use common::client;
+    use simple_pub_sub::client::HandlerError;
+    use simple_pub_sub::message::Msg;
+    use simple_pub_sub::server::ServerHandle;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, timeout};
+
+    async fn publish(server: &ServerHandle, messages: &[(&str, &str)]) {
+        let mut publisher = client(server);
+        publisher.connect().await.unwrap();
+        for (topic, message) in messages {
+            publisher
+                .publish(topic.to_string(), message.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn next<T>(received: &mut mpsc::UnboundedReceiver<T>) -> T {
+        timeout(Duration::from_secs(2), received.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let server = common::start_server(Default::default()).await;
+        let mut subscriber = client(&server);
+        let (tx, mut received) = mpsc::unbounded_channel();
+
+        // sync and async handlers, every handler matching the topic is called.
+        let pattern = tx.clone();
+        subscriber.on("a/+".to_string(), move |msg: Msg| {
+            let _ = pattern.send(format!("a/+ {}", msg.topic));
+        });
+        let topic = tx.clone();
+        subscriber.on("a/b".to_string(), move |msg: Msg| {
+            let topic = topic.clone();
+            async move {
+                sleep(Duration::from_millis(10)).await;
+                let _ = topic.send(format!("a/b {}", msg.topic));
+            }
+        });
+        subscriber.on("c".to_string(), move |msg: Msg| {
+            let _ = tx.send(format!("c {}", msg.topic));
+        });
+        let running = tokio::spawn(async move { subscriber.run().await });
+        sleep(Duration::from_millis(100)).await;
+
+        publish(&server, &[("a/b", "one"), ("a/d", "two"), ("c", "three")]).await;
+        let mut messages = vec![];
+        for _ in 0..4 {
+            messages.push(next(&mut received).await);
+        }
+        messages.sort();
+        assert_eq!(messages, ["a/+ a/b", "a/+ a/d", "a/b a/b", "c c"]);
+
+        // the loop ends with the connection.
+        server.shutdown().await.unwrap();
+        let result = timeout(Duration::from_secs(2), running).await.unwrap();
+        assert!(result.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency() {
+        let server = common::start_server(Default::default()).await;
+        let mut subscriber = client(&server);
+        subscriber.concurrency(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let (tx, mut received) = mpsc::unbounded_channel();
+        subscriber.on("jobs".to_string(), move |_: Msg| {
+            let running = running.clone();
+            let tx = tx.clone();
+            async move {
+                let at_once = running.fetch_add(1, Ordering::SeqCst) + 1;
+                sleep(Duration::from_millis(100)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                let _ = tx.send(at_once);
+            }
+        });
+        tokio::spawn(async move { subscriber.run().await });
+        sleep(Duration::from_millis(100)).await;
+
+        publish(&server, &[("jobs", "1"); 6]).await;
+        let mut at_once = vec![];
+        for _ in 0..6 {
+            at_once.push(next(&mut received).await);
+        }
+        assert_eq!(at_once.iter().max(), Some(&2), "{at_once:?}");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handler_errors() {
+        let server = common::start_server(Default::default()).await;
+        let mut subscriber = client(&server);
+        let (tx, mut errors) = mpsc::unbounded_channel();
+        subscriber.on_error(move |error: HandlerError| {
+            let _ = tx.send(error);
+        });
+        subscriber.on("orders/+".to_string(), |msg: Msg| {
+            String::from_utf8(msg.message).map(|_| ())
+        });
+        subscriber.on("panics".to_string(), |msg: Msg| async move {
+            assert!(msg.message.is_empty(), "handler panicked");
+        });
+        tokio::spawn(async move { subscriber.run().await });
+        sleep(Duration::from_millis(100)).await;
+
+        // the errors and the panics are reported, the loop keeps running.
+        let mut publisher = client(&server);
+        publisher.connect().await.unwrap();
+        publisher
+            .publish("orders/1".to_string(), vec![0xff])
+            .await
+            .unwrap();
+        let error = next(&mut errors).await;
+        assert_eq!(error.pattern, "orders/+");
+        assert_eq!(error.topic, "orders/1");
+        assert!(error.to_string().contains("utf-8"), "{error}");
+
+        publish(&server, &[("panics", "now")]).await;
+        let error = next(&mut errors).await;
+        assert_eq!(error.pattern, "panics");
+        assert!(error.to_string().contains("panic"), "{error}");
+
+        publisher
+            .publish("orders/2".to_string(), vec![0xfe])
+            .await
+            .unwrap();
+        assert_eq!(next(&mut errors).await.topic, "orders/2");
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+mod common;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Duration};
+
+/// sends a http request, returns the stream positioned after the request.
+async fn send_request(
+    http: SocketAddr,
+    path: &str,
+    method: &str,
+    auth: Option<&str>,
+    body: &str,
+) -> TcpStream {
+    let mut stream = TcpStream::connect(http).await.unwrap();
+    let mut request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    if let Some(auth) = auth {
+        request.push_str(&format!("Authorization: {auth}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream
+}
+
+/// returns the status line and the full response.
+async fn request(
+    http: SocketAddr,
+    path: &str,
+    method: &str,
+    auth: Option<&str>,
+    body: &str,
+) -> (String, String) {
+    let mut stream = send_request(http, path, method, auth, body).await;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response.lines().next().unwrap_or_default().to_string();
+    (status, response)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::server::ServerHandle;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-http-test.passwd";
+    const ACL: &str = "/tmp/simple-pub-sub-http-test.acl";
+    // alice:secret
+    const BASIC: &str = "Basic YWxpY2U6c2VjcmV0";
+    // ops:secret
+    const OPS: &str = "Basic b3BzOnNlY3JldA==";
+
+    async fn client(server: &ServerHandle) -> simple_pub_sub::client::Client {
+        let mut client = common::password_client(server, "alice", "secret");
+        client.connect().await.unwrap();
+        client
+    }
+
+    async fn unauthenticated(http: SocketAddr) {
+        let (status, _) = request(http, "/topics/orders", "POST", None, "order").await;
+        assert!(status.contains("401"));
+        let (status, _) = request(
+            http,
+            "/topics/orders",
+            "POST",
+            Some("Basic YWxpY2U6d3Jvbmc="),
+            "order",
+        )
+        .await;
+        assert!(status.contains("401"));
+    }
+
+    async fn publish_and_query(server: &ServerHandle) {
+        let http = server.listeners().http.unwrap();
+        let mut client_sub = client(server).await;
+        client_sub.subscribe("orders/eu".to_string()).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        let (status, _) = request(http, "/topics/orders/eu", "POST", Some(BASIC), "order 1").await;
+        assert!(status.contains("200"));
+
+        let msg = client_sub.read_message().await.unwrap();
+        // skip the subscription ack.
+        let msg = if msg.header.pkt_type == simple_pub_sub::PktType::PUBLISH {
+            msg
+        } else {
+            client_sub.read_message().await.unwrap()
+        };
+        assert_eq!(msg.topic, "orders/eu");
+        assert_eq!(msg.message, b"order 1");
+
+        let (status, response) = request(http, "/topics/orders/eu", "GET", Some(BASIC), "").await;
+        assert!(status.contains("200"));
+        assert!(response.contains("application/json"));
+        assert!(response.contains("\"orders/eu\":[\"1\"]"));
+        assert!(response.contains("alice"));
+    }
+
+    async fn server_sent_events(server: &ServerHandle) {
+        let http = server.listeners().http.unwrap();
+        let stream = send_request(http, "/events/alerts/+", "GET", Some(BASIC), "").await;
+        sleep(Duration::from_millis(200)).await;
+
+        let mut client_pub = client(server).await;
+        client_pub
+            .publish("alerts/disk".to_string(), b"disk full".to_vec())
+            .await
+            .unwrap();
+
+        client_pub
+            .publish("alerts/raw".to_string(), vec![0xff, 0x00, b'\r'])
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(stream).lines();
+        let mut next_data = async || {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                let mut event = None;
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    if let Some(name) = line.strip_prefix("event: ") {
+                        event = Some(name.to_string());
+                    }
+                    if let Some(data) = line.strip_prefix("data: ") {
+                        return (event, data.to_string());
+                    }
+                }
+                (None, String::new())
+            })
+            .await
+            .unwrap()
+        };
+        // the events carry the topic the message was published on.
+        assert_eq!(
+            next_data().await,
+            (
+                None,
+                r#"{"message":"disk full","topic":"alerts/disk"}"#.to_string()
+            )
+        );
+        // the binary payloads are base64 encoded.
+        assert_eq!(
+            next_data().await,
+            (
+                Some("base64".to_string()),
+                r#"{"base64":true,"message":"/wAN","topic":"alerts/raw"}"#.to_string()
+            )
+        );
+    }
+
+    async fn long_poll(http: SocketAddr) {
+        let (status, _) = request(http, "/poll/idle?timeout=1", "GET", Some(BASIC), "").await;
+        assert!(status.contains("204"));
+
+        let poll = tokio::spawn(request(
+            http,
+            "/poll/jobs/%23?timeout=5",
+            "GET",
+            Some(BASIC),
+            "",
+        ));
+        sleep(Duration::from_millis(200)).await;
+        let (status, _) = request(http, "/topics/jobs/1", "POST", Some(BASIC), "job 1").await;
+        assert!(status.contains("200"));
+        let (status, response) = poll.await.unwrap();
+        assert!(status.contains("200"));
+        assert!(response.contains("application/json"));
+        assert!(response.ends_with(r#"{"message":"job 1","topic":"jobs/1"}"#));
+    }
+
+    async fn invalid_topics(http: SocketAddr) {
+        // the published topics can not have wildcards.
+        let (status, response) =
+            request(http, "/topics/sensors/+", "POST", Some(BASIC), "21").await;
+        assert!(status.contains("400"));
+        assert!(response.contains("Invalid topic: sensors/+"));
+        // the subscription patterns must be valid, `#` must be the last level.
+        let (status, _) = request(http, "/events/a/%23/b", "GET", Some(BASIC), "").await;
+        assert!(status.contains("400"));
+        let (status, _) = request(http, "/poll/a/%23/b?timeout=1", "GET", Some(BASIC), "").await;
+        assert!(status.contains("400"));
+    }
+
+    async fn admin(http: SocketAddr) {
+        let (status, _) = request(http, "/admin", "POST", Some(BASIC), r#"{"op":"clients"}"#).await;
+        assert!(status.contains("403"));
+        let (status, response) =
+            request(http, "/admin", "POST", Some(OPS), r#"{"op":"clients"}"#).await;
+        assert!(status.contains("200"));
+        assert!(response.contains("application/json"));
+        let (status, _) = request(http, "/admin", "POST", Some(OPS), r#"{"op":"reboot"}"#).await;
+        assert!(status.contains("400"));
+        let (status, response) = request(
+            http,
+            "/admin",
+            "POST",
+            Some(OPS),
+            r#"{"op":"kick","client_id":"missing"}"#,
+        )
+        .await;
+        assert!(status.contains("404"));
+        assert!(response.contains("Unknown client: missing"));
+    }
+
+    #[tokio::test]
+    async fn test_all() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        simple_pub_sub::auth::add_user(CREDENTIALS, "ops", "secret").unwrap();
+        std::fs::write(ACL, "allow * all #\nallow ops admin #\n").unwrap();
+
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            acl: Some(ACL.to_string()),
+            http: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let http = server.listeners().http.unwrap();
+        unauthenticated(http).await;
+        publish_and_query(&server).await;
+        server_sent_events(&server).await;
+        long_poll(http).await;
+        invalid_topics(http).await;
+        admin(http).await;
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+mod common;
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::client::Client;
+    use simple_pub_sub::server::{BrokerConfig, ServerHandle};
+    use simple_pub_sub::PktType;
+    use tokio::time::{sleep, timeout};
+
+    fn client(server: &ServerHandle, session: Option<&str>) -> Client {
+        let mut client = common::client(server);
+        if let Some(session) = session {
+            client.persistent_session(session.to_string());
+        }
+        client
+    }
+
+    /// subscribes with a persistent session and disconnects,
+    /// the messages are held by the broker until the session is resumed.
+    async fn suspended_subscriber(server: &ServerHandle, session: &str) {
+        let mut subscriber = client(server, Some(session));
+        subscriber.connect().await.unwrap();
+        subscriber.subscribe("news".to_string()).await.unwrap();
+        let ack = subscriber.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+        drop(subscriber);
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    /// a message of 20 bytes with the topic.
+    fn message(i: usize) -> Vec<u8> {
+        format!("message-{i:08}").into_bytes()
+    }
+
+    async fn query(server: &ServerHandle) -> String {
+        let mut client = client(server, None);
+        client.connect().await.unwrap();
+        client.query("news".to_string()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let server = common::start_server(BrokerConfig {
+            memory_limit: Some(100),
+            ..Default::default()
+        })
+        .await;
+        suspended_subscriber(&server, "reader-1").await;
+
+        let mut publisher = client(&server, None);
+        publisher.connect().await.unwrap();
+        for i in 0..5 {
+            publisher
+                .publish("news".to_string(), message(i))
+                .await
+                .unwrap();
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(query(&server).await.contains(r#""used":100"#));
+
+        // the publishes are rejected once the queued messages reach the limit.
+        let err = publisher
+            .publish("news".to_string(), message(5))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Memory limit exceeded"), "{err}");
+
+        // the memory is released once the messages are delivered.
+        let mut subscriber = client(&server, Some("reader-1"));
+        subscriber.connect().await.unwrap();
+        for i in 0..5 {
+            let msg = subscriber.read_message().await.unwrap();
+            assert_eq!(msg.message, message(i));
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert!(query(&server).await.contains(r#""used":0"#));
+        publisher
+            .publish("news".to_string(), message(6))
+            .await
+            .unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_memory_limit() {
+        let server = common::start_server(BrokerConfig {
+            client_memory_limit: Some(40),
+            ..Default::default()
+        })
+        .await;
+        suspended_subscriber(&server, "reader-2").await;
+
+        // the copies over the limit of the subscriber are dropped.
+        let mut publisher = client(&server, None);
+        publisher.connect().await.unwrap();
+        for i in 0..4 {
+            publisher
+                .publish("news".to_string(), message(i))
+                .await
+                .unwrap();
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        let mut subscriber = client(&server, Some("reader-2"));
+        subscriber.connect().await.unwrap();
+        for i in 0..2 {
+            let msg = subscriber.read_message().await.unwrap();
+            assert_eq!(msg.message, message(i));
+        }
+        assert!(
+            timeout(Duration::from_millis(300), subscriber.read_message())
+                .await
+                .is_err()
+        );
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+mod common;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Duration};
+
+/// fetches the metrics endpoint, returns the full response.
+async fn scrape(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            metrics: Some("127.0.0.1:0".to_string()),
+            metrics_topics: vec!["sensors".to_string()],
+            ..Default::default()
+        })
+        .await;
+
+        let mut client_sub = common::connected_client(&server).await;
+        client_sub.subscribe("sensors".to_string()).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        let mut client_pub = common::connected_client(&server).await;
+        client_pub
+            .publish("sensors".to_string(), b"21.5".to_vec())
+            .await
+            .unwrap();
+        client_pub
+            .publish("nobody".to_string(), b"lost".to_vec())
+            .await
+            .unwrap();
+
+        let msg = client_sub.read_message().await.unwrap();
+        // skip the subscription ack.
+        let msg = if msg.header.pkt_type == simple_pub_sub::PktType::PUBLISH {
+            msg
+        } else {
+            client_sub.read_message().await.unwrap()
+        };
+        assert_eq!(msg.message, b"21.5");
+        sleep(Duration::from_millis(200)).await;
+
+        let response = scrape(server.listeners().metrics.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("application/openmetrics-text"));
+        assert!(response.contains("simple_pub_sub_connections_total{transport=\"tcp\"} 2"));
+        assert!(response.contains("simple_pub_sub_clients 2"));
+        assert!(response.contains("simple_pub_sub_messages_in_total{topic=\"sensors\"} 1"));
+        assert!(response.contains("simple_pub_sub_bytes_in_total{topic=\"sensors\"} 4"));
+        assert!(response.contains("simple_pub_sub_messages_out_total{topic=\"sensors\"} 1"));
+        // the topics not matching the patterns are counted together.
+        assert!(response.contains("simple_pub_sub_messages_in_total{topic=\"other\"} 1"));
+        assert!(!response.contains("topic=\"nobody\""));
+        assert!(
+            response.contains("simple_pub_sub_dropped_messages_total{reason=\"no_subscribers\"} 1")
+        );
+        assert!(response.contains("simple_pub_sub_delivery_latency_seconds_count 1"));
+        assert!(response.contains("simple_pub_sub_topics 1"));
+        assert!(response.contains("simple_pub_sub_subscriptions 1"));
+        assert!(response.contains("simple_pub_sub_queue_depth"));
+        server.shutdown().await.unwrap();
+    }
+}
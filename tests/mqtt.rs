@@ -0,0 +1,310 @@
+mod common;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::PktType;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-mqtt-test.passwd";
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+        assert!(body.len() < 128);
+        let mut bytes = vec![first, body.len() as u8];
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn connect_packet(
+        client_id: &str,
+        level: u8,
+        credentials: Option<(&str, &str)>,
+        will: Option<(&str, &str)>,
+    ) -> Vec<u8> {
+        let mut flags = 0x02;
+        let mut body = string("MQTT");
+        body.push(level);
+        let mut payload = string(client_id);
+        if let Some((topic, message)) = will {
+            flags |= 0x04;
+            payload.extend(string(topic));
+            payload.extend(string(message));
+        }
+        if let Some((username, password)) = credentials {
+            flags |= 0xc0;
+            payload.extend(string(username));
+            payload.extend(string(password));
+        }
+        body.push(flags);
+        body.extend(60u16.to_be_bytes());
+        body.extend(payload);
+        packet(0x10, &body)
+    }
+
+    /// MQTT client speaking the raw packets.
+    struct Mqtt {
+        stream: TcpStream,
+    }
+
+    impl Mqtt {
+        /// connects and returns the `CONNACK` return code.
+        async fn connect(addr: SocketAddr, connect: Vec<u8>) -> (Mqtt, u8) {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut mqtt = Mqtt { stream };
+            mqtt.send(connect).await;
+            let (first, body) = mqtt.read().await;
+            assert_eq!(first, 0x20);
+            (mqtt, body[1])
+        }
+
+        async fn send(&mut self, bytes: Vec<u8>) {
+            self.stream.write_all(&bytes).await.unwrap();
+        }
+
+        /// reads a packet, returns the first byte and the body.
+        async fn read(&mut self) -> (u8, Vec<u8>) {
+            timeout(Duration::from_secs(5), async {
+                let first = self.stream.read_u8().await.unwrap();
+                let mut len = 0;
+                for i in 0..4 {
+                    let byte = self.stream.read_u8().await.unwrap();
+                    len |= usize::from(byte & 0x7f) << (7 * i);
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                }
+                let mut body = vec![0; len];
+                self.stream.read_exact(&mut body).await.unwrap();
+                (first, body)
+            })
+            .await
+            .unwrap()
+        }
+
+        async fn subscribe(&mut self, pkid: u16, filter: &str, qos: u8) -> u8 {
+            let mut body = pkid.to_be_bytes().to_vec();
+            body.extend(string(filter));
+            body.push(qos);
+            self.send(packet(0x82, &body)).await;
+            let (first, body) = self.read().await;
+            assert_eq!(first, 0x90);
+            assert_eq!(body[..2], pkid.to_be_bytes());
+            body[2]
+        }
+
+        async fn publish(&mut self, topic: &str, message: &[u8], qos: u8, pkid: u16) {
+            let mut body = string(topic);
+            if qos > 0 {
+                body.extend(pkid.to_be_bytes());
+            }
+            body.extend_from_slice(message);
+            self.send(packet(0x30 | (qos << 1), &body)).await;
+        }
+
+        /// reads a `PUBLISH`, returns the topic, the message, the QoS and the packet id.
+        async fn next_publish(&mut self) -> (String, Vec<u8>, u8, u16) {
+            let (first, body) = self.read().await;
+            assert_eq!(first >> 4, 3);
+            let qos = (first >> 1) & 0x03;
+            let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            let mut rest = &body[2 + topic_len..];
+            let mut pkid = 0;
+            if qos > 0 {
+                pkid = u16::from_be_bytes([rest[0], rest[1]]);
+                rest = &rest[2..];
+            }
+            (topic, rest.to_vec(), qos, pkid)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mqtt() {
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            mqtt: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let mqtt = server.listeners().mqtt.unwrap();
+
+        // only MQTT 3.1.1 is accepted.
+        let (_, code) = Mqtt::connect(mqtt, connect_packet("old", 5, None, None)).await;
+        assert_eq!(code, 1);
+
+        let (mut device, code) = Mqtt::connect(mqtt, connect_packet("device", 4, None, None)).await;
+        assert_eq!(code, 0);
+        // the subscriptions are granted QoS 0, the invalid filters are rejected.
+        assert_eq!(device.subscribe(1, "sensors/+/temp", 2).await, 0);
+        assert_eq!(device.subscribe(2, "commands/#", 0).await, 0);
+        assert_eq!(device.subscribe(3, "bad/#/filter", 0).await, 0x80);
+
+        // native to MQTT, with QoS 0.
+        let mut native = common::connected_client(&server).await;
+        native
+            .publish("sensors/kitchen/temp".to_string(), b"21.5".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            device.next_publish().await,
+            ("sensors/kitchen/temp".to_string(), b"21.5".to_vec(), 0, 0)
+        );
+        native
+            .publish("commands/restart".to_string(), b"now".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            device.next_publish().await,
+            ("commands/restart".to_string(), b"now".to_vec(), 0, 0)
+        );
+
+        // MQTT to native, the QoS 1 publish is acknowledged.
+        let mut subscriber = common::connected_client(&server).await;
+        subscriber.subscribe("alerts".to_string()).await.unwrap();
+        let ack = subscriber.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+        device.publish("alerts", b"disk full", 1, 7).await;
+        assert_eq!(device.read().await, (0x40, vec![0, 7]));
+        let msg = subscriber.read_message().await.unwrap();
+        assert_eq!(
+            (msg.topic.as_str(), msg.message.as_slice()),
+            ("alerts", &b"disk full"[..])
+        );
+
+        // ping and unsubscribe.
+        device.send(packet(0xc0, &[])).await;
+        assert_eq!(device.read().await, (0xd0, vec![]));
+        let mut body = 9u16.to_be_bytes().to_vec();
+        body.extend(string("commands/#"));
+        device.send(packet(0xa2, &body)).await;
+        assert_eq!(device.read().await, (0xb0, vec![0, 9]));
+        sleep(Duration::from_millis(100)).await;
+        let resp = native.query("commands/#".to_string()).await.unwrap();
+        assert!(resp.contains(r#""commands/#":["0"]"#), "{resp}");
+
+        // the will is published when the connection is lost.
+        let (lost, code) = Mqtt::connect(
+            mqtt,
+            connect_packet("lost", 4, None, Some(("alerts", "device offline"))),
+        )
+        .await;
+        assert_eq!(code, 0);
+        std::mem::drop(lost);
+        let msg = subscriber.read_message().await.unwrap();
+        assert_eq!(msg.message, b"device offline");
+
+        // the will is discarded on DISCONNECT.
+        let (mut closed, _) = Mqtt::connect(
+            mqtt,
+            connect_packet("closed", 4, None, Some(("alerts", "device offline"))),
+        )
+        .await;
+        closed.send(packet(0xe0, &[])).await;
+        device.publish("alerts", b"next", 0, 0).await;
+        let msg = subscriber.read_message().await.unwrap();
+        assert_eq!(msg.message, b"next");
+
+        // a filter starting with a wildcard does not match the `$` topics.
+        assert_eq!(device.subscribe(10, "#", 0).await, 0);
+        assert_eq!(device.subscribe(11, "$internal/+", 0).await, 0);
+        native
+            .publish("$internal/state".to_string(), b"one".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            device.next_publish().await,
+            ("$internal/state".to_string(), b"one".to_vec(), 0, 0)
+        );
+        let mut body = 12u16.to_be_bytes().to_vec();
+        body.extend(string("$internal/+"));
+        device.send(packet(0xa2, &body)).await;
+        assert_eq!(device.read().await, (0xb0, vec![0, 12]));
+        native
+            .publish("$internal/state".to_string(), b"two".to_vec())
+            .await
+            .unwrap();
+        native
+            .publish("other".to_string(), b"three".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            device.next_publish().await,
+            ("other".to_string(), b"three".to_vec(), 0, 0)
+        );
+
+        // a client connecting with the same identifier takes over.
+        let (mut device2, code) =
+            Mqtt::connect(mqtt, connect_packet("device", 4, None, None)).await;
+        assert_eq!(code, 0);
+        let mut byte = [0];
+        let closed = timeout(Duration::from_secs(2), device.stream.read(&mut byte)).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+        device2.send(packet(0xc0, &[])).await;
+        assert_eq!(device2.read().await, (0xd0, vec![]));
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_auth() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        simple_pub_sub::auth::add_user(CREDENTIALS, "mallory", "secret").unwrap();
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            mqtt: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let mqtt = server.listeners().mqtt.unwrap();
+
+        let (_, code) = Mqtt::connect(mqtt, connect_packet("device", 4, None, None)).await;
+        assert_eq!(code, 5);
+        let (_, code) = Mqtt::connect(
+            mqtt,
+            connect_packet("device", 4, Some(("alice", "wrong")), None),
+        )
+        .await;
+        assert_eq!(code, 4);
+        let (mut device, code) = Mqtt::connect(
+            mqtt,
+            connect_packet("device", 4, Some(("alice", "secret")), None),
+        )
+        .await;
+        assert_eq!(code, 0);
+        assert_eq!(device.subscribe(1, "news", 0).await, 0);
+
+        let mut native = common::password_client(&server, "alice", "secret");
+        native.connect().await.unwrap();
+        let resp = native.query("news".to_string()).await.unwrap();
+        assert!(resp.contains(r#""identities":["alice"]"#), "{resp}");
+
+        // the client identifier of alice is not taken over by another identity.
+        let (_, code) = Mqtt::connect(
+            mqtt,
+            connect_packet("device", 4, Some(("mallory", "secret")), None),
+        )
+        .await;
+        assert_eq!(code, 2);
+        assert_eq!(device.subscribe(2, "alerts", 0).await, 0);
+        let (mut device2, code) = Mqtt::connect(
+            mqtt,
+            connect_packet("device", 4, Some(("alice", "secret")), None),
+        )
+        .await;
+        assert_eq!(code, 0);
+        let mut byte = [0];
+        let closed = timeout(Duration::from_secs(2), device.stream.read(&mut byte)).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+        device2.send(packet(0xc0, &[])).await;
+        assert_eq!(device2.read().await, (0xd0, vec![]));
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+mod common;
+use tokio::time::{sleep, Duration};
+fn openssl(args: &[&str]) {
+    use std::process::Command;
+    let op = Command::new("openssl").args(args).output();
+    println!("openssl {:?}: {:?}", args, op);
+}
+
+async fn create_mtls_certs() {
+    // server identity
+    openssl(&[
+        "req",
+        "-x509",
+        "-newkey",
+        "rsa:2048",
+        "-keyout",
+        "certs/mtls-server-key.pem",
+        "-out",
+        "certs/mtls-server.pem",
+        "-days",
+        "365",
+        "-nodes",
+        "-subj",
+        "/CN=localhost",
+        "-addext",
+        "subjectAltName=DNS:localhost",
+        "-addext",
+        "basicConstraints=critical,CA:FALSE",
+    ]);
+    // CA for the client certificates
+    openssl(&[
+        "req",
+        "-x509",
+        "-newkey",
+        "rsa:2048",
+        "-keyout",
+        "certs/mtls-ca-key.pem",
+        "-out",
+        "certs/mtls-ca.pem",
+        "-days",
+        "365",
+        "-nodes",
+        "-subj",
+        "/CN=simple-pub-sub-ca",
+    ]);
+    // client certificate signed by the CA
+    openssl(&[
+        "req",
+        "-newkey",
+        "rsa:2048",
+        "-keyout",
+        "certs/mtls-client-key.pem",
+        "-out",
+        "certs/mtls-client.csr",
+        "-nodes",
+        "-subj",
+        "/CN=alice",
+    ]);
+    openssl(&[
+        "x509",
+        "-req",
+        "-in",
+        "certs/mtls-client.csr",
+        "-CA",
+        "certs/mtls-ca.pem",
+        "-CAkey",
+        "certs/mtls-ca-key.pem",
+        "-CAcreateserial",
+        "-out",
+        "certs/mtls-client.pem",
+        "-days",
+        "365",
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient};
+    use simple_pub_sub::server::{ServerHandle, Tcp};
+
+    fn client(server: &ServerHandle, with_cert: bool) -> Client {
+        let (client_cert, client_key) = if with_cert {
+            (
+                Some("certs/mtls-client.pem".to_string()),
+                Some("certs/mtls-client-key.pem".to_string()),
+            )
+        } else {
+            (None, None)
+        };
+        Client::new(PubSubClient::Tcp(PubSubTcpClient {
+            server: "localhost".to_string(),
+            cert: Some("certs/mtls-server.pem".to_string()),
+            client_cert,
+            client_key,
+            ..common::tcp_client(server)
+        }))
+    }
+
+    async fn mtls_client_subscribe(server: &ServerHandle) {
+        let mut client_sub = client(server, true);
+        let mut client_pub = client(server, true);
+        let mut client_query = client(server, true);
+
+        client_sub.connect().await.unwrap();
+        client_pub.connect().await.unwrap();
+        client_query.connect().await.unwrap();
+
+        client_sub.subscribe("abc".to_string()).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        // the identity from the client certificate is reported in the query.
+        let resp = client_query.query("abc".to_string()).await.unwrap();
+        assert!(resp.contains("alice"));
+
+        client_pub
+            .publish(
+                "abc".to_string(),
+                "test message".to_string().into_bytes().to_vec(),
+            )
+            .await
+            .unwrap();
+
+        let msg = client_sub.read_message().await.unwrap();
+        // skip the subscription ack.
+        let msg = if msg.header.pkt_type == simple_pub_sub::PktType::PUBLISH {
+            msg
+        } else {
+            client_sub.read_message().await.unwrap()
+        };
+        assert!(msg.topic == "abc");
+    }
+
+    async fn client_without_cert_rejected(server: &ServerHandle) {
+        let mut client = client(server, false);
+        let rejected = match client.connect().await {
+            Err(_) => true,
+            Ok(_) => client
+                .publish(
+                    "abc".to_string(),
+                    "test message".to_string().into_bytes().to_vec(),
+                )
+                .await
+                .is_err(),
+        };
+        assert!(rejected);
+    }
+
+    #[tokio::test]
+    async fn test_all() {
+        create_mtls_certs().await;
+        let server = common::start(Tcp {
+            cert: Some("certs/mtls-server.pem".to_string()),
+            key: Some("certs/mtls-server-key.pem".to_string()),
+            client_ca: Some("certs/mtls-ca.pem".to_string()),
+            ..common::tcp(Default::default())
+        })
+        .await;
+        client_without_cert_rejected(&server).await;
+        mtls_client_subscribe(&server).await;
+        server.shutdown().await.unwrap();
+    }
+}
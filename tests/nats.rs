@@ -0,0 +1,239 @@
+mod common;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::PktType;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-nats-test.passwd";
+    const ACL: &str = "/tmp/simple-pub-sub-nats-test.acl";
+
+    /// NATS client speaking the raw text protocol.
+    struct Nats {
+        stream: BufReader<TcpStream>,
+    }
+
+    impl Nats {
+        /// connects and reads the `INFO` of the server.
+        async fn connect(addr: SocketAddr) -> (Nats, serde_json::Value) {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut nats = Nats {
+                stream: BufReader::new(stream),
+            };
+            let line = nats.read_line().await;
+            let info = line.strip_prefix("INFO ").unwrap();
+            (nats, serde_json::from_str(info).unwrap())
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.stream
+                .get_mut()
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+
+        async fn read_line(&mut self) -> String {
+            timeout(Duration::from_secs(5), async {
+                let mut line = String::new();
+                self.stream.read_line(&mut line).await.unwrap();
+                line.trim_end().to_string()
+            })
+            .await
+            .unwrap()
+        }
+
+        /// sends a `PING` and waits for the `PONG`.
+        async fn flush(&mut self) {
+            self.send("PING").await;
+            assert_eq!(self.read_line().await, "PONG");
+        }
+
+        /// reads a `MSG`, returns the control line and the payload.
+        async fn next_msg(&mut self) -> (String, String) {
+            let line = self.read_line().await;
+            assert!(line.starts_with("MSG "), "{line}");
+            let size: usize = line.rsplit(' ').next().unwrap().parse().unwrap();
+            let mut payload = vec![0; size + 2];
+            self.stream.read_exact(&mut payload).await.unwrap();
+            payload.truncate(size);
+            (line, String::from_utf8(payload).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nats() {
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            nats: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let nats = server.listeners().nats.unwrap();
+
+        let (mut subscriber, info) = Nats::connect(nats).await;
+        assert_eq!(info["max_payload"], 65535);
+        assert_eq!(info["auth_required"], false);
+        subscriber.send(r#"CONNECT {"verbose":false}"#).await;
+        subscriber.send("SUB sensors.*.temp 1").await;
+        subscriber.send("SUB alerts.> 2").await;
+        subscriber.send("SUB bad/subject 3").await;
+        assert_eq!(subscriber.read_line().await, "-ERR 'Invalid Subject'");
+        subscriber.flush().await;
+        sleep(Duration::from_millis(100)).await;
+
+        // native to NATS on the wildcards.
+        let mut native = common::connected_client(&server).await;
+        native
+            .publish("sensors/kitchen/temp".to_string(), b"21.5".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            subscriber.next_msg().await,
+            (
+                "MSG sensors.kitchen.temp 1 4".to_string(),
+                "21.5".to_string()
+            )
+        );
+        // `>` needs at least one token.
+        native
+            .publish("alerts".to_string(), b"skipped".to_vec())
+            .await
+            .unwrap();
+        native
+            .publish("alerts/disk/full".to_string(), b"sda".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            subscriber.next_msg().await,
+            ("MSG alerts.disk.full 2 3".to_string(), "sda".to_string())
+        );
+
+        // NATS to native.
+        let mut native_sub = common::connected_client(&server).await;
+        native_sub
+            .subscribe("news/today".to_string())
+            .await
+            .unwrap();
+        let ack = native_sub.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+        let (mut publisher, _) = Nats::connect(nats).await;
+        publisher.send(r#"CONNECT {"verbose":true}"#).await;
+        assert_eq!(publisher.read_line().await, "+OK");
+        publisher.send("PUB news.today 5\r\nhello").await;
+        assert_eq!(publisher.read_line().await, "+OK");
+        let msg = native_sub.read_message().await.unwrap();
+        assert_eq!(
+            (msg.topic.as_str(), msg.message.as_slice()),
+            ("news/today", &b"hello"[..])
+        );
+
+        // the queue group members receive the messages in turn.
+        let (mut worker1, _) = Nats::connect(nats).await;
+        let (mut worker2, _) = Nats::connect(nats).await;
+        for worker in [&mut worker1, &mut worker2] {
+            worker.send("CONNECT {}").await;
+            worker.send("SUB jobs workers 1").await;
+            worker.flush().await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        for job in ["a", "b", "c", "d"] {
+            native
+                .publish("jobs".to_string(), job.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+        assert_eq!(worker1.next_msg().await.1, "a");
+        assert_eq!(worker2.next_msg().await.1, "b");
+        assert_eq!(worker1.next_msg().await.1, "c");
+        assert_eq!(worker2.next_msg().await.1, "d");
+        let resp = native.query("jobs".to_string()).await.unwrap();
+        assert!(resp.contains(r#""jobs":["1"]"#), "{resp}");
+
+        // request and reply between the NATS clients.
+        let (mut service, _) = Nats::connect(nats).await;
+        service.send("CONNECT {}").await;
+        service.send("SUB time 7").await;
+        service.flush().await;
+        publisher.send(r#"CONNECT {"verbose":false}"#).await;
+        publisher.send("SUB _INBOX.abc 9").await;
+        publisher.flush().await;
+        sleep(Duration::from_millis(100)).await;
+        publisher.send("PUB time _INBOX.abc 3\r\nnow").await;
+        assert_eq!(
+            service.next_msg().await,
+            ("MSG time 7 _INBOX.abc 3".to_string(), "now".to_string())
+        );
+        service.send("PUB _INBOX.abc 5\r\n12:00").await;
+        assert_eq!(
+            publisher.next_msg().await,
+            ("MSG _INBOX.abc 9 5".to_string(), "12:00".to_string())
+        );
+
+        // the auto unsubscribe counts the messages already delivered.
+        service.send("UNSUB 7 2").await;
+        service.flush().await;
+        publisher.send("PUB time 1\r\nx").await;
+        assert_eq!(service.next_msg().await.0, "MSG time 7 1");
+        sleep(Duration::from_millis(100)).await;
+        let resp = native.query("time".to_string()).await.unwrap();
+        assert!(resp.contains(r#""time":["0"]"#), "{resp}");
+
+        service.send("FOO").await;
+        assert_eq!(
+            service.read_line().await,
+            "-ERR 'Unknown Protocol Operation'"
+        );
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nats_auth() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        std::fs::write(ACL, "deny * subscribe billing/#\nallow * all #\n").unwrap();
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            acl: Some(ACL.to_string()),
+            nats: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let nats = server.listeners().nats.unwrap();
+
+        let (mut client, info) = Nats::connect(nats).await;
+        assert_eq!(info["auth_required"], true);
+        client.send("PUB news 1\r\nx").await;
+        assert_eq!(client.read_line().await, "-ERR 'Authorization Violation'");
+
+        let (mut client, _) = Nats::connect(nats).await;
+        client
+            .send(r#"CONNECT {"user":"alice","pass":"wrong"}"#)
+            .await;
+        assert_eq!(client.read_line().await, "-ERR 'Authorization Violation'");
+
+        let (mut client, _) = Nats::connect(nats).await;
+        client
+            .send(r#"CONNECT {"user":"alice","pass":"secret"}"#)
+            .await;
+        // the wildcards including a denied subject are denied.
+        for subject in [">", "*.invoices", "billing.*"] {
+            client.send(&format!("SUB {subject} 2")).await;
+            assert_eq!(
+                client.read_line().await,
+                format!("-ERR 'Permissions Violation for Subscription to \"{subject}\"'")
+            );
+        }
+        client.send("SUB news 1").await;
+        client.flush().await;
+        sleep(Duration::from_millis(100)).await;
+        let mut native = common::password_client(&server, "alice", "secret");
+        native.connect().await.unwrap();
+        let resp = native.query("news".to_string()).await.unwrap();
+        assert!(resp.contains(r#""identities":["alice"]"#), "{resp}");
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+mod common;
+use tokio::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::rate_limit::RateLimitAction;
+    use simple_pub_sub::server::ServerHandle;
+
+    const RATE_LIMITS: &str = "/tmp/simple-pub-sub-test.ratelimits";
+
+    async fn start_server(action: RateLimitAction) -> ServerHandle {
+        common::start_server(simple_pub_sub::server::BrokerConfig {
+            rate_limits: Some(RATE_LIMITS.to_string()),
+            rate_limit_action: action,
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn rejected(server: &ServerHandle) {
+        let mut client = common::connected_client(server).await;
+        for _ in 0..2 {
+            let result = client
+                .publish("logs/app".to_string(), b"line".to_vec())
+                .await;
+            assert!(result.is_ok());
+        }
+        let result = client
+            .publish("logs/app".to_string(), b"line".to_vec())
+            .await;
+        assert!(result.is_err());
+
+        // the topics matching the pattern share the limit.
+        let result = client
+            .publish("logs/db".to_string(), b"line".to_vec())
+            .await;
+        assert!(result.is_err());
+
+        // the other topics are not limited by the topic rule.
+        let result = client
+            .publish("metrics".to_string(), b"line".to_vec())
+            .await;
+        assert!(result.is_ok());
+    }
+
+    async fn throttled(server: &ServerHandle) {
+        let mut client = common::connected_client(server).await;
+        let start = std::time::Instant::now();
+        // burst of 10 messages, the remaining 10 are limited to 10 messages/s.
+        for _ in 0..20 {
+            let result = client.publish("metrics".to_string(), b"1".to_vec()).await;
+            assert!(result.is_ok());
+        }
+        assert!(start.elapsed() >= Duration::from_millis(800));
+    }
+
+    #[tokio::test]
+    async fn test_all() {
+        std::fs::write(
+            RATE_LIMITS,
+            "topic logs/# 2 -\n\
+             client * 10 -\n",
+        )
+        .unwrap();
+        let reject_server = start_server(RateLimitAction::Reject).await;
+        let throttle_server = start_server(RateLimitAction::Throttle).await;
+        rejected(&reject_server).await;
+        throttled(&throttle_server).await;
+        reject_server.shutdown().await.unwrap();
+        throttle_server.shutdown().await.unwrap();
+    }
+}
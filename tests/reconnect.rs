@@ -0,0 +1,313 @@
+mod common;
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::client::{
+        Client, ConnectionEvent, PubSubClient, PubSubTcpClient, ReconnectPolicy,
+    };
+    use simple_pub_sub::server::{ServerHandle, Tcp};
+    use simple_pub_sub::PktType;
+    use tokio::sync::broadcast;
+    use tokio::time::{sleep, timeout};
+    use tokio_stream::StreamExt;
+
+    /// starts the server on the port, the port 0 picks a free one.
+    async fn start_server(port: u16) -> ServerHandle {
+        common::start(Tcp {
+            port,
+            ..common::tcp(Default::default())
+        })
+        .await
+    }
+
+    fn client(port: u16, policy: Option<ReconnectPolicy>) -> Client {
+        let mut client = Client::new(PubSubClient::Tcp(PubSubTcpClient {
+            server: "127.0.0.1".to_string(),
+            port,
+            cert: None,
+            cert_password: None,
+            client_cert: None,
+            client_key: None,
+        }));
+        if let Some(policy) = policy {
+            client.reconnect(policy);
+        }
+        client
+    }
+
+    fn policy(outbox: usize) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(200),
+            outbox,
+            ..Default::default()
+        }
+    }
+
+    /// waits for the event, returns the events before it.
+    async fn wait_for(
+        events: &mut broadcast::Receiver<ConnectionEvent>,
+        event: ConnectionEvent,
+    ) -> Vec<ConnectionEvent> {
+        let mut before = vec![];
+        loop {
+            let next = timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if next == event {
+                return before;
+            }
+            before.push(next);
+        }
+    }
+
+    async fn publish(port: u16, message: &str) {
+        let mut publisher = client(port, None);
+        publisher.connect().await.unwrap();
+        publisher
+            .publish("news".to_string(), message.as_bytes().to_vec())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(10), Duration::from_secs(1));
+
+        // the jitter waits between the half and the full delay.
+        let policy = ReconnectPolicy {
+            jitter: true,
+            ..policy
+        };
+        for attempt in 1..10 {
+            let delay = policy.delay(attempt);
+            let full = ReconnectPolicy {
+                jitter: false,
+                ..policy.clone()
+            }
+            .delay(attempt);
+            assert!(delay >= full / 2 && delay <= full, "{delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_and_resubscribe() {
+        let server = start_server(0).await;
+        let port = server.local_addr().port().unwrap();
+        let mut subscriber = client(port, Some(policy(0)));
+        let mut events = subscriber.events();
+        subscriber.connect().await.unwrap();
+        assert_eq!(events.recv().await.unwrap(), ConnectionEvent::Connected);
+        subscriber.subscribe("news".to_string()).await.unwrap();
+
+        let (tx, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(msg) = subscriber.read_message().await {
+                if msg.header.pkt_type == PktType::PUBLISH {
+                    let _ = tx.send(String::from_utf8(msg.message).unwrap());
+                }
+            }
+        });
+        publish(port, "before").await;
+        assert_eq!(received.recv().await.unwrap(), "before");
+
+        // the broker restarts, the subscriber connects again to the same topics.
+        server.shutdown().await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        let server = start_server(port).await;
+        let before = wait_for(&mut events, ConnectionEvent::Connected).await;
+        assert!(before.len() > 2, "{before:?}");
+        assert!(matches!(before[0], ConnectionEvent::Disconnected(_)));
+        assert!(before
+            .iter()
+            .skip(1)
+            .all(|event| matches!(event, ConnectionEvent::Reconnecting { .. })));
+
+        sleep(Duration::from_millis(100)).await;
+        publish(port, "after").await;
+        let msg = timeout(Duration::from_secs(2), received.recv()).await;
+        assert_eq!(msg.unwrap().unwrap(), "after");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbox() {
+        let server = start_server(0).await;
+        let port = server.local_addr().port().unwrap();
+        let mut client = client(port, Some(policy(10)));
+        client.connect().await.unwrap();
+        let (publisher, subscriber) = client.split().unwrap();
+        let mut events = publisher.events();
+        let mut subscription = subscriber.subscribe("news".to_string()).await.unwrap();
+
+        server.shutdown().await.unwrap();
+        assert!(matches!(
+            timeout(Duration::from_secs(2), events.recv()).await,
+            Ok(Ok(ConnectionEvent::Disconnected(_)))
+        ));
+
+        // the publishes made while disconnected are sent after the reconnect,
+        // the subscription stays open.
+        let publishing = tokio::spawn(async move {
+            publisher
+                .publish("news".to_string(), b"queued".to_vec())
+                .await
+        });
+        sleep(Duration::from_millis(200)).await;
+        let server = start_server(port).await;
+        timeout(Duration::from_secs(5), publishing)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let msg = timeout(Duration::from_secs(2), subscription.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.message, b"queued");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_outbox() {
+        let server = start_server(0).await;
+        let port = server.local_addr().port().unwrap();
+        let mut client = client(port, Some(policy(0)));
+        client.connect().await.unwrap();
+        let (publisher, _subscriber) = client.split().unwrap();
+        let mut events = publisher.events();
+
+        server.shutdown().await.unwrap();
+        assert!(matches!(
+            timeout(Duration::from_secs(2), events.recv()).await,
+            Ok(Ok(ConnectionEvent::Disconnected(_)))
+        ));
+        // without an outbox the publishes fail while disconnected.
+        let result = timeout(
+            Duration::from_secs(1),
+            publisher.publish("news".to_string(), b"lost".to_vec()),
+        )
+        .await
+        .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_attempts() {
+        let server = start_server(0).await;
+        let port = server.local_addr().port().unwrap();
+        let mut client = client(
+            port,
+            Some(ReconnectPolicy {
+                max_attempts: Some(2),
+                ..policy(10)
+            }),
+        );
+        client.connect().await.unwrap();
+        let (publisher, subscriber) = client.split().unwrap();
+        let mut events = publisher.events();
+        let mut subscription = subscriber.subscribe("news".to_string()).await.unwrap();
+
+        // the client gives up, the streams end and the requests fail.
+        server.shutdown().await.unwrap();
+        let before = wait_for(&mut events, ConnectionEvent::Closed).await;
+        assert_eq!(before.len(), 3, "{before:?}");
+        let end = timeout(Duration::from_secs(1), subscription.next()).await;
+        assert!(end.unwrap().is_none());
+        assert!(publisher
+            .publish("news".to_string(), b"lost".to_vec())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_without_split() {
+        let server = start_server(0).await;
+        let port = server.local_addr().port().unwrap();
+        let mut publisher = client(
+            port,
+            Some(ReconnectPolicy {
+                max_attempts: Some(1),
+                ..policy(1)
+            }),
+        );
+        publisher.connect().await.unwrap();
+        server.shutdown().await.unwrap();
+
+        // the client gives up, the first publish is kept and the second one is dropped.
+        for message in ["kept", "dropped"] {
+            assert!(publisher
+                .publish("news".to_string(), message.as_bytes().to_vec())
+                .await
+                .is_err());
+        }
+
+        let server = start_server(port).await;
+        let mut subscriber = client(port, None);
+        subscriber.connect().await.unwrap();
+        subscriber.subscribe("news".to_string()).await.unwrap();
+        let ack = subscriber.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+
+        // the outbox is sent on the next reconnect, before the new publish.
+        publisher
+            .publish("news".to_string(), b"after".to_vec())
+            .await
+            .unwrap();
+        for expected in ["kept", "after"] {
+            let msg = timeout(Duration::from_secs(2), subscriber.read_message())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg.message, expected.as_bytes());
+        }
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requests_after_resubscribe() {
+        let server = start_server(0).await;
+        let port = server.local_addr().port().unwrap();
+        let mut client = client(port, Some(policy(10)));
+        client.connect().await.unwrap();
+        client.subscribe("news".to_string()).await.unwrap();
+        let ack = client.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+
+        server.shutdown().await.unwrap();
+        sleep(Duration::from_millis(300)).await;
+        let server = start_server(port).await;
+
+        // the publish reconnects, the ack of the resubscription is read
+        // before the publish is sent again.
+        timeout(
+            Duration::from_secs(5),
+            client.publish("alerts".to_string(), b"after".to_vec()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let resp = client.query("news".to_string()).await.unwrap();
+        let resp: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(resp["news"].as_array().map(Vec::len), Some(1), "{resp}");
+        publish(port, "after").await;
+        let msg = timeout(Duration::from_secs(2), client.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.header.pkt_type, PktType::PUBLISH);
+        assert_eq!(msg.message, b"after");
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+mod common;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::PktType;
+
+    const CREDENTIALS: &str = "/tmp/simple-pub-sub-redis-test.passwd";
+
+    /// RESP2 reply, the bulk strings are read as utf-8.
+    #[derive(Debug, PartialEq)]
+    enum Reply {
+        Simple(String),
+        Error(String),
+        Integer(i64),
+        Bulk(Option<String>),
+        Array(Vec<Reply>),
+    }
+
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(Some(value.to_string()))
+    }
+
+    /// Redis client speaking the raw protocol.
+    struct Redis {
+        stream: BufReader<TcpStream>,
+    }
+
+    impl Redis {
+        async fn connect(addr: SocketAddr) -> Redis {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            Redis {
+                stream: BufReader::new(stream),
+            }
+        }
+
+        async fn send(&mut self, args: &[&str]) {
+            let mut bytes = format!("*{}\r\n", args.len()).into_bytes();
+            for arg in args {
+                bytes.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+            }
+            self.stream.get_mut().write_all(&bytes).await.unwrap();
+        }
+
+        async fn command(&mut self, args: &[&str]) -> Reply {
+            self.send(args).await;
+            self.read().await
+        }
+
+        async fn read(&mut self) -> Reply {
+            timeout(Duration::from_secs(5), self.read_reply())
+                .await
+                .unwrap()
+        }
+
+        async fn read_reply(&mut self) -> Reply {
+            let mut line = String::new();
+            self.stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end().to_string();
+            let (kind, value) = line.split_at(1);
+            match kind {
+                "+" => Reply::Simple(value.to_string()),
+                "-" => Reply::Error(value.to_string()),
+                ":" => Reply::Integer(value.parse().unwrap()),
+                "$" => {
+                    let len: i64 = value.parse().unwrap();
+                    if len < 0 {
+                        return Reply::Bulk(None);
+                    }
+                    let mut data = vec![0; len as usize + 2];
+                    self.stream.read_exact(&mut data).await.unwrap();
+                    data.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(data).unwrap()))
+                }
+                "*" => {
+                    let len: usize = value.parse().unwrap();
+                    let mut items = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        items.push(Box::pin(self.read_reply()).await);
+                    }
+                    Reply::Array(items)
+                }
+                _ => panic!("Invalid reply: {line}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redis() {
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            redis: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let redis = server.listeners().redis.unwrap();
+
+        let mut publisher = Redis::connect(redis).await;
+        assert_eq!(
+            publisher.command(&["PING"]).await,
+            Reply::Simple("PONG".to_string())
+        );
+
+        let mut subscriber = Redis::connect(redis).await;
+        subscriber.send(&["SUBSCRIBE", "news", "sports"]).await;
+        assert_eq!(
+            subscriber.read().await,
+            Reply::Array(vec![bulk("subscribe"), bulk("news"), Reply::Integer(1)])
+        );
+        assert_eq!(
+            subscriber.read().await,
+            Reply::Array(vec![bulk("subscribe"), bulk("sports"), Reply::Integer(2)])
+        );
+        assert_eq!(
+            subscriber.command(&["PSUBSCRIBE", "sensors/*"]).await,
+            Reply::Array(vec![
+                bulk("psubscribe"),
+                bulk("sensors/*"),
+                Reply::Integer(3)
+            ])
+        );
+        // only the pub/sub commands are allowed while subscribed.
+        assert!(matches!(
+            subscriber.command(&["PUBLISH", "news", "x"]).await,
+            Reply::Error(e) if e.starts_with("ERR Can't execute 'publish'")
+        ));
+
+        // redis to native and to redis.
+        let mut native_sub = common::connected_client(&server).await;
+        native_sub.subscribe("news".to_string()).await.unwrap();
+        let ack = native_sub.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+        assert_eq!(
+            publisher.command(&["PUBLISH", "news", "hello"]).await,
+            Reply::Integer(0)
+        );
+        let msg = native_sub.read_message().await.unwrap();
+        assert_eq!(
+            (msg.topic.as_str(), msg.message.as_slice()),
+            ("news", &b"hello"[..])
+        );
+        assert_eq!(
+            subscriber.read().await,
+            Reply::Array(vec![bulk("message"), bulk("news"), bulk("hello")])
+        );
+
+        // native to redis on a pattern.
+        let mut native_pub = common::connected_client(&server).await;
+        native_pub
+            .publish("sensors/kitchen/temp".to_string(), b"21.5".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            subscriber.read().await,
+            Reply::Array(vec![
+                bulk("pmessage"),
+                bulk("sensors/*"),
+                bulk("sensors/kitchen/temp"),
+                bulk("21.5")
+            ])
+        );
+
+        // PUBSUB from the QUERY of the topic manager.
+        let channels = publisher.command(&["PUBSUB", "CHANNELS"]).await;
+        assert_eq!(channels, Reply::Array(vec![bulk("news"), bulk("sports")]));
+        assert_eq!(
+            publisher
+                .command(&["PUBSUB", "NUMSUB", "news", "sports", "other"])
+                .await,
+            Reply::Array(vec![
+                bulk("news"),
+                Reply::Integer(2),
+                bulk("sports"),
+                Reply::Integer(1),
+                bulk("other"),
+                Reply::Integer(0)
+            ])
+        );
+        assert_eq!(
+            publisher.command(&["PUBSUB", "NUMPAT"]).await,
+            Reply::Integer(1)
+        );
+
+        // unsubscribe from all the channels.
+        subscriber.send(&["UNSUBSCRIBE"]).await;
+        assert_eq!(
+            subscriber.read().await,
+            Reply::Array(vec![bulk("unsubscribe"), bulk("news"), Reply::Integer(2)])
+        );
+        assert_eq!(
+            subscriber.read().await,
+            Reply::Array(vec![bulk("unsubscribe"), bulk("sports"), Reply::Integer(1)])
+        );
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            publisher.command(&["PUBSUB", "NUMSUB", "sports"]).await,
+            Reply::Array(vec![bulk("sports"), Reply::Integer(0)])
+        );
+        assert!(matches!(
+            publisher.command(&["FLUSHALL"]).await,
+            Reply::Error(e) if e.starts_with("ERR unknown command")
+        ));
+        assert_eq!(
+            publisher.command(&["QUIT"]).await,
+            Reply::Simple("OK".to_string())
+        );
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_redis_auth() {
+        let _ = std::fs::remove_file(CREDENTIALS);
+        simple_pub_sub::auth::add_user(CREDENTIALS, "alice", "secret").unwrap();
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            redis: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        })
+        .await;
+        let redis = server.listeners().redis.unwrap();
+
+        let mut client = Redis::connect(redis).await;
+        assert!(matches!(
+            client.command(&["PUBLISH", "news", "x"]).await,
+            Reply::Error(e) if e.starts_with("NOAUTH")
+        ));
+        assert!(matches!(
+            client.command(&["AUTH", "alice", "wrong"]).await,
+            Reply::Error(e) if e.starts_with("WRONGPASS")
+        ));
+        assert_eq!(
+            client.command(&["AUTH", "alice", "secret"]).await,
+            Reply::Simple("OK".to_string())
+        );
+        assert_eq!(
+            client.command(&["PUBLISH", "news", "x"]).await,
+            Reply::Integer(0)
+        );
+        server.shutdown().await.unwrap();
+    }
+}
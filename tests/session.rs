@@ -0,0 +1,248 @@
+mod common;
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::auth::{ConnectRequest, Credentials};
+    use simple_pub_sub::client::Client;
+    use simple_pub_sub::message::Msg;
+    use simple_pub_sub::server::{BrokerConfig, ServerHandle, ServerTrait as _};
+    use simple_pub_sub::PktType;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::{sleep, timeout};
+
+    fn client(server: &ServerHandle, session: Option<&str>) -> Client {
+        let mut client = common::client(server);
+        if let Some(session) = session {
+            client.persistent_session(session.to_string());
+        }
+        client
+    }
+
+    /// subscribes and waits for the ack.
+    async fn subscribe(client: &mut Client, topic: &str) {
+        client.subscribe(topic.to_string()).await.unwrap();
+        let ack = client.read_message().await.unwrap();
+        assert_eq!(ack.header.pkt_type, PktType::SUBSCRIBEACK);
+    }
+
+    async fn publish(server: &ServerHandle, messages: &[&str]) {
+        let mut publisher = client(server, None);
+        publisher.connect().await.unwrap();
+        for message in messages {
+            publisher
+                .publish("news".to_string(), message.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn subscribers(server: &ServerHandle) -> String {
+        let mut client = client(server, None);
+        client.connect().await.unwrap();
+        client.query("news".to_string()).await.unwrap()
+    }
+
+    async fn read_published(client: &mut Client) -> String {
+        let msg = timeout(Duration::from_secs(2), client.read_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.header.pkt_type, PktType::PUBLISH);
+        String::from_utf8(msg.message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let server = common::start_server(Default::default()).await;
+        let mut subscriber = client(&server, Some("worker-1"));
+        subscriber.connect().await.unwrap();
+        assert!(!subscriber.session_present());
+        subscribe(&mut subscriber, "news").await;
+        drop(subscriber);
+        sleep(Duration::from_millis(100)).await;
+
+        // the messages are queued while the client is disconnected.
+        publish(&server, &["one", "two", "three"]).await;
+        assert!(subscribers(&server).await.contains(r#""news":["1"]"#));
+
+        let mut subscriber = client(&server, Some("worker-1"));
+        subscriber.connect().await.unwrap();
+        assert!(subscriber.session_present());
+        assert_eq!(read_published(&mut subscriber).await, "one");
+        assert_eq!(read_published(&mut subscriber).await, "two");
+        assert_eq!(read_published(&mut subscriber).await, "three");
+
+        // the subscription is still active without subscribing again.
+        publish(&server, &["four"]).await;
+        assert_eq!(read_published(&mut subscriber).await, "four");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit() {
+        let server = common::start_server(BrokerConfig {
+            session_queue: Some(2),
+            ..Default::default()
+        })
+        .await;
+        let mut subscriber = client(&server, Some("worker-2"));
+        subscriber.connect().await.unwrap();
+        subscribe(&mut subscriber, "news").await;
+        drop(subscriber);
+        sleep(Duration::from_millis(100)).await;
+
+        // the oldest messages are dropped over the limit.
+        publish(&server, &["one", "two", "three", "four", "five"]).await;
+        sleep(Duration::from_millis(100)).await;
+
+        let mut subscriber = client(&server, Some("worker-2"));
+        subscriber.connect().await.unwrap();
+        assert_eq!(read_published(&mut subscriber).await, "four");
+        assert_eq!(read_published(&mut subscriber).await, "five");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_queue_rejected() {
+        let server = common::tcp(BrokerConfig {
+            session_queue: Some(0),
+            ..Default::default()
+        });
+        let server = server.bind().await.unwrap();
+        assert!(server.ready().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let server = common::start_server(BrokerConfig {
+            session_expiry: Some(Duration::from_secs(1)),
+            ..Default::default()
+        })
+        .await;
+        let mut subscriber = client(&server, Some("worker-3"));
+        subscriber.connect().await.unwrap();
+        subscribe(&mut subscriber, "news").await;
+        drop(subscriber);
+
+        // the session and its subscriptions are removed after the expiry.
+        sleep(Duration::from_millis(2500)).await;
+        assert!(subscribers(&server).await.contains(r#""news":["0"]"#));
+
+        let mut subscriber = client(&server, Some("worker-3"));
+        subscriber.connect().await.unwrap();
+        assert!(!subscriber.session_present());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_takeover() {
+        let server = common::start_server(Default::default()).await;
+        let mut first = client(&server, Some("worker-4"));
+        first.connect().await.unwrap();
+        subscribe(&mut first, "news").await;
+
+        // the connection using the session is disconnected.
+        let mut second = client(&server, Some("worker-4"));
+        second.connect().await.unwrap();
+        assert!(second.session_present());
+        assert!(first.read_message().await.is_err());
+
+        publish(&server, &["one"]).await;
+        assert_eq!(read_published(&mut second).await, "one");
+        assert!(subscribers(&server).await.contains(r#""news":["1"]"#));
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_discard_other_identity() {
+        const CREDENTIALS: &str = "/tmp/simple-pub-sub-session-test.passwd";
+        let _ = std::fs::remove_file(CREDENTIALS);
+        for username in ["alice", "bob"] {
+            simple_pub_sub::auth::add_user(CREDENTIALS, username, "secret").unwrap();
+        }
+        let server = common::start_server(BrokerConfig {
+            credentials: Some(CREDENTIALS.to_string()),
+            ..Default::default()
+        })
+        .await;
+        let credentials = |username: &str| Credentials::Password {
+            username: username.to_string(),
+            password: "secret".to_string(),
+        };
+        let mut subscriber = client(&server, Some("worker-5"));
+        subscriber.credentials(credentials("alice"));
+        subscriber.connect().await.unwrap();
+        subscribe(&mut subscriber, "news").await;
+        drop(subscriber);
+        sleep(Duration::from_millis(100)).await;
+
+        // a non persistent connect with the session id of another identity is refused.
+        let request = ConnectRequest {
+            session_id: Some("worker-5".to_string()),
+            ..ConnectRequest::from(&credentials("bob"))
+        };
+        let connect = Msg::new(
+            PktType::CONNECT,
+            "".to_string(),
+            Some(serde_json::to_vec(&request).unwrap()),
+        );
+        let port = server.local_addr().port().unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(&connect.bytes()).await.unwrap();
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[3], PktType::ERROR.byte());
+
+        let mut subscriber = client(&server, Some("worker-5"));
+        subscriber.credentials(credentials("alice"));
+        subscriber.connect().await.unwrap();
+        assert!(subscriber.session_present());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_id_of_a_connection() {
+        const ACL: &str = "/tmp/simple-pub-sub-session-test.acl";
+        std::fs::write(ACL, "allow * all #\nallow * admin #\n").unwrap();
+        let server = common::start_server(BrokerConfig {
+            acl: Some(ACL.to_string()),
+            ..Default::default()
+        })
+        .await;
+        let mut victim = client(&server, None);
+        victim.connect().await.unwrap();
+        subscribe(&mut victim, "news").await;
+        let victim_id = victim.clients().await.unwrap()[0].id.clone();
+
+        // the session named after the connection id does not take its place.
+        let mut subscriber = client(&server, Some(&victim_id));
+        subscriber.connect().await.unwrap();
+        subscribe(&mut subscriber, "alerts").await;
+        let mut publisher = client(&server, None);
+        publisher.connect().await.unwrap();
+        publisher
+            .publish("alerts".to_string(), b"alert".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            timeout(Duration::from_secs(2), subscriber.read_message())
+                .await
+                .unwrap()
+                .unwrap()
+                .message,
+            b"alert"
+        );
+        assert!(timeout(Duration::from_millis(300), victim.read_message())
+            .await
+            .is_err());
+
+        drop(subscriber);
+        sleep(Duration::from_millis(100)).await;
+        let clients = publisher.clients().await.unwrap();
+        assert!(clients.iter().any(|c| c.id == victim_id));
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+mod common;
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use common::connected_client as client;
+    use simple_pub_sub::admin::AdminRequest;
+    use simple_pub_sub::client::Subscription;
+    use simple_pub_sub::PktType;
+    use tokio::time::{sleep, timeout};
+    use tokio_stream::StreamExt;
+
+    async fn next(subscription: &mut Subscription) -> String {
+        let msg = timeout(Duration::from_secs(2), subscription.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.header.pkt_type, PktType::PUBLISH);
+        String::from_utf8(msg.message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_publish_from_tasks() {
+        let server = common::start_server(Default::default()).await;
+        let (publisher, subscriber) = client(&server).await.split().unwrap();
+        let mut subscription = subscriber.subscribe("abc".to_string()).await.unwrap();
+
+        // the publisher is used by several tasks while the subscription is read,
+        // the acks of the publishes are not in the stream.
+        let tasks = (0..4)
+            .map(|i| {
+                let publisher = publisher.clone();
+                tokio::spawn(async move {
+                    for j in 0..5 {
+                        publisher
+                            .publish("abc".to_string(), format!("{i}-{j}").into_bytes())
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut received = vec![];
+        for _ in 0..20 {
+            received.push(next(&mut subscription).await);
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        received.sort();
+        let mut expected = (0..4)
+            .flat_map(|i| (0..5).map(move |j| format!("{i}-{j}")))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(received, expected);
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let server = common::start_server(Default::default()).await;
+        let (publisher, subscriber) = client(&server).await.split().unwrap();
+        let mut pattern = subscriber.subscribe("a/+".to_string()).await.unwrap();
+        let mut topic = subscriber.subscribe("a/b".to_string()).await.unwrap();
+        let mut other = subscriber.subscribe("c".to_string()).await.unwrap();
+        assert_eq!(pattern.topic(), "a/+");
+
+        // every subscription matching the topic gets the message.
+        publisher
+            .publish("a/b".to_string(), b"one".to_vec())
+            .await
+            .unwrap();
+        publisher
+            .publish("c".to_string(), b"two".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut pattern).await, "one");
+        assert_eq!(next(&mut topic).await, "one");
+        assert_eq!(next(&mut other).await, "two");
+
+        // the responses and the errors go to their callers.
+        let response = publisher.query("c".to_string()).await.unwrap();
+        assert!(response.contains(r#""c":["1"]"#), "{response}");
+        let error = publisher
+            .admin(AdminRequest::Kick {
+                client_id: "unknown".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Not authorized"), "{error}");
+
+        // the topic is unsubscribed when its subscription is dropped.
+        drop(other);
+        sleep(Duration::from_millis(100)).await;
+        let response = publisher.query("c".to_string()).await.unwrap();
+        assert!(response.contains(r#""c":["0"]"#), "{response}");
+        assert!(timeout(Duration::from_millis(200), pattern.next())
+            .await
+            .is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscription() {
+        let server = common::start_server(Default::default()).await;
+        let mut client = client(&server).await;
+        client.split_capacity(1);
+        let (publisher, subscriber) = client.split().unwrap();
+        let mut slow = subscriber.subscribe("slow".to_string()).await.unwrap();
+        let mut fast = subscriber.subscribe("fast".to_string()).await.unwrap();
+
+        // the messages that do not fit in the channel of the slow subscription are
+        // dropped for it, the publishes and the other subscription go on.
+        for i in 0..3 {
+            publisher
+                .publish("slow".to_string(), format!("{i}").into_bytes())
+                .await
+                .unwrap();
+        }
+        publisher
+            .publish("fast".to_string(), b"fast".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut fast).await, "fast");
+        assert_eq!(next(&mut slow).await, "0");
+        assert!(timeout(Duration::from_millis(200), slow.next())
+            .await
+            .is_err());
+
+        // the subscription gets the messages again once it is read.
+        publisher
+            .publish("slow".to_string(), b"3".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(next(&mut slow).await, "3");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed() {
+        let server = common::start_server(Default::default()).await;
+        let (publisher, subscriber) = client(&server).await.split().unwrap();
+        let mut subscription = subscriber.subscribe("abc".to_string()).await.unwrap();
+
+        // the streams end and the requests fail once the connection is closed.
+        server.shutdown().await.unwrap();
+        let end = timeout(Duration::from_secs(2), subscription.next()).await;
+        assert!(end.unwrap().is_none());
+        assert!(publisher
+            .publish("abc".to_string(), b"lost".to_vec())
+            .await
+            .is_err());
+        assert!(subscriber.subscribe("abc".to_string()).await.is_err());
+    }
+}
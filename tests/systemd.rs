@@ -0,0 +1,173 @@
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient, PubSubUnixClient};
+
+    const NOTIFY_SOCKET: &str = "/tmp/simple-pub-sub-systemd-test.notify";
+    const UNIX_NOTIFY_SOCKET: &str = "/tmp/simple-pub-sub-systemd-unix-test.notify";
+    const SOCKET: &str = "/tmp/simple-pub-sub-systemd-test.sock";
+    const UNUSED_SOCKET: &str = "/tmp/simple-pub-sub-systemd-unused.sock";
+
+    /// binds the notify socket the broker sends the states to.
+    fn notify_socket(path: &str) -> UnixDatagram {
+        let _ = std::fs::remove_file(path);
+        let socket = UnixDatagram::bind(path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    /// reads the notifications until the state.
+    fn wait_for(socket: &UnixDatagram, state: &str) {
+        let mut buf = [0; 256];
+        loop {
+            let n = socket.recv(&mut buf).unwrap();
+            if std::str::from_utf8(&buf[..n]).unwrap() == state {
+                return;
+            }
+        }
+    }
+
+    /// starts the broker like systemd does, with the sockets from the fd 3 on
+    /// and `LISTEN_PID` set to the pid of the broker.
+    fn spawn_broker<F: AsFd>(
+        fds: &[F],
+        names: &str,
+        notify_socket: &str,
+        args: &[String],
+    ) -> Child {
+        let fds = fds
+            .iter()
+            .map(|fd| fd.as_fd().as_raw_fd())
+            .collect::<Vec<_>>();
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+            .arg(env!("CARGO_BIN_EXE_simple-pub-sub"))
+            .args(args)
+            .env("LISTEN_FDS", fds.len().to_string())
+            .env("LISTEN_FDNAMES", names)
+            .env("NOTIFY_SOCKET", notify_socket)
+            .env("WATCHDOG_USEC", "200000")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        unsafe {
+            command.pre_exec(move || {
+                for (i, fd) in fds.iter().enumerate() {
+                    let target = 3 + i as i32;
+                    // dup2 clears FD_CLOEXEC, it is cleared here when the fd is already the target.
+                    let result = if *fd == target {
+                        nix::libc::fcntl(target, nix::libc::F_SETFD, 0)
+                    } else {
+                        nix::libc::dup2(*fd, target)
+                    };
+                    if result < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        command.spawn().unwrap()
+    }
+
+    /// sends SIGTERM to the broker.
+    fn terminate(broker: &mut Child) {
+        Command::new("kill")
+            .arg(broker.id().to_string())
+            .status()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_activation_tcp() {
+        let notify = notify_socket(NOTIFY_SOCKET);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let http_addr = http_listener.local_addr().unwrap();
+        // the ports are still bound here, the broker must use the passed sockets.
+        let mut broker = spawn_broker(
+            &[&listener, &http_listener],
+            "broker:http",
+            NOTIFY_SOCKET,
+            &[
+                "server".to_string(),
+                "--http".to_string(),
+                http_addr.to_string(),
+                "tcp".to_string(),
+                "127.0.0.1".to_string(),
+                port.to_string(),
+            ],
+        );
+        wait_for(&notify, "READY=1");
+
+        let mut client = Client::new(PubSubClient::Tcp(PubSubTcpClient {
+            server: "127.0.0.1".to_string(),
+            port,
+            cert: None,
+            cert_password: None,
+            client_cert: None,
+            client_key: None,
+        }));
+        client.connect().await.unwrap();
+        let resp = client.query("news".to_string()).await.unwrap();
+        assert!(resp.contains(r#""news":["0"]"#), "{resp}");
+
+        let mut http = std::net::TcpStream::connect(http_addr).unwrap();
+        http.write_all(b"GET /topics HTTP/1.0\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        http.read_to_string(&mut resp).unwrap();
+        assert!(
+            resp.starts_with("HTTP/1.0 200") || resp.starts_with("HTTP/1.1 200"),
+            "{resp}"
+        );
+
+        wait_for(&notify, "WATCHDOG=1");
+        terminate(&mut broker);
+        wait_for(&notify, "STOPPING=1");
+        assert!(broker.wait().unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_socket_activation_unix() {
+        let notify = notify_socket(UNIX_NOTIFY_SOCKET);
+        let _ = std::fs::remove_file(SOCKET);
+        let _ = std::fs::remove_file(UNUSED_SOCKET);
+        let listener = std::os::unix::net::UnixListener::bind(SOCKET).unwrap();
+        let mut broker = spawn_broker(
+            &[&listener],
+            "broker",
+            UNIX_NOTIFY_SOCKET,
+            &[
+                "server".to_string(),
+                "unix".to_string(),
+                UNUSED_SOCKET.to_string(),
+            ],
+        );
+        wait_for(&notify, "READY=1");
+
+        let mut client = Client::new(PubSubClient::Unix(PubSubUnixClient {
+            path: SOCKET.to_string(),
+        }));
+        client.connect().await.unwrap();
+        let resp = client.query("news".to_string()).await.unwrap();
+        assert!(resp.contains(r#""news":["0"]"#), "{resp}");
+
+        terminate(&mut broker);
+        wait_for(&notify, "STOPPING=1");
+        assert!(broker.wait().unwrap().success());
+        // the socket file belongs to systemd, it is kept for the next start.
+        assert!(std::path::Path::new(SOCKET).exists());
+        assert!(!std::path::Path::new(UNUSED_SOCKET).exists());
+    }
+}
@@ -1,38 +1,14 @@
-use tokio::time::{sleep, Duration};
+mod common;
 #[cfg(test)]
 mod tests {
 
-    use super::*;
-    use anyhow::Result;
-    use log::info;
-    use simple_pub_sub::server::ServerTrait as _;
-
-    async fn start_serever() -> Result<()> {
-        println!("server started");
-        let server = simple_pub_sub::server::ServerType::Tcp(simple_pub_sub::server::Tcp {
-            host: "localhost".to_string(),
-            port: 6480,
-            cert: None,
-            cert_password: None,
-            capacity: 1024,
-        });
-        server.start().await
-    }
-
-    async fn client_publish() {
-        env_logger::init();
-        sleep(Duration::from_millis(1000)).await;
-        let client_type = simple_pub_sub::client::PubSubTcpClient {
-            server: "localhost".to_string(),
-            port: 6480,
-            cert: None,
-            cert_password: None,
-        };
+    use crate::common::{self, client};
+    use simple_pub_sub::server::{ServerHandle, ServerTrait as _};
+    use tracing::info;
 
+    async fn client_publish(server: &ServerHandle) {
         // initialize the client.
-        let mut client = simple_pub_sub::client::Client::new(
-            simple_pub_sub::client::PubSubClient::Tcp(client_type),
-        );
+        let mut client = client(server);
         // connect the client.
         client.connect().await.unwrap();
 
@@ -48,35 +24,15 @@ mod tests {
         assert!(result.is_ok());
     }
 
-    async fn client_subscribe() {
-        sleep(Duration::from_millis(500)).await;
-        let client_type = simple_pub_sub::client::PubSubTcpClient {
-            server: "localhost".to_string(),
-            port: 6480,
-            cert: None,
-            cert_password: None,
-        };
-        let client_type_pub = simple_pub_sub::client::PubSubTcpClient {
-            server: "localhost".to_string(),
-            port: 6480,
-            cert: None,
-            cert_password: None,
-        };
-
+    async fn client_subscribe(server: &ServerHandle) {
         // initialize the client.
-        let mut client_sub = simple_pub_sub::client::Client::new(
-            simple_pub_sub::client::PubSubClient::Tcp(client_type),
-        );
-        let mut client_pub = simple_pub_sub::client::Client::new(
-            simple_pub_sub::client::PubSubClient::Tcp(client_type_pub),
-        );
+        let mut client_sub = client(server);
+        let mut client_pub = client(server);
 
         // connect the client.
         client_sub.connect().await.unwrap();
         client_pub.connect().await.unwrap();
 
-        // connect the client.
-        client_sub.connect().await.unwrap();
         // subscribe to the given topic.
         client_sub.subscribe("abc".to_string()).await.unwrap();
         client_pub
@@ -93,10 +49,47 @@ mod tests {
 
     #[tokio::test]
     async fn test_all() {
-        let server = tokio::spawn(start_serever());
-        sleep(Duration::from_millis(500)).await;
-        client_publish().await;
-        client_subscribe().await;
-        std::mem::drop(server);
+        tracing_subscriber::fmt::init();
+        let server = common::start_server(Default::default()).await;
+        client_publish(&server).await;
+        client_subscribe(&server).await;
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let server = common::start_server(Default::default()).await;
+        let port = server.local_addr().port().unwrap();
+        let mut subscriber = client(&server);
+        subscriber.connect().await.unwrap();
+        subscriber.subscribe("abc".to_string()).await.unwrap();
+
+        server.shutdown().await.unwrap();
+        // the connected clients are disconnected and the port is released.
+        assert!(subscriber.read_message().await.is_err());
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ready_fails() {
+        let server = simple_pub_sub::server::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            cert: None,
+            cert_password: None,
+            key: None,
+            client_ca: None,
+            capacity: 1024,
+            config: simple_pub_sub::server::BrokerConfig {
+                acl: Some("/tmp/simple-pub-sub-missing.acl".to_string()),
+                ..Default::default()
+            },
+        };
+        let server = server.bind().await.unwrap();
+        let err = server.ready().await.unwrap_err();
+        assert!(err.to_string().contains("could not start"), "{err}");
+        assert!(server.join().await.is_err());
     }
 }
@@ -1,4 +1,4 @@
-use tokio::time::{sleep, Duration};
+mod common;
 async fn create_tls_certs() {
     use std::process::Command;
     // openssl req -x509 -newkey rsa:4096 -keyout key.pem -out cert.pem -days 365 -nodes -subj "/CN=localhost"
@@ -19,6 +19,10 @@ async fn create_tls_certs() {
             "-nodes",
             "-subj",
             "/CN=localhost",
+            "-addext",
+            "subjectAltName=DNS:localhost",
+            "-addext",
+            "basicConstraints=critical,CA:FALSE",
         ])
         .output();
     println!("certs created: {:?}", cert_gen_op);
@@ -42,40 +46,21 @@ async fn create_tls_certs() {
 mod tests {
 
     use super::*;
+    use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient};
+    use simple_pub_sub::server::{ServerHandle, Tcp};
 
-    async fn start_serever() {
-        let host = "0.0.0.0".to_string();
-        let port = 6481;
-        let cert = "certs/identity.pfx".to_string();
-        let password = "password".to_string();
-
-        println!("server started");
-        let server = simple_pub_sub::server::Server {
-            server_type: simple_pub_sub::server::ServerType::Tcp(simple_pub_sub::server::Tcp {
-                host: host.clone(),
-                port,
-                cert: Some(cert.clone()),
-                cert_password: Some(password.clone()),
-                capacity: 1024,
-            }),
-        };
-        let _ = server.start().await;
-    }
-
-    async fn tls_client_publish() {
-        env_logger::init();
-
-        let client_type = simple_pub_sub::client::PubSubTcpClient {
+    fn client(server: &ServerHandle) -> Client {
+        Client::new(PubSubClient::Tcp(PubSubTcpClient {
             server: "localhost".to_string(),
-            port: 6481,
             cert: Some("certs/cert.pem".to_string()),
             cert_password: Some("password".to_string()),
-        };
+            ..common::tcp_client(server)
+        }))
+    }
 
+    async fn tls_client_publish(server: &ServerHandle) {
         // initialize the client.
-        let mut client = simple_pub_sub::client::Client::new(
-            simple_pub_sub::client::PubSubClient::Tcp(client_type),
-        );
+        let mut client = client(server);
         // connect the client.
         let _ = client.connect().await;
 
@@ -90,27 +75,10 @@ mod tests {
         assert!(result.is_ok());
     }
 
-    async fn tls_client_subscribe() {
-        let client_type = simple_pub_sub::client::PubSubTcpClient {
-            server: "localhost".to_string(),
-            port: 6481,
-            cert: Some("certs/cert.pem".to_string()),
-            cert_password: Some("password".to_string()),
-        };
-        let client_type_pub = simple_pub_sub::client::PubSubTcpClient {
-            server: "localhost".to_string(),
-            port: 6481,
-            cert: Some("certs/cert.pem".to_string()),
-            cert_password: Some("password".to_string()),
-        };
-
+    async fn tls_client_subscribe(server: &ServerHandle) {
         // initialize the client.
-        let mut client_sub = simple_pub_sub::client::Client::new(
-            simple_pub_sub::client::PubSubClient::Tcp(client_type),
-        );
-        let mut client_pub = simple_pub_sub::client::Client::new(
-            simple_pub_sub::client::PubSubClient::Tcp(client_type_pub),
-        );
+        let mut client_sub = client(server);
+        let mut client_pub = client(server);
 
         // connect the client.
         client_sub.connect().await.unwrap();
@@ -135,11 +103,30 @@ mod tests {
 
     #[tokio::test]
     async fn test_all() {
+        tracing_subscriber::fmt::init();
         create_tls_certs().await;
-        let server = tokio::spawn(start_serever());
-        sleep(Duration::from_millis(500)).await;
-        tls_client_publish().await;
-        tls_client_subscribe().await;
-        std::mem::drop(server);
+
+        #[cfg(not(feature = "rustls"))]
+        {
+            let server = common::start(Tcp {
+                cert: Some("certs/identity.pfx".to_string()),
+                cert_password: Some("password".to_string()),
+                ..common::tcp(Default::default())
+            })
+            .await;
+            tls_client_publish(&server).await;
+            tls_client_subscribe(&server).await;
+            server.shutdown().await.unwrap();
+        }
+
+        let server = common::start(Tcp {
+            cert: Some("certs/cert.pem".to_string()),
+            key: Some("certs/key.pem".to_string()),
+            ..common::tcp(Default::default())
+        })
+        .await;
+        tls_client_publish(&server).await;
+        tls_client_subscribe(&server).await;
+        server.shutdown().await.unwrap();
     }
 }
@@ -0,0 +1,123 @@
+mod common;
+use tokio::time::Duration;
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::auth::Credentials;
+    use simple_pub_sub::client::Client;
+    use simple_pub_sub::server::ServerHandle;
+    use simple_pub_sub::token::{self, Claims};
+
+    const SECRET: &str = "/tmp/simple-pub-sub-test.secret";
+    const PRIVATE_KEY: &str = "/tmp/simple-pub-sub-test-ed25519.pem";
+    const PUBLIC_KEY: &str = "/tmp/simple-pub-sub-test-ed25519.pub.pem";
+
+    fn client(server: &ServerHandle, token: String) -> Client {
+        let mut client = common::client(server);
+        client.credentials(Credentials::Token(token));
+        client
+    }
+
+    fn claims(valid_for: Duration) -> Claims {
+        Claims::new(
+            "batch-job".to_string(),
+            valid_for,
+            vec!["jobs/+/result".to_string()],
+            vec!["jobs/#".to_string()],
+        )
+    }
+
+    async fn publish(client: &mut Client, topic: &str) -> bool {
+        client
+            .publish(topic.to_string(), b"test message".to_vec())
+            .await
+            .is_ok()
+    }
+
+    async fn topic_claims(server: &ServerHandle) {
+        let token = token::mint_with_secret(SECRET, &claims(Duration::from_secs(60))).unwrap();
+        let mut client = client(server, token);
+        client.connect().await.unwrap();
+        assert!(publish(&mut client, "jobs/42/result").await);
+        assert!(!publish(&mut client, "jobs/42/logs").await);
+        assert!(client.subscribe("jobs/42/logs".to_string()).await.is_ok());
+        assert!(client.read_message().await.is_ok());
+
+        // the patterns wider than the claims are denied.
+        for pattern in ["#", "+/42/logs"] {
+            client.subscribe(pattern.to_string()).await.unwrap();
+            assert!(client.read_message().await.is_err(), "{pattern}");
+        }
+        client.subscribe("jobs/+/logs".to_string()).await.unwrap();
+        assert!(client.read_message().await.is_ok());
+    }
+
+    async fn invalid_token_rejected(server: &ServerHandle) {
+        // signed with a different secret.
+        std::fs::write("/tmp/simple-pub-sub-test-other.secret", "other").unwrap();
+        let token = token::mint_with_secret(
+            "/tmp/simple-pub-sub-test-other.secret",
+            &claims(Duration::from_secs(60)),
+        )
+        .unwrap();
+        assert!(client(server, token).connect().await.is_err());
+
+        let mut expired = claims(Duration::ZERO);
+        expired.exp -= 10;
+        let token = token::mint_with_secret(SECRET, &expired).unwrap();
+        assert!(client(server, token).connect().await.is_err());
+    }
+
+    async fn expired_mid_session(server: &ServerHandle) {
+        let token = token::mint_with_secret(SECRET, &claims(Duration::from_secs(2))).unwrap();
+        let mut client = client(server, token);
+        client.connect().await.unwrap();
+        assert!(publish(&mut client, "jobs/1/result").await);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), client.read_message())
+            .await
+            .unwrap();
+        assert!(result.unwrap_err().to_string().contains("Token expired"));
+    }
+
+    #[tokio::test]
+    async fn test_hs256() {
+        std::fs::write(SECRET, "secret\n").unwrap();
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            token_secret: Some(SECRET.to_string()),
+            ..Default::default()
+        })
+        .await;
+        topic_claims(&server).await;
+        invalid_token_rejected(&server).await;
+        expired_mid_session(&server).await;
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_eddsa() {
+        let status = std::process::Command::new("openssl")
+            .args(["genpkey", "-algorithm", "ed25519", "-out", PRIVATE_KEY])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = std::process::Command::new("openssl")
+            .args(["pkey", "-in", PRIVATE_KEY, "-pubout", "-out", PUBLIC_KEY])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let server = common::start_server(simple_pub_sub::server::BrokerConfig {
+            token_public_key: Some(PUBLIC_KEY.to_string()),
+            ..Default::default()
+        })
+        .await;
+        let token =
+            token::mint_with_private_key(PRIVATE_KEY, &claims(Duration::from_secs(60))).unwrap();
+        let mut client = client(&server, token);
+        client.connect().await.unwrap();
+        assert!(publish(&mut client, "jobs/7/result").await);
+        server.shutdown().await.unwrap();
+    }
+}
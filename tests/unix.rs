@@ -1,31 +1,33 @@
+mod common;
 use tokio::time::{sleep, Duration};
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use log::info;
-    use simple_pub_sub::server::ServerTrait as _;
-
-    async fn start_serever(addr: String) {
-        println!("server started");
-        let server = simple_pub_sub::server::ServerType::Unix(simple_pub_sub::server::Unix {
-            path: addr.clone(),
+    use simple_pub_sub::server::{ServerHandle, ServerTrait as _};
+    use tracing::info;
+
+    /// starts the server on the socket path.
+    async fn start_server(path: &str) -> ServerHandle {
+        common::start(simple_pub_sub::server::Unix {
+            path: path.to_string(),
+            mode: None,
+            owner: None,
+            group: None,
             capacity: 1024,
-        });
-        let result = server.start().await;
-
-        info!("{:?}", result);
+            config: Default::default(),
+        })
+        .await
     }
 
     #[tokio::test]
     async fn client_publish() {
         // std::env::set_var("RUST_LOG", "trace");
-        env_logger::init();
+        tracing_subscriber::fmt::init();
 
         let path = "/tmp/sample2.sock".to_string();
 
-        let server = tokio::spawn(start_serever(path.clone()));
-        sleep(Duration::from_millis(500)).await;
+        let server = start_server(&path).await;
         let client_type = simple_pub_sub::client::PubSubUnixClient { path };
 
         // initialize the client.
@@ -43,8 +45,7 @@ mod tests {
             )
             .await;
         info!("{:?}", result);
-        std::mem::drop(server);
-        sleep(Duration::from_millis(500)).await;
+        server.shutdown().await.unwrap();
         assert!(result.is_ok());
     }
 
@@ -53,8 +54,7 @@ mod tests {
         // std::env::set_var("RUST_LOG", "trace");
         let path = "/tmp/sock1.sock".to_string();
 
-        let server = tokio::spawn(start_serever(path.clone()));
-        sleep(Duration::from_millis(500)).await;
+        let server = start_server(&path).await;
         let client_type = simple_pub_sub::client::PubSubUnixClient { path: path.clone() };
         let client_type_pub = simple_pub_sub::client::PubSubUnixClient { path };
 
@@ -85,7 +85,127 @@ mod tests {
         let msg = client_sub.read_message().await.unwrap();
         assert!(msg.topic == "abc");
 
-        std::mem::drop(server);
-        sleep(Duration::from_millis(500)).await;
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn peer_credentials() {
+        let path = "/tmp/sock-peercred.sock".to_string();
+        let server = simple_pub_sub::server::ServerType::Unix(simple_pub_sub::server::Unix {
+            path: path.clone(),
+            mode: Some(0o660),
+            owner: None,
+            group: None,
+            capacity: 1024,
+            config: Default::default(),
+        });
+        let server = server.bind().await.unwrap();
+        server.ready().await.unwrap();
+        assert_eq!(
+            server.local_addr(),
+            &simple_pub_sub::server::LocalAddr::Unix(path.clone().into())
+        );
+
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+
+        let mut client_sub =
+            simple_pub_sub::client::Client::new(simple_pub_sub::client::PubSubClient::Unix(
+                simple_pub_sub::client::PubSubUnixClient { path: path.clone() },
+            ));
+        let mut client_query =
+            simple_pub_sub::client::Client::new(simple_pub_sub::client::PubSubClient::Unix(
+                simple_pub_sub::client::PubSubUnixClient { path: path.clone() },
+            ));
+        client_sub.connect().await.unwrap();
+        client_query.connect().await.unwrap();
+        client_sub.subscribe("abc".to_string()).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        // the subscriber is identified by the user of the test process.
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let resp = client_query.query("abc".to_string()).await.unwrap();
+        assert!(resp.contains(&user.name));
+
+        // the socket is in use, a second broker must not take it over.
+        let second = simple_pub_sub::server::ServerType::Unix(simple_pub_sub::server::Unix {
+            path: path.clone(),
+            mode: None,
+            owner: None,
+            group: None,
+            capacity: 1024,
+            config: Default::default(),
+        });
+        assert!(second.start().await.is_err());
+        assert!(std::path::Path::new(&path).exists());
+
+        server.shutdown().await.unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn peer_credentials_connect() {
+        let path = "/tmp/sock-peercred-connect.sock".to_string();
+        let credentials = "/tmp/simple-pub-sub-unix-test.passwd";
+        let _ = std::fs::remove_file(credentials);
+        simple_pub_sub::auth::add_user(credentials, "alice", "secret").unwrap();
+        let server = simple_pub_sub::server::ServerType::Unix(simple_pub_sub::server::Unix {
+            path: path.clone(),
+            mode: None,
+            owner: None,
+            group: None,
+            capacity: 1024,
+            config: simple_pub_sub::server::BrokerConfig {
+                credentials: Some(credentials.to_string()),
+                ..Default::default()
+            },
+        });
+        let server = server.bind().await.unwrap();
+        server.ready().await.unwrap();
+
+        // the connect without credentials keeps the peer credentials identity.
+        let mut client =
+            simple_pub_sub::client::Client::new(simple_pub_sub::client::PubSubClient::Unix(
+                simple_pub_sub::client::PubSubUnixClient { path: path.clone() },
+            ));
+        client.persistent_session("unix-worker".to_string());
+        client.connect().await.unwrap();
+        client.subscribe("abc".to_string()).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+        let mut client_query =
+            simple_pub_sub::client::Client::new(simple_pub_sub::client::PubSubClient::Unix(
+                simple_pub_sub::client::PubSubUnixClient { path: path.clone() },
+            ));
+        client_query.connect().await.unwrap();
+        let resp = client_query.query("abc".to_string()).await.unwrap();
+        assert!(resp.contains(&user.name));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale_socket_removed() {
+        let path = "/tmp/sock-stale.sock".to_string();
+        let _ = std::fs::remove_file(&path);
+        // a socket file left behind without a listener.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(std::path::Path::new(&path).exists());
+
+        let server = start_server(&path).await;
+        let mut client =
+            simple_pub_sub::client::Client::new(simple_pub_sub::client::PubSubClient::Unix(
+                simple_pub_sub::client::PubSubUnixClient { path },
+            ));
+        client.connect().await.unwrap();
+        let result = client.publish("abc".to_string(), b"test".to_vec()).await;
+        assert!(result.is_ok());
+        server.shutdown().await.unwrap();
     }
 }
@@ -0,0 +1,109 @@
+mod common;
+use tokio::time::{sleep, Duration};
+
+fn create_tls_certs() {
+    let op = std::process::Command::new("openssl")
+        .args([
+            "req",
+            "-x509",
+            "-newkey",
+            "rsa:2048",
+            "-keyout",
+            "certs/ws-key.pem",
+            "-out",
+            "certs/ws-cert.pem",
+            "-days",
+            "365",
+            "-nodes",
+            "-subj",
+            "/CN=localhost",
+            "-addext",
+            "subjectAltName=DNS:localhost",
+            "-addext",
+            "basicConstraints=critical,CA:FALSE",
+        ])
+        .output();
+    println!("certs created: {:?}", op);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use simple_pub_sub::client::{Client, PubSubClient, PubSubTcpClient};
+    use simple_pub_sub::server::{ServerHandle, ServerType, Tcp};
+
+    fn client(server: &ServerHandle, cert: Option<String>) -> Client {
+        Client::new(PubSubClient::WebSocket(PubSubTcpClient {
+            server: "localhost".to_string(),
+            cert,
+            ..common::tcp_client(server)
+        }))
+    }
+
+    async fn client_publish(server: &ServerHandle, cert: Option<String>) {
+        let mut client = client(server, cert);
+        client.connect().await.unwrap();
+        let result = client
+            .publish(
+                "abc".to_string(),
+                "test message".to_string().into_bytes().to_vec(),
+            )
+            .await;
+        assert!(result.is_ok());
+    }
+
+    async fn client_subscribe(server: &ServerHandle, cert: Option<String>) {
+        let mut client_sub = client(server, cert.clone());
+        let mut client_pub = client(server, cert);
+        client_sub.connect().await.unwrap();
+        client_pub.connect().await.unwrap();
+
+        client_sub.subscribe("abc".to_string()).await.unwrap();
+        sleep(Duration::from_millis(200)).await;
+
+        client_pub
+            .publish(
+                "abc".to_string(),
+                "test message".to_string().into_bytes().to_vec(),
+            )
+            .await
+            .unwrap();
+
+        let msg = client_sub.read_message().await.unwrap();
+        // skip the subscription ack.
+        let msg = if msg.header.pkt_type == simple_pub_sub::PktType::PUBLISH {
+            msg
+        } else {
+            client_sub.read_message().await.unwrap()
+        };
+        assert_eq!(msg.topic, "abc");
+        assert_eq!(msg.message, b"test message");
+
+        let resp = client_pub.query("abc".to_string()).await.unwrap();
+        assert!(resp.contains("\"1\""));
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let server = common::start(ServerType::WebSocket(common::tcp(Default::default()))).await;
+        client_publish(&server, None).await;
+        client_subscribe(&server, None).await;
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_tls() {
+        create_tls_certs();
+        let cert = Some("certs/ws-cert.pem".to_string());
+        let server = common::start(ServerType::WebSocket(Tcp {
+            cert: cert.clone(),
+            key: Some("certs/ws-key.pem".to_string()),
+            ..common::tcp(Default::default())
+        }))
+        .await;
+        client_publish(&server, cert.clone()).await;
+        client_subscribe(&server, cert).await;
+        server.shutdown().await.unwrap();
+    }
+}